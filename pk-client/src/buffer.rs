@@ -1,60 +1,262 @@
 use pk_common::piece_table::*;
 use pk_common::protocol;
 use pk_common::Direction;
+use crate::search::Query;
 use std::path::PathBuf;
+use std::ops::Range;
+
+// another version of a buffer's file that the buffer is being compared with
+pub struct BufferDiff {
+    // where the other version came from, like "server" or "disk"
+    pub other_name: String,
+    pub other: PieceTable,
+    // old lines are in `other`, new lines are in the buffer
+    pub hunks: Vec<pk_common::diff::Hunk>,
+    // the action id and length of the text the hunks were found in. Typing in insert mode changes the
+    // length without starting a new action
+    diffed: (usize, usize)
+}
+
+// a conflict left in a buffer by a merge, held by anchors so that it stays put while the rest of the
+// buffer is edited. Anchors move along with text inserted right at them, so to keep text typed just
+// outside the conflict out of it, `range` is anchored on its first and last characters. Sections are
+// anchored on the marker line ending before them and the marker after them, so that text typed at
+// either end of a section is part of it
+struct MergeHunk {
+    range: (Anchor, Anchor),
+    ours: (Anchor, Anchor),
+    base: (Anchor, Anchor),
+    theirs: (Anchor, Anchor)
+}
 
 pub struct Buffer {
     pub text: PieceTable,
     pub server_name: String,
     pub path: PathBuf,
+    pub workspace: Option<crate::editor_state::Workspace>,
     pub file_id: protocol::FileId,
     pub format: protocol::TextFormat,
+    pub view: protocol::FileView,
+    // set if the file is only partially loaded, along with how many lines have been loaded so far
+    pub window: Option<protocol::FileWindow>,
+    pub loaded_lines: usize,
+    pub loading_window: bool,
     pub version: usize,
     pub currently_in_conflict: bool,
+    // write the file to disk once the server has the current text
+    pub write_requested: bool,
     pub cursor_index: usize,
+    // more cursors that edits happen at along with `cursor_index`, in no particular order. They move along
+    // with edits, and one is gone once its text is deleted
+    cursors: Vec<Anchor>,
     pub highlights: Option<Vec<crate::piece_table_render::Highlight>>,
     pub last_highlighted_action_id: usize,
-    pub current_query: Option<String>,
-    pub last_char_query: Option<(char, bool, Direction)>
+    // what the highlighter found out about the text, and the action id and length of the text it looked at
+    syntax: Option<(usize, usize, crate::syntax_highlight::Syntax)>,
+    pub query: Option<Query>,
+    // the start of every match of `query` and the action id and length of the text they were found in
+    query_matches: Option<(usize, usize, Vec<usize>)>,
+    pub last_char_query: Option<(char, bool, Direction)>,
+    // named positions that move along with the text as it's edited. `<` and `>` are the start and end of
+    // the last visual selection, and `'` is where the cursor was before the last jump
+    pub marks: std::collections::HashMap<char, Anchor>,
+    // the selection in this buffer the last time it was drawn in the active pane, for other clients to see
+    pub selection: Option<std::ops::Range<usize>>,
+    // where other clients editing the same file are
+    pub remote_cursors: Vec<protocol::RemoteCursor>,
+    // the client that has claimed the file on the server, possibly this one
+    pub lock: Option<protocol::FileLock>,
+    // the server version being merged with while there are conflicts left to resolve
+    pub merging: Option<usize>,
+    merge_hunks: Vec<MergeHunk>,
+    pub diff: Option<BufferDiff>
 }
 
 impl Buffer {
     pub fn with_text(s: &str) -> Buffer {
         Buffer {
             text: PieceTable::with_text(s),
-            version: 0, file_id: protocol::FileId(0), cursor_index: 0,
+            version: 0, file_id: protocol::FileId(0), cursor_index: 0, cursors: Vec::new(),
             server_name: "".into(),
-            path: "".into(), currently_in_conflict: false, format: protocol::TextFormat::default(),
+            path: "".into(), workspace: None, currently_in_conflict: false, write_requested: false, format: protocol::TextFormat::default(),
+            view: protocol::FileView::Text, window: None, loaded_lines: 0, loading_window: false,
             highlights: None,
             last_highlighted_action_id: 0,
-            current_query: None,
-            last_char_query: None
+            syntax: None,
+            query: None,
+            query_matches: None,
+            last_char_query: None,
+            marks: std::collections::HashMap::new(),
+            selection: None,
+            remote_cursors: Vec::new(),
+            lock: None,
+            merging: None,
+            merge_hunks: Vec::new(),
+            diff: None
         }
     }
 
     pub fn from_server(server_name: String, path: PathBuf, file_id: protocol::FileId, contents: String, version: usize, format: protocol::TextFormat) -> Buffer {
         Buffer {
             text: PieceTable::with_text(&contents),
-            file_id, version, cursor_index: 0,
-            server_name, path,
-            currently_in_conflict: false, format,
+            file_id, version, cursor_index: 0, cursors: Vec::new(),
+            server_name, path, workspace: None,
+            currently_in_conflict: false, write_requested: false, format,
+            view: protocol::FileView::Text, window: None, loaded_lines: 0, loading_window: false,
             highlights: None,
             last_highlighted_action_id: 0,
-            current_query: None,
-            last_char_query: None
+            syntax: None,
+            query: None,
+            query_matches: None,
+            last_char_query: None,
+            marks: std::collections::HashMap::new(),
+            selection: None,
+            remote_cursors: Vec::new(),
+            lock: None,
+            merging: None,
+            merge_hunks: Vec::new(),
+            diff: None
+        }
+    }
+
+    // the path to show the user, relative to the root of the buffer's workspace if it's in one
+    pub fn display_path(&self) -> std::borrow::Cow<'_, str> {
+        self.workspace.as_ref()
+            .and_then(|ws| self.path.strip_prefix(&ws.root).ok())
+            .unwrap_or(&self.path)
+            .to_string_lossy()
+    }
+
+    // replace all the text in a way that can be undone. Only the lines that changed are edited, so
+    // marks and cursors in the rest of the text stay where they are
+    pub fn replace_text(&mut self, s: &str) {
+        let old = self.text.text();
+        let (old_lines, new_lines) = (pk_common::diff::split_lines(&old), pk_common::diff::split_lines(s));
+        let offsets = |lines: &[&str]| lines.iter()
+            .scan(0, |ix, l| { let start = *ix; *ix += l.len(); Some(start) })
+            .chain(std::iter::once(lines.iter().map(|l| l.len()).sum()))
+            .collect::<Vec<usize>>();
+        let (old_starts, new_starts) = (offsets(&old_lines), offsets(&new_lines));
+        // go from the end so the offsets of the hunks still to apply don't move
+        for h in pk_common::diff::diff(&old_lines, &new_lines).into_iter().rev() {
+            let (start, end) = (old_starts[h.old.start], old_starts[h.old.end]);
+            let new = &s[new_starts[h.new.start]..new_starts[h.new.end]];
+            if end > start {
+                self.text.delete_range(start, end);
+            }
+            self.text.insert_range(new, start);
+            if self.cursor_index >= end {
+                self.cursor_index = self.cursor_index + new.len() - (end - start);
+            } else if self.cursor_index > start {
+                self.cursor_index = start + (self.cursor_index - start).min(new.len().saturating_sub(1));
+            }
+        }
+        self.cursor_index = self.cursor_index.min(self.text.len());
+    }
+
+    // replace the text with a merge with the server's `server_version`, with each conflict written out
+    // between markers for the user to resolve
+    pub fn start_merge(&mut self, chunks: &[pk_common::diff::MergeChunk], server_version: usize) {
+        let (text, conflicts) = pk_common::diff::with_markers(chunks, "local", "server");
+        self.replace_text(&text);
+        self.clear_merge_hunks();
+        for c in conflicts {
+            let mut anchor = |start: usize, end: usize| (self.text.anchor(start), self.text.anchor(end));
+            let hunk = MergeHunk {
+                range: anchor(c.range.start, c.range.end - 1),
+                ours: anchor(c.ours.start - 1, c.ours.end),
+                base: anchor(c.base.start - 1, c.base.end),
+                theirs: anchor(c.theirs.start - 1, c.theirs.end)
+            };
+            self.merge_hunks.push(hunk);
+        }
+        self.merging = Some(server_version);
+    }
+
+    fn clear_merge_hunks(&mut self) {
+        for h in self.merge_hunks.drain(..) {
+            for (a, b) in [h.range, h.ours, h.base, h.theirs] {
+                self.text.remove_anchor(a);
+                self.text.remove_anchor(b);
+            }
+        }
+    }
+
+    // the conflicts left in the buffer by a merge, in order. Conflicts that have had any of their markers
+    // edited away count as resolved by hand
+    pub fn merge_conflicts(&self) -> Vec<pk_common::diff::MarkedConflict> {
+        let at = |a: Anchor| self.text.anchor_index(a);
+        let section = |(a, b): (Anchor, Anchor)| Some(at(a)? + 1..at(b)?);
+        let mut conflicts: Vec<_> = self.merge_hunks.iter().filter_map(|h| Some(pk_common::diff::MarkedConflict {
+            range: at(h.range.0)?..at(h.range.1)? + 1,
+            ours: section(h.ours)?, base: section(h.base)?, theirs: section(h.theirs)?
+        })).collect();
+        conflicts.sort_by_key(|c| c.range.start);
+        conflicts
+    }
+
+    // once every conflict is resolved, the buffer becomes the next version after the one it was merged
+    // with and can be synced again. Returns true if the merge is done
+    pub fn finish_merge(&mut self) -> bool {
+        match self.merging {
+            Some(v) if self.merge_conflicts().is_empty() => {
+                self.clear_merge_hunks();
+                self.version = v;
+                self.merging = None;
+                self.currently_in_conflict = false;
+                true
+            },
+            _ => false
+        }
+    }
+
+    // start comparing the buffer with `other`
+    pub fn start_diff(&mut self, other_name: String, other: &str) {
+        let other = PieceTable::with_text(other);
+        self.diff = Some(BufferDiff {
+            other_name,
+            hunks: pk_common::diff::diff_tables(&other, &self.text),
+            other,
+            diffed: (self.text.most_recent_action_id(), self.text.len())
+        });
+    }
+
+    // recompute the diff if the buffer has changed since it was last computed
+    pub fn update_diff(&mut self) {
+        if let Some(d) = self.diff.as_mut() {
+            let key = (self.text.most_recent_action_id(), self.text.len());
+            if d.diffed != key {
+                d.hunks = pk_common::diff::diff_tables(&d.other, &self.text);
+                d.diffed = key;
+            }
         }
     }
 
+    // hex views and partially loaded files can't be edited
+    pub fn read_only(&self) -> bool {
+        self.view != protocol::FileView::Text || self.window.is_some()
+    }
+
+    // indentation settings for this file's type take precedence over the client configuration
+    pub fn tabstop(&self, config: &crate::config::Config) -> usize {
+        self.format.settings.tabstop.unwrap_or(config.tabstop)
+    }
+
+    pub fn softtab(&self, config: &crate::config::Config) -> bool {
+        self.format.settings.soft_tab.unwrap_or(config.softtab)
+    }
+
     pub fn sense_indent_level(&self, at: usize, config: &crate::config::Config) -> usize {
         let mut i = self.current_start_of_line(at);
         let mut indent_level = 0;
-        if config.softtab {
+        let tabstop = self.tabstop(config);
+        if self.softtab(config) {
             let mut space_counter = 0;
             loop {
                 match self.text.char_at(i) {
                     Some(' ') => {
                         space_counter += 1;
-                        if space_counter == 4 {
+                        if space_counter == tabstop {
                             space_counter = 0;
                             indent_level += 1;
                         }
@@ -78,11 +280,11 @@ impl Buffer {
 
     pub fn indent_with_mutator(&mut self, ins: &mut crate::piece_table::TableMutator, count: usize, config: &crate::config::Config) -> usize {
         if count == 0 { return 0; }
-        if config.softtab {
-            for _ in 0..(count*config.tabstop) {
+        if self.softtab(config) {
+            for _ in 0..(count*self.tabstop(config)) {
                 ins.push_char(&mut self.text, ' ');
             }
-            count * config.tabstop
+            count * self.tabstop(config)
         } else {
             for _ in 0..count {
                 ins.push_char(&mut self.text, '\t');
@@ -93,13 +295,13 @@ impl Buffer {
 
     pub fn indent(&mut self, at: usize, count: usize, config: &crate::config::Config) -> usize {
         if count == 0 { return 0; }
-        if config.softtab {
+        if self.softtab(config) {
             let mut ins = self.text.insert_mutator(at);
-            for _ in 0..(count*config.tabstop) {
+            for _ in 0..(count*self.tabstop(config)) {
                 ins.push_char(&mut self.text, ' ');
             }
             ins.finish(&mut self.text);
-            count * config.tabstop
+            count * self.tabstop(config)
         } else {
             for _ in 0..count {
                 self.text.insert_range("\t", at);
@@ -110,8 +312,8 @@ impl Buffer {
 
     pub fn undent(&mut self, at: usize, count: usize, config: &crate::config::Config) {
         if count == 0 { return; }
-        if config.softtab { 
-            let mut spaces_left = count * config.tabstop;
+        if self.softtab(config) { 
+            let mut spaces_left = count * self.tabstop(config);
             loop {
                 match self.text.char_at(at) {
                     Some(c) if c.is_whitespace() => {
@@ -120,7 +322,7 @@ impl Buffer {
                     }
                     Some('\t') => {
                         self.text.delete_range(at, at+1);
-                        spaces_left -= config.tabstop;
+                        spaces_left -= self.tabstop(config);
                     }
                     Some(_) | None => break
                 }
@@ -170,73 +372,127 @@ impl Buffer {
         ln
     }
 
+    // the index of the start of line `line`, or the end of the text if there aren't that many lines
+    pub fn line_start_index(&self, line: usize) -> usize {
+        let mut ix = 0;
+        for _ in 0..line {
+            match self.text.index_of('\n', ix) {
+                Some(nl) => ix = nl + 1,
+                None => return self.text.len()
+            }
+        }
+        ix
+    }
+
+    // the line that the end of the text is on, not counting an empty line after a final newline
+    pub fn last_line(&self) -> usize {
+        self.line_for_index(self.text.len().saturating_sub(1))
+    }
+
     pub fn last_line_index(&self, at: usize) -> usize {
         self.text.last_index_of('\n', at)
             .and_then(|eoll| self.text.last_index_of('\n', eoll)).map(|i| i+1)
             .unwrap_or(0)
     }
-    
-    pub fn set_query(&mut self, s: String) {
-        self.current_query = Some(s);
+
+    // the first character on the line `at` is on that isn't a space or tab
+    pub fn first_non_blank(&self, at: usize) -> usize {
+        let start = self.current_start_of_line(at);
+        start + self.text.chars(start).take_while(|c| *c == ' ' || *c == '\t').count()
+    }
+
+    pub fn set_mark(&mut self, name: char, index: usize) {
+        let a = self.text.anchor(index);
+        if let Some(old) = self.marks.insert(name, a) {
+            self.text.remove_anchor(old);
+        }
+    }
+
+    // where a mark is, unless it was never set or its text has been deleted
+    pub fn mark(&self, name: char) -> Option<usize> {
+        self.marks.get(&name).and_then(|a| self.text.anchor_index(*a))
+    }
+
+    // the part of each line in `lines` that's in `columns`, which is empty at the end of lines too short
+    // to reach them
+    pub fn block_ranges(&self, lines: Range<usize>, columns: Range<usize>) -> Vec<Range<usize>> {
+        lines.map(|ln| {
+            let start = self.line_start_index(ln);
+            let end = self.text.index_of('\n', start).unwrap_or(self.text.len());
+            (start + columns.start).min(end) .. (start + columns.end).min(end)
+        }).collect()
     }
     
-    pub fn next_query_index(&self, from: usize, direction: crate::Direction, mut wrap: bool) -> Option<usize> {
-        use crate::Direction::*;
-        if self.current_query.is_none() { return None; }
-        let mut chrs = self.text.chars(from);
-        let mut qury: Vec<char> = self.current_query.as_ref().unwrap().chars().collect();
-        if direction == Backward {
-            qury.reverse();
+    // where the cursors other than the main one are now
+    pub fn cursors(&self) -> Vec<usize> {
+        self.cursors.iter().filter_map(|a| self.text.anchor_index(*a)).collect()
+    }
+
+    pub fn has_cursors(&self) -> bool {
+        self.cursors.iter().any(|a| self.text.anchor_index(*a).is_some())
+    }
+
+    // every cursor, including the main one, in order
+    pub fn all_cursors(&self) -> Vec<usize> {
+        let mut all: Vec<usize> = self.cursors().into_iter().map(|c| c.min(self.text.len())).collect();
+        all.push(self.cursor_index);
+        all.sort_unstable();
+        all.dedup();
+        all
+    }
+
+    pub fn add_cursor(&mut self, index: usize) {
+        if index != self.cursor_index && !self.cursors().contains(&index) {
+            let a = self.text.anchor(index);
+            self.cursors.push(a);
         }
-        let mut qury_ix = 0;
-        let mut chrs_ix = from;
-        let mut qury_match = None;
-        //dbg!(&qury, from, direction);
-        loop {
-            //dbg!(&qury, qury_ix, chrs_ix, qury_match);
-            match match direction {
-                Forward => chrs.next(),
-                Backward => chrs.next_back()
-            } {
-                Some(c) if qury[qury_ix] == c => {
-                    //println!("found {} at {}/{}", c, qury_ix, chrs_ix);
-                    if qury_ix == 0 {
-                        qury_match = Some(chrs_ix);
-                    }
-                    qury_ix += 1;
-                    if qury_ix == qury.len() {
-                        return match direction {
-                            Forward => qury_match,
-                            Backward => Some(chrs_ix)
-                        };
-                    }
-                },
-                Some(_) => {
-                    //println!("reset at {}", chrs_ix);
-                    qury_ix = 0;
-                    qury_match = None;
-                },
-                None => {
-                    if wrap {
-                        wrap = false;
-                        chrs_ix = match direction {
-                            Forward => 0,
-                            Backward => from
-                        };
-                        chrs = self.text.chars(from);
-                    } else {
-                        break;
-                    }
-                }
-            }
-            chrs_ix = match direction {
-                Forward => chrs_ix + 1,
-                Backward => if chrs_ix == 0 {
-                    if wrap { from } else { return None; }
-                } else { chrs_ix - 1 },
-            };
+    }
+
+    pub fn remove_cursor(&mut self, index: usize) {
+        let text = &mut self.text;
+        self.cursors.retain(|a| match text.anchor_index(*a) {
+            Some(ix) if ix != index => true,
+            _ => { text.remove_anchor(*a); false }
+        });
+    }
+
+    pub fn clear_cursors(&mut self) {
+        for a in self.cursors.drain(..) {
+            self.text.remove_anchor(a);
         }
-        None
+    }
+
+    pub fn set_syntax(&mut self, syntax: crate::syntax_highlight::Syntax) {
+        self.syntax = Some((self.text.most_recent_action_id(), self.text.len(), syntax));
+    }
+
+    // the syntax of the text if it hasn't changed since it was last highlighted
+    pub fn syntax(&self) -> Option<&crate::syntax_highlight::Syntax> {
+        let key = (self.text.most_recent_action_id(), self.text.len());
+        self.syntax.as_ref().filter(|(id, len, _)| (*id, *len) == key).map(|(_, _, s)| s)
+    }
+
+    pub fn set_query(&mut self, pattern: &str) -> Result<(), regex::Error> {
+        self.query = Some(Query::new(pattern)?);
+        self.query_matches = None;
+        Ok(())
+    }
+
+    pub fn next_query_index(&self, from: usize, direction: crate::Direction, wrap: bool) -> Option<usize> {
+        self.query.as_ref()?.next_match(&self.text, from, direction, wrap).map(|m| m.start)
+    }
+
+    // how many matches of the query start at or before the cursor, and how many there are in total
+    pub fn query_position(&mut self) -> Option<(usize, usize)> {
+        let query = self.query.as_ref()?;
+        let key = (self.text.most_recent_action_id(), self.text.len());
+        if self.query_matches.as_ref().map_or(true, |(id, len, _)| (*id, *len) != key) {
+            let mut starts = Vec::new();
+            query.for_each_match(&self.text, 0, self.text.len(), |m| { starts.push(m.start); true });
+            self.query_matches = Some((key.0, key.1, starts));
+        }
+        let starts = &self.query_matches.as_ref().unwrap().2;
+        Some((starts.partition_point(|s| *s <= self.cursor_index), starts.len()))
     }
 }
 
@@ -248,7 +504,7 @@ mod test {
         let src = "abc def abc abl abc pqr abc vwx yz\n";
         let qry = "abc";
         let mut buf = Buffer::with_text(src);
-        buf.set_query(qry.into());
+        buf.set_query(qry).unwrap();
         let mut ix = 0;
         loop {
             println!("cr = {}", &src[ix..].escape_debug());
@@ -271,7 +527,7 @@ mod test {
         let src = "abc def abc abl abc pqr abc vwx yz\n";
         let qry = "abc";
         let mut buf = Buffer::with_text(src);
-        buf.set_query(qry.into());
+        buf.set_query(qry).unwrap();
         let mut ix = src.len()-1;
         loop {
             println!("cr = {}", &src[..ix].escape_debug());
@@ -288,5 +544,114 @@ mod test {
             }
         }
     }
-}
 
+    #[test]
+    fn search_regex() {
+        let mut buf = Buffer::with_text("Foo bar\nfoo baz\nfood\n");
+        buf.set_query(r"foo\b").unwrap();
+        assert_eq!(buf.next_query_index(1, crate::Direction::Forward, false), Some(8));
+        assert_eq!(buf.next_query_index(9, crate::Direction::Forward, true), Some(0));
+        assert_eq!(buf.next_query_index(8, crate::Direction::Backward, false), Some(0));
+        buf.set_query("Foo").unwrap();
+        assert_eq!(buf.next_query_index(1, crate::Direction::Forward, true), Some(0));
+        buf.set_query("^ba|d$").unwrap();
+        assert_eq!(buf.next_query_index(0, crate::Direction::Forward, false), Some(19));
+        buf.cursor_index = 9;
+        buf.set_query("o+").unwrap();
+        assert_eq!(buf.query_position(), Some((2, 3)));
+    }
+
+    #[test]
+    fn marks_follow_edits() {
+        let mut buf = Buffer::with_text("abc\n  def\nghi\n");
+        buf.set_mark('a', 7);
+        buf.text.insert_range("xyz\n", 0);
+        assert_eq!(buf.mark('a'), Some(11));
+        assert_eq!(buf.first_non_blank(11), 10);
+        buf.text.delete_range(10, 13);
+        assert_eq!(buf.mark('a'), None);
+    }
+
+    #[test]
+    fn all_cursors() {
+        let mut buf = Buffer::with_text("abc\ndef");
+        buf.cursor_index = 4;
+        buf.add_cursor(6);
+        buf.add_cursor(0);
+        buf.add_cursor(4);
+        buf.add_cursor(6);
+        assert_eq!(buf.cursors(), vec![6, 0]);
+        assert_eq!(buf.all_cursors(), vec![0, 4, 6]);
+        // cursors move along with edits, and go away with their text
+        buf.text.insert_range("xy", 5);
+        assert_eq!(buf.cursors(), vec![8, 0]);
+        buf.text.delete_range(0, 1);
+        assert_eq!(buf.cursors(), vec![7]);
+        assert!(buf.has_cursors());
+        buf.remove_cursor(7);
+        assert!(!buf.has_cursors());
+        buf.add_cursor(1);
+        buf.clear_cursors();
+        assert_eq!(buf.cursors(), vec![]);
+        assert!(buf.text.anchors_in(0..buf.text.len() + 1).is_empty());
+    }
+
+    #[test]
+    fn replace_text_keeps_marks() {
+        let mut buf = Buffer::with_text("one\ntwo\nthree\nfour\n");
+        buf.set_mark('a', 1);
+        buf.set_mark('b', 5);
+        buf.set_mark('c', 15);
+        buf.add_cursor(9);
+        buf.cursor_index = 16;
+        buf.replace_text("zero\none\n2\nthree\nfour\n");
+        assert_eq!(buf.text.text(), "zero\none\n2\nthree\nfour\n");
+        // only the changed line loses its marks
+        assert_eq!(buf.mark('a'), Some(6));
+        assert_eq!(buf.mark('b'), None);
+        assert_eq!(buf.mark('c'), Some(18));
+        assert_eq!(buf.cursors(), vec![12]);
+        assert_eq!(buf.cursor_index, 19);
+        // a cursor on a changed line stays on it
+        buf.cursor_index = 10;
+        buf.replace_text("zero\none\n\nthree\nfour\n");
+        assert_eq!(buf.cursor_index, 9);
+    }
+
+    #[test]
+    fn finish_merge() {
+        use pk_common::diff::merge3;
+        let mut buf = Buffer::with_text("one\ntwo\nthree\n");
+        assert!(!buf.finish_merge(), "not merging");
+        buf.currently_in_conflict = true;
+        buf.start_merge(&merge3("one\ntwo\nthree\n", "one\n2\nthree\n", "one\nTWO\nthree\n"), 3);
+        let c = buf.merge_conflicts();
+        assert_eq!(c.len(), 1);
+        assert_eq!(buf.text.copy_range(c[0].ours.start, c[0].ours.end), "2\n");
+        assert_eq!(buf.text.copy_range(c[0].base.start, c[0].base.end), "two\n");
+        assert_eq!(buf.text.copy_range(c[0].theirs.start, c[0].theirs.end), "TWO\n");
+        assert!(!buf.finish_merge());
+
+        // text typed at the edges of a section is part of it, and right outside the conflict isn't
+        buf.text.insert_range("x", c[0].ours.end);
+        buf.text.insert_range("y", c[0].ours.start);
+        buf.text.insert_range("z", c[0].range.end + 2);
+        let c = buf.merge_conflicts();
+        assert_eq!(buf.text.copy_range(c[0].ours.start, c[0].ours.end), "y2\nx");
+        assert_eq!(buf.text.copy_range(c[0].range.end, buf.text.len()), "zthree\n");
+
+        // taking out a marker by hand resolves the conflict
+        let marker_line = buf.text.copy_range(c[0].range.start, c[0].ours.start);
+        assert_eq!(marker_line, "<<<<<<< local\n");
+        buf.text.delete_range(c[0].range.start, c[0].ours.start);
+        assert!(buf.merge_conflicts().is_empty());
+        assert!(buf.finish_merge());
+        assert_eq!((buf.version, buf.merging, buf.currently_in_conflict), (3, None, false));
+    }
+
+    #[test]
+    fn block_ranges() {
+        let buf = Buffer::with_text("abcdef\nab\n\nabcd");
+        assert_eq!(buf.block_ranges(0..4, 1..3), vec![1..3, 8..9, 10..10, 12..14]);
+    }
+}
@@ -44,7 +44,11 @@ pub enum Command {
     Leader(char),
     Viewport(ViewportMotion),
     ChangeMode(ModeTag),
-    VisualSwitchSides
+    VisualSwitchSides,
+    // `q` followed by a register starts recording a macro into it
+    Record(char),
+    PlayMacro { register: char, count: usize },
+    SetMark(char)
 }
 
 impl Command {
@@ -81,10 +85,19 @@ impl Command {
                 op_count: 1, target_register: '"'
             }),
 
-            Some('v') => return Ok(Command::ChangeMode(ModeTag::Visual)),
+            Some('v') => return Ok(Command::ChangeMode(ModeTag::Visual(VisualKind::Char))),
+            Some('V') => return Ok(Command::ChangeMode(ModeTag::Visual(VisualKind::Line))),
             Some(':') => return Ok(Command::ChangeMode(ModeTag::Command)),
             Some('/') => return Ok(Command::ChangeMode(ModeTag::Search(Direction::Forward))),
             Some('?') => return Ok(Command::ChangeMode(ModeTag::Search(Direction::Backward))),
+            Some('m') if visual_mode.is_none() => {
+                schars.next();
+                return Ok(Command::SetMark(schars.next().ok_or(Error::IncompleteCommand)?))
+            },
+            Some('q') if visual_mode.is_none() => {
+                schars.next();
+                return Ok(Command::Record(schars.next().ok_or(Error::IncompleteCommand)?))
+            },
             Some('r') => {
                 schars.next();
                 return Ok(Command::Edit {
@@ -106,6 +119,13 @@ impl Command {
             Some('u') => return Ok(Command::Undo { count: opcount.unwrap_or(1) }),
             Some('U') => return Ok(Command::Redo { count: opcount.unwrap_or(1) }),
             Some('J') => return Ok(Command::JoinLine { count: opcount.unwrap_or(1) }),
+            Some('@') => {
+                schars.next();
+                return Ok(Command::PlayMacro {
+                    register: schars.next().ok_or(Error::IncompleteCommand)?,
+                    count: opcount.unwrap_or(1)
+                })
+            },
             Some('d') => Some(Operator::Delete),
             Some('c') => Some(Operator::Change),
             Some('y') => Some(Operator::Yank),
@@ -149,6 +169,12 @@ impl Command {
             Some(mo) if op.is_some() => mo,
             Some(_) | None => Motion::parse(&mut schars, opchar, s)?
         };
+        // `5G` goes to line 5 rather than going to the last line 5 times, and `50%` halfway through the text
+        match (mo.mo, opcount) {
+            (MotionType::ToLine(_), Some(_)) => mo.mo = MotionType::ToLine(opcount),
+            (MotionType::MatchingBracket, Some(n)) => mo.mo = MotionType::FilePercent(n),
+            _ => {}
+        }
         if op.is_some() {
             Ok(Command::Edit {
                 op: op.unwrap(),
@@ -173,7 +199,25 @@ impl Command {
         }
     }
      
+    // whether running this command could change the text of the current buffer
+    pub fn modifies_text(&self) -> bool {
+        match self {
+            Command::Put { .. } | Command::Undo { .. } | Command::Redo { .. } | Command::JoinLine { .. }
+                | Command::Repeat { .. } => true,
+            Command::Edit { op, .. } => match op {
+                Operator::Yank => false,
+                Operator::MoveAndEnterMode(mode) => *mode == ModeTag::Insert,
+                _ => true
+            },
+            Command::ChangeMode(mode) => *mode == ModeTag::Insert,
+            _ => false
+        }
+    }
+
     pub fn execute(&self, state: &mut editor_state::EditorState, client: PClientState) -> Result<Option<ModeTag>, Error> {
+        if self.modifies_text() && state.current_buffer().map_or(false, |b| b.read_only()) {
+            return Err(Error::InvalidCommand("buffer is read only".into()));
+        }
         if let Command::Repeat { count } = self {
             let mut cmd = state.last_command.ok_or_else(|| Error::InvalidCommand("no previous command".into()))?;
             dbg!(cmd);
@@ -203,32 +247,60 @@ impl Command {
                 return cmd.execute(state, client);
             }
         }
+        if self.at_each_cursor() && state.current_buffer().map_or(false, |b| b.has_cursors()) {
+            // the jump list only gets where the main cursor was, once for the whole command
+            if let Command::Move(mo) = self {
+                if mo.mo.is_jump() { state.push_jump(); }
+            }
+            return state.for_each_cursor(|state| self.execute_at_cursor(state, client.clone(), false));
+        }
+        self.execute_at_cursor(state, client, true)
+    }
+
+    // commands that happen at every cursor when there's more than one. A visual selection only belongs to
+    // the main cursor, so operators on one don't
+    fn at_each_cursor(&self) -> bool {
+        match self {
+            Command::Edit { mo: Motion { mo: MotionType::Passthrough(..), .. }, .. } => false,
+            Command::Move(_) | Command::Put { .. } | Command::JoinLine { .. } | Command::Edit { .. } => true,
+            _ => false
+        }
+    }
+
+    // `jump` is false when the jump list has already been taken care of
+    fn execute_at_cursor(&self, state: &mut editor_state::EditorState, client: PClientState, jump: bool) -> Result<Option<ModeTag>, Error> {
+        if let Command::Move(mo) | Command::Edit { mo, .. } = self {
+            if mo.mo.uses_syntax() { state.refresh_syntax(); }
+        }
         match self {
             Command::Move(mo) => {
-                if let Some(buf) = state.current_buffer_mut() {
-                    let Range { start: _, end } = mo.range(buf, buf.cursor_index, 1);
-                    buf.cursor_index = end;
+                let target = on_screen(state, mo);
+                if let Some(bi) = state.current_buffer_index() {
+                    let buf = &mut state.buffers[bi];
+                    let Range { start, end } = target.range(buf, buf.cursor_index, 1);
+                    let moved_lines = (buf.line_for_index(start), buf.line_for_index(end));
+                    if jump && mo.mo.is_jump() && start != end {
+                        state.push_jump();
+                    }
+                    // Ctrl-D and Ctrl-U scroll by as many lines as the cursor moves
+                    if let MotionType::HalfPage(_) = mo.mo {
+                        if let PaneContent::Buffer { viewport_start, .. } = &mut state.current_pane_mut().content {
+                            *viewport_start = (*viewport_start + moved_lines.1).saturating_sub(moved_lines.0);
+                        }
+                    }
+                    state.buffers[bi].cursor_index = end;
                 }
                 Ok(None)
             },
+            Command::SetMark(name) => {
+                state.set_mark(*name)?;
+                Ok(None)
+            },
             Command::Put { count: _, source_register, clear_register } => {
                 state.last_command = Some(*self);
                 if let Some(buf) = state.current_buffer_index() {
+                    let src = register_text(state, *source_register)?;
                     let buf = &mut state.buffers[buf];
-                    let src = match source_register {
-                        '*' => {
-                            use copypasta::ClipboardProvider;
-                            match copypasta::ClipboardContext::new().and_then(|mut cb| cb.get_contents()) {
-                                Ok(snip) => snip,
-                                Err(e) => {
-                                    ClientState::process_usr_msgp(client,
-                                        UserMessage::error(format!("error getting clipboard contents: {}", e), None));
-                                    return Ok(None);
-                                }
-                            }
-                        },
-                        srg => state.registers.get(srg).ok_or(Error::EmptyRegister(*source_register))?.clone()
-                    };
                     // we need to check here to see if src contains a full line so that we can put it _after_ the current line
                     let insertion_point = if let Some('\n') = src.chars().last() {
                         //println!("X");
@@ -252,6 +324,14 @@ impl Command {
                 }
                 Ok(None)
             },
+            Command::Redo { count } => {
+                if let Some(buf) = state.current_buffer_mut() {
+                    for _ in 0..*count {
+                        if !buf.text.redo() { break; }
+                    }
+                }
+                Ok(None)
+            },
             Command::JoinLine { count } => {
                 state.last_command = Some(*self);
                 if let Some(buf) = state.current_buffer_mut() {
@@ -264,14 +344,16 @@ impl Command {
             },
             Command::Edit { op, op_count, mo, target_register } => {
                 state.last_command = Some(*self);
+                let mo = &on_screen(state, mo);
                 let buf = if let Some(b) = state.current_buffer_index() { 
                     &mut state.buffers[b]
                 } else { return Err(Error::InvalidCommand("".into())); };
                 match op {
                     Operator::Delete | Operator::Change => {
-                        let mut r = mo.range(buf, buf.cursor_index, *op_count);
-                        if mo.mo.inclusive() {
-                            r.end += 1;
+                        let mut r = mo.op_range(buf, buf.cursor_index, *op_count);
+                        // changing lines leaves an empty one to type on
+                        if *op == Operator::Change && mo.mo.linewise() && r.end > r.start && buf.text.char_at(r.end - 1) == Some('\n') {
+                            r.end -= 1;
                         }
                         if r.start != r.end {
                             // adjust range for changing so that it doesn't grab trailing
@@ -295,10 +377,7 @@ impl Command {
                         })
                     },
                     Operator::Yank => {
-                        let mut r = mo.range(buf, buf.cursor_index, *op_count);
-                        if mo.mo.inclusive() {
-                            r.end += 1;
-                        }
+                        let r = mo.op_range(buf, buf.cursor_index, *op_count);
                         let txt = buf.text.copy_range(r.start, r.end);
                         if *target_register == '*' {
                             use copypasta::ClipboardProvider;
@@ -340,13 +419,11 @@ impl Command {
                         Ok(Some(*mode))
                     }
                     Operator::Indent(direction) => {
-                        let r = mo.range(buf, buf.cursor_index, *op_count);
+                        let r = mo.op_range(buf, buf.cursor_index, *op_count);
                         let mut ln = buf.current_start_of_line(r.start);
-                        let end = buf.current_start_of_line(match *direction {
-                            Direction::Forward => r.end,
-                            Direction::Backward => r.end - 1
-                        } + if mo.mo.inclusive() { 1 } else { 0 });
-                        while ln <= end {
+                        // counted in lines, since indenting moves the lines after it
+                        let lines = buf.line_for_index(r.end.saturating_sub(1).max(r.start)) - buf.line_for_index(ln) + 1;
+                        for _ in 0..lines {
                             //println!("ln = {}, r = {:?}", ln, r);
                             if *direction == Direction::Forward {
                                 buf.indent(ln, 1, &client.read().unwrap().config);
@@ -368,6 +445,14 @@ impl Command {
                 }
                 Ok(Some(mode))
             },
+            Command::Record(register) => {
+                state.recording = Some((*register, Vec::new()));
+                Ok(None)
+            },
+            Command::PlayMacro { register, count } => {
+                state.play_macro(*register, *count)?;
+                Ok(None)
+            },
             Command::Leader(c) => match c {
                 'h' | 'j' | 'k' | 'l' => {
                     if let Some(ng) = state.current_pane().neighbors[match c {
@@ -424,6 +509,18 @@ impl Command {
                             *viewport_start = *viewport_start + *count;
                             *viewport_start
                         }
+                    } else if let PaneContent::Diff { buffer_index, viewport_start } = state.current_pane().content {
+                        // the buffer is scrolled along with its diff, so that the two stay lined up
+                        let vp = if *dir == Direction::Forward { viewport_start.saturating_sub(*count) } else { viewport_start + *count };
+                        let line = state.buffers[buffer_index].diff.as_ref().map_or(vp, |d| pk_common::diff::new_line_for(&d.hunks, vp));
+                        for p in state.panes.values_mut() {
+                            match &mut p.content {
+                                PaneContent::Buffer { buffer_index: bi, viewport_start, .. } if *bi == buffer_index => *viewport_start = line,
+                                PaneContent::Diff { buffer_index: bi, viewport_start } if *bi == buffer_index => *viewport_start = vp,
+                                _ => {}
+                            }
+                        }
+                        vp
                     } else {
                         return Err(Error::InvalidCommand("can't move viewport on non-buffer pane".into()));
                     };
@@ -443,6 +540,27 @@ impl Command {
     }
 }
 
+// `H`, `M`, `L`, Ctrl-D and Ctrl-U as a motion to a line, now that the lines on screen are known
+pub fn on_screen(state: &editor_state::EditorState, mo: &Motion) -> Motion {
+    match (&state.current_pane().content, state.current_buffer()) {
+        (PaneContent::Buffer { viewport_start, viewport_end, .. }, Some(buf)) =>
+            mo.on_screen(buf, buf.cursor_index, *viewport_start .. *viewport_end + 1),
+        _ => *mo
+    }
+}
+
+// the text in a register, where `*` is the system clipboard
+pub fn register_text(state: &editor_state::EditorState, register: char) -> Result<String, Error> {
+    match register {
+        '*' => {
+            use copypasta::ClipboardProvider;
+            copypasta::ClipboardContext::new().and_then(|mut cb| cb.get_contents())
+                .map_err(|e| Error::InvalidCommand(format!("error getting clipboard contents: {}", e)))
+        },
+        r => state.registers.get(&r).cloned().ok_or(Error::EmptyRegister(r))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -486,6 +604,16 @@ mod tests {
                 target_register: 'a'
             }
         );
+        assert_eq!(Command::parse("qa")?, Command::Record('a'));
+        assert_eq!(Command::parse("3@@")?, Command::PlayMacro { register: '@', count: 3 });
+        assert_eq!(Command::parse("12G")?, Command::Move(Motion { count: 12, mo: MotionType::ToLine(Some(12)) }));
+        assert_eq!(Command::parse("d'a")?,
+            Command::Edit{
+                op: Operator::Delete, op_count: 1,
+                mo: Motion { count: 1, mo: MotionType::Mark { name: 'a', linewise: true } },
+                target_register: '"'
+            }
+        );
         Ok(())
     }
 
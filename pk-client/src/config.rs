@@ -126,9 +126,28 @@ impl Colorscheme {
     }
 }
 
+// starting a server for the `local` connection when nothing is listening
+#[derive(Clone, Debug)]
+pub struct LocalServerConfig {
+    pub spawn: bool,
+    // pk-server to run, by default the one next to this executable or else whatever is on the PATH
+    pub executable: Option<String>,
+    // how long the spawned server waits with no clients connected before exiting
+    pub idle_exit: u64
+}
+
+impl Default for LocalServerConfig {
+    fn default() -> Self {
+        LocalServerConfig { spawn: true, executable: None, idle_exit: 300 }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct Config {
     pub autoconnect_servers: Vec<(String, String)>,
+    pub local_server: LocalServerConfig,
+    // what other clients editing the same files see this one as
+    pub user_name: String,
     pub font: (String, f32),
     pub tabstop: usize,
     pub softtab: bool,
@@ -182,6 +201,21 @@ impl Config {
             }
         }
 
+        if let Some(n) = val.get("user-name").and_then(Value::as_str) {
+            cfg.user_name = n.into();
+        }
+
+        if let Some(ls) = val.get("local-server") {
+            use std::convert::TryInto;
+            cfg.local_server.spawn = ls.get("spawn").and_then(Value::as_bool).unwrap_or(cfg.local_server.spawn);
+            cfg.local_server.executable = ls.get("executable").and_then(Value::as_str).map(String::from);
+            match ls.get("idle-exit").and_then(Value::as_integer) {
+                Some(s) => cfg.local_server.idle_exit = s.try_into()
+                    .map_err(|_| Error::ConfigParseError("Expected positive idle-exit value".into(), Some(ls.clone())))?,
+                None => {}
+            };
+        }
+
         if let Some(f) = val.get("font").and_then(Value::as_table) {
             cfg.font = (
                 f.get("name").and_then(Value::as_str)
@@ -213,7 +247,9 @@ impl Config {
 impl Default for Config {
     fn default() -> Self {
         Config {
-            autoconnect_servers: vec![("local".into(), "ipc://pk".into())],
+            autoconnect_servers: vec![("local".into(), pk_common::local_server_url())],
+            local_server: LocalServerConfig::default(),
+            user_name: std::env::var("USER").or_else(|_| std::env::var("USERNAME")).unwrap_or_else(|_| String::from("anonymous")),
             font: ("Consolas".into(), 14.0),
             tabstop: 4, softtab: true,
             colors: Colorscheme::default(),
@@ -1,12 +1,15 @@
 use std::sync::{Arc,RwLock};
-use std::collections::{HashMap, BTreeMap};
+use std::collections::{HashMap, BTreeMap, VecDeque};
 use futures::prelude::*;
 use pk_common::*;
 use crate::server::Server;
-use pk_common::piece_table::PieceTable;
 use crate::buffer::Buffer;
 use crate::config::Config;
+use crate::sync_queue::{SyncQueue, PendingSync};
+use crate::recovery::RecoveryJournal;
 use super::Error;
+use crate::keys::{self, Key};
+use pk_common::piece_table::Anchor;
 
 pub enum UserMessageType {
     Error, Warning, Info
@@ -49,6 +52,49 @@ impl UserMessage {
     }
 }
 
+// a workspace that has been opened on a server
+#[derive(Clone, Debug)]
+pub struct Workspace {
+    pub server_name: String,
+    pub id: protocol::WorkspaceId,
+    pub root: std::path::PathBuf,
+    pub name: String
+}
+
+// a place in a file for a global mark or the jump list. It moves along with edits while the file is
+// open, and stays where it last was once the buffer is closed
+#[derive(Clone, Debug)]
+pub struct Location {
+    pub server_name: String,
+    pub path: std::path::PathBuf,
+    // None once the buffer has been closed, and then `index` is where it was
+    anchor: Option<Anchor>,
+    index: usize
+}
+
+impl Location {
+    pub fn new(buf: &mut Buffer, index: usize) -> Location {
+        Location {
+            server_name: buf.server_name.clone(), path: buf.path.clone(),
+            anchor: Some(buf.text.anchor(index)), index
+        }
+    }
+
+    pub fn buffer_index(&self, state: &EditorState) -> Option<usize> {
+        state.buffers.iter().position(|b| b.server_name == self.server_name && b.path == self.path)
+    }
+
+    pub fn index(&self, buf: &Buffer) -> usize {
+        self.anchor.and_then(|a| buf.text.anchor_index(a)).unwrap_or(self.index).min(buf.text.len().saturating_sub(1))
+    }
+
+    fn forget(&self, state: &mut EditorState) {
+        if let (Some(a), Some(bi)) = (self.anchor, self.buffer_index(state)) {
+            state.buffers[bi].text.remove_anchor(a);
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub enum PaneContent {
     Empty,
@@ -57,6 +103,11 @@ pub enum PaneContent {
         viewport_start: usize,
         viewport_end: usize,
         scroll_lock: bool
+    },
+    // the version a buffer is being compared with, scrolled along with the buffer
+    Diff {
+        buffer_index: usize,
+        viewport_start: usize
     }
 }
 
@@ -66,6 +117,10 @@ impl PaneContent {
             buffer_index, viewport_start: 0, viewport_end: 0, scroll_lock: true
         }
     }
+
+    pub fn diff(buffer_index: usize) -> PaneContent {
+        PaneContent::Diff { buffer_index, viewport_start: 0 }
+    }
 }
 
 use runic::Rect;
@@ -313,6 +368,15 @@ impl Split {
     // }
 }
 
+// a macro that plays itself stops after this many levels
+pub const MACRO_DEPTH_LIMIT: usize = 100;
+// and macros can't queue more than this many keys for one typed key, so that a macro that plays
+// itself more than once can't run for ever
+pub const MACRO_KEY_LIMIT: usize = 100_000;
+
+// the oldest jumps are forgotten past this many
+pub const JUMP_LIST_LEN: usize = 100;
+
 pub struct EditorState {
     pub buffers: Vec<Buffer>, 
     pub registers: BTreeMap<char, String>,
@@ -322,7 +386,27 @@ pub struct EditorState {
 
     pub last_command: Option<crate::command::Command>,
     
-    pub line_command_history: Vec<String>
+    pub line_command_history: Vec<String>,
+
+    // the register a macro is being recorded into, and the keys typed so far
+    pub recording: Option<(char, Vec<Key>)>,
+    // keys from macros waiting to be handled, each with how many macros deep it is
+    pub pending_keys: VecDeque<(Key, usize)>,
+    // how many macros deep the key being handled is
+    pub macro_depth: usize,
+    // how many keys macros have queued since a key was last typed
+    pub macro_keys_queued: usize,
+    pub last_macro: Option<char>,
+
+    // marks `A` to `Z`, which can be in any file
+    pub global_marks: HashMap<char, Location>,
+    // places the cursor jumped away from, oldest first. `jump_index` is the one Ctrl-O/Ctrl-I last went to,
+    // or the length of the list if they haven't been used since the last jump
+    pub jumps: Vec<Location>,
+    pub jump_index: usize,
+
+    // the same highlighter that draws the buffers, so that syntax is there for commands that need it
+    pub highlighter: Option<Arc<crate::syntax_highlight::Highlighter>>
 }
 
 pub struct ClientState {
@@ -336,6 +420,14 @@ pub struct ClientState {
     pub usrmsgs: Vec<UserMessage>,
     pub selected_usrmsg: usize,
 
+    pub sync_queue: SyncQueue,
+    pub recovery: RecoveryJournal,
+
+    pub workspaces: Vec<Workspace>,
+
+    // identifies this client to servers
+    pub client_id: protocol::ClientId,
+
     pub config: Config
 }
 
@@ -353,8 +445,191 @@ impl EditorState {
             current_pane: 0,
             registers: BTreeMap::new(),
             last_command: None,
-            line_command_history: Vec::new()
+            line_command_history: Vec::new(),
+            recording: None,
+            pending_keys: VecDeque::new(),
+            macro_depth: 0,
+            macro_keys_queued: 0,
+            last_macro: None,
+            global_marks: HashMap::new(),
+            jumps: Vec::new(),
+            jump_index: 0,
+            highlighter: None
+        }
+    }
+
+    // the current buffer's syntax is only found when it's drawn, so text objects run from `:g`, `:normal`
+    // or a macro would otherwise see it as it was before the keys that came first in them
+    pub fn refresh_syntax(&mut self) {
+        if let (Some(h), Some(bi)) = (self.highlighter.clone(), self.current_buffer_index()) {
+            let buf = &mut self.buffers[bi];
+            if buf.syntax().is_none() {
+                let (_, syntax) = h.compute_highlighting(buf);
+                buf.set_syntax(syntax);
+            }
+        }
+    }
+
+    // `a` to `z`, `<` and `>` are marks in the current buffer, and `A` to `Z` are marks that can be jumped
+    // to from any buffer
+    pub fn set_mark(&mut self, name: char) -> Result<(), Error> {
+        let bi = self.current_buffer_index().ok_or_else(|| Error::InvalidCommand("no buffer to set a mark in".into()))?;
+        if name.is_ascii_uppercase() {
+            if let Some(old) = self.global_marks.remove(&name) {
+                old.forget(self);
+                if let Some(obi) = old.buffer_index(self) {
+                    self.buffers[obi].marks.remove(&name);
+                }
+            }
+            let buf = &mut self.buffers[bi];
+            let loc = Location::new(buf, buf.cursor_index);
+            // so that it's also a motion within its own buffer
+            if let Some(a) = loc.anchor {
+                buf.marks.insert(name, a);
+            }
+            self.global_marks.insert(name, loc);
+        } else if name.is_ascii_lowercase() || name == '<' || name == '>' {
+            let buf = &mut self.buffers[bi];
+            buf.set_mark(name, buf.cursor_index);
+        } else {
+            return Err(Error::InvalidCommand(format!("can't set mark '{}", name)));
+        }
+        Ok(())
+    }
+
+    // remembers where the cursor is before it jumps somewhere else, forgetting the jumps that Ctrl-O went
+    // back past
+    pub fn push_jump(&mut self) {
+        for old in self.jumps.split_off(self.jump_index.min(self.jumps.len())) {
+            old.forget(self);
+        }
+        if let Some(bi) = self.current_buffer_index() {
+            let buf = &mut self.buffers[bi];
+            let cursor = buf.cursor_index;
+            buf.set_mark('\'', cursor);
+            // more than one jump from the same line is only worth going back to once
+            let same_line = self.jumps.last().map_or(false, |last|
+                last.server_name == buf.server_name && last.path == buf.path
+                    && buf.line_for_index(last.index(buf)) == buf.line_for_index(cursor));
+            if !same_line {
+                let loc = Location::new(buf, cursor);
+                self.jumps.push(loc);
+                if self.jumps.len() > JUMP_LIST_LEN {
+                    let oldest = self.jumps.remove(0);
+                    oldest.forget(self);
+                }
+            }
+        }
+        self.jump_index = self.jumps.len();
+    }
+
+    // the next place to go back to or forward to in the jump list
+    pub fn next_jump(&mut self, direction: Direction) -> Option<Location> {
+        match direction {
+            Direction::Backward => {
+                if self.jump_index >= self.jumps.len() {
+                    // so that Ctrl-I can come back here
+                    self.push_jump();
+                    self.jump_index = self.jumps.len().saturating_sub(1);
+                }
+                if self.jump_index == 0 { return None; }
+                self.jump_index -= 1;
+            },
+            Direction::Forward => {
+                if self.jump_index + 1 >= self.jumps.len() { return None; }
+                self.jump_index += 1;
+            }
+        }
+        Some(self.jumps[self.jump_index].clone())
+    }
+
+    // shows a buffer in the current pane, keeping the pane's scroll position if it's already there
+    pub fn show_buffer(&mut self, buffer_index: usize) {
+        if self.current_buffer_index() != Some(buffer_index) {
+            self.current_pane_mut().content = PaneContent::buffer(buffer_index);
+        }
+    }
+
+    // jump list entries and global marks in a buffer that's being closed stay where they are now
+    pub fn settle_locations(&mut self, buffer_index: usize) {
+        let buf = &self.buffers[buffer_index];
+        for loc in self.jumps.iter_mut().chain(self.global_marks.values_mut()) {
+            if loc.server_name == buf.server_name && loc.path == buf.path {
+                loc.index = loc.index(buf);
+                loc.anchor = None;
+            }
+        }
+    }
+
+    // runs `f` with the main cursor at each of the current buffer's cursors in turn, last to first so that edits
+    // don't move the cursors still to come, then leaves every cursor where `f` put it. The edits are undone
+    // all at once, and the first error stops the rest
+    pub fn for_each_cursor<R>(&mut self, mut f: impl FnMut(&mut EditorState) -> Result<R, Error>) -> Result<R, Error> {
+        let bi = self.current_buffer_index().ok_or_else(|| Error::InvalidCommand("no buffer".into()))?;
+        let buf = &mut self.buffers[bi];
+        let main = buf.cursor_index.min(buf.text.len());
+        let first_action = buf.text.next_action_id;
+        // each cursor, and whether it's the main one, as an anchor so that the ones already done move along
+        // with the edits at the rest
+        let cursors: Vec<(bool, Anchor)> = buf.all_cursors().into_iter().map(|c| (c == main, buf.text.anchor(c))).collect();
+        buf.clear_cursors();
+        let mut res = Err(Error::InvalidCommand("no cursors".into()));
+        for (_, a) in cursors.iter().rev() {
+            let buf = &mut self.buffers[bi];
+            // a cursor whose text was deleted at another one is gone
+            match buf.text.anchor_index(*a) {
+                Some(ix) => buf.cursor_index = ix,
+                None => continue
+            }
+            res = f(self);
+            let buf = &mut self.buffers[bi];
+            buf.text.move_anchor(*a, buf.cursor_index);
+            if res.is_err() { break; }
+        }
+        let buf = &mut self.buffers[bi];
+        buf.text.squash_history(first_action);
+        buf.cursor_index = cursors.iter().find(|(m, _)| *m).and_then(|(_, a)| buf.text.anchor_index(*a))
+            .unwrap_or_else(|| main.min(buf.text.len()));
+        for (_, a) in cursors {
+            if let Some(ix) = buf.text.anchor_index(a) {
+                buf.add_cursor(ix);
+            }
+            buf.text.remove_anchor(a);
+        }
+        res
+    }
+
+    // keeps the recorded keys in their register as text, so they can be put and edited
+    pub fn stop_recording(&mut self) {
+        if let Some((reg, mut keys)) = self.recording.take() {
+            // the `q` that stopped the recording
+            if self.macro_depth == 0 { keys.pop(); }
+            self.registers.insert(reg, keys::keys_to_string(&keys));
+        }
+    }
+
+    // queues the keys in a register to be played `count` times, ahead of any keys already waiting
+    pub fn play_macro(&mut self, reg: char, count: usize) -> Result<(), Error> {
+        let reg = if reg == '@' {
+            self.last_macro.ok_or_else(|| Error::InvalidCommand("no macro has been played yet".into()))?
+        } else { reg };
+        if self.macro_depth >= MACRO_DEPTH_LIMIT {
+            return Err(Error::InvalidCommand(format!("macros nested more than {} deep", MACRO_DEPTH_LIMIT)));
+        }
+        let keys = keys::parse_keys(self.registers.get(&reg).ok_or(Error::EmptyRegister(reg))?);
+        let queued = self.macro_keys_queued.saturating_add(keys.len().saturating_mul(count));
+        if queued > MACRO_KEY_LIMIT {
+            return Err(Error::InvalidCommand(format!("macros played more than {} keys", MACRO_KEY_LIMIT)));
+        }
+        self.macro_keys_queued = queued;
+        self.last_macro = Some(reg);
+        let depth = self.macro_depth + 1;
+        for _ in 0..count {
+            for k in keys.iter().rev() {
+                self.pending_keys.push_front((*k, depth));
+            }
         }
+        Ok(())
     }
 
     pub fn current_pane(&self) -> &Pane {
@@ -374,6 +649,11 @@ impl EditorState {
         }
     }
 
+    // the buffer showing a file, for responses that arrive after buffers may have been opened or closed
+    pub fn buffer_index_for_file(&self, server_name: &str, id: protocol::FileId) -> Option<usize> {
+        self.buffers.iter().position(|b| b.server_name == server_name && b.file_id == id)
+    }
+
     pub fn current_buffer(&self) -> Option<&Buffer> {
         match self.current_pane().content {
             PaneContent::Buffer { buffer_index: ix, .. } => {
@@ -395,8 +675,36 @@ impl EditorState {
 }
 
 pub type PEditorState = Arc<RwLock<EditorState>>;
+
+// unique enough to tell apart the clients connected to one server
+fn new_client_id() -> protocol::ClientId {
+    let t = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default();
+    protocol::ClientId(t.as_nanos() as u64 ^ ((std::process::id() as u64) << 32))
+}
+
+// how many times to try to connect to a local server that was just started, 100ms apart
+const LOCAL_SERVER_START_TRIES: usize = 30;
 pub type PClientState = Arc<RwLock<ClientState>>;
 
+// runs a function when it goes away, so that a sync can say it's finished no matter how it ended
+pub struct SyncDone(Option<Box<dyn FnOnce() + Send + Sync>>);
+
+impl SyncDone {
+    pub fn new(f: impl FnOnce() + Send + Sync + 'static) -> SyncDone {
+        SyncDone(Some(Box::new(f)))
+    }
+
+    pub fn none() -> SyncDone {
+        SyncDone(None)
+    }
+}
+
+impl Drop for SyncDone {
+    fn drop(&mut self) {
+        if let Some(f) = self.0.take() { f(); }
+    }
+}
+
 impl ClientState {
     pub fn with_config(config: Config) -> ClientState {
         use futures::executor::ThreadPoolBuilder;
@@ -407,22 +715,49 @@ impl ClientState {
             should_exit: false,
             usrmsgs: Vec::new(),
             selected_usrmsg: 0,
+            sync_queue: SyncQueue::default(),
+            recovery: RecoveryJournal::default(),
+            workspaces: Vec::new(),
+            client_id: new_client_id(),
             config
         }
     }
 
     pub fn connect_to_server(state: PClientState, name: String, url: &str) {
-        let tp = {state.read().unwrap().thread_pool.clone()};
-        let stp = tp.clone();
+        let (tp, local_config, client_id) = {
+            let state = state.read().unwrap();
+            (state.thread_pool.clone(), state.config.local_server.clone(), state.client_id)
+        };
         let url = url.to_owned();
-            match Server::init(&url, stp) {
+        let mut res = Server::init(&url, tp.clone(), client_id);
+        // nothing is listening where the local server should be, so start one
+        if res.is_err() && name == "local" && local_config.spawn && url.starts_with("ipc://") {
+            res = Server::spawn_local(&url, &local_config).and_then(|exe| {
+                ClientState::process_usr_msgp(state.clone(), UserMessage::info(
+                    format!("Started local server {} on {}", exe.to_string_lossy(), url), None));
+                let mut tries = 0;
+                loop {
+                    // give it a moment to start listening
+                    std::thread::sleep(std::time::Duration::from_millis(100));
+                    match Server::init(&url, tp.clone(), client_id) {
+                        Ok(s) => break Ok(s),
+                        Err(e) if tries >= LOCAL_SERVER_START_TRIES => break Err(e),
+                        Err(_) => tries += 1
+                    }
+                }
+            });
+        }
+            match res {
                 Ok(s) => {
-                    let mut state = state.write().unwrap();
-                    //println!("c {:?}", std::time::Instant::now());
-                    state.servers.insert(name.clone(), s);
-                    ClientState::process_usr_msg(&mut state, UserMessage::info(
-                            format!("Connected to {} ({})!", name, url),
-                            None));
+                    {
+                        let mut state = state.write().unwrap();
+                        //println!("c {:?}", std::time::Instant::now());
+                        state.servers.insert(name.clone(), s);
+                        ClientState::process_usr_msg(&mut state, UserMessage::info(
+                                format!("Connected to {} ({})!", name, url),
+                                None));
+                    }
+                    ClientState::say_hello(state, name);
                 }
                 Err(e) => {
                     let mut state = state.write().unwrap();
@@ -437,8 +772,32 @@ impl ClientState {
             }
     }
 
+    // let a server know who this client is, so that other clients can see who's editing with them
+    pub fn say_hello(state: PClientState, server_name: String) {
+        let name = state.read().unwrap().config.user_name.clone();
+        ClientState::make_request_async_raw(state, server_name, protocol::Request::Hello { name }, |cs, resp| {
+            if let protocol::Response::Error { message } = resp {
+                cs.write().unwrap().process_error_str(format!("error introducing client to server: {}", message));
+            }
+        });
+    }
+
     pub fn make_request_async<F>(state: PClientState, server_name: impl AsRef<str>, request: protocol::Request, f: F)
         where F: FnOnce(PClientState, protocol::Response) + Send + Sync + 'static
+    {
+        ClientState::make_request_async_raw(state, server_name, request, move |ess, resp| {
+            match resp {
+                protocol::Response::Error { message } => {
+                    ess.write().unwrap().process_error_str(message);
+                },
+                _ => f(ess, resp)
+            }
+        });
+    }
+
+    // like make_request_async, but error responses are passed to `f` instead of being shown to the user
+    pub fn make_request_async_raw<F>(state: PClientState, server_name: impl AsRef<str>, request: protocol::Request, f: F)
+        where F: FnOnce(PClientState, protocol::Response) + Send + Sync + 'static
     {
         let mut cs = state.write().unwrap();
         let req_fut = match {
@@ -455,84 +814,292 @@ impl ClientState {
         let ess = state.clone();
         cs.thread_pool.spawn_ok(req_fut.then(move |resp: protocol::Response| async move
         {
+            f(ess, resp)
+        }));
+    }
+
+    pub fn open_workspace(state: PClientState, server_name: String, root: std::path::PathBuf) {
+        ClientState::make_request_async(state, server_name.clone(), protocol::Request::OpenWorkspace { root }, move |cs, resp| {
             match resp {
-                protocol::Response::Error { message } => {
-                    ess.write().unwrap().process_error_str(message);
+                protocol::Response::WorkspaceInfo { id, root, name } => {
+                    let mut cs = cs.write().unwrap();
+                    // the most recently opened workspace goes last, since it's the default for relative paths
+                    cs.workspaces.retain(|w| !(w.server_name == server_name && w.id == id));
+                    cs.process_usr_msg(UserMessage::info(
+                            format!("opened workspace {} ({}:{})", name, server_name, root.to_string_lossy()), None));
+                    cs.workspaces.push(Workspace { server_name, id, root, name });
                 },
-                _ => f(ess, resp)
+                _ => cs.write().unwrap().process_error_str(String::from("unexpected response to open workspace request"))
             }
-        }));
+        });
+    }
+
+    // the workspace that relative paths on `server_name` are relative to: the one the current buffer
+    // is in, or else the most recently opened one
+    pub fn default_workspace(&self, estate: &EditorState, server_name: &str) -> Option<Workspace> {
+        estate.current_buffer().and_then(|b| b.workspace.as_ref())
+            .filter(|w| w.server_name == server_name)
+            .or_else(|| self.workspaces.iter().rev().find(|w| w.server_name == server_name))
+            .cloned()
     }
 
     pub fn open_buffer(state: PClientState, ess: PEditorState, server_name: String, path: std::path::PathBuf,
         f: impl FnOnce(&mut EditorState, PClientState, usize) + Send + Sync + 'static)
     {
         let sstate = state.clone();
-        ClientState::make_request_async(state, server_name.clone(), protocol::Request::OpenFile { path: path.clone() }, move |_, resp| {
+        let workspace = {
+            let cs = state.read().unwrap();
+            let es = ess.read().unwrap();
+            cs.default_workspace(&es, &server_name).map(|w| w.id)
+        };
+        ClientState::make_request_async(state, server_name.clone(), protocol::Request::OpenFile { path, workspace }, move |cs, resp| {
             match resp {
-                protocol::Response::FileInfo { id, contents, version, format } => {
-                    let mut estate = ess.write().unwrap();
-                    let buffer_index = estate.buffers.len();
-                    estate.buffers.push(Buffer::from_server(String::from(server_name),
-                        path, id, contents, version, format));
-                    f(&mut estate, sstate, buffer_index);
+                protocol::Response::FileInfo { id, path, workspace, contents, version, format, view, window } => {
+                    let workspace = workspace.and_then(|wid| cs.read().unwrap().workspaces.iter()
+                        .find(|w| w.server_name == server_name && w.id == wid).cloned());
+                    // if there are queued edits for this file left over from when the server was
+                    // unreachable, pick them back up. the AutosyncWorker will replay them, and if
+                    // the file changed on the server in the mean time the usual conflict flow runs
+                    let (queued, recovered) = {
+                        let cs = cs.read().unwrap();
+                        (cs.sync_queue.get(&server_name, &path).map(|p| (p.text.clone(), p.base_version)),
+                         cs.recovery.load(&server_name, &path))
+                    };
+                    let buffer_index = {
+                        let mut estate = ess.write().unwrap();
+                        let buffer_index = estate.buffers.len();
+                        match queued {
+                            Some((text, base_version)) => {
+                                let mut b = Buffer::from_server(server_name.clone(), path.clone(), id, text, base_version, format);
+                                b.version = base_version;
+                                estate.buffers.push(b);
+                            },
+                            None => estate.buffers.push(Buffer::from_server(server_name.clone(),
+                                path.clone(), id, contents.clone(), version, format))
+                        }
+                        estate.buffers[buffer_index].workspace = workspace;
+                        estate.buffers[buffer_index].view = view;
+                        estate.buffers[buffer_index].window = window;
+                        estate.buffers[buffer_index].loaded_lines = contents.matches('\n').count();
+                        f(&mut estate, sstate, buffer_index);
+                        buffer_index
+                    };
+                    match recovered {
+                        Ok(Some(r)) => ClientState::offer_recovery(cs, ess, buffer_index, r, contents, version),
+                        Ok(None) => {},
+                        Err(e) => cs.write().unwrap().process_error_str(
+                            format!("error loading recovered edits for {}:{}: {}", server_name, path.to_string_lossy(), e))
+                    }
                 },
-                _ => panic!() 
+                _ => cs.write().unwrap().process_error_str(String::from("unexpected response to open file request"))
             }
         });
     }
 
+    // moves the cursor to a location, opening its file first if it isn't already open. `linewise` goes to
+    // the first character on its line instead
+    pub fn jump_to(state: PClientState, ess: PEditorState, loc: Location, linewise: bool) {
+        let (server_name, path) = (loc.server_name.clone(), loc.path.clone());
+        let place_cursor = move |estate: &mut EditorState, buffer_index: usize| {
+            estate.show_buffer(buffer_index);
+            let buf = &mut estate.buffers[buffer_index];
+            let ix = loc.index(buf);
+            buf.cursor_index = if linewise { buf.first_non_blank(ix) } else { ix };
+        };
+        let open = {
+            let mut estate = ess.write().unwrap();
+            match estate.buffers.iter().position(|b| b.server_name == server_name && b.path == path) {
+                Some(bi) => { place_cursor(&mut estate, bi); true },
+                None => false
+            }
+        };
+        if !open {
+            ClientState::open_buffer(state, ess, server_name, path, move |estate, cstate, buffer_index| {
+                place_cursor(estate, buffer_index);
+                cstate.write().unwrap().force_redraw = true;
+            });
+        }
+    }
+
+    // send a queued version of a file that isn't open to its server. If the file changed on the server in the mean
+    // time, it stays queued so that the usual conflict flow runs once the file is opened
+    pub fn replay_queued(state: PClientState, p: PendingSync) {
+        let PendingSync { server_name, path, base_version, text, .. } = p;
+        ClientState::make_request_async_raw(state, server_name.clone(),
+            protocol::Request::OpenFile { path: path.clone(), workspace: None },
+            move |css, resp| {
+                let id = match resp {
+                    protocol::Response::FileInfo { id, .. } => id,
+                    _ => {
+                        css.write().unwrap().sync_queue.failed(&server_name, &path);
+                        return;
+                    }
+                };
+                ClientState::make_request_async_raw(css.clone(), server_name.clone(),
+                    protocol::Request::SyncFile { id, new_text: text, version: base_version + 1 },
+                    move |css, resp| {
+                        {
+                            let mut cs = css.write().unwrap();
+                            match resp {
+                                protocol::Response::Ack => {
+                                    cs.unqueue_sync(&server_name, &path);
+                                },
+                                protocol::Response::VersionConflict { .. } | protocol::Response::Locked { .. } => {
+                                    cs.sync_queue.failed(&server_name, &path);
+                                    cs.process_usr_msg(UserMessage::warning(
+                                        format!("{}:{} changed on the server, open it to merge the queued edits",
+                                            server_name, path.to_string_lossy()), None));
+                                },
+                                _ => cs.sync_queue.failed(&server_name, &path)
+                            }
+                        }
+                        ClientState::make_request_async_raw(css, server_name, protocol::Request::CloseFile(id), |_, _| {});
+                    });
+            });
+    }
+
     pub fn sync_buffer(state: PClientState, ed_state: PEditorState, buffer_index: usize) {
-        let (server_name, id, new_text, version) = {
+        ClientState::sync_buffer_then(state, ed_state, buffer_index, SyncDone::none());
+    }
+
+    // sync a buffer, and let `done` know once the server has answered. If there is nothing to send,
+    // or a sync of the buffer is already in flight, `done` runs right away
+    pub fn sync_buffer_then(state: PClientState, ed_state: PEditorState, buffer_index: usize, done: SyncDone) {
+        let client_id = state.read().unwrap().client_id;
+        let (server_name, path, id, new_text, version, action_id) = {
             let state = ed_state.read().unwrap();
             let b = &state.buffers[buffer_index];
-            if b.currently_in_conflict { return; }
-            (b.server_name.clone(), b.file_id, b.text.text(), b.version+1)
+            if b.currently_in_conflict || b.read_only() { return; }
+            // the server would refuse it anyways. The edits stay queued until the lock goes away
+            if b.lock.as_ref().map_or(false, |l| l.client != client_id) { return; }
+            (b.server_name.clone(), b.path.clone(), b.file_id, b.text.text(), b.version+1,
+                b.text.most_recent_action_id())
         };
-        ClientState::make_request_async(state, server_name,
+        {
+            // only one sync per buffer can be in flight at a time, otherwise the versions could
+            // arrive out of order. whatever changed in the mean time is sent once this one finishes
+            let mut cs = state.write().unwrap();
+            if cs.sync_queue.get(&server_name, &path).map_or(false, |p| p.in_flight) { return; }
+            cs.sync_queue.push(PendingSync {
+                server_name: server_name.clone(), path: path.clone(),
+                base_version: version-1, text: new_text.clone(), in_flight: true
+            });
+        }
+        ClientState::make_request_async_raw(state, server_name.clone(),
             protocol::Request::SyncFile { id, new_text, version },
             move |css, resp| {
+                let done = done;
                 match resp {
                     protocol::Response::Ack => {
+                        let (changed_since, write_requested) = {
+                            let mut state = ed_state.write().unwrap();
+                            let b = &mut state.buffers[buffer_index];
+                            b.version = version;
+                            (b.text.most_recent_action_id() != action_id, b.write_requested)
+                        };
+                        {
+                            let mut cs = css.write().unwrap();
+                            cs.unqueue_sync(&server_name, &path);
+                            if !changed_since {
+                                if let Err(e) = cs.recovery.remove(&server_name, &path) {
+                                    cs.process_error(e);
+                                }
+                            }
+                        }
+                        if changed_since {
+                            ClientState::sync_buffer_then(css, ed_state, buffer_index, done);
+                        } else if write_requested {
+                            ClientState::write_synced_buffer(css, ed_state, buffer_index);
+                        }
+                    },
+                    protocol::Response::Error { message } => {
+                        let mut cs = css.write().unwrap();
+                        if cs.servers.get(&server_name).map_or(false, |s| s.is_connected()) {
+                            cs.unqueue_sync(&server_name, &path);
+                            cs.process_error_str(message);
+                        } else {
+                            // the server is unreachable: keep the version around (on disk too, in
+                            // case the client exits before the server comes back) and let the
+                            // AutosyncWorker retry it later
+                            cs.sync_queue.failed(&server_name, &path);
+                            if let Err(e) = cs.sync_queue.persist() {
+                                cs.process_error(e);
+                            }
+                        }
+                        let mut state = ed_state.write().unwrap();
+                        let b = &mut state.buffers[buffer_index];
+                        if b.write_requested {
+                            b.write_requested = false;
+                            cs.process_error_str(format!("could not write {}:{}, the server did not get the latest changes",
+                                b.server_name, b.display_path()));
+                        }
+                    },
+                    protocol::Response::Locked { by, .. } => {
+                        let mut cs = css.write().unwrap();
+                        cs.sync_queue.failed(&server_name, &path);
                         let mut state = ed_state.write().unwrap();
-                        state.buffers[buffer_index].version = version;
+                        let b = &mut state.buffers[buffer_index];
+                        b.write_requested = false;
+                        cs.process_error_str(format!("{}:{} is locked by {}, changes will be sent once it is unlocked",
+                            b.server_name, b.display_path(), by.name));
+                        b.lock = Some(by);
                     },
                     protocol::Response::VersionConflict { id, client_version_recieved: _,
-                        server_version, server_text } =>
+                        server_version, server_text, base_text } =>
                     {
-                        // TODO: probably need to show a nice little dialog, ask the user what they
-                        // want to do about the conflict. this becomes a tricky situation since
-                        // there's no reason to become Git, but it is nice to able to handle this
-                        // situation in a nice way
-                        let m = {
+                        css.write().unwrap().unqueue_sync(&server_name, &path);
+                        let (m, merged) = {
                             let mut ed_state = ed_state.write().unwrap();
                             let b = &mut ed_state.buffers[buffer_index];
-                            b.currently_in_conflict = true;
-                            format!("Server version of {}:{} conflicts with local version!",
-                                b.server_name, b.path.to_str().unwrap_or(""))
+                            // changes to different parts of the file can just be put together
+                            let merged = base_text.map(|base| diff::merge3(&base, &b.text.text(), &server_text));
+                            match merged {
+                                Some(chunks) if !diff::has_conflicts(&chunks) => {
+                                    // there are no conflicts to mark
+                                    b.replace_text(&diff::with_markers(&chunks, "local", "server").0);
+                                    b.version = server_version;
+                                    (format!("Merged changes to {}:{} from the server", b.server_name, b.display_path()), None)
+                                },
+                                merged => {
+                                    b.currently_in_conflict = true;
+                                    (format!("Server version of {}:{} conflicts with local version!",
+                                        b.server_name, b.display_path()), Some(merged))
+                                }
+                            }
                         };
+                        let merged = match merged {
+                            Some(merged) => merged,
+                            None => {
+                                ClientState::process_usr_msgp(css, UserMessage::info(m, None));
+                                return;
+                            }
+                        };
+                        let mut options = vec![
+                            "Keep local version".into(),
+                            "Open server version/Discard local".into(),
+                            "Open server version in new buffer".into()
+                        ];
+                        if merged.is_some() {
+                            options.push("Merge".into());
+                        }
                         css.write().unwrap().usrmsgs.push(UserMessage::warning(m,
-                                Some((vec![
-                                        "Keep local version".into(),
-                                        "Open server version/Discard local".into(),
-                                        "Open server version in new buffer".into()
-                                ], Box::new(move |index, _| {
+                                Some((options, Box::new(move |index, cs| {
                                     let mut state = ed_state.write().unwrap();
+                                    let mut msg = None;
                                     match index {
-                                        0 => {
+                                        1 => {
                                             // next time we sync, overwrite server version
                                             state.buffers[buffer_index].version = 
                                                 server_version;
                                             state.buffers[buffer_index].currently_in_conflict = false;
                                         },
-                                        1 => {
+                                        2 => {
                                             state.buffers[buffer_index].version =
                                                 server_version;
-                                            state.buffers[buffer_index].text =
-                                                PieceTable::with_text(&server_text);
+                                            state.buffers[buffer_index].replace_text(&server_text);
                                             state.buffers[buffer_index].currently_in_conflict = false;
                                         },
-                                        2 => {
+                                        3 => {
                                             let cp = state.current_pane;
                                             let nbi = state.buffers.len();
                                             Pane::split(&mut state.panes, cp, true, 0.5,
@@ -546,17 +1113,152 @@ impl ClientState {
                                             // to sync the conflicting version again. TODO: some
                                             // way to manually clear the flag?
                                         },
+                                        4 => if let Some(chunks) = merged.as_ref() {
+                                            // the conflicts are resolved in the buffer with merge mode, which
+                                            // clears the conflict flag once they're all gone
+                                            state.buffers[buffer_index].start_merge(chunks, server_version);
+                                            msg = Some(UserMessage::info("Use :merge to pick a side for each conflict".into(), None));
+                                        },
                                         _ => {} 
                                     }
+                                    // the client state is always locked before the editor state
+                                    drop(state);
+                                    if let Some(m) = msg {
+                                        ClientState::process_usr_msgp(cs, m);
+                                    }
                                 })))
                         ));
                     }
-                    _ => panic!() 
+                    _ => {
+                        let mut cs = css.write().unwrap();
+                        cs.unqueue_sync(&server_name, &path);
+                        cs.process_error_str(String::from("unexpected response to sync request"));
+                    }
             }
             }
         );
     }
 
+    // fetch the next piece of a partially loaded file and tack it on to the end of the buffer
+    pub fn load_next_window(state: PClientState, ed_state: PEditorState, buffer_index: usize) {
+        let (server_name, id, offset) = {
+            let mut es = ed_state.write().unwrap();
+            let b = &mut es.buffers[buffer_index];
+            match b.window {
+                Some(w) if !w.complete() && !b.loading_window => {
+                    b.loading_window = true;
+                    (b.server_name.clone(), b.file_id, w.end)
+                },
+                _ => return
+            }
+        };
+        let es = ed_state.clone();
+        ClientState::make_request_async_raw(state, server_name.clone(),
+            protocol::Request::ReadFileWindow { id, offset, len: WINDOW_SIZE },
+            move |cs, resp| {
+                let err = {
+                    let mut es = es.write().unwrap();
+                    let b = match es.buffer_index_for_file(&server_name, id) {
+                        Some(bi) => &mut es.buffers[bi],
+                        None => return
+                    };
+                    b.loading_window = false;
+                    match resp {
+                        protocol::Response::FileWindow { id: wid, contents, window } if wid == id => {
+                            b.text.append_unrecorded(&contents);
+                            // once all of it is here, it can be edited like any other file
+                            b.window = Some(window).filter(|w| !w.complete());
+                            b.loaded_lines += contents.matches('\n').count();
+                            None
+                        },
+                        protocol::Response::Error { message } => Some(message),
+                        _ => Some(String::from("unexpected response to read window request"))
+                    }
+                };
+                let mut cs = cs.write().unwrap();
+                match err {
+                    Some(e) => cs.process_error_str(e),
+                    None => cs.force_redraw = true
+                }
+            });
+    }
+
+    // ask the user whether to put back edits that were journaled before the client went down
+    fn offer_recovery(state: PClientState, ed_state: PEditorState, buffer_index: usize,
+        r: crate::recovery::RecoveredBuffer, server_text: String, server_version: usize)
+    {
+        let msg = format!("Found unsaved edits to {}:{} from a previous session",
+            r.server_name, r.path.to_string_lossy());
+        let r = std::sync::Mutex::new(Some(r));
+        state.write().unwrap().process_usr_msg(UserMessage::warning(msg,
+            Some((vec![ "Restore edits".into(), "Discard edits".into() ],
+            Box::new(move |index, cs| {
+                let r = match r.lock().unwrap().take() {
+                    Some(r) => r,
+                    None => return
+                };
+                match index {
+                    1 => {
+                        {
+                            let mut es = ed_state.write().unwrap();
+                            let b = &mut es.buffers[buffer_index];
+                            // if the server still has the text the edits were made against, they
+                            // apply cleanly on top of the current version. otherwise, syncing
+                            // with the old version runs through the usual conflict resolution
+                            b.version = if r.base_text() == server_text { server_version } else { r.base_version };
+                            b.replace_text(&r.text.text());
+                        }
+                        ClientState::sync_buffer(cs, ed_state.clone(), buffer_index);
+                    },
+                    2 => {
+                        let res = cs.read().unwrap().recovery.remove(&r.server_name, &r.path);
+                        if let Err(e) = res {
+                            cs.write().unwrap().process_error(e);
+                        }
+                    },
+                    _ => {}
+                }
+            })))));
+    }
+
+    // sync the buffer with the server, then have the server write it to disk
+    pub fn write_buffer(state: PClientState, ed_state: PEditorState, buffer_index: usize) {
+        let conflict = {
+            let mut es = ed_state.write().unwrap();
+            let b = &mut es.buffers[buffer_index];
+            if b.currently_in_conflict {
+                Some(format!("{}:{} is in conflict with the server version, resolve the conflict before writing",
+                    b.server_name, b.display_path()))
+            } else {
+                b.write_requested = true;
+                None
+            }
+        };
+        match conflict {
+            Some(m) => state.write().unwrap().process_error_str(m),
+            None => ClientState::sync_buffer(state, ed_state, buffer_index)
+        }
+    }
+
+    fn write_synced_buffer(state: PClientState, ed_state: PEditorState, buffer_index: usize) {
+        let (server_name, id, path) = {
+            let mut es = ed_state.write().unwrap();
+            let b = &mut es.buffers[buffer_index];
+            b.write_requested = false;
+            (b.server_name.clone(), b.file_id, b.display_path().into_owned())
+        };
+        ClientState::make_request_async(state, server_name.clone(), protocol::Request::WriteFile { id },
+            move |cs, resp| {
+                match resp {
+                    protocol::Response::Ack => ClientState::process_usr_msgp(cs, UserMessage::info(
+                            format!("wrote {}:{}", server_name, path), None)),
+                    protocol::Response::Locked { by, .. } => cs.write().unwrap().process_error_str(
+                            format!("could not write {}:{}, it is locked by {}", server_name, path, by.name)),
+                    _ => cs.write().unwrap().process_error_str(String::from("unexpected response to write request"))
+                }
+            });
+    }
+
     pub fn process_usr_msg(&mut self, um: UserMessage) {
         self.usrmsgs.push(um);
         self.force_redraw = true;
@@ -569,20 +1271,87 @@ impl ClientState {
     pub fn process_error_str(&mut self, e: String) {
         self.process_usr_msg(UserMessage::error(e, None));
     }
+    // forget a version that no longer needs to be sent
+    pub fn unqueue_sync(&mut self, server_name: &str, path: &std::path::Path) {
+        if let Err(e) = self.sync_queue.remove(server_name, path) {
+            self.process_error(e);
+        }
+    }
+
     pub fn process_error<E: std::error::Error>(&mut self, e: E) {
         self.process_error_str(format!("{}", e));
     }
 }
 
+// how much more of a partially loaded file to ask for at a time
+const WINDOW_SIZE: u64 = 1024 * 1024;
+
 pub struct AutosyncWorker {
     cstate: PClientState,
     state: PEditorState,
-    last_synced_action_ids: HashMap<String, HashMap<protocol::FileId, usize>> 
+    last_synced_action_ids: HashMap<String, HashMap<protocol::FileId, usize>>,
+    server_connected: HashMap<String, bool>
 }
 
 impl AutosyncWorker {
     pub fn new(cstate: PClientState, state: PEditorState) -> AutosyncWorker {
-        AutosyncWorker { cstate, state, last_synced_action_ids: HashMap::new() }
+        AutosyncWorker { cstate, state, last_synced_action_ids: HashMap::new(), server_connected: HashMap::new() }
+    }
+
+    // let the user know when a server goes away or comes back, so they know whether their edits
+    // are actually making it to the server. Returns the servers that came back
+    fn check_connections(&mut self) -> Vec<String> {
+        let mut cs = self.cstate.write().unwrap();
+        let mut msgs = Vec::new();
+        let mut reconnected = Vec::new();
+        for (name, server) in cs.servers.iter() {
+            let now = server.is_connected();
+            // edits queued before the client last exited are replayed as soon as the server is there
+            let queued = cs.sync_queue.pending_for(name).next().is_some();
+            let before = self.server_connected.insert(name.clone(), now).unwrap_or(!queued);
+            if before && !now {
+                msgs.push(UserMessage::warning(
+                    format!("Lost connection to {}, edits will be queued until it is reachable again", name), None));
+            } else if !before && now {
+                let n = cs.sync_queue.pending_for(name).count();
+                msgs.push(UserMessage::info(
+                    format!("Reconnected to {}, replaying {} queued edit{}", name, n, if n == 1 { "" } else { "s" }), None));
+                reconnected.push(name.clone());
+            }
+        }
+        for m in msgs {
+            cs.process_usr_msg(m);
+        }
+        reconnected
+    }
+
+    // tell each server where the cursors are, and find out where everyone else's are
+    fn update_presence(&mut self) {
+        let updates: Vec<_> = {
+            let cstate = self.cstate.read().unwrap();
+            let state = self.state.read().unwrap();
+            state.buffers.iter()
+                .filter(|b| cstate.servers.get(&b.server_name).map_or(false, |s| s.is_connected()))
+                .map(|b| (b.server_name.clone(), b.file_id, b.cursor_index,
+                          b.selection.as_ref().map(|r| (r.start, r.end))))
+                .collect()
+        };
+        for (server_name, id, cursor, selection) in updates {
+            let ess = self.state.clone();
+            ClientState::make_request_async_raw(self.cstate.clone(), server_name.clone(),
+                protocol::Request::UpdatePresence { id, cursor, selection },
+                move |_, resp| {
+                    let (cursors, lock) = match resp {
+                        protocol::Response::Presence { cursors, lock, .. } => (cursors, lock),
+                        _ => (Vec::new(), None)
+                    };
+                    let mut state = ess.write().unwrap();
+                    if let Some(b) = state.buffers.iter_mut().find(|b| b.server_name == server_name && b.file_id == id) {
+                        b.remote_cursors = cursors;
+                        b.lock = lock;
+                    }
+                });
+        }
     }
 
     pub fn run(&mut self) {
@@ -591,28 +1360,179 @@ impl AutosyncWorker {
             // should this function directly manipulate the futures? 
             // it would be possible to join all the request futures together and then poll them
             // with only one task, which would be more efficent.
+            for server_name in self.check_connections() {
+                ClientState::say_hello(self.cstate.clone(), server_name.clone());
+                // queued versions of open files are sent along with the other changed buffers below
+                let replay = {
+                    let mut cstate = self.cstate.write().unwrap();
+                    let state = self.state.read().unwrap();
+                    cstate.sync_queue.start_replay(&server_name,
+                        |path| state.buffers.iter().any(|b| b.server_name == server_name && b.path == path))
+                };
+                for p in replay {
+                    ClientState::replay_queued(self.cstate.clone(), p);
+                }
+            }
             let mut need_sync = Vec::new();
+            // journals are written after the locks are released, so editing doesn't wait on the disk
+            let mut journals = Vec::new();
             {
+            let cstate = self.cstate.read().unwrap();
             let state = self.state.read().unwrap();
             for (i,b) in state.buffers.iter().enumerate() {
-                if let Some(last_synced_action_id) = self.last_synced_action_ids
+                if b.read_only() { continue; }
+                // retry versions that didn't make it to the server last time
+                let queued = cstate.sync_queue.get(&b.server_name, &b.path).map_or(false, |p| !p.in_flight);
+                let changed = self.last_synced_action_ids
                     .entry(b.server_name.clone())
                         .or_insert_with(HashMap::new)
                     .insert(b.file_id, b.text.most_recent_action_id())
-                {
-                    if last_synced_action_id < b.text.most_recent_action_id() {
-                        need_sync.push(i);
+                    .map_or(false, |last_synced_action_id| last_synced_action_id < b.text.most_recent_action_id());
+                if changed {
+                    match cstate.recovery.snapshot(b) {
+                        Ok(Some(j)) => journals.push((b.path.clone(), j)),
+                        Ok(None) => {},
+                        Err(e) => println!("error writing recovery journal for {}: {}", b.path.to_string_lossy(), e)
                     }
                 }
+                if queued || changed {
+                    need_sync.push(i);
+                }
             }
             }
+            for (path, j) in journals {
+                if let Err(e) = j.write() {
+                    println!("error writing recovery journal for {}: {}", path.to_string_lossy(), e);
+                }
+            }
             // println!("autosync {:?}", need_sync);
             for i in need_sync {
                 ClientState::sync_buffer(self.cstate.clone(), self.state.clone(), i);
             }
+            self.update_presence();
         }
     }
 }
 
+#[cfg(test)]
+pub mod test {
+    use super::*;
 
+    // an editor with one pane showing a buffer of `text`, not connected to any server
+    pub fn state_with_text(text: &str) -> (PClientState, PEditorState) {
+        let mut es = EditorState::new();
+        es.buffers.push(Buffer::with_text(text));
+        es.panes.insert(0, Pane::whole_screen(PaneContent::buffer(0)));
+        (Arc::new(RwLock::new(ClientState::default())), Arc::new(RwLock::new(es)))
+    }
+
+    #[test]
+    fn macro_key_limit() {
+        let mut es = EditorState::new();
+        es.registers.insert('a', "x@a@a".into());
+        assert!(es.play_macro('a', MACRO_KEY_LIMIT / 5).is_ok());
+        assert_eq!(es.pending_keys.len(), MACRO_KEY_LIMIT);
+        assert!(es.play_macro('a', 1).is_err());
+        assert_eq!(es.pending_keys.len(), MACRO_KEY_LIMIT);
+        es.pending_keys.clear();
+        es.macro_keys_queued = 0;
+        assert!(es.play_macro('a', usize::MAX).is_err());
+        assert!(es.pending_keys.is_empty());
+    }
 
+    #[test]
+    fn buffer_for_file() {
+        let mut es = EditorState::new();
+        for (server_name, id) in [("local", 1), ("remote", 1), ("local", 2)] {
+            let mut b = Buffer::with_text("");
+            b.server_name = server_name.into();
+            b.file_id = protocol::FileId(id);
+            es.buffers.push(b);
+        }
+        assert_eq!(es.buffer_index_for_file("remote", protocol::FileId(1)), Some(1));
+        // closing a buffer moves the ones after it, so responses have to look theirs up again
+        es.buffers.remove(0);
+        assert_eq!(es.buffer_index_for_file("local", protocol::FileId(2)), Some(1));
+        assert_eq!(es.buffer_index_for_file("local", protocol::FileId(1)), None);
+    }
+
+    #[test]
+    fn settled_locations() {
+        let (_, es) = state_with_text("one\ntwo\nthree\n");
+        let mut es = es.write().unwrap();
+        es.buffers[0].cursor_index = 4;
+        es.push_jump();
+        es.buffers[0].cursor_index = 8;
+        es.set_mark('T').unwrap();
+        es.buffers[0].text.insert_range("zero\n", 0);
+        es.settle_locations(0);
+        assert!(es.jumps.iter().chain(es.global_marks.values()).all(|l| l.anchor.is_none()));
+        assert_eq!(es.jumps[0].index, 9);
+        assert_eq!(es.global_marks[&'T'].index, 13);
+        // later changes don't move them, and forgetting them leaves the buffer's own marks alone
+        es.buffers[0].text.delete_range(0, 5);
+        assert_eq!(es.jumps[0].index(&es.buffers[0]), 9);
+        let old = es.jumps.remove(0);
+        old.forget(&mut es);
+        assert_eq!(es.buffers[0].mark('T'), Some(8));
+    }
+
+    #[test]
+    fn for_each_cursor() {
+        let (_, es) = state_with_text("ab\ncd\nef\n");
+        let mut es = es.write().unwrap();
+        es.buffers[0].cursor_index = 3;
+        es.buffers[0].add_cursor(0);
+        es.buffers[0].add_cursor(6);
+        let mut seen = Vec::new();
+        es.for_each_cursor(|es| {
+            let buf = es.current_buffer_mut().unwrap();
+            seen.push(buf.cursor_index);
+            buf.text.insert_range("xy", buf.cursor_index);
+            buf.cursor_index += 1;
+            Ok(())
+        }).unwrap();
+        assert_eq!(seen, vec![6, 3, 0]);
+        let buf = &es.buffers[0];
+        assert_eq!(buf.text.text(), "xyab\nxycd\nxyef\n");
+        assert_eq!((buf.cursor_index, buf.all_cursors()), (6, vec![1, 6, 11]));
+        // the first error stops the rest, and the cursors are kept
+        let mut calls = 0;
+        let res: Result<(), Error> = es.for_each_cursor(|_| { calls += 1; Err(Error::InvalidCommand("stop".into())) });
+        assert!(res.is_err());
+        assert_eq!(calls, 1);
+        assert_eq!(es.buffers[0].all_cursors(), vec![1, 6, 11]);
+        // the edits at every cursor are undone at once
+        es.buffers[0].text.undo();
+        assert_eq!(es.buffers[0].text.text(), "ab\ncd\nef\n");
+        // deleting another cursor's text takes it away
+        let (_, es) = state_with_text("abc\ndef\n");
+        let mut es = es.write().unwrap();
+        es.buffers[0].cursor_index = 1;
+        es.buffers[0].add_cursor(0);
+        es.buffers[0].add_cursor(4);
+        es.for_each_cursor(|es| {
+            let buf = es.current_buffer_mut().unwrap();
+            let start = buf.current_start_of_line(buf.cursor_index);
+            buf.text.delete_range(start, buf.cursor_index + 1);
+            buf.cursor_index = start;
+            Ok(())
+        }).unwrap();
+        let buf = &es.buffers[0];
+        assert_eq!(buf.text.text(), "c\nef\n");
+        assert_eq!((buf.cursor_index, buf.all_cursors()), (0, vec![0, 2]));
+    }
+
+    #[test]
+    fn one_jump_for_every_cursor() {
+        use crate::command::Command;
+        let (cs, es) = state_with_text("a\nb\nc\nd\n");
+        let mut es = es.write().unwrap();
+        es.buffers[0].add_cursor(2);
+        es.buffers[0].add_cursor(4);
+        Command::parse("G").unwrap().execute(&mut es, cs).unwrap();
+        assert_eq!(es.jumps.len(), 1);
+        assert_eq!(es.jumps[0].index(&es.buffers[0]), 0);
+        assert_eq!(es.buffers[0].all_cursors(), vec![6]);
+    }
+}
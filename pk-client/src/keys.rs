@@ -0,0 +1,114 @@
+use runic::*;
+use std::fmt;
+
+// a key press as modes see it. Keys can be written out as text like `ihello<Esc>`, which is how macros
+// are kept in registers
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Key {
+    Char(char),
+    Ctrl(char),
+    Escape, Enter, Backspace, Delete, Tab, ShiftTab,
+    Left, Right, Up, Down
+}
+
+const KEY_NAMES: &[(&str, Key)] = &[
+    ("Esc", Key::Escape), ("CR", Key::Enter), ("BS", Key::Backspace), ("Del", Key::Delete),
+    ("Tab", Key::Tab), ("S-Tab", Key::ShiftTab), ("Left", Key::Left), ("Right", Key::Right),
+    ("Up", Key::Up), ("Down", Key::Down), ("lt", Key::Char('<'))
+];
+
+impl Key {
+    // the key for a window event, if it is one. Characters come from `ReceivedCharacter`, and everything
+    // else from `KeyboardInput`, so that each press only makes one key
+    pub fn from_event(e: &Event, modifiers: ModifiersState) -> Option<Key> {
+        match e {
+            Event::ReceivedCharacter(c) if !c.is_control() => Some(Key::Char(*c)),
+            Event::KeyboardInput { input: KeyboardInput { virtual_keycode: Some(vk), state: ElementState::Pressed, .. }, .. } => {
+                Some(match vk {
+                    VirtualKeyCode::Escape => Key::Escape,
+                    VirtualKeyCode::Return => Key::Enter,
+                    VirtualKeyCode::Back => Key::Backspace,
+                    VirtualKeyCode::Delete => Key::Delete,
+                    VirtualKeyCode::Tab if modifiers.shift() => Key::ShiftTab,
+                    VirtualKeyCode::Tab => Key::Tab,
+                    VirtualKeyCode::Left => Key::Left,
+                    VirtualKeyCode::Right => Key::Right,
+                    VirtualKeyCode::Up => Key::Up,
+                    VirtualKeyCode::Down => Key::Down,
+                    // the letter keys are the only ones named by a single letter
+                    _ if modifiers.ctrl() => {
+                        let name = format!("{:?}", vk);
+                        if name.len() != 1 { return None; }
+                        Key::Ctrl(name.chars().next().unwrap().to_ascii_lowercase())
+                    },
+                    _ => return None
+                })
+            },
+            _ => None
+        }
+    }
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Key::Char('<') => write!(f, "<lt>"),
+            Key::Char(c) => write!(f, "{}", c),
+            Key::Ctrl(c) => write!(f, "<C-{}>", c),
+            k => write!(f, "<{}>", KEY_NAMES.iter().find(|(_, nk)| nk == k).unwrap().0)
+        }
+    }
+}
+
+pub fn keys_to_string(keys: &[Key]) -> String {
+    keys.iter().map(|k| k.to_string()).collect()
+}
+
+// the keys written in `s`. Anything in angle brackets that doesn't name a key is just those characters,
+// and newlines and tabs are Enter and Tab, so that a macro can be put in the buffer, edited and yanked back
+pub fn parse_keys(s: &str) -> Vec<Key> {
+    let mut keys = Vec::new();
+    let mut rest = s;
+    while let Some(c) = rest.chars().next() {
+        rest = &rest[c.len_utf8()..];
+        if c == '<' {
+            if let Some(end) = rest.find('>') {
+                let name = &rest[..end];
+                let key = KEY_NAMES.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, k)| *k)
+                    .or_else(|| match (name.get(..2), name.get(2..).filter(|l| l.chars().count() == 1)) {
+                        (Some("C-"), Some(l)) | (Some("c-"), Some(l)) => l.chars().next().map(|l| Key::Ctrl(l.to_ascii_lowercase())),
+                        _ => None
+                    });
+                if let Some(k) = key {
+                    keys.push(k);
+                    rest = &rest[end+1..];
+                    continue;
+                }
+            }
+        }
+        keys.push(match c {
+            '\n' => Key::Enter,
+            '\t' => Key::Tab,
+            c => Key::Char(c)
+        });
+    }
+    keys
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let keys = vec![Key::Char('i'), Key::Char('<'), Key::Char('a'), Key::Escape, Key::Ctrl('e'), Key::ShiftTab, Key::Char('>')];
+        assert_eq!(keys_to_string(&keys), "i<lt>a<Esc><C-e><S-Tab>>");
+        assert_eq!(parse_keys(&keys_to_string(&keys)), keys);
+    }
+
+    #[test]
+    fn parse_text() {
+        assert_eq!(parse_keys("a<b>\n<esc>"), vec![Key::Char('a'), Key::Char('<'), Key::Char('b'), Key::Char('>'),
+            Key::Enter, Key::Escape]);
+    }
+}
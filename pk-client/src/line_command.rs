@@ -1,9 +1,52 @@
 use super::*;
+use crate::buffer::Buffer;
+use pk_common::piece_table::Anchor;
+use std::ops::Range;
  
 pub trait CommandFn {
     fn process(&self, cs: PClientState, es: PEditorState, a: &regex::Captures) -> mode::ModeEventResult;
 }
 
+// every line command, with the pattern that picks it and captures its arguments. The first match is the one that runs
+pub fn command_table() -> Vec<(regex::Regex, Rc<dyn CommandFn>)> {
+    use regex::Regex;
+    vec![
+        (Regex::new("^test (.*)").unwrap(), Rc::new(TestCommand)),
+        (Regex::new("^q").unwrap(), Rc::new(QuitCommand)),
+        (Regex::new("^dbg pt").unwrap(), Rc::new(DebugPieceTableCommand)),
+        (Regex::new("^dbg rg").unwrap(), Rc::new(DebugRegistersCommand)),
+        (Regex::new(r#"^dbg server(?:\s+(?P<server_name>\w+))?"#).unwrap(), Rc::new(DebugServerCommand)),
+        (Regex::new(r#"^ws(?:\s+(?:(?P<server_name>\w+):)?(?P<path>.+))?$"#).unwrap(), Rc::new(WorkspaceCommand)),
+        (Regex::new(r#"^ef\s+(?P<query>.+)"#).unwrap(), Rc::new(FindFileCommand)),
+        (Regex::new(r#"^e\s+(?:(?P<server_name>\w+):)?(?P<path>.*)"#).unwrap(), Rc::new(EditFileCommand)),
+        (Regex::new(r#"^b(?P<subcmd>\w+)?\s+(?P<name_query>.*)"#).unwrap(), Rc::new(BufferCommand)),
+        (Regex::new("^sync").unwrap(), Rc::new(SyncFileCommand)),
+        (Regex::new("^wa").unwrap(), Rc::new(WriteAllFilesCommand)),
+        (Regex::new("^w").unwrap(), Rc::new(WriteFileCommand)),
+        (Regex::new(r#"^fmt(?:\s+(?P<opts>.*))?"#).unwrap(), Rc::new(TextFormatCommand)),
+        (Regex::new(r#"^autosave\s+(?P<policy>\w+)(?:\s+(?P<secs>\d+))?"#).unwrap(), Rc::new(AutosavePolicyCommand)),
+        (Regex::new(r#"^(?P<un>un)?lock$"#).unwrap(), Rc::new(LockFileCommand)),
+        (Regex::new("^merge$").unwrap(), Rc::new(MergeCommand)),
+        (Regex::new(r#"^diff(?:\s+(?P<source>server|disk|off))?$"#).unwrap(), Rc::new(DiffCommand)),
+        (Regex::new(r#"^shutdown(?:\s+(?P<server_name>\w+))?"#).unwrap(), Rc::new(ShutdownServerCommand)),
+        (Regex::new(r#"^con\s+(?P<server_name>\w+)\s(?P<server_url>.*)"#).unwrap(), Rc::new(ConnectToServerCommand)),
+        (Regex::new(&format!(r#"^{}s(?P<args>[^\w\s].*)$"#, LINE_RANGE)).unwrap(), Rc::new(SubstituteCommand)),
+        (Regex::new(&format!(r#"^{}{}"#, LINE_RANGE, GLOBAL)).unwrap(), Rc::new(GlobalCommand)),
+        (Regex::new(&format!(r#"^{}d(?:elete)?(?:\s+(?P<reg>\S))?$"#, LINE_RANGE)).unwrap(), Rc::new(DeleteLinesCommand)),
+        (Regex::new(&format!(r#"^{}(?:(?P<move>m(?:ove)?)|t|co(?:py)?)\s*(?P<dest>{})$"#, LINE_RANGE, LINE_ADDRESS)).unwrap(), Rc::new(MoveLinesCommand)),
+        (Regex::new(&format!(r#"^{}sort(?P<reverse>!)?(?:\s+(?P<flags>[niu]+))?$"#, LINE_RANGE)).unwrap(), Rc::new(SortLinesCommand)),
+        (Regex::new(&format!(r#"^{}norm(?:al)?\s(?P<keys>.*)$"#, LINE_RANGE)).unwrap(), Rc::new(NormalKeysCommand)),
+        (Regex::new(r#"(\?|/)(.*)"#).unwrap(), Rc::new(SearchCommand))
+    ]
+}
+
+pub fn run_line_command(table: &[(regex::Regex, Rc<dyn CommandFn>)], cs: PClientState, es: PEditorState, cmd: &str) -> mode::ModeEventResult {
+    match table.iter().find_map(|(re, f)| re.captures(cmd).map(|args| (f, args))) {
+        Some((f, args)) => f.process(cs, es, &args),
+        None => Err(Error::InvalidCommand(cmd.into()))
+    }
+}
+
 pub struct TestCommand;
 
 impl CommandFn for TestCommand {
@@ -46,6 +89,80 @@ impl CommandFn for DebugRegistersCommand {
     }
 }
 
+pub struct DebugServerCommand;
+
+impl CommandFn for DebugServerCommand {
+    fn process(&self, cs: PClientState, es: PEditorState, a: &regex::Captures) -> mode::ModeEventResult {
+        let server_name = a.name("server_name").map(|m| m.as_str().to_owned())
+            .or_else(|| es.read().unwrap().current_buffer().map(|b| b.server_name.clone()))
+            .unwrap_or_else(|| String::from("local"));
+        ClientState::make_request_async(cs, server_name.clone(), protocol::Request::ServerStatus, move |cs, resp| {
+            match resp {
+                protocol::Response::ServerStatus(st) => {
+                    let mut m = format!("{}: up {}s, {} client(s), {} workspace(s), {} request(s), {} error(s), {} write error(s)",
+                        server_name, st.uptime_secs, st.connected_clients, st.workspaces, st.requests, st.errors, st.write_errors);
+                    if !st.clients.is_empty() {
+                        m += &format!("\n  clients: {}", st.clients.join(", "));
+                    }
+                    for f in st.open_files.iter() {
+                        m += &format!("\n  #{} {} v{} (disk v{}) autosave {:?}", f.id.0,
+                            f.path.as_ref().map_or_else(|| String::from("<no path>"), |p| p.to_string_lossy().into_owned()),
+                            f.version, f.disk_version, f.autosave);
+                        if let Some(l) = f.lock.as_ref() {
+                            m += &format!(", locked by {}", l.name);
+                        }
+                        if let Some(ms) = f.unsaved_for_ms {
+                            m += &format!(", unsaved for {}ms", ms);
+                        }
+                    }
+                    ClientState::process_usr_msgp(cs, UserMessage::info(m, None));
+                },
+                _ => cs.write().unwrap().process_error_str(String::from("unexpected response to server status request"))
+            }
+        });
+        Ok(Some(Box::new(NormalMode::new())))
+    }
+}
+
+pub struct ShutdownServerCommand;
+
+impl CommandFn for ShutdownServerCommand {
+    fn process(&self, cs: PClientState, es: PEditorState, a: &regex::Captures) -> mode::ModeEventResult {
+        let server_name = a.name("server_name").map(|m| m.as_str().to_owned())
+            .or_else(|| es.read().unwrap().current_buffer().map(|b| b.server_name.clone()))
+            .unwrap_or_else(|| String::from("local"));
+        let buffers: Vec<usize> = es.read().unwrap().buffers.iter().enumerate()
+            .filter(|(_, b)| b.server_name == server_name)
+            .map(|(i, _)| i).collect();
+        let shutdown = {
+            let cs = cs.clone();
+            move || ClientState::make_request_async(cs, server_name.clone(), protocol::Request::Shutdown, move |cs, resp| {
+                match resp {
+                    protocol::Response::Ack => ClientState::process_usr_msgp(cs,
+                        UserMessage::info(format!("{} is shutting down", server_name), None)),
+                    _ => cs.write().unwrap().process_error_str(String::from("unexpected response to shutdown request"))
+                }
+            })
+        };
+        if buffers.is_empty() {
+            shutdown();
+            return Ok(Some(Box::new(NormalMode::new())));
+        }
+        // make sure the server has everything before it goes: the last sync to finish asks it to shut down
+        let pending = Arc::new(std::sync::atomic::AtomicUsize::new(buffers.len()));
+        let shutdown = Arc::new(std::sync::Mutex::new(Some(shutdown)));
+        for i in buffers {
+            let (pending, shutdown) = (pending.clone(), shutdown.clone());
+            ClientState::sync_buffer_then(cs.clone(), es.clone(), i, SyncDone::new(move || {
+                if pending.fetch_sub(1, std::sync::atomic::Ordering::SeqCst) == 1 {
+                    if let Some(f) = shutdown.lock().unwrap().take() { f(); }
+                }
+            }));
+        }
+        Ok(Some(Box::new(NormalMode::new())))
+    }
+}
+
 pub struct EditFileCommand;
 
 impl CommandFn for EditFileCommand {
@@ -55,6 +172,7 @@ impl CommandFn for EditFileCommand {
         let path = a.name("path").map(|m| PathBuf::from(m.as_str()))
             .ok_or(Error::InvalidCommand("missing path for editing a file".into()))?;
         ClientState::open_buffer(cs, es, server_name, path, |state, cstate, buffer_index| {
+            state.push_jump();
             state.current_pane_mut().content = PaneContent::buffer(buffer_index);
             cstate.write().unwrap().force_redraw = true;
         });
@@ -62,6 +180,70 @@ impl CommandFn for EditFileCommand {
     }
 }
 
+pub struct WorkspaceCommand;
+
+impl CommandFn for WorkspaceCommand {
+    fn process(&self, cs: PClientState, es: PEditorState, a: &regex::Captures) -> mode::ModeEventResult {
+        use std::path::PathBuf;
+        match a.name("path") {
+            Some(path) => {
+                let server_name = a.name("server_name").map(|m| m.as_str().to_owned())
+                    .or_else(|| es.read().unwrap().current_buffer().map(|b| b.server_name.clone()))
+                    .unwrap_or_else(|| String::from("local"));
+                ClientState::open_workspace(cs, server_name, PathBuf::from(path.as_str()));
+            },
+            None => {
+                let mut cs = cs.write().unwrap();
+                let m = UserMessage::info(
+                    cs.workspaces.iter().fold(String::from("workspaces ="),
+                        |s, w| s + &format!(" {} ({}:{})", w.name, w.server_name, w.root.to_string_lossy())), None);
+                cs.process_usr_msg(m);
+            }
+        }
+        Ok(Some(Box::new(NormalMode::new())))
+    }
+}
+
+pub struct FindFileCommand;
+
+impl CommandFn for FindFileCommand {
+    fn process(&self, cs: PClientState, es: PEditorState, a: &regex::Captures) -> mode::ModeEventResult {
+        let query = a.name("query")
+            .ok_or_else(|| Error::InvalidCommand("expected file name".into()))?.as_str().to_owned();
+        let workspace = {
+            let cs = cs.read().unwrap();
+            let es = es.read().unwrap();
+            let server_name = es.current_buffer().map(|b| b.server_name.clone()).unwrap_or_else(|| String::from("local"));
+            cs.default_workspace(&es, &server_name)
+        }.ok_or_else(|| Error::InvalidCommand("no workspace to find files in, open one with ws <path>".into()))?;
+        let ess = es.clone();
+        ClientState::make_request_async(cs, workspace.server_name.clone(), protocol::Request::ListWorkspaceFiles { id: workspace.id },
+            move |cs, resp| {
+                match resp {
+                    protocol::Response::WorkspaceFiles { paths, .. } => {
+                        use fuzzy_matcher::{FuzzyMatcher, skim::SkimMatcherV2};
+                        let matcher = SkimMatcherV2::default();
+                        let best = paths.iter()
+                            .filter_map(|p| p.to_str().and_then(|s| matcher.fuzzy_match(s, &query)).map(|m| (p, m)))
+                            .max_by_key(|(_, m)| *m);
+                        match best {
+                            Some((path, _)) => ClientState::open_buffer(cs, ess, workspace.server_name.clone(),
+                                workspace.root.join(path), |state, cstate, buffer_index| {
+                                    state.push_jump();
+                                    state.current_pane_mut().content = PaneContent::buffer(buffer_index);
+                                    cstate.write().unwrap().force_redraw = true;
+                                }),
+                            None => cs.write().unwrap().process_error_str(
+                                format!("no file in workspace {} matches {}", workspace.name, query))
+                        }
+                    },
+                    _ => cs.write().unwrap().process_error_str(String::from("unexpected response to list files request"))
+                }
+            });
+        Ok(Some(Box::new(NormalMode::new())))
+    }
+}
+
 pub struct BufferCommand;
 
 impl CommandFn for BufferCommand {
@@ -81,7 +263,9 @@ impl CommandFn for BufferCommand {
         match a.name("subcmd").map(|m| m.as_str()) {
             None => {
                 if let Some((buffer_index, _score)) = bufs.get(0) {
-                    es.write().unwrap().current_pane_mut().content = PaneContent::buffer(*buffer_index);
+                    let mut es = es.write().unwrap();
+                    es.push_jump();
+                    es.current_pane_mut().content = PaneContent::buffer(*buffer_index);
                     Ok(Some(Box::new(NormalMode::new())))
                 } else {
                     Err(Error::InvalidCommand(format!("no matching buffer for {}", name_query)))
@@ -90,6 +274,7 @@ impl CommandFn for BufferCommand {
             Some("x") => {
                 if let Some((index, _score)) = bufs.get(0) {
                     let mut state = es.write().unwrap();
+                    state.settle_locations(*index);
                     let buf = state.buffers.remove(*index);
                     drop(state);
                     ClientState::make_request_async(cs, buf.server_name, protocol::Request::CloseFile(buf.file_id), 
@@ -112,7 +297,7 @@ impl CommandFn for BufferCommand {
                 let estate = es.read().unwrap();
                 let m = UserMessage::info(
                     bufs.iter().fold(String::from("matching buffers ="),
-                            |s, b| s + " " + estate.buffers[b.0].path.to_str().unwrap_or("")), None);
+                            |s, b| s + " " + &estate.buffers[b.0].display_path()), None);
                 state.process_usr_msg(m); 
                 Ok(Some(Box::new(NormalMode::new())))
             },
@@ -131,6 +316,210 @@ impl CommandFn for SyncFileCommand {
     }
 }
 
+pub struct WriteFileCommand;
+
+impl CommandFn for WriteFileCommand {
+    fn process(&self, cs: PClientState, es: PEditorState, _: &regex::Captures) -> mode::ModeEventResult {
+        let cb = { es.read().unwrap().current_buffer_index().ok_or_else(|| Error::InvalidCommand("no buffer to write".into()))? };
+        ClientState::write_buffer(cs, es, cb);
+        Ok(Some(Box::new(NormalMode::new())))
+    }
+}
+
+pub struct WriteAllFilesCommand;
+
+impl CommandFn for WriteAllFilesCommand {
+    fn process(&self, cs: PClientState, es: PEditorState, _: &regex::Captures) -> mode::ModeEventResult {
+        let n = { es.read().unwrap().buffers.len() };
+        for i in 0..n {
+            ClientState::write_buffer(cs.clone(), es.clone(), i);
+        }
+        Ok(Some(Box::new(NormalMode::new())))
+    }
+}
+
+pub struct AutosavePolicyCommand;
+
+impl CommandFn for AutosavePolicyCommand {
+    fn process(&self, cs: PClientState, es: PEditorState, args: &regex::Captures) -> mode::ModeEventResult {
+        use protocol::AutosavePolicy;
+        let secs = args.name("secs").map(|s| s.as_str().parse::<u64>()).transpose()
+            .map_err(|_| Error::InvalidCommand("expected a number of seconds".into()))?;
+        let policy = match args.name("policy").map(|m| m.as_str()) {
+            Some("manual") => AutosavePolicy::Manual,
+            Some("interval") => AutosavePolicy::Interval(secs.unwrap_or(1)),
+            Some("idle") => AutosavePolicy::OnIdle(secs.unwrap_or(1)),
+            _ => return Err(Error::InvalidCommand("expected autosave policy (manual, interval or idle)".into()))
+        };
+        let (server_name, id) = {
+            let es = es.read().unwrap();
+            let b = es.current_buffer().ok_or_else(|| Error::InvalidCommand("no buffer to set autosave policy for".into()))?;
+            (b.server_name.clone(), b.file_id)
+        };
+        ClientState::make_request_async(cs, server_name, protocol::Request::SetAutosavePolicy { id, policy }, |_, _| {});
+        Ok(Some(Box::new(NormalMode::new())))
+    }
+}
+
+pub struct LockFileCommand;
+
+impl CommandFn for LockFileCommand {
+    fn process(&self, cs: PClientState, es: PEditorState, args: &regex::Captures) -> mode::ModeEventResult {
+        let unlock = args.name("un").is_some();
+        let (server_name, id) = {
+            let es = es.read().unwrap();
+            let bi = es.current_buffer_index().ok_or_else(|| Error::InvalidCommand("no buffer to lock".into()))?;
+            (es.buffers[bi].server_name.clone(), es.buffers[bi].file_id)
+        };
+        let lock = {
+            let cs = cs.read().unwrap();
+            protocol::FileLock { client: cs.client_id, name: cs.config.user_name.clone() }
+        };
+        let req = if unlock { protocol::Request::UnlockFile { id } } else { protocol::Request::LockFile { id } };
+        ClientState::make_request_async(cs, server_name.clone(), req, move |cs, resp| {
+            // the client state has to be locked before the editor state
+            let mut cs = cs.write().unwrap();
+            let mut es = es.write().unwrap();
+            let b = match es.buffer_index_for_file(&server_name, id) {
+                Some(bi) => &mut es.buffers[bi],
+                None => return
+            };
+            match resp {
+                protocol::Response::Ack => {
+                    b.lock = if unlock { None } else { Some(lock) };
+                    cs.force_redraw = true;
+                },
+                protocol::Response::Locked { by, .. } => {
+                    cs.process_error_str(format!("{}:{} is locked by {}", b.server_name, b.display_path(), by.name));
+                    b.lock = Some(by);
+                },
+                _ => cs.process_error_str(String::from("unexpected response to lock request"))
+            }
+        });
+        Ok(Some(Box::new(NormalMode::new())))
+    }
+}
+
+pub struct MergeCommand;
+
+impl CommandFn for MergeCommand {
+    fn process(&self, cs: PClientState, es: PEditorState, _: &regex::Captures) -> mode::ModeEventResult {
+        let mut es = es.write().unwrap();
+        let b = es.current_buffer_mut().ok_or_else(|| Error::InvalidCommand("no buffer to merge".into()))?;
+        if b.merging.is_none() {
+            return Err(Error::InvalidCommand("no merge in progress".into()));
+        }
+        // the conflicts might have all been fixed by hand
+        if b.finish_merge() {
+            let m = format!("Finished merging {}:{}", b.server_name, b.display_path());
+            drop(es);
+            ClientState::process_usr_msgp(cs, UserMessage::info(m, None));
+            return Ok(Some(Box::new(NormalMode::new())));
+        }
+        let n = b.merge_conflicts().len();
+        Ok(Some(Box::new(mode::MergeMode::new(n))))
+    }
+}
+
+pub struct DiffCommand;
+
+impl CommandFn for DiffCommand {
+    fn process(&self, cs: PClientState, es: PEditorState, args: &regex::Captures) -> mode::ModeEventResult {
+        let source = args.name("source").map_or("server", |m| m.as_str());
+        let (server_name, id, buffer_index) = {
+            let es = es.read().unwrap();
+            let bi = es.current_buffer_index().ok_or_else(|| Error::InvalidCommand("no buffer to compare".into()))?;
+            (es.buffers[bi].server_name.clone(), es.buffers[bi].file_id, bi)
+        };
+        if source == "off" {
+            let mut state = es.write().unwrap();
+            state.buffers[buffer_index].diff = None;
+            let diff_panes: Vec<usize> = state.panes.iter()
+                .filter(|(_, p)| match p.content { PaneContent::Diff { buffer_index: bi, .. } => bi == buffer_index, _ => false })
+                .map(|(i, _)| *i).collect();
+            for i in diff_panes {
+                if state.panes.len() > 1 {
+                    let next = Pane::remove(&mut state.panes, i);
+                    if state.current_pane == i { state.current_pane = next; }
+                } else {
+                    state.panes.get_mut(&i).unwrap().content = PaneContent::Empty;
+                }
+            }
+            return Ok(Some(Box::new(NormalMode::new())));
+        }
+        let other_name = String::from(source);
+        ClientState::make_request_async(cs, server_name.clone(), protocol::Request::ReadFileText { id, from_disk: source == "disk" },
+            move |cs, resp| {
+                match resp {
+                    protocol::Response::FileText { contents, .. } => {
+                        let mut state = es.write().unwrap();
+                        let buffer_index = match state.buffer_index_for_file(&server_name, id) {
+                            Some(bi) => bi,
+                            None => return
+                        };
+                        state.buffers[buffer_index].start_diff(other_name, &contents);
+                        // show the other version next to the buffer, unless it already is
+                        let shown = state.panes.values().any(|p| match p.content {
+                            PaneContent::Diff { buffer_index: bi, .. } => bi == buffer_index, _ => false
+                        });
+                        if !shown {
+                            let cp = state.current_pane;
+                            Pane::split(&mut state.panes, cp, true, 0.5, PaneContent::diff(buffer_index));
+                        }
+                        drop(state);
+                        cs.write().unwrap().force_redraw = true;
+                    },
+                    _ => cs.write().unwrap().process_error_str(String::from("unexpected response to read file request"))
+                }
+            });
+        Ok(Some(Box::new(NormalMode::new())))
+    }
+}
+
+pub struct TextFormatCommand;
+
+impl CommandFn for TextFormatCommand {
+    fn process(&self, cs: PClientState, es: PEditorState, args: &regex::Captures) -> mode::ModeEventResult {
+        let (server_name, id, mut format) = {
+            let es = es.read().unwrap();
+            let b = es.current_buffer().ok_or_else(|| Error::InvalidCommand("no buffer to change format of".into()))?;
+            (b.server_name.clone(), b.file_id, b.format.clone())
+        };
+        let opts = args.name("opts").map(|m| m.as_str()).unwrap_or("");
+        if opts.trim().is_empty() {
+            ClientState::process_usr_msgp(cs, UserMessage::info(format!("{}", format), None));
+            return Ok(Some(Box::new(NormalMode::new())));
+        }
+        for opt in opts.split_whitespace() {
+            match opt.to_ascii_lowercase().as_str() {
+                "lf" => format.line_ending = protocol::LineEnding::LF,
+                "crlf" => format.line_ending = protocol::LineEnding::CRLF,
+                "bom" => format.bom = true,
+                "nobom" => format.bom = false,
+                "eol" => format.final_newline = true,
+                "noeol" => format.final_newline = false,
+                enc => format.encoding = enc.parse()
+                    .map_err(|_| Error::InvalidCommand(format!("unknown text format option {}", opt)))?
+            }
+        }
+        ClientState::make_request_async(cs, server_name.clone(), protocol::Request::SetTextFormat { id, format: format.clone() },
+            move |cs, resp| {
+                match resp {
+                    protocol::Response::Ack => {
+                        let mut es = es.write().unwrap();
+                        if let Some(bi) = es.buffer_index_for_file(&server_name, id) {
+                            es.buffers[bi].format = format;
+                        }
+                    },
+                    protocol::Response::Locked { by, .. } => cs.write().unwrap().process_error_str(
+                        format!("could not change the format, the file is locked by {}", by.name)),
+                    _ => cs.write().unwrap().process_error_str(String::from("unexpected response to set format request"))
+                }
+            });
+        Ok(Some(Box::new(NormalMode::new())))
+    }
+}
+
 pub struct ConnectToServerCommand;
 
 impl CommandFn for ConnectToServerCommand {
@@ -149,18 +538,815 @@ pub struct SearchCommand;
 
 impl CommandFn for SearchCommand {
     fn process(&self, cs: PClientState, es: PEditorState, args: &regex::Captures) -> mode::ModeEventResult {
-        let mut es = es.write().unwrap();
-        let cb = es.current_buffer_mut().unwrap();
-        cb.set_query(args.get(2).unwrap().as_str().into());
-        match cb.next_query_index(cb.cursor_index, match args.get(1).unwrap().as_str() {
+        let direction = match args.get(1).unwrap().as_str() {
             "/" => Direction::Forward,
             "?" => Direction::Backward,
             _ => panic!()
-        }, true) {
-            Some(ix) => cb.cursor_index = ix,
-            None => ClientState::process_usr_msgp(cs, UserMessage::error(format!("no matches for \"{}\"", args.get(2).unwrap().as_str()), None))
+        };
+        let pattern = args.get(2).unwrap().as_str();
+        let mut es = es.write().unwrap();
+        let cb = es.current_buffer_mut().unwrap();
+        // an empty pattern searches for the last one again
+        if !pattern.is_empty() || cb.query.is_none() {
+            cb.set_query(pattern).map_err(|e| Error::InvalidCommand(format!("invalid search pattern: {}", e)))?;
+        }
+        let from = match direction {
+            Direction::Forward => cb.cursor_index + 1,
+            Direction::Backward => cb.cursor_index
+        };
+        match cb.next_query_index(from, direction, true) {
+            Some(ix) => {
+                es.push_jump();
+                es.current_buffer_mut().unwrap().cursor_index = ix;
+            },
+            None => {
+                let msg = format!("no matches for \"{}\"", cb.query.as_ref().unwrap().pattern);
+                drop(es);
+                ClientState::process_usr_msgp(cs, UserMessage::error(msg, None));
+            }
         }
         Ok(Some(Box::new(NormalMode::new())))
     }
 }
 
+
+// the range before a line command, like `%`, `.,$`, `'<,'>` or `3,7+2`
+pub const LINE_RANGE: &str = r"(?P<range>%|(?:[.$\d+-]|'.)*(?:,(?:[.$\d+-]|'.)*)?)";
+// a single line, for commands like `m` that take one after their name
+pub const LINE_ADDRESS: &str = r"(?:[.$\d+-]|'.)+";
+// `g/pattern/command` or `v/pattern/command` after a range
+const GLOBAL: &str = r"(?P<cmd>g!?|v)(?P<args>[^\w\s].*)$";
+
+fn take_number(chars: &mut std::iter::Peekable<std::str::Chars>) -> Option<usize> {
+    let mut n = None;
+    while let Some(d) = chars.peek().and_then(|c| c.to_digit(10)) {
+        n = Some(n.unwrap_or(0) * 10 + d as usize);
+        chars.next();
+    }
+    n
+}
+
+// one end of a line range: a line number, `.` for the cursor's line, `$` for the last line or `'a` for
+// the line a mark is on, followed by any number of `+n`/`-n` offsets. Line 0 comes out as -1, since
+// it's only valid as a place to put lines before the first one
+fn address_line(buf: &Buffer, addr: &str) -> Result<isize, Error> {
+    let invalid = || Error::InvalidCommand(format!("invalid line address \"{}\"", addr));
+    let last = buf.last_line() as isize;
+    let mut chars = addr.trim().chars().peekable();
+    let mut line = match chars.peek() {
+        Some('.') => { chars.next(); buf.line_for_index(buf.cursor_index) as isize },
+        Some('$') => { chars.next(); last },
+        Some('\'') => {
+            chars.next();
+            let m = chars.next().ok_or_else(invalid)?;
+            let ix = buf.mark(m).ok_or_else(|| Error::InvalidCommand(format!("mark '{} isn't set", m)))?;
+            buf.line_for_index(ix) as isize
+        },
+        Some(c) if c.is_ascii_digit() => take_number(&mut chars).unwrap() as isize - 1,
+        _ => buf.line_for_index(buf.cursor_index) as isize
+    };
+    while let Some(sign) = chars.next() {
+        let n = take_number(&mut chars).unwrap_or(1) as isize;
+        match sign {
+            '+' => line += n,
+            '-' => line -= n,
+            _ => return Err(invalid())
+        }
+    }
+    if line < -1 || line > last {
+        return Err(Error::InvalidCommand(format!("line {} is out of range", line + 1)));
+    }
+    Ok(line)
+}
+
+fn parse_line_address(buf: &Buffer, addr: &str) -> Result<usize, Error> {
+    match address_line(buf, addr)? {
+        -1 => Err(Error::InvalidCommand("line 0 is out of range".into())),
+        line => Ok(line as usize)
+    }
+}
+
+// the first and last lines (counting from zero) of a range matched by `LINE_RANGE`. Without a range,
+// commands act on the line the cursor is on
+pub fn parse_line_range(buf: &Buffer, range: &str) -> Result<(usize, usize), Error> {
+    let range = range.trim();
+    if range == "%" {
+        return Ok((0, buf.last_line()));
+    }
+    let mut addrs = range.splitn(2, ',');
+    let first = parse_line_address(buf, addrs.next().unwrap())?;
+    let last = match addrs.next() {
+        Some(a) => parse_line_address(buf, a)?,
+        None => first
+    };
+    Ok((first.min(last), first.max(last)))
+}
+
+// split `s` at each `delim` that isn't escaped with a backslash, into at most `n` parts
+fn split_delimited(s: &str, delim: char, n: usize) -> Vec<String> {
+    let mut parts = vec![String::new()];
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c == delim && parts.len() < n {
+            parts.push(String::new());
+            continue;
+        }
+        let part = parts.last_mut().unwrap();
+        if c == '\\' {
+            match chars.next() {
+                Some(e) if e == delim => part.push(e),
+                Some(e) => { part.push('\\'); part.push(e); },
+                None => part.push('\\')
+            }
+        } else {
+            part.push(c);
+        }
+    }
+    parts
+}
+
+// replacements refer to captures like vim's `\1` and `&` for the whole match, or like `$1` and `${name}`,
+// and can have `\n` and `\t` in them. `\&` and `\$` are literal
+fn replacement_template(s: &str) -> String {
+    let mut t = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match (c, if c == '\\' { chars.next() } else { None }) {
+            ('\\', Some('n')) => t.push('\n'),
+            ('\\', Some('t')) => t.push('\t'),
+            ('\\', Some('$')) => t.push_str("$$"),
+            ('\\', Some(d)) if d.is_ascii_digit() => { t.push_str("${"); t.push(d); t.push('}'); },
+            ('\\', Some(e)) => t.push(e),
+            ('&', _) => t.push_str("${0}"),
+            (c, _) => t.push(c)
+        }
+    }
+    t
+}
+
+// one change that a substitute command makes
+pub struct Replacement {
+    pub range: Range<usize>,
+    pub text: String
+}
+
+impl Replacement {
+    // make this change, where the changes made before it have moved the text by `shift`. Returns the
+    // shift after this change
+    pub fn apply(&self, buf: &mut Buffer, shift: isize) -> isize {
+        let start = (self.range.start as isize + shift) as usize;
+        if !self.range.is_empty() {
+            buf.text.delete_range(start, start + self.range.len());
+        }
+        buf.text.insert_range(&self.text, start);
+        buf.cursor_index = start;
+        shift + self.text.len() as isize - self.range.len() as isize
+    }
+}
+
+// every match of `regex` in `lines`, or only the first in each line unless `global`, along with the
+// text to replace it with
+fn find_replacements(buf: &Buffer, regex: &regex::Regex, template: &str, lines: (usize, usize), global: bool) -> Vec<Replacement> {
+    let start = buf.line_start_index(lines.0);
+    let end = buf.line_start_index(lines.1 + 1);
+    let mut replacements = Vec::new();
+    if start >= end { return replacements; }
+    let mut line_start = start;
+    for line in buf.text.copy_range(start, end).split_inclusive('\n') {
+        for caps in regex.captures_iter(line.strip_suffix('\n').unwrap_or(line)) {
+            let m = caps.get(0).unwrap();
+            let mut text = String::new();
+            caps.expand(template, &mut text);
+            replacements.push(Replacement { range: line_start + m.start() .. line_start + m.end(), text });
+            if !global { break; }
+        }
+        line_start += line.len();
+    }
+    replacements
+}
+
+pub struct SubstituteCommand;
+
+impl CommandFn for SubstituteCommand {
+    fn process(&self, _cs: PClientState, es: PEditorState, args: &regex::Captures) -> mode::ModeEventResult {
+        let sargs = args.name("args").unwrap().as_str();
+        let delim = sargs.chars().next().unwrap();
+        let parts = split_delimited(&sargs[delim.len_utf8()..], delim, 3);
+        let (mut global, mut confirm, mut ignore_case) = (false, false, None);
+        for f in parts.get(2).map_or("", |f| f.trim()).chars() {
+            match f {
+                'g' => global = true,
+                'c' => confirm = true,
+                'i' => ignore_case = Some(true),
+                'I' => ignore_case = Some(false),
+                _ => return Err(Error::InvalidCommand(format!("unknown substitute flag '{}'", f)))
+            }
+        }
+        let mut es = es.write().unwrap();
+        let buf = es.current_buffer_mut().ok_or_else(|| Error::InvalidCommand("no buffer to substitute in".into()))?;
+        if buf.read_only() {
+            return Err(Error::InvalidCommand("buffer is read only".into()));
+        }
+        let lines = parse_line_range(buf, args.name("range").map_or("", |r| r.as_str()))?;
+        // an empty pattern substitutes the last one searched for
+        let pattern = if parts[0].is_empty() {
+            buf.query.as_ref().map(|q| q.pattern.clone())
+                .ok_or_else(|| Error::InvalidCommand("no previous search pattern".into()))?
+        } else {
+            parts[0].clone()
+        };
+        let query = match ignore_case {
+            Some(ic) => crate::search::Query::with_case(&pattern, ic),
+            None => crate::search::Query::new(&pattern)
+        }.map_err(|e| Error::InvalidCommand(format!("invalid search pattern: {}", e)))?;
+        let template = replacement_template(parts.get(1).map_or("", |r| r.as_str()));
+        let replacements = find_replacements(buf, query.regex(), &template, lines, global);
+        buf.set_query(&pattern).ok();
+        if replacements.is_empty() {
+            return Err(Error::NoMatches(pattern));
+        }
+        if confirm {
+            return Ok(Some(Box::new(SubstituteConfirmMode::new(buf, replacements))));
+        }
+        // all of the changes are undone together
+        let first_action = buf.text.next_action_id;
+        let mut shift = 0;
+        for r in replacements.iter() {
+            shift = r.apply(buf, shift);
+        }
+        buf.text.squash_history(first_action);
+        buf.cursor_index = buf.current_start_of_line(buf.cursor_index);
+        Ok(Some(Box::new(NormalMode::new())))
+    }
+}
+
+// the range a command was given, or `default` when it was left out
+fn line_range_or(buf: &Buffer, args: &regex::Captures, default: Option<(usize, usize)>) -> Result<(usize, usize), Error> {
+    match (args.name("range").map_or("", |r| r.as_str().trim()), default) {
+        ("", Some(d)) => Ok(d),
+        (range, _) => parse_line_range(buf, range)
+    }
+}
+
+// the position of the end of a line, which is gone once the whole line has been deleted
+fn line_end_index(buf: &Buffer, line: usize) -> usize {
+    let next = buf.line_start_index(line + 1);
+    if next > 0 && buf.text.char_at(next - 1) == Some('\n') { next - 1 } else { next }
+}
+
+// delete whole lines, returning their text
+fn delete_lines(buf: &mut Buffer, first: usize, last: usize) -> String {
+    let mut start = buf.line_start_index(first);
+    let end = buf.line_start_index(last + 1);
+    let text = buf.text.copy_range(start, end);
+    // without a newline after the last line, take the one before the first line instead so that an empty line isn't left behind
+    if !text.ends_with('\n') && start > 0 {
+        start -= 1;
+    }
+    if end > start {
+        buf.text.delete_range(start, end);
+    }
+    text
+}
+
+// put whole lines after line `after`, or before the first line if it is -1
+fn insert_lines(buf: &mut Buffer, after: isize, lines: &str) {
+    let mut at = if after < 0 { 0 } else { buf.line_start_index(after as usize + 1) };
+    let mut lines = lines.to_string();
+    if !lines.ends_with('\n') {
+        lines.push('\n');
+    }
+    if at == buf.text.len() && at > 0 && buf.text.char_at(at - 1) != Some('\n') {
+        buf.text.insert_range("\n", at);
+        at += 1;
+        lines.pop();
+    }
+    buf.text.insert_range(&lines, at);
+}
+
+// the anchors on lines `first` to `last`, with the line each is on and how far into it it is
+fn line_anchors(buf: &Buffer, first: usize, last: usize) -> Vec<(Anchor, usize, usize)> {
+    let mut end = buf.line_start_index(last + 1);
+    // an anchor can be just past the last line when it doesn't end in a newline
+    if end == buf.text.len() { end += 1; }
+    buf.text.anchors_in(buf.line_start_index(first)..end).into_iter().map(|(a, ix)| {
+        let line = buf.line_for_index(ix);
+        (a, line, ix - buf.line_start_index(line))
+    }).collect()
+}
+
+// put anchors from `line_anchors` back on the lines they're on now. Moving lines deletes and inserts
+// them again, which would otherwise lose their anchors, like the marks on them
+fn restore_line_anchors(buf: &mut Buffer, anchors: Vec<(Anchor, usize, usize)>, new_line: impl Fn(usize) -> usize) {
+    for (a, line, col) in anchors {
+        let line = new_line(line);
+        let ix = (buf.line_start_index(line) + col).min(line_end_index(buf, line));
+        buf.text.move_anchor(a, ix);
+    }
+}
+
+pub struct DeleteLinesCommand;
+
+impl CommandFn for DeleteLinesCommand {
+    fn process(&self, _cs: PClientState, es: PEditorState, args: &regex::Captures) -> mode::ModeEventResult {
+        let mut es = es.write().unwrap();
+        let buf = es.current_buffer_mut().ok_or_else(|| Error::InvalidCommand("no buffer to delete lines from".into()))?;
+        if buf.read_only() {
+            return Err(Error::InvalidCommand("buffer is read only".into()));
+        }
+        let (first, last) = line_range_or(buf, args, None)?;
+        let first_action = buf.text.next_action_id;
+        let mut text = delete_lines(buf, first, last);
+        buf.text.squash_history(first_action);
+        buf.cursor_index = buf.current_start_of_line(buf.line_start_index(first).min(buf.text.len().saturating_sub(1)));
+        // registers hold whole lines with a newline at the end, so that they're put as lines
+        if !text.ends_with('\n') {
+            text.push('\n');
+        }
+        let reg = args.name("reg").and_then(|r| r.as_str().chars().next()).unwrap_or('"');
+        es.registers.insert(reg, text);
+        Ok(Some(Box::new(NormalMode::new())))
+    }
+}
+
+pub struct MoveLinesCommand;
+
+impl CommandFn for MoveLinesCommand {
+    fn process(&self, _cs: PClientState, es: PEditorState, args: &regex::Captures) -> mode::ModeEventResult {
+        let mut es = es.write().unwrap();
+        let buf = es.current_buffer_mut().ok_or_else(|| Error::InvalidCommand("no buffer to move lines in".into()))?;
+        if buf.read_only() {
+            return Err(Error::InvalidCommand("buffer is read only".into()));
+        }
+        let (first, last) = line_range_or(buf, args, None)?;
+        let dest = address_line(buf, args.name("dest").unwrap().as_str())?;
+        let count = (last - first) as isize;
+        let first_action = buf.text.next_action_id;
+        // the line the cursor ends up on, which is the last of the moved or copied lines
+        let cursor_line = if args.name("move").is_none() {
+            let lines = buf.text.copy_range(buf.line_start_index(first), buf.line_start_index(last + 1));
+            insert_lines(buf, dest, &lines);
+            dest + 1 + count
+        } else if dest >= first as isize - 1 && dest <= last as isize {
+            if dest != first as isize - 1 && dest != last as isize {
+                return Err(Error::InvalidCommand("can't move lines into themselves".into()));
+            }
+            last as isize
+        } else if dest > last as isize {
+            let (dest, count) = (dest as usize, count as usize);
+            let anchors = line_anchors(buf, first, dest);
+            let lines = buf.text.copy_range(buf.line_start_index(first), buf.line_start_index(last + 1));
+            insert_lines(buf, dest as isize, &lines);
+            delete_lines(buf, first, last);
+            restore_line_anchors(buf, anchors, |ln| match ln {
+                ln if ln >= first && ln <= last => ln - first + dest - count,
+                ln if ln > last && ln <= dest => ln - count - 1,
+                ln => ln
+            });
+            dest as isize
+        } else {
+            let (to, count) = ((dest + 1) as usize, count as usize);
+            // the line before `first` loses its newline when the last line has none
+            let anchors = line_anchors(buf, to, last);
+            let lines = delete_lines(buf, first, last);
+            insert_lines(buf, dest, &lines);
+            restore_line_anchors(buf, anchors, |ln| match ln {
+                ln if ln >= first && ln <= last => ln - first + to,
+                ln if ln >= to && ln < first => ln + count + 1,
+                ln => ln
+            });
+            (to + count) as isize
+        };
+        buf.text.squash_history(first_action);
+        buf.cursor_index = buf.line_start_index(cursor_line as usize);
+        Ok(Some(Box::new(NormalMode::new())))
+    }
+}
+
+pub struct SortLinesCommand;
+
+impl CommandFn for SortLinesCommand {
+    fn process(&self, _cs: PClientState, es: PEditorState, args: &regex::Captures) -> mode::ModeEventResult {
+        let flags = args.name("flags").map_or("", |f| f.as_str());
+        let (numeric, ignore_case, unique) = (flags.contains('n'), flags.contains('i'), flags.contains('u'));
+        let mut es = es.write().unwrap();
+        let buf = es.current_buffer_mut().ok_or_else(|| Error::InvalidCommand("no buffer to sort".into()))?;
+        if buf.read_only() {
+            return Err(Error::InvalidCommand("buffer is read only".into()));
+        }
+        let (first, last) = line_range_or(buf, args, Some((0, buf.last_line())))?;
+        let start = buf.line_start_index(first);
+        let end = buf.line_start_index(last + 1);
+        if start >= end {
+            return Ok(Some(Box::new(NormalMode::new())));
+        }
+        let text = buf.text.copy_range(start, end);
+        let number = regex::Regex::new(r"-?\d+").unwrap();
+        // lines sort by the first number in them with `n`, and lines without a number come first
+        let key = |ln: &str| -> (Option<i64>, String) {
+            if numeric {
+                (Some(number.find(ln).and_then(|m| m.as_str().parse().ok()).unwrap_or(i64::MIN)), String::new())
+            } else if ignore_case {
+                (None, ln.to_lowercase())
+            } else {
+                (None, ln.to_string())
+            }
+        };
+        let mut lines: Vec<(_, &str)> = text.strip_suffix('\n').unwrap_or(&text).split('\n').map(|ln| (key(ln), ln)).collect();
+        lines.sort_by(|a, b| a.0.cmp(&b.0));
+        if args.name("reverse").is_some() {
+            lines.reverse();
+        }
+        if unique {
+            lines.dedup_by(|a, b| a.0 == b.0);
+        }
+        let mut sorted = lines.iter().map(|(_, ln)| *ln).collect::<Vec<_>>().join("\n");
+        if text.ends_with('\n') {
+            sorted.push('\n');
+        }
+        if sorted != text {
+            let first_action = buf.text.next_action_id;
+            buf.text.delete_range(start, end);
+            buf.text.insert_range(&sorted, start);
+            buf.text.squash_history(first_action);
+        }
+        buf.cursor_index = start;
+        Ok(Some(Box::new(NormalMode::new())))
+    }
+}
+
+// run `keys` as if they were typed in Normal mode. Once a command enters Insert mode, the rest of the
+// keys are inserted as text
+pub fn run_normal_keys(state: &mut EditorState, cs: PClientState, keys: &str) -> Result<(), Error> {
+    use crate::command::Command;
+    let mut pending = String::new();
+    let mut chars = keys.chars();
+    while let Some(c) = chars.next() {
+        pending.push(c);
+        let cmd = match Command::parse(&pending) {
+            Ok(cmd) => cmd,
+            Err(Error::IncompleteCommand) => continue,
+            Err(e) => return Err(e)
+        };
+        pending.clear();
+        // macros are played by the main loop after the command is over, not on each line
+        if let Command::Record(_) | Command::PlayMacro { .. } = cmd {
+            return Err(Error::InvalidCommand("macros can't be recorded or played by normal".into()));
+        }
+        match cmd.execute(state, cs.clone())? {
+            None | Some(ModeTag::Normal) => {},
+            Some(ModeTag::Insert) => {
+                let rest: String = chars.collect();
+                if let Some(buf) = state.current_buffer_mut() {
+                    buf.text.insert_range(&rest, buf.cursor_index);
+                    buf.cursor_index += rest.len();
+                }
+                return Ok(());
+            },
+            Some(mode) => return Err(Error::InvalidCommand(format!("can't switch to {:?} mode from normal", mode)))
+        }
+    }
+    Ok(())
+}
+
+// runs `f` with the cursor at the start of each line in `lines`, even as `f` adds and removes lines.
+// Lines that have been deleted by the time they would be reached are skipped. All of the changes made
+// are undone together
+fn for_each_line<F>(es: &PEditorState, lines: impl Iterator<Item=usize>, mut f: F) -> Result<(), Error>
+    where F: FnMut() -> Result<(), Error>
+{
+    let (buffer_index, anchors, first_action) = {
+        let mut es = es.write().unwrap();
+        let buffer_index = es.current_buffer_index().ok_or_else(|| Error::InvalidCommand("no buffer to run commands in".into()))?;
+        let buf = &mut es.buffers[buffer_index];
+        let ends: Vec<usize> = lines.map(|ln| line_end_index(buf, ln)).collect();
+        (buffer_index, ends.into_iter().map(|ix| buf.text.anchor(ix)).collect::<Vec<_>>(), buf.text.next_action_id)
+    };
+    let mut res = Ok(());
+    for a in anchors.iter() {
+        {
+            let mut es = es.write().unwrap();
+            if es.current_buffer_index() != Some(buffer_index) {
+                res = Err(Error::InvalidCommand("the current buffer changed".into()));
+                break;
+            }
+            let buf = &mut es.buffers[buffer_index];
+            match buf.text.anchor_index(*a) {
+                Some(ix) => buf.cursor_index = buf.current_start_of_line(ix),
+                None => continue
+            }
+        }
+        if let Err(e) = f() {
+            res = Err(e);
+            break;
+        }
+    }
+    let mut es = es.write().unwrap();
+    if let Some(buf) = es.buffers.get_mut(buffer_index) {
+        for a in anchors {
+            buf.text.remove_anchor(a);
+        }
+        buf.text.squash_history(first_action);
+    }
+    res
+}
+
+pub struct NormalKeysCommand;
+
+impl CommandFn for NormalKeysCommand {
+    fn process(&self, cs: PClientState, es: PEditorState, args: &regex::Captures) -> mode::ModeEventResult {
+        let keys = args.name("keys").unwrap().as_str();
+        let (first, last) = {
+            let es = es.read().unwrap();
+            let buf = es.current_buffer().ok_or_else(|| Error::InvalidCommand("no buffer to run keys in".into()))?;
+            line_range_or(buf, args, None)?
+        };
+        for_each_line(&es, first..=last, || run_normal_keys(&mut es.write().unwrap(), cs.clone(), keys))?;
+        Ok(Some(Box::new(NormalMode::new())))
+    }
+}
+
+pub struct GlobalCommand;
+
+impl CommandFn for GlobalCommand {
+    fn process(&self, cs: PClientState, es: PEditorState, args: &regex::Captures) -> mode::ModeEventResult {
+        let invert = args.name("cmd").unwrap().as_str() != "g";
+        let gargs = args.name("args").unwrap().as_str();
+        let delim = gargs.chars().next().unwrap();
+        let parts = split_delimited(&gargs[delim.len_utf8()..], delim, 2);
+        let cmd = parts.get(1).map_or("", |c| c.trim());
+        if cmd.is_empty() {
+            return Err(Error::InvalidCommand("expected a command to run on each line".into()));
+        }
+        let table = command_table();
+        if regex::Regex::new(&format!("^{}{}", LINE_RANGE, GLOBAL)).unwrap().is_match(cmd) {
+            return Err(Error::InvalidCommand("g can't run inside of g".into()));
+        }
+        let lines = {
+            let mut es = es.write().unwrap();
+            let buf = es.current_buffer_mut().ok_or_else(|| Error::InvalidCommand("no buffer to run commands in".into()))?;
+            let (first, last) = line_range_or(buf, args, Some((0, buf.last_line())))?;
+            let pattern = if parts[0].is_empty() {
+                buf.query.as_ref().map(|q| q.pattern.clone())
+                    .ok_or_else(|| Error::InvalidCommand("no previous search pattern".into()))?
+            } else {
+                parts[0].clone()
+            };
+            buf.set_query(&pattern).map_err(|e| Error::InvalidCommand(format!("invalid search pattern: {}", e)))?;
+            let query = buf.query.as_ref().unwrap();
+            let start = buf.line_start_index(first);
+            let end = buf.line_start_index(last + 1);
+            let text = if start < end { buf.text.copy_range(start, end) } else { String::new() };
+            text.split_inclusive('\n').enumerate()
+                .filter(|(_, ln)| query.regex().is_match(ln.strip_suffix('\n').unwrap_or(ln)) != invert)
+                .map(|(i, _)| first + i).collect::<Vec<_>>()
+        };
+        for_each_line(&es, lines.into_iter(), || {
+            match run_line_command(&table, cs.clone(), es.clone(), cmd) {
+                Ok(Some(m)) if m.mode_tag() != ModeTag::Normal =>
+                    Err(Error::InvalidCommand(format!("g can't run commands that enter {:?} mode", m.mode_tag()))),
+                // it's fine for a command to not find anything on some of the lines
+                Ok(_) | Err(Error::NoMatches(_)) => Ok(()),
+                Err(e) => Err(e)
+            }
+        })?;
+        Ok(Some(Box::new(NormalMode::new())))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // ten lines, "0" to "9", with the cursor on line 4
+    fn numbered() -> Buffer {
+        let mut buf = Buffer::with_text(&(0..10).map(|i| format!("{}\n", i)).collect::<String>());
+        buf.cursor_index = buf.line_start_index(4);
+        buf
+    }
+
+    #[test]
+    fn line_ranges() {
+        let mut buf = numbered();
+        assert_eq!(parse_line_range(&buf, "%").unwrap(), (0, 9));
+        assert_eq!(parse_line_range(&buf, "").unwrap(), (4, 4));
+        assert_eq!(parse_line_range(&buf, ".").unwrap(), (4, 4));
+        assert_eq!(parse_line_range(&buf, ".,$").unwrap(), (4, 9));
+        assert_eq!(parse_line_range(&buf, "3,7+2").unwrap(), (2, 8));
+        assert_eq!(parse_line_range(&buf, ".-2,.+").unwrap(), (2, 5));
+        assert_eq!(parse_line_range(&buf, "7,3").unwrap(), (2, 6));
+        buf.set_mark('<', buf.line_start_index(1));
+        buf.set_mark('>', buf.line_start_index(6) + 1);
+        assert_eq!(parse_line_range(&buf, "'<,'>").unwrap(), (1, 6));
+        assert!(parse_line_range(&buf, "'a").is_err());
+        assert!(parse_line_range(&buf, "11").is_err());
+        assert!(parse_line_range(&buf, "0").is_err());
+        assert!(parse_line_range(&buf, "$+1").is_err());
+    }
+
+    #[test]
+    fn delimited() {
+        assert_eq!(split_delimited("a/b/g", '/', 3), vec!["a", "b", "g"]);
+        assert_eq!(split_delimited(r"a\/b/c", '/', 3), vec!["a/b", "c"]);
+        assert_eq!(split_delimited(r"\d+/x\n", '/', 3), vec![r"\d+", r"x\n"]);
+        assert_eq!(split_delimited("a/b/c/d", '/', 2), vec!["a", "b/c/d"]);
+        assert_eq!(split_delimited(r"a#b\#c#", '#', 3), vec!["a", "b#c", ""]);
+        assert_eq!(split_delimited(r"a\", '/', 3), vec![r"a\"]);
+    }
+
+    #[test]
+    fn replacement_templates() {
+        assert_eq!(replacement_template(r"\1-\2"), "${1}-${2}");
+        assert_eq!(replacement_template("[&]"), "[${0}]");
+        assert_eq!(replacement_template(r"\&\$"), "&$$");
+        assert_eq!(replacement_template(r"a\nb\tc"), "a\nb\tc");
+        assert_eq!(replacement_template("$1${name}"), "$1${name}");
+    }
+
+    #[test]
+    fn replacements() {
+        let buf = Buffer::with_text("foo bar foo\nfoo\nbaz foo\n");
+        let apply = |regex: &str, template: &str, lines, global| {
+            let mut buf = Buffer::with_text(&buf.text.text());
+            let regex = regex::Regex::new(regex).unwrap();
+            let mut shift = 0;
+            for r in find_replacements(&buf, &regex, &replacement_template(template), lines, global) {
+                shift = r.apply(&mut buf, shift);
+            }
+            buf.text.text()
+        };
+        assert_eq!(apply("foo", "x", (0, 2), false), "x bar foo\nx\nbaz x\n");
+        assert_eq!(apply("foo", "x", (0, 2), true), "x bar x\nx\nbaz x\n");
+        assert_eq!(apply("foo", "x", (1, 1), true), "foo bar foo\nx\nbaz foo\n");
+        assert_eq!(apply(r"(\w+) (\w+)", r"\2 \1", (0, 2), false), "bar foo foo\nfoo\nfoo baz\n");
+        assert_eq!(apply("o+", "<&>", (1, 2), true), "foo bar foo\nf<oo>\nbaz f<oo>\n");
+        assert_eq!(apply(r"(?P<c>b)a", r"${c}\n", (0, 2), true), "foo b\nr foo\nfoo\nb\nz foo\n");
+        assert!(find_replacements(&buf, &regex::Regex::new("nope").unwrap(), "", (0, 2), true).is_empty());
+    }
+
+    // run line commands on `text` with the cursor on `line`, returning the text and the cursor's line after
+    fn run(text: &str, line: usize, cmds: &[&str]) -> Result<(String, usize), Error> {
+        let (cs, es) = crate::editor_state::test::state_with_text(text);
+        {
+            let mut es = es.write().unwrap();
+            let buf = es.current_buffer_mut().unwrap();
+            buf.cursor_index = buf.line_start_index(line);
+        }
+        let table = command_table();
+        for cmd in cmds {
+            run_line_command(&table, cs.clone(), es.clone(), cmd)?;
+        }
+        let es = es.read().unwrap();
+        let buf = es.current_buffer().unwrap();
+        Ok((buf.text.text(), buf.line_for_index(buf.cursor_index)))
+    }
+
+    #[test]
+    fn line_addresses() {
+        let mut buf = numbered();
+        assert_eq!(address_line(&buf, "0").unwrap(), -1);
+        assert_eq!(address_line(&buf, "").unwrap(), 4);
+        assert_eq!(address_line(&buf, "$-3").unwrap(), 6);
+        assert_eq!(address_line(&buf, "1+1+1").unwrap(), 2);
+        assert_eq!(address_line(&buf, ".--").unwrap(), 2);
+        assert!(address_line(&buf, "0-1").is_err());
+        assert!(address_line(&buf, "3x").is_err());
+        buf.set_mark('a', buf.line_start_index(8));
+        assert_eq!(address_line(&buf, "'a-1").unwrap(), 7);
+    }
+
+    #[test]
+    fn delete_and_insert_lines() {
+        let mut buf = Buffer::with_text("a\nb\nc\nd\n");
+        assert_eq!(delete_lines(&mut buf, 1, 2), "b\nc\n");
+        assert_eq!(buf.text.text(), "a\nd\n");
+        insert_lines(&mut buf, -1, "x");
+        insert_lines(&mut buf, 2, "y\n");
+        assert_eq!(buf.text.text(), "x\na\nd\ny\n");
+        let mut buf = Buffer::with_text("a\nb\nc");
+        assert_eq!(delete_lines(&mut buf, 2, 2), "c");
+        assert_eq!(buf.text.text(), "a\nb");
+        insert_lines(&mut buf, 1, "z\n");
+        assert_eq!(buf.text.text(), "a\nb\nz");
+        insert_lines(&mut buf, 0, "q\n");
+        assert_eq!(buf.text.text(), "a\nq\nb\nz");
+    }
+
+    #[test]
+    fn move_lines() {
+        let text = "0\n1\n2\n3\n4\n";
+        assert_eq!(run(text, 0, &["2,3m5"]).unwrap(), ("0\n3\n4\n1\n2\n".into(), 4));
+        assert_eq!(run(text, 0, &["4,5m0"]).unwrap(), ("3\n4\n0\n1\n2\n".into(), 1));
+        assert_eq!(run(text, 3, &["m$"]).unwrap(), ("0\n1\n2\n4\n3\n".into(), 4));
+        assert_eq!(run(text, 3, &["t."]).unwrap(), ("0\n1\n2\n3\n3\n4\n".into(), 4));
+        assert_eq!(run(text, 0, &["1,2co0"]).unwrap(), ("0\n1\n0\n1\n2\n3\n4\n".into(), 1));
+        assert_eq!(run(text, 0, &["2,3m1"]).unwrap().0, text);
+        assert!(run(text, 0, &["1,3m2"]).is_err());
+        assert_eq!(run("a\nb\nc", 2, &["m0"]).unwrap(), ("c\na\nb".into(), 0));
+        assert_eq!(run("a\nb\nc", 0, &["m$"]).unwrap(), ("b\nc\na".into(), 2));
+    }
+
+    #[test]
+    fn moved_lines_keep_marks() {
+        let (cs, es) = crate::editor_state::test::state_with_text("a\nb\nc\nd");
+        {
+            let mut es = es.write().unwrap();
+            let buf = es.current_buffer_mut().unwrap();
+            buf.set_mark('a', 0);
+            buf.set_mark('d', 7);
+        }
+        let table = command_table();
+        run_line_command(&table, cs.clone(), es.clone(), "1m$").unwrap();
+        run_line_command(&table, cs, es.clone(), "3m0").unwrap();
+        let es = es.read().unwrap();
+        let buf = es.current_buffer().unwrap();
+        assert_eq!(buf.text.text(), "d\nb\nc\na");
+        assert_eq!(buf.mark('a'), Some(6));
+        // the mark was past the end of the last line, so it's on the end of the line now
+        assert_eq!(buf.mark('d'), Some(1));
+    }
+
+    #[test]
+    fn sort_lines() {
+        let text = "b\n10\nA\n9\nb\n";
+        assert_eq!(run(text, 0, &["sort"]).unwrap().0, "10\n9\nA\nb\nb\n");
+        assert_eq!(run(text, 0, &["sort u"]).unwrap().0, "10\n9\nA\nb\n");
+        assert_eq!(run(text, 0, &["sort! i"]).unwrap().0, "b\nb\nA\n9\n10\n");
+        assert_eq!(run(text, 0, &["sort n"]).unwrap().0, "b\nA\nb\n9\n10\n");
+        assert_eq!(run(text, 0, &["2,4sort"]).unwrap(), ("b\n10\n9\nA\nb\n".into(), 1));
+        assert_eq!(run("c\nb\na", 0, &["sort"]).unwrap().0, "a\nb\nc");
+    }
+
+    #[test]
+    fn global() {
+        let text = "a1\nb\na2\nc\na3\n";
+        assert_eq!(run(text, 0, &["g/a/d"]).unwrap().0, "b\nc\n");
+        assert_eq!(run(text, 0, &["v/a/d"]).unwrap().0, "a1\na2\na3\n");
+        assert_eq!(run(text, 0, &["g!/a/d"]).unwrap().0, "a1\na2\na3\n");
+        assert_eq!(run(text, 0, &["2,4g/a/d"]).unwrap().0, "a1\nb\nc\na3\n");
+        assert_eq!(run(text, 0, &["g/a/s/\\d/#/"]).unwrap().0, "a#\nb\na#\nc\na#\n");
+        assert_eq!(run(text, 0, &["g/a/m0"]).unwrap().0, "a3\na2\na1\nb\nc\n");
+        assert_eq!(run(text, 0, &["g/^/m0"]).unwrap().0, "a3\nc\na2\nb\na1\n");
+        assert_eq!(run(text, 0, &["g/./t$"]).unwrap().0, "a1\nb\na2\nc\na3\na1\nb\na2\nc\na3\n");
+        // a line that's gone before it's reached is skipped
+        assert_eq!(run("1\n2\n3\n4\n", 0, &["g/./.,+1d"]).unwrap().0, "");
+        // commands that find nothing on some lines are fine
+        assert_eq!(run(text, 0, &["g/./s/a/x/"]).unwrap().0, "x1\nb\nx2\nc\nx3\n");
+        assert!(run(text, 0, &["g/a/g/b/d"]).is_err());
+        assert!(run(text, 0, &["g/a/"]).is_err());
+        // the whole command is undone at once
+        let (cs, es) = crate::editor_state::test::state_with_text(text);
+        run_line_command(&command_table(), cs, es.clone(), "g/a/m0").unwrap();
+        let mut es = es.write().unwrap();
+        let buf = es.current_buffer_mut().unwrap();
+        buf.text.undo();
+        assert_eq!(buf.text.text(), text);
+    }
+
+    #[test]
+    fn each_line() {
+        let (_, es) = crate::editor_state::test::state_with_text("a\nb\nc\nd\n");
+        let mut seen = Vec::new();
+        for_each_line(&es, vec![0, 1, 3].into_iter(), || {
+            let mut es = es.write().unwrap();
+            let buf = es.current_buffer_mut().unwrap();
+            seen.push(buf.text.char_at(buf.cursor_index).unwrap());
+            // deleting the line after this one means line 1 is never reached
+            if buf.cursor_index == 0 {
+                delete_lines(buf, 1, 1);
+            }
+            buf.text.insert_range("-", buf.cursor_index);
+            Ok(())
+        }).unwrap();
+        assert_eq!(seen, vec!['a', 'd']);
+        let mut es = es.write().unwrap();
+        let buf = es.current_buffer_mut().unwrap();
+        assert_eq!(buf.text.text(), "-a\nc\n-d\n");
+        assert!(buf.text.anchors_in(0..buf.text.len() + 1).is_empty());
+        buf.text.undo();
+        assert_eq!(buf.text.text(), "a\nb\nc\nd\n");
+    }
+
+    #[test]
+    fn normal_keys() {
+        let text = "one\ntwo\nthree\n";
+        assert_eq!(run(text, 0, &["%norm Ax"]).unwrap().0, "onex\ntwox\nthreex\n");
+        assert_eq!(run(text, 0, &["2,3normal x"]).unwrap().0, "one\nwo\nhree\n");
+        assert_eq!(run(text, 0, &["g/t/norm I#"]).unwrap().0, "one\n#two\n#three\n");
+        assert!(run(text, 0, &["norm qaq"]).is_err());
+        assert!(run(text, 0, &["norm v"]).is_err());
+    }
+
+    #[test]
+    fn normal_keys_see_syntax() {
+        let (cs, es) = crate::editor_state::test::state_with_text("g(\")\", 1)\ng(\")\", 2)\n");
+        {
+            let mut es = es.write().unwrap();
+            es.buffers[0].path = "test.rs".into();
+            es.highlighter = Some(Arc::new(crate::syntax_highlight::Highlighter::from_toml(None)));
+        }
+        // the second line's brackets are found with the syntax of the text after the first one changed
+        run_line_command(&command_table(), cs, es.clone(), "%norm f,di(").unwrap();
+        assert_eq!(es.read().unwrap().buffers[0].text.text(), "g()\ng()\n");
+    }
+}
@@ -12,6 +12,10 @@ mod server;
 mod editor_state;
 mod config;
 mod syntax_highlight;
+mod sync_queue;
+mod recovery;
+mod search;
+mod keys;
 
 use runic::*;
 use pk_common::*;
@@ -20,6 +24,7 @@ use piece_table_render::PieceTableRenderer;use mode::*;use std::rc::Rc;
 use std::sync::{Arc, RwLock};
 use editor_state::*;
 use config::Config;
+use keys::Key;
 
 use std::error::Error as ErrorTrait;
 
@@ -30,6 +35,7 @@ pub enum Error {
     UnknownCommand(String),
     ConfigParseError(String, Option<toml::Value>),
     EmptyRegister(char),
+    NoMatches(String),
     Other(Box<dyn ErrorTrait + 'static>)
 }
 
@@ -41,6 +47,7 @@ impl std::fmt::Display for Error {
             Error::UnknownCommand(cmd) => write!(f, "unknown command: {}", cmd),
             Error::ConfigParseError(cmd, val) => write!(f, "bad configuration: {} (value = {:?})", cmd, val),
             Error::EmptyRegister(c) => write!(f, "nothing in register \"{}", c),
+            Error::NoMatches(pattern) => write!(f, "no matches for \"{}\"", pattern),
             Error::Other(e) => e.fmt(f)
         }
     }
@@ -66,22 +73,52 @@ struct PkApp {
     fnt: Font,
     txr: PieceTableRenderer,
     cmd_txr: PieceTableRenderer,
+    // panes showing the other side of a diff get their own layout cache, since the text is so similar
+    diff_txr: PieceTableRenderer,
     mode: Box<dyn Mode>,
     state: PEditorState,
     client: PClientState,
     synh: Option<Vec<piece_table_render::Highlight>>,
     last_highlighted_version: usize,
-        highlighter: syntax_highlight::Highlighter
+    modifiers: ModifiersState,
+        highlighter: Arc<syntax_highlight::Highlighter>
     }
     
+impl PkApp {
+    // `depth` is how many macros deep the key came from, zero if it was typed
+    fn key(&mut self, k: Key, depth: usize) {
+        {
+            let mut state = self.state.write().unwrap();
+            state.macro_depth = depth;
+            if depth == 0 {
+                state.macro_keys_queued = 0;
+                if let Some((_, keys)) = state.recording.as_mut() {
+                    keys.push(k);
+                }
+            }
+        }
+        match self.mode.event(k, self.client.clone(), self.state.clone()) {
+            Ok(Some(new_mode)) => { self.mode = new_mode },
+            Ok(None) => {},
+            Err(e) => {
+                println!("{:?}", e);
+                // the rest of a macro is dropped once something in it fails
+                self.state.write().unwrap().pending_keys.clear();
+                self.mode = Box::new(NormalMode::new());
+                self.client.write().unwrap().process_error(e);
+            }
+        };
+    }
+}
+
     impl runic::App for PkApp {
         fn init(rx: &mut RenderContext) -> Self {
             let mut cargs = pico_args::Arguments::from_env();
         
+        let projd = directories_next::ProjectDirs::from("", "", "pk").expect("compute application directory");
         let (config, errmsg) = if cargs.contains("--default-config") {
             (Config::default(), None)
         } else {
-            let projd = directories_next::ProjectDirs::from("", "", "pk").expect("compute application directory");
             let config_dir = cargs.opt_value_from_str("--config").unwrap()
                 .unwrap_or_else(|| std::path::Path::join(projd.config_dir(), "client.toml"));
             std::fs::read_to_string(config_dir).map_or_else(|e| {
@@ -98,6 +135,20 @@ struct PkApp {
         if let Some(em) = errmsg {
             client.process_usr_msg(em);
         }
+        match sync_queue::SyncQueue::load(projd.data_dir().join("sync-queue")) {
+            Ok(q) => {
+                if q.len() > 0 {
+                    client.process_usr_msg(UserMessage::info(
+                            format!("{} file(s) have edits that haven't reached their server yet, they will be synced when opened", q.len()), None));
+                }
+                client.sync_queue = q;
+            },
+            Err(e) => client.process_usr_msg(UserMessage::error(
+                    format!("error loading queued edits: {}", e), None))
+        }
+        client.recovery = recovery::RecoveryJournal::new(projd.data_dir().join("recovery"));
+        let recovered: Vec<(String, std::path::PathBuf)> = client.recovery.list().into_iter()
+            .map(|r| (r.server_name, r.path)).collect();
 
         estate.panes.insert(0, Pane::whole_screen(PaneContent::Empty));
 
@@ -111,9 +162,45 @@ struct PkApp {
             ClientState::connect_to_server(client.clone(), name.clone(), url);
         }
 
+        if recovered.len() > 0 {
+            let es = estate.clone();
+            ClientState::process_usr_msgp(client.clone(), UserMessage::warning(
+                format!("Found unsaved edits to {} file(s) from a previous session", recovered.len()),
+                Some((vec!["Open files to review".into(), "Discard all".into()], Box::new(move |index, cs| {
+                    match index {
+                        1 => for (server_name, path) in recovered.iter() {
+                            ClientState::open_buffer(cs.clone(), es.clone(), server_name.clone(), path.clone(),
+                            |estate, _, buffer_index| {
+                                let cnt = PaneContent::buffer(buffer_index);
+                                if let PaneContent::Empty = estate.current_pane().content {
+                                    estate.current_pane_mut().content = cnt;
+                                } else {
+                                    let cp = estate.current_pane;
+                                    Pane::split(&mut estate.panes, cp, true, 0.5, cnt);
+                                }
+                            });
+                        },
+                        2 => for (server_name, path) in recovered.iter() {
+                            let res = cs.read().unwrap().recovery.remove(server_name, path);
+                            if let Err(e) = res {
+                                cs.write().unwrap().process_error(e);
+                            }
+                        },
+                        _ => {}
+                    }
+                })))));
+        }
+
+        if let Some(root) = cargs.opt_value_from_str::<&str, std::path::PathBuf>("--workspace").unwrap() {
+            let root = std::env::current_dir().map(|d| d.join(&root)).unwrap_or(root);
+            ClientState::open_workspace(client.clone(), "local".into(), root);
+        }
+
         let free_args = cargs.free().unwrap();
         for farg in free_args.iter() {
-            ClientState::open_buffer(client.clone(), estate.clone(), "local".into(), std::path::PathBuf::from(farg),
+            // the local server might have been started somewhere else
+            let path = std::env::current_dir().map(|d| d.join(farg)).unwrap_or_else(|_| std::path::PathBuf::from(farg));
+            ClientState::open_buffer(client.clone(), estate.clone(), "local".into(), path,
             |estate, _, buffer_index| {
                 let cnt = PaneContent::buffer(buffer_index);
                 if estate.panes.len() == 1 {
@@ -129,7 +216,8 @@ struct PkApp {
             asw.run();
         });
 
-        let highlighter = syntax_highlight::Highlighter::from_toml(config.syntax_coloring.as_ref());
+        let highlighter = Arc::new(syntax_highlight::Highlighter::from_toml(config.syntax_coloring.as_ref()));
+        estate.write().unwrap().highlighter = Some(highlighter.clone());
 
         let fnt = rx.new_font(&config.font.0, config.font.1,
                               FontWeight::Regular, FontStyle::Normal).unwrap();
@@ -138,10 +226,12 @@ struct PkApp {
         let mut cmd_txr = PieceTableRenderer::init(rx, fnt.clone(), em_bounds);
         cmd_txr.cursor_style = CursorStyle::Line;
         cmd_txr.highlight_line = false;
+        let mut diff_txr = PieceTableRenderer::init(rx, fnt.clone(), em_bounds);
+        diff_txr.highlight_line = false;
         PkApp {
             mode: if free_args.len() == 0 { Box::new(mode::CommandMode::new()) } else { Box::new(mode::NormalMode::new()) },
-            fnt, txr, cmd_txr, state: estate, client, synh: None, last_highlighted_version: 0,
-            highlighter
+            fnt, txr, cmd_txr, diff_txr, state: estate, client, synh: None, last_highlighted_version: 0,
+            highlighter, modifiers: ModifiersState::default()
         }
     }
 
@@ -158,16 +248,19 @@ struct PkApp {
         }
         match e {
             Event::CloseRequested => *event_loop_flow = ControlFlowOpts::Exit,
+            Event::ModifiersChanged(ms) => self.modifiers = ms,
             _ => {
-                match self.mode.event(e, self.client.clone(), self.state.clone()) {
-                    Ok(Some(new_mode)) => { self.mode = new_mode },
-                    Ok(None) => {},
-                    Err(e) => {
-                        println!("{:?}", e);
-                        self.mode = Box::new(NormalMode::new());
-                        self.client.write().unwrap().process_error(e);
+                if let Some(k) = Key::from_event(&e, self.modifiers) {
+                    self.key(k, 0);
+                    // keys from macros are played until there are none left
+                    loop {
+                        let next = self.state.write().unwrap().pending_keys.pop_front();
+                        match next {
+                            Some((k, depth)) => self.key(k, depth),
+                            None => break
+                        }
                     }
-                };
+                }
             }
         }
     }
@@ -178,6 +271,7 @@ struct PkApp {
         let mut state = self.state.write().unwrap();
 
         let config = &client.config;
+        let client_id = client.client_id;
 
         rx.clear(config.colors.background);
 
@@ -222,8 +316,14 @@ struct PkApp {
         } else { rx.bounds().h };
 
         let screen_bounds = Rect::xywh(0.0, 0.0, rx.bounds().w, usrmsg_y);
-
-        for i in state.panes.keys().cloned().collect::<Vec<_>>() {
+        // partially loaded buffers that have been scrolled close to the end of what is loaded
+        let mut need_windows = Vec::new();
+
+        // diff panes follow the viewport of the buffer they're next to, so draw them after it's been scrolled
+        let recording = state.recording.as_ref().map(|(r, _)| format!(" recording @{}", r)).unwrap_or_default();
+        let mut pane_order = state.panes.keys().cloned().collect::<Vec<_>>();
+        pane_order.sort_by_key(|i| match state.panes[i].content { PaneContent::Diff { .. } => 1, _ => 0 });
+        for i in pane_order {
             let bounds = Rect::xywh(screen_bounds.x + screen_bounds.w * state.panes[&i].bounds.x + 1.0,
                                     screen_bounds.y + screen_bounds.h * state.panes[&i].bounds.y + 1.0,
                                     screen_bounds.w * state.panes[&i].bounds.w - 1.0, screen_bounds.h * state.panes[&i].bounds.h - 1.0);
@@ -239,33 +339,65 @@ struct PkApp {
                     let editor_bounds = Rect::xywh(bounds.x, bounds.y + self.txr.em_bounds.h + 4.0, bounds.w,
                                                        bounds.h);
                     let curln = buf.line_for_index(buf.cursor_index);
+                    let search_pos = buf.query_position();
 
                     // draw status line
                     rx.set_color(config.colors.quarter_gray);
                     rx.fill_rect(Rect::xywh(bounds.x, bounds.y, bounds.w, self.txr.em_bounds.h+2.0));
                     rx.set_color(if active { config.colors.accent[1] } else { config.colors.three_quarter_gray });
                     rx.draw_text(Rect::xywh(bounds.x + 8.0, bounds.y + 1.0, bounds.w, 1000.0),
-                        &format!("{} | ln {} col {} {}| {}:{} v{}{} [{}]", self.mode, curln + 1,
+                        &format!("{}{} | ln {} col {} {}| {}:{} v{}{} [{}{}]{}{}{}{}", self.mode, recording, curln + 1,
                             buf.column_for_index(buf.cursor_index),
                             if !scroll_lock { "!L " } else { "" },
-                            buf.server_name, buf.path.to_str().unwrap_or("!"), buf.version,
-                            if buf.currently_in_conflict { "⮾" } else { "" }, buf.format.stype
+                            buf.server_name, buf.display_path(), buf.version,
+                            if buf.currently_in_conflict { "⮾" } else { "" }, buf.format,
+                            if buf.view == protocol::FileView::Hex { " hex" } else { "" },
+                            match buf.window {
+                                Some(w) if !w.complete() => format!(" RO {}%", w.end * 100 / w.total.max(1)),
+                                _ if buf.read_only() => String::from(" RO"),
+                                _ => String::new()
+                            },
+                            match buf.lock.as_ref() {
+                                Some(l) if l.client == client_id => String::from(" locked"),
+                                Some(l) => format!(" locked by {}", l.name),
+                                None => String::new()
+                            },
+                            if buf.remote_cursors.is_empty() { String::new() } else {
+                                format!(" +{}", buf.remote_cursors.iter().map(|c| c.name.as_str()).collect::<Vec<_>>().join(","))
+                            },
+                            match search_pos {
+                                Some((i, n)) => format!(" [{}/{}]", i, n),
+                                None => String::new()
+                            }
                     ), &self.fnt);
 
                     self.txr.cursor_style = if active { self.mode.cursor_style() } else { CursorStyle::Box };
                     let mut vp = viewport_start;
                     if scroll_lock { self.txr.ensure_line_visible(&mut vp, curln, editor_bounds); }
                     if buf.highlights.is_none() || buf.last_highlighted_action_id < buf.text.most_recent_action_id() 
-                        || self.mode.mode_tag() == ModeTag::Insert
+                        || self.mode.mode_tag() == ModeTag::Insert || buf.syntax().is_none()
                     {
                         //let hstart = std::time::Instant::now();
-                        buf.highlights = Some(self.highlighter.compute_highlighting(buf));
+                        let (highlights, syntax) = self.highlighter.compute_highlighting(buf);
+                        buf.highlights = Some(highlights);
+                        buf.set_syntax(syntax);
                         buf.last_highlighted_action_id = buf.text.most_recent_action_id();
                         self.txr.invalidate_layout_cashe(buf.current_start_of_line(buf.cursor_index) .. buf.next_line_index(buf.cursor_index));
                         // println!("highlight took {}ms", (std::time::Instant::now()-hstart).as_nanos() as f32 / 1000000.0);
                     }
-                    self.txr.paint(rx, &buf.text, vp, buf.cursor_index,
-                        &config, editor_bounds, buf.highlights.as_ref(), true, self.mode.selection());
+                    if active { buf.selection = self.mode.selection().map(|s| s.span()); }
+                    buf.update_diff();
+                    if let Some(d) = buf.diff.as_ref() {
+                        let tints: Vec<_> = d.hunks.iter().map(|h| (h.new.clone(), config.colors.accent[3].with_alpha(0.15))).collect();
+                        self.txr.paint_line_tints(rx, vp, editor_bounds, &tints);
+                    }
+                    let viewport_end = self.txr.viewport_end(vp, &editor_bounds);
+                    self.txr.search_matches = match self.mode.pending_search() {
+                        Some(q) if active => q.matches(&buf.text, buf.line_start_index(vp), buf.line_start_index(viewport_end + 1)),
+                        _ => Vec::new()
+                    };
+                    self.txr.paint(rx, &buf.text, vp, buf.cursor_index, &buf.cursors(),
+                        &config, editor_bounds, buf.highlights.as_ref(), true, self.mode.selection().as_ref(), &buf.remote_cursors);
 
                      /*let mut y = 30.0;
                      let mut global_index = 0;
@@ -276,11 +408,44 @@ struct PkApp {
                          global_index += p.length;
                          y += 16.0;
                      }*/
+                    if buf.window.map_or(false, |w| !w.complete()) && !buf.loading_window
+                        && viewport_end + (viewport_end - vp) >= buf.loaded_lines
+                    {
+                        need_windows.push(buffer_index);
+                    }
                     state.panes.get_mut(&i).unwrap().content = PaneContent::Buffer {
                         buffer_index,
                         viewport_start: vp, scroll_lock,
-                        viewport_end: self.txr.viewport_end(vp, &editor_bounds)
+                        viewport_end
+                    };
+                },
+                PaneContent::Diff { buffer_index, viewport_start } => {
+                    let partner_vp = state.panes.values().filter_map(|p| match p.content {
+                        PaneContent::Buffer { buffer_index: bi, viewport_start, .. } if bi == buffer_index => Some(viewport_start),
+                        _ => None
+                    }).next().unwrap_or(0);
+                    let buf = match state.buffers.get(buffer_index) {
+                        Some(b) if b.diff.is_some() => b,
+                        _ => continue
                     };
+                    let d = buf.diff.as_ref().unwrap();
+                    let editor_bounds = Rect::xywh(bounds.x, bounds.y + self.txr.em_bounds.h + 4.0, bounds.w, bounds.h);
+                    rx.set_color(config.colors.quarter_gray);
+                    rx.fill_rect(Rect::xywh(bounds.x, bounds.y, bounds.w, self.txr.em_bounds.h+2.0));
+                    rx.set_color(if active { config.colors.accent[1] } else { config.colors.three_quarter_gray });
+                    rx.draw_text(Rect::xywh(bounds.x + 8.0, bounds.y + 1.0, bounds.w, 1000.0),
+                        &format!("diff | {}:{} ({}) {} difference{}", buf.server_name, buf.display_path(), d.other_name,
+                            d.hunks.len(), if d.hunks.len() == 1 { "" } else { "s" }), &self.fnt);
+                    // the diff pane stays where it was scrolled to unless that no longer lines up with the buffer
+                    let vp = if diff::new_line_for(&d.hunks, viewport_start) == partner_vp { viewport_start }
+                        else { diff::old_line_for(&d.hunks, partner_vp) };
+                    let tints: Vec<_> = d.hunks.iter().map(|h| (h.old.clone(), config.colors.accent[0].with_alpha(0.15))).collect();
+                    self.diff_txr.paint_line_tints(rx, vp, editor_bounds, &tints);
+                    self.diff_txr.cursor_style = CursorStyle::Box;
+                    // there's no cursor on this side
+                    self.diff_txr.paint(rx, &d.other, vp, d.other.len() + 1, &[],
+                        &config, editor_bounds, None, true, None, &[]);
+                    state.panes.get_mut(&i).unwrap().content = PaneContent::Diff { buffer_index, viewport_start: vp };
                 },
                 PaneContent::Empty => {
                     rx.set_color(config.colors.accent[5]);
@@ -295,15 +460,21 @@ struct PkApp {
             rx.set_color(config.colors.quarter_gray);
             rx.fill_rect(Rect::xywh(0.0, self.txr.em_bounds.h+2.0, rx.bounds().w, self.txr.em_bounds.h+2.0));
             rx.set_color(config.colors.three_quarter_gray);
-            self.cmd_txr.paint(rx, pending_cmd, 0, cmd_cur_index, &config,
+            self.cmd_txr.paint(rx, pending_cmd, 0, cmd_cur_index, &[], &config,
                                Rect::xywh(8.0, self.txr.em_bounds.h+2.0, rx.bounds().w-8.0, rx.bounds().h-20.0),
-                               None, false, None);
+                               None, false, None, &[]);
         }
 
         let end = std::time::Instant::now();
         rx.set_color(config.colors.quarter_gray);
         rx.draw_text(Rect::xywh(rx.bounds().w-148.0, rx.bounds().h - 20.0, 1000.0, 1000.0), &format!("f{}ms", (end-start).as_nanos() as f32 / 1000000.0), &self.fnt);
 
+        drop(state);
+        drop(client);
+        for buffer_index in need_windows {
+            ClientState::load_next_window(self.client.clone(), self.state.clone(), buffer_index);
+        }
+
     }
 }
 
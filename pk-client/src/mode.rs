@@ -1,30 +1,50 @@
 
 use std::fmt;
-use runic::*;
 use super::*;
 use std::ops::Range;
+use crate::buffer::Buffer;
 
 pub enum CursorStyle {
     Line, Block, Box, Underline
 }
 
+// what a mode has selected. A block is the same columns on each of its lines, and its span runs from where
+// the selection started to where the cursor is
+#[derive(Clone, Debug, PartialEq)]
+pub enum Selection {
+    Chars(Range<usize>),
+    Block { lines: Range<usize>, columns: Range<usize>, span: Range<usize> }
+}
+
+impl Selection {
+    // everything from the first selected character to the last, inclusive
+    pub fn span(&self) -> Range<usize> {
+        match self {
+            Selection::Chars(r) => r.clone(),
+            Selection::Block { span, .. } => span.clone()
+        }
+    }
+}
+
 pub type ModeEventResult = Result<Option<Box<dyn Mode>>, Error>;
 
 pub trait Mode : fmt::Display {
-    fn event(&mut self, e: Event, client: PClientState, state: PEditorState) -> ModeEventResult;
+    fn event(&mut self, k: Key, client: PClientState, state: PEditorState) -> ModeEventResult;
     fn mode_tag(&self) -> ModeTag;
     fn cursor_style(&self) -> CursorStyle { CursorStyle::Block }
     fn cmd_line(&self) -> Option<(usize, &PieceTable)> { None }
-    fn selection(&self) -> Option<&Range<usize>> { None }
+    fn selection(&self) -> Option<Selection> { None }
+    // the search being typed, to highlight its matches as it changes
+    fn pending_search(&mut self) -> Option<&search::Query> { None }
 }
 
 pub struct NormalMode {
-    pending_buf: String, ctrl_pressed: bool
+    pending_buf: String
 }
 
 impl NormalMode {
     pub fn new() -> NormalMode {
-        NormalMode { pending_buf: String::new(), ctrl_pressed: false }
+        NormalMode { pending_buf: String::new() }
     }
 }
 
@@ -39,49 +59,113 @@ impl Mode for NormalMode {
         ModeTag::Normal
     }
 
-    fn event(&mut self, e: Event, client: PClientState, state: PEditorState) -> ModeEventResult {
-        match e {
-            Event::ModifiersChanged(ms) => {
-                self.ctrl_pressed = ms.ctrl();
+    fn event(&mut self, k: Key, client: PClientState, state: PEditorState) -> ModeEventResult {
+        match k {
+            Key::Escape => {
+                // with nothing typed, Esc goes back to a single cursor
+                if self.pending_buf.is_empty() {
+                    if let Some(buf) = state.write().unwrap().current_buffer_mut() {
+                        buf.clear_cursors();
+                    }
+                }
+                self.pending_buf.clear();
+                Ok(None)
+            },
+            // adds a cursor at the next match of the last search, keeping the one that was there
+            Key::Ctrl('n') => {
+                self.pending_buf.clear();
+                let mut state = state.write().unwrap();
+                let buf = state.current_buffer_mut().ok_or_else(|| Error::InvalidCommand("no buffer".into()))?;
+                if buf.query.is_none() {
+                    return Err(Error::InvalidCommand("no previous search".into()));
+                }
+                let next = buf.next_query_index((buf.cursor_index + 1).min(buf.text.len()), Direction::Forward, true)
+                    .ok_or_else(|| Error::InvalidCommand("no matches for the last search".into()))?;
+                let old = buf.cursor_index;
+                buf.cursor_index = next;
+                buf.remove_cursor(next);
+                buf.add_cursor(old);
+                Ok(None)
+            },
+            Key::Left => {
+                let mut state = state.write().unwrap();
+                state.push_jump();
+                match &mut state.current_pane_mut().content {
+                    PaneContent::Buffer { buffer_index, .. } => 
+                        *buffer_index = buffer_index.saturating_sub(1),
+                    _ => {}
+                }
+                Ok(None)
+            },
+            Key::Right => {
+                let mut state = state.write().unwrap();
+                let numbufs = state.buffers.len();
+                state.push_jump();
+                match &mut state.current_pane_mut().content {
+                    PaneContent::Buffer { buffer_index, .. } => 
+                        *buffer_index = (*buffer_index + 1).min(numbufs.saturating_sub(1)),
+                    _ => {}
+                }
+                Ok(None)
+            }
+            Key::Ctrl('e') => {
+                Ok(Some(Box::new(UserMessageInteractionMode::new(client))))
+            }
+            // Tab and Ctrl-I are the same key in a terminal
+            Key::Ctrl('o') | Key::Ctrl('i') | Key::Tab => {
+                self.pending_buf.clear();
+                let dir = if k == Key::Ctrl('o') { Direction::Backward } else { Direction::Forward };
+                let next = state.write().unwrap().next_jump(dir);
+                if let Some(loc) = next {
+                    ClientState::jump_to(client, state, loc, false);
+                }
                 Ok(None)
             }
             
-            Event::KeyboardInput { input: KeyboardInput { virtual_keycode: Some(vk), state: ElementState::Pressed, .. }, .. } => {
-                match vk {
-                    VirtualKeyCode::Escape => {
-                        self.pending_buf.clear();
-                        Ok(None)
-                    },
-                    VirtualKeyCode::Left => {
-                        let mut state = state.write().unwrap();
-                        match &mut state.current_pane_mut().content {
-                            PaneContent::Buffer { buffer_index, .. } => 
-                                *buffer_index = buffer_index.saturating_sub(1),
-                            _ => {}
-                        }
-                        Ok(None)
-                    },
-                    VirtualKeyCode::Right => {
-                        let mut state = state.write().unwrap();
-                        let numbufs = state.buffers.len();
-                        match &mut state.current_pane_mut().content {
-                            PaneContent::Buffer { buffer_index, .. } => 
-                                *buffer_index = (*buffer_index + 1).min(numbufs.saturating_sub(1)),
-                            _ => {}
-                        }
-                        Ok(None)
-                    }
-                    VirtualKeyCode::E if self.ctrl_pressed => {
-                        Ok(Some(Box::new(UserMessageInteractionMode::new(client))))
-                    }
-                    _ => Ok(None) 
+            Key::Ctrl('v') => {
+                self.pending_buf.clear();
+                let state = state.read().unwrap();
+                match state.current_buffer() {
+                    Some(buf) => Ok(Some(Box::new(VisualMode::new(buf, VisualKind::Block)))),
+                    None => Ok(None)
                 }
             },
-            
-            Event::ReceivedCharacter(c) if !c.is_control() => {
+
+            // half a screen down or up, or the count typed before it times that
+            Key::Ctrl('d') | Key::Ctrl('u') => {
+                use crate::motion::{Motion, MotionType};
+                let count = self.pending_buf.parse().unwrap_or(1);
+                self.pending_buf.clear();
+                let dir = if k == Key::Ctrl('d') { Direction::Forward } else { Direction::Backward };
+                super::command::Command::Move(Motion { count, mo: MotionType::HalfPage(dir) })
+                    .execute(&mut state.write().unwrap(), client)?;
+                Ok(None)
+            },
+
+            Key::Char('q') if self.pending_buf.is_empty() && state.read().unwrap().recording.is_some() => {
+                state.write().unwrap().stop_recording();
+                Ok(None)
+            },
+
+            Key::Char(c) => {
                 use super::command::*;
                 self.pending_buf.push(c);
                 match Command::parse(&self.pending_buf) {
+                    // global marks can be in other files, which might need to be opened
+                    Ok(Command::Move(crate::motion::Motion { mo: crate::motion::MotionType::Mark { name, linewise }, .. }))
+                        if name.is_ascii_uppercase() =>
+                    {
+                        self.pending_buf.clear();
+                        let loc = {
+                            let mut state = state.write().unwrap();
+                            let loc = state.global_marks.get(&name).cloned()
+                                .ok_or_else(|| Error::InvalidCommand(format!("mark '{} isn't set", name)))?;
+                            state.push_jump();
+                            loc
+                        };
+                        ClientState::jump_to(client, state, loc, linewise);
+                        Ok(None)
+                    },
                     Ok(cmd) => {
                         let res = {
                             match cmd.execute(&mut state.write().unwrap(), client) {
@@ -97,12 +181,12 @@ impl Mode for NormalMode {
                             None | Some(ModeTag::Normal) => Ok(None),
                             Some(ModeTag::Command) => Ok(Some(Box::new(CommandMode::new()))),
                             Some(ModeTag::Search(dir)) => Ok(Some(Box::new(CommandMode::search(dir)))),
-                            Some(ModeTag::Visual) => Ok(Some(Box::new(VisualMode::new(state.read().unwrap().current_buffer().unwrap().cursor_index)))),
+                            Some(ModeTag::Visual(kind)) => Ok(Some(Box::new(VisualMode::new(state.read().unwrap().current_buffer().unwrap(), kind)))),
                             Some(ModeTag::Insert) => {
                                 let mut state = state.write().unwrap();
                                 if let PaneContent::Buffer { buffer_index, .. } = state.current_pane().content {
                                     let buf = &mut state.buffers[buffer_index];
-                                    Ok(Some(Box::new(InsertMode::new(buf.text.insert_mutator(buf.cursor_index))))) 
+                                    Ok(Some(Box::new(InsertMode::at_cursors(buf))))
                                 } else {
                                     Err(Error::InvalidCommand("".into()))
                                 }
@@ -126,24 +210,115 @@ impl Mode for NormalMode {
 }
 
 
+// text typed on the first line of a block is copied to the rest of its lines when Insert mode is done
+struct BlockInsert {
+    start: usize,
+    lines: Range<usize>,
+    column: usize,
+    pad: bool,
+    first_action: usize
+}
+
 pub struct InsertMode {
     tmut: Option<piece_table::TableMutator>,
-    ctrl_pressed: bool,
-    shift_pressed: bool,
-    clipboard: Option<copypasta::ClipboardContext>
+    clipboard: Option<copypasta::ClipboardContext>,
+    block: Option<BlockInsert>,
+    // with more than one cursor, text is inserted at each of them instead of with `tmut`, and everything
+    // since this action is undone together
+    at_cursors: Option<usize>
 }
 
 impl InsertMode {
-    fn new(tmut: piece_table::TableMutator) -> InsertMode {
+    fn new(tmut: Option<piece_table::TableMutator>) -> InsertMode {
         InsertMode {
-            tmut: Some(tmut),
-            shift_pressed: false,
-            ctrl_pressed: false,
+            tmut,
             clipboard: copypasta::ClipboardContext::new()
                 // this should really probably be a user error message instead of just dumping into stdout
-                .map_or_else(|e| { println!("error getting clipboard: {}", e); None }, |cx| Some(cx))
+                .map_or_else(|e| { println!("error getting clipboard: {}", e); None }, |cx| Some(cx)),
+            block: None,
+            at_cursors: None
         }
     }
+
+    // starts inserting at the buffer's cursor, or all of them if it has more than one
+    fn at_cursors(buf: &mut Buffer) -> InsertMode {
+        if !buf.has_cursors() {
+            return InsertMode::new(Some(buf.text.insert_mutator(buf.cursor_index)));
+        }
+        let mut im = InsertMode::new(None);
+        im.at_cursors = Some(buf.text.next_action_id);
+        im
+    }
+
+    fn clipboard_contents(&mut self, client: &PClientState) -> Option<String> {
+        use copypasta::ClipboardProvider;
+        match self.clipboard.as_mut().map(|cb| cb.get_contents()) {
+            Some(Ok(snip)) => return Some(snip),
+            Some(Err(e)) => ClientState::process_usr_msgp(client.clone(),
+                UserMessage::error(format!("error getting clipboard contents: {}", e), None)),
+            None => ClientState::process_usr_msgp(client.clone(),
+                UserMessage::warning("no clipboard avaliable!".into(), None))
+        }
+        None
+    }
+
+    // types a key at every cursor
+    fn event_at_cursors(&mut self, first_action: usize, k: Key, client: PClientState, state: &mut EditorState) -> ModeEventResult {
+        let text = match k {
+            Key::Char(c) => c.to_string(),
+            Key::Ctrl('v') => match self.clipboard_contents(&client) {
+                Some(snip) => snip,
+                None => return Ok(None)
+            },
+            Key::Tab | Key::ShiftTab => {
+                let buf = state.current_buffer().ok_or_else(|| Error::InvalidCommand("no buffer".into()))?;
+                let cfg = &client.read().unwrap().config;
+                if buf.softtab(cfg) || k == Key::Tab { " ".repeat(buf.tabstop(cfg)) } else { "\t".into() }
+            },
+            Key::Enter => "\n".into(),
+            Key::Backspace => {
+                state.for_each_cursor(|es| {
+                    let buf = es.current_buffer_mut().ok_or_else(|| Error::InvalidCommand("no buffer".into()))?;
+                    if buf.cursor_index > 0 {
+                        buf.text.delete_range(buf.cursor_index - 1, buf.cursor_index);
+                        buf.cursor_index -= 1;
+                    }
+                    Ok(())
+                })?;
+                return Ok(None);
+            },
+            Key::Escape => {
+                if let Some(buf) = state.current_buffer_mut() {
+                    buf.text.squash_history(first_action);
+                }
+                return Ok(Some(Box::new(NormalMode::new())));
+            },
+            _ => return Ok(None)
+        };
+        state.for_each_cursor(|es| {
+            let buf = es.current_buffer_mut().ok_or_else(|| Error::InvalidCommand("no buffer".into()))?;
+            let cfg = &client.read().unwrap().config;
+            let indent_level = buf.sense_indent_level(buf.cursor_index, cfg);
+            buf.text.insert_range(&text, buf.cursor_index);
+            buf.cursor_index += text.len();
+            if k == Key::Enter {
+                buf.cursor_index += buf.indent(buf.cursor_index, indent_level, cfg);
+            }
+            Ok(())
+        })?;
+        Ok(None)
+    }
+
+    // inserts at `column` on every line of a block, padding short lines with spaces if `pad` is set or
+    // skipping them otherwise. Everything since `first_action` is undone together
+    fn block(buf: &mut Buffer, lines: Range<usize>, column: usize, pad: bool, first_action: usize) -> InsertMode {
+        let start = block_column_index(buf, lines.start, column, pad)
+            .unwrap_or_else(|| buf.next_line_index(buf.line_start_index(lines.start)).saturating_sub(1));
+        buf.cursor_index = start;
+        let mut im = InsertMode::new(Some(buf.text.insert_mutator(start)));
+        im.block = Some(BlockInsert { start, lines: lines.start+1 .. lines.end, column, pad, first_action });
+        im
+    }
 }
 
 impl fmt::Display for InsertMode {
@@ -159,71 +334,70 @@ impl Mode for InsertMode {
         ModeTag::Insert
     }
 
-    fn event(&mut self, e: Event, client: PClientState, state: PEditorState) -> ModeEventResult {
-        use copypasta::ClipboardProvider;
+    fn event(&mut self, k: Key, client: PClientState, state: PEditorState) -> ModeEventResult {
         let mut state = state.write().unwrap();
+        if let Some(first_action) = self.at_cursors {
+            return self.event_at_cursors(first_action, k, client, &mut state);
+        }
         if let PaneContent::Buffer { buffer_index, .. } = state.current_pane().content {
             let buf = &mut state.buffers[buffer_index];
-            match e {
-                Event::ReceivedCharacter(c) if !c.is_control() => {
+            match k {
+                Key::Char(c) => {
                     self.tmut.as_mut().unwrap().push_char(&mut buf.text, c);
                     buf.cursor_index += 1;
                     Ok(None)
                 },
-                Event::ModifiersChanged(ms) => {
-                    self.shift_pressed = ms.shift();
-                    self.ctrl_pressed = ms.ctrl();
+                Key::Ctrl('v') => {
+                    if let Some(snip) = self.clipboard_contents(&client) {
+                        self.tmut.as_mut().unwrap().push_str(&mut buf.text, &snip);
+                    }
                     Ok(None)
                 },
-                Event::KeyboardInput {
-                    input: KeyboardInput { virtual_keycode: Some(vk), state: ElementState::Pressed, .. }, ..
-                } => {
-                    match vk {
-                        VirtualKeyCode::V if self.ctrl_pressed => {
-                            match self.clipboard.as_mut().map(|cb| cb.get_contents()) {
-                                Some(Ok(snip)) => self.tmut.as_mut().unwrap().push_str(&mut buf.text, &snip),
-                                Some(Err(e)) => ClientState::process_usr_msgp(client,
-                                    UserMessage::error(format!("error getting clipboard contents: {}", e), None)),
-                                None => ClientState::process_usr_msgp(client,
-                                    UserMessage::warning("no clipboard avaliable!".into(), None))
-                            }
-                            Ok(None)
-                        },
-                        VirtualKeyCode::Tab => {
-                            let (softtab, tabstop) = {
-                                let cfg = &client.read().unwrap().config;
-                                (cfg.softtab, cfg.tabstop)
-                            };
+                Key::Tab | Key::ShiftTab => {
+                    let (softtab, tabstop) = {
+                        let cfg = &client.read().unwrap().config;
+                        (buf.softtab(cfg), buf.tabstop(cfg))
+                    };
 
-                            if softtab || !self.shift_pressed {
-                                for _ in 0..tabstop {
-                                    self.tmut.as_mut().unwrap().push_char(&mut buf.text, ' ');
+                    if softtab || k == Key::Tab {
+                        for _ in 0..tabstop {
+                            self.tmut.as_mut().unwrap().push_char(&mut buf.text, ' ');
+                        }
+                        buf.cursor_index += tabstop;
+                    } else {
+                        self.tmut.as_mut().unwrap().push_char(&mut buf.text, '\t');
+                        buf.cursor_index += 1;
+                    }
+                    Ok(None)
+                },
+                Key::Backspace => {
+                    if !self.tmut.as_mut().unwrap().pop_char(&mut buf.text) {
+                        buf.cursor_index -= 1;
+                    }
+                    Ok(None)
+                },
+                Key::Enter => {
+                    self.tmut.as_mut().unwrap().push_char(&mut buf.text, '\n');
+                    let cfg = &client.read().unwrap().config;
+                    buf.cursor_index += 1 + buf.indent_with_mutator(self.tmut.as_mut().unwrap(), buf.sense_indent_level(buf.cursor_index, cfg), cfg);
+                    Ok(None)
+                }
+                Key::Escape => {
+                    self.tmut.take().unwrap().finish(&mut buf.text);
+                    if let Some(b) = self.block.take() {
+                        let text = if buf.cursor_index > b.start { buf.text.copy_range(b.start, buf.cursor_index) } else { String::new() };
+                        // like Vim, typing more than one line only changes the first
+                        if !text.is_empty() && !text.contains('\n') {
+                            for line in b.lines.rev() {
+                                if let Some(ix) = block_column_index(buf, line, b.column, b.pad) {
+                                    buf.text.insert_range(&text, ix);
                                 }
-                                buf.cursor_index += tabstop;
-                            } else {
-                                self.tmut.as_mut().unwrap().push_char(&mut buf.text, '\t');
-                                buf.cursor_index += 1;
                             }
-                            Ok(None)
-                        },
-                        VirtualKeyCode::Back => {
-                            if !self.tmut.as_mut().unwrap().pop_char(&mut buf.text) {
-                                buf.cursor_index -= 1;
-                            }
-                            Ok(None)
-                        },
-                        VirtualKeyCode::Return => {
-                            self.tmut.as_mut().unwrap().push_char(&mut buf.text, '\n');
-                            let cfg = &client.read().unwrap().config;
-                            buf.cursor_index += 1 + buf.indent_with_mutator(self.tmut.as_mut().unwrap(), buf.sense_indent_level(buf.cursor_index, cfg), cfg);
-                            Ok(None)
                         }
-                        VirtualKeyCode::Escape => {
-                            self.tmut.take().unwrap().finish(&mut buf.text);
-                            Ok(Some(Box::new(NormalMode::new())))
-                        },
-                        _ => Ok(None)
+                        buf.text.squash_history(b.first_action);
+                        buf.cursor_index = b.start;
                     }
+                    Ok(Some(Box::new(NormalMode::new())))
                 },
                 _ => Ok(None)
             }
@@ -233,23 +407,78 @@ impl Mode for InsertMode {
 
 pub struct VisualMode {
     pending_buf: String,
+    kind: VisualKind,
+    // from where the selection started to the cursor, which can be before it
     selection: Range<usize>,
+    shown: Selection,
     side: bool
 }
 
 impl VisualMode {
-    fn new(start_index: usize) -> VisualMode {
-        VisualMode {
+    fn new(buf: &Buffer, kind: VisualKind) -> VisualMode {
+        let start_index = buf.cursor_index;
+        let mut vm = VisualMode {
             pending_buf: String::new(),
+            kind,
             selection: start_index .. start_index,
+            shown: Selection::Chars(start_index .. start_index),
             side: true
+        };
+        vm.update(buf);
+        vm
+    }
+}
+
+impl VisualMode {
+    // `'<` and `'>` refer to the selection once visual mode is over
+    fn remember_selection(&self, state: &PEditorState) {
+        if let Some(buf) = state.write().unwrap().current_buffer_mut() {
+            let span = self.shown.span();
+            buf.set_mark('<', span.start);
+            buf.set_mark('>', span.end);
+        }
+    }
+
+    // works out what is selected once the selection or its kind changes
+    fn update(&mut self, buf: &Buffer) {
+        let first = self.selection.start.min(self.selection.end);
+        let last = self.selection.start.max(self.selection.end);
+        self.shown = match self.kind {
+            VisualKind::Char => Selection::Chars(first .. last),
+            VisualKind::Line => Selection::Chars(buf.current_start_of_line(first) .. buf.next_line_index(last).saturating_sub(1)),
+            VisualKind::Block => {
+                let (a, b) = (buf.column_for_index(self.selection.start), buf.column_for_index(self.selection.end));
+                Selection::Block {
+                    lines: buf.line_for_index(first) .. buf.line_for_index(last) + 1,
+                    columns: a.min(b) .. a.max(b) + 1,
+                    span: first .. last
+                }
+            }
+        };
+    }
+
+    // `v`, `V` and Ctrl-V change to that kind of selection, or back to Normal mode if it's the one being used
+    fn switch_kind(&mut self, kind: VisualKind, state: &PEditorState) -> ModeEventResult {
+        self.pending_buf.clear();
+        if kind == self.kind {
+            self.remember_selection(state);
+            return Ok(Some(Box::new(NormalMode::new())));
         }
-   }
+        self.kind = kind;
+        if let Some(buf) = state.read().unwrap().current_buffer() {
+            self.update(buf);
+        }
+        Ok(None)
+    }
 }
 
 impl fmt::Display for VisualMode {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "visual [{}]", self.pending_buf)
+        write!(f, "{} [{}]", match self.kind {
+            VisualKind::Char => "visual",
+            VisualKind::Line => "visual line",
+            VisualKind::Block => "visual block"
+        }, self.pending_buf)
     }
 }
     
@@ -257,29 +486,42 @@ impl Mode for VisualMode {
     fn cursor_style(&self) -> CursorStyle { CursorStyle::Block }
     
     fn mode_tag(&self) -> ModeTag {
-        ModeTag::Visual
+        ModeTag::Visual(self.kind)
     }
     
-    fn selection(&self) -> Option<&Range<usize>> {
-        Some(&self.selection)
+    fn selection(&self) -> Option<Selection> {
+        Some(self.shown.clone())
     }
     
-    fn event(&mut self, e: Event, client: PClientState, state: PEditorState) -> ModeEventResult {
-        match e {
-            Event::KeyboardInput { input: KeyboardInput { virtual_keycode: Some(vk), state: ElementState::Pressed, .. }, .. } => {
-                match vk {
-                    VirtualKeyCode::Escape => {
-                        self.pending_buf.clear();
-                        Ok(Some(Box::new(NormalMode::new())))
-                    },
-                    _ => Ok(None) 
+    fn event(&mut self, k: Key, client: PClientState, state: PEditorState) -> ModeEventResult {
+        match k {
+            Key::Escape => {
+                self.pending_buf.clear();
+                self.remember_selection(&state);
+                Ok(Some(Box::new(NormalMode::new())))
+            },
+
+            Key::Ctrl('v') => self.switch_kind(VisualKind::Block, &state),
+
+            // puts a cursor on each selected line, in the same column as the cursor
+            Key::Ctrl('n') => {
+                self.remember_selection(&state);
+                if let Some(buf) = state.write().unwrap().current_buffer_mut() {
+                    let span = self.shown.span();
+                    let column = buf.column_for_index(buf.cursor_index);
+                    let lines = buf.line_for_index(span.start) .. buf.line_for_index(span.end) + 1;
+                    for r in buf.block_ranges(lines, column .. column) {
+                        buf.add_cursor(r.start);
+                    }
                 }
+                Ok(Some(Box::new(NormalMode::new())))
             },
             
-            Event::ReceivedCharacter(c) if !c.is_control() => {
+            Key::Char(c) => {
                 use super::command::*;
                 self.pending_buf.push(c);
-                match Command::parse_2(&self.pending_buf, Some(crate::motion::Motion::passthrough(&self.selection))) {
+                match Command::parse_2(&self.pending_buf, Some(crate::motion::Motion::passthrough(&self.shown.span()))) {
+                    Ok(Command::ChangeMode(ModeTag::Visual(kind))) => self.switch_kind(kind, &state),
                     Ok(Command::VisualSwitchSides) => {
                         self.side = !self.side;
                         self.pending_buf.clear();
@@ -293,7 +535,10 @@ impl Mode for VisualMode {
                         Ok(None)
                     },
                     Ok(Command::Move(mo)) => {
-                        if let Some(buf) = state.write().unwrap().current_buffer_mut() {
+                        let mut state = state.write().unwrap();
+                        if mo.mo.uses_syntax() { state.refresh_syntax(); }
+                        let mo = on_screen(&state, &mo);
+                        if let Some(buf) = state.current_buffer_mut() {
                             let Range { start: _, end } = mo.range(buf, buf.cursor_index, 1);
                             if self.side {
                                 self.selection.end = end;
@@ -301,11 +546,37 @@ impl Mode for VisualMode {
                                 self.selection.start = end;
                             }
                             buf.cursor_index = end;
+                            self.update(buf);
                         }
                         self.pending_buf.clear();
                         Ok(None)
                     },
                     Ok(cmd) => {
+                        self.remember_selection(&state);
+                        if let Selection::Block { lines, columns, .. } = &self.shown {
+                            if let Some(res) = block_edit(&cmd, lines.clone(), columns.clone(), &state) {
+                                self.pending_buf.clear();
+                                return res;
+                            }
+                        }
+                        if let (Command::Put { source_register, .. }, Selection::Chars(r)) = (&cmd, &self.shown) {
+                            self.pending_buf.clear();
+                            put_over(&mut state.write().unwrap(), *source_register, r.clone(), self.kind == VisualKind::Line)?;
+                            return Ok(Some(Box::new(NormalMode::new())));
+                        }
+                        let cmd = match cmd {
+                            // changing whole lines leaves an empty line to type on
+                            Command::Edit { op: Operator::Change, op_count, target_register, .. } if self.kind == VisualKind::Line => {
+                                let r = self.shown.span();
+                                let ends_line = state.read().unwrap().current_buffer()
+                                    .map_or(false, |b| b.text.char_at(r.end) == Some('\n'));
+                                Command::Edit {
+                                    op: Operator::Change, op_count, target_register,
+                                    mo: crate::motion::Motion::passthrough(&(r.start .. if ends_line && r.end > r.start { r.end - 1 } else { r.end }))
+                                }
+                            },
+                            cmd => cmd
+                        };
                         let res = {
                             match cmd.execute(&mut state.write().unwrap(), client) {
                                 Ok(r) => r,
@@ -317,14 +588,14 @@ impl Mode for VisualMode {
                         };
                         self.pending_buf.clear();
                         match res {
-                            Some(ModeTag::Visual) => Ok(None),
-                            Some(ModeTag::Command) => Ok(Some(Box::new(CommandMode::new()))),
+                            Some(ModeTag::Visual(_)) => Ok(None),
+                            Some(ModeTag::Command) => Ok(Some(Box::new(CommandMode::visual_range()))),
                             None | Some(ModeTag::Normal) => Ok(Some(Box::new(NormalMode::new()))),
                             Some(ModeTag::Insert) => {
                                 let mut state = state.write().unwrap();
                                 if let PaneContent::Buffer { buffer_index, .. } = state.current_pane().content {
                                     let buf = &mut state.buffers[buffer_index];
-                                    Ok(Some(Box::new(InsertMode::new(buf.text.insert_mutator(buf.cursor_index))))) 
+                                    Ok(Some(Box::new(InsertMode::at_cursors(buf))))
                                 } else {
                                     Err(Error::InvalidCommand("".into()))
                                 }
@@ -344,7 +615,103 @@ impl Mode for VisualMode {
             },
             _ => Ok(None)
         }
-        }
+    }
+}
+
+// `p` in Visual mode puts the register in place of the selection. Whole lines are always put as lines
+fn put_over(es: &mut EditorState, register: char, r: Range<usize>, lines: bool) -> Result<(), Error> {
+    let mut text = crate::command::register_text(es, register)?;
+    let buf = es.current_buffer_mut().ok_or_else(|| Error::InvalidCommand("no buffer to put in".into()))?;
+    if buf.read_only() {
+        return Err(Error::InvalidCommand("buffer is read only".into()));
+    }
+    if lines && !text.ends_with('\n') { text.push('\n'); }
+    let first_action = buf.text.next_action_id;
+    let end = (r.end + 1).min(buf.text.len());
+    if r.start < end { buf.text.delete_range(r.start, end); }
+    if !text.is_empty() { buf.text.insert_range(&text, r.start); }
+    buf.text.squash_history(first_action);
+    buf.cursor_index = r.start;
+    Ok(())
+}
+
+// where to insert at `column` on a line for a block insert, padding the line with spaces if it's too short
+// and `pad` is set
+fn block_column_index(buf: &mut Buffer, line: usize, column: usize, pad: bool) -> Option<usize> {
+    let start = buf.line_start_index(line);
+    let end = buf.text.index_of('\n', start).unwrap_or(buf.text.len());
+    if start + column <= end {
+        Some(start + column)
+    } else if pad {
+        buf.text.insert_range(&" ".repeat(start + column - end), end);
+        Some(start + column)
+    } else {
+        None
+    }
+}
+
+// `d`, `y`, `c`, `p`, `I` and `A` on a block selection work on the part of each line in the block. Anything
+// else works on all the text from the start of the block to its end, so this returns None
+fn block_edit(cmd: &crate::command::Command, lines: Range<usize>, columns: Range<usize>, state: &PEditorState) -> Option<ModeEventResult> {
+    use crate::command::*;
+    use crate::motion::{Motion, MotionType};
+    let mut es = state.write().unwrap();
+    let es = &mut *es;
+    let bi = es.current_buffer_index()?;
+    let buf = &mut es.buffers[bi];
+    let ranges = buf.block_ranges(lines.clone(), columns.clone());
+    let first_action = buf.text.next_action_id;
+    let copy = |buf: &Buffer| ranges.iter().map(|r| buf.text.copy_range(r.start, r.end)).collect::<Vec<_>>().join("\n");
+    let normal = || Some(Ok(Some(Box::new(NormalMode::new()) as Box<dyn Mode>)));
+    if buf.read_only() && cmd.modifies_text() {
+        return Some(Err(Error::InvalidCommand("buffer is read only".into())));
+    }
+    match cmd {
+        Command::Edit { op: Operator::Yank, target_register, .. } => {
+            es.registers.insert(*target_register, copy(buf));
+            buf.cursor_index = ranges[0].start;
+            normal()
+        },
+        Command::Edit { op: op @ Operator::Delete, target_register, .. } | Command::Edit { op: op @ Operator::Change, target_register, .. } => {
+            es.registers.insert(*target_register, copy(buf));
+            // from the bottom up, so that the ranges above stay put
+            for r in ranges.iter().rev().filter(|r| !r.is_empty()) {
+                buf.text.delete_range(r.start, r.end);
+            }
+            buf.cursor_index = ranges[0].start;
+            if *op == Operator::Change {
+                Some(Ok(Some(Box::new(InsertMode::block(buf, lines, columns.start, false, first_action)))))
+            } else {
+                buf.text.squash_history(first_action);
+                normal()
+            }
+        },
+        Command::Edit { op: Operator::MoveAndEnterMode(ModeTag::Insert), mo: Motion { mo: MotionType::StartOfLine, .. }, .. } =>
+            Some(Ok(Some(Box::new(InsertMode::block(buf, lines, columns.start, false, first_action))))),
+        Command::Edit { op: Operator::MoveAndEnterMode(ModeTag::Insert), mo: Motion { mo: MotionType::EndOfLine, .. }, .. } =>
+            Some(Ok(Some(Box::new(InsertMode::block(buf, lines, columns.end, true, first_action))))),
+        Command::Put { source_register, .. } => {
+            let text = match register_text(es, *source_register) {
+                Ok(t) => t,
+                Err(e) => return Some(Err(e))
+            };
+            let buf = &mut es.buffers[bi];
+            // each line of the block gets the matching line of text, or all of them get it if there's only one
+            let text_lines: Vec<&str> = text.trim_end_matches('\n').split('\n').collect();
+            for (i, r) in ranges.iter().enumerate().rev() {
+                if !r.is_empty() { buf.text.delete_range(r.start, r.end); }
+                let line = if text_lines.len() == 1 { text_lines.get(0) } else { text_lines.get(i) };
+                match line {
+                    Some(line) if !line.is_empty() => buf.text.insert_range(line, r.start),
+                    _ => {}
+                }
+            }
+            buf.cursor_index = ranges[0].start;
+            buf.text.squash_history(first_action);
+            normal()
+        },
+        _ => None
+    }
 }
 
 use piece_table::TableMutator;
@@ -354,13 +721,13 @@ pub struct CommandMode {
     commands: Vec<(regex::Regex, Rc<dyn line_command::CommandFn>)>,
     cursor_index: usize,
     history_index: usize,
-    command_line: PieceTable
+    command_line: PieceTable,
+    // the search the command line was last compiled into, so that it isn't compiled again every frame
+    pending_query: Option<(String, Option<search::Query>)>
 }
 
 impl CommandMode {
     fn with_table(mut pt: PieceTable) -> CommandMode {
-        use regex::Regex;
-        use line_command::*;
         let len = pt.len();
         let cursor_mutator = pt.insert_mutator(len);
         CommandMode {
@@ -368,17 +735,8 @@ impl CommandMode {
             command_line: pt,
             cursor_index: len,
             history_index: 0,
-            commands: vec![
-                (Regex::new("^test (.*)").unwrap(), Rc::new(TestCommand)),
-                (Regex::new("^q").unwrap(), Rc::new(QuitCommand)),
-                (Regex::new("^dbg pt").unwrap(), Rc::new(DebugPieceTableCommand)),
-                (Regex::new("^dbg rg").unwrap(), Rc::new(DebugRegistersCommand)),
-                (Regex::new(r#"^e\s+(?:(?P<server_name>\w+):)?(?P<path>.*)"#).unwrap(), Rc::new(EditFileCommand)),
-                (Regex::new(r#"^b(?P<subcmd>\w+)?\s+(?P<name_query>.*)"#).unwrap(), Rc::new(BufferCommand)),
-                (Regex::new("^sync").unwrap(), Rc::new(SyncFileCommand)),
-                (Regex::new(r#"^con\s+(?P<server_name>\w+)\s(?P<server_url>.*)"#).unwrap(), Rc::new(ConnectToServerCommand)),
-                (Regex::new(r#"(\?|/)(.*)"#).unwrap(), Rc::new(SearchCommand))
-            ],
+            commands: line_command::command_table(),
+            pending_query: None
         }
     }
     
@@ -386,6 +744,11 @@ impl CommandMode {
         CommandMode::with_table(PieceTable::default())
     }
     
+    // the command line after `:` in visual mode starts with the selected lines as the range
+    pub fn visual_range() -> CommandMode {
+        CommandMode::with_table(PieceTable::with_text("'<,'>"))
+    }
+
     pub fn search(dir: Direction) -> CommandMode {
         CommandMode::with_table(PieceTable::with_text(match dir {
             Direction::Forward => "/",
@@ -418,71 +781,221 @@ impl Mode for CommandMode {
     fn cmd_line(&self) -> Option<(usize, &PieceTable)> {
         Some((self.cursor_index, &self.command_line))
     }
+
+    fn pending_search(&mut self) -> Option<&search::Query> {
+        let cmd = self.command_line.text();
+        if !cmd.starts_with('/') && !cmd.starts_with('?') || cmd.len() == 1 { return None; }
+        if self.pending_query.as_ref().map_or(true, |(c, _)| *c != cmd) {
+            let query = search::Query::new(&cmd[1..]).ok();
+            self.pending_query = Some((cmd, query));
+        }
+        self.pending_query.as_ref().and_then(|(_, q)| q.as_ref())
+    }
     
     
-    fn event(&mut self, e: Event, cs: PClientState, es: PEditorState) 
+    fn event(&mut self, k: Key, cs: PClientState, es: PEditorState) 
         -> ModeEventResult
     {
         let pending_command = &mut self.command_line;
-        match e {
-            Event::ReceivedCharacter(c) if !c.is_control() => {
+        match k {
+            Key::Char(c) => {
                 self.cursor_mutator.push_char(pending_command, c);
                 self.cursor_index += 1;
                 Ok(None)
             },
-            Event::KeyboardInput { input: KeyboardInput { virtual_keycode: Some(vk), state: ElementState::Pressed, .. }, .. } => {
-                match vk {
-                    VirtualKeyCode::Left => {
-                        self.cursor_index = self.cursor_index.saturating_sub(1);
-                        self.cursor_mutator = pending_command.insert_mutator(self.cursor_index);
-                        Ok(None)
-                    },
-                    VirtualKeyCode::Right => {
-                        self.cursor_index = (self.cursor_index+1).min(pending_command.len());
-                        self.cursor_mutator = pending_command.insert_mutator(self.cursor_index);
-                        Ok(None)
-                    },
-                    VirtualKeyCode::Up => {
-                        {self.history_index = (self.history_index + 1).min(es.read().unwrap().line_command_history.len());}
-                        self.load_history_cmd(es);
-                        Ok(None)
-                    },
-                    VirtualKeyCode::Down => {
-                        self.history_index = self.history_index.saturating_sub(1);
-                        self.load_history_cmd(es);
-                        Ok(None)
+            Key::Left => {
+                self.cursor_index = self.cursor_index.saturating_sub(1);
+                self.cursor_mutator = pending_command.insert_mutator(self.cursor_index);
+                Ok(None)
+            },
+            Key::Right => {
+                self.cursor_index = (self.cursor_index+1).min(pending_command.len());
+                self.cursor_mutator = pending_command.insert_mutator(self.cursor_index);
+                Ok(None)
+            },
+            Key::Up => {
+                {self.history_index = (self.history_index + 1).min(es.read().unwrap().line_command_history.len());}
+                self.load_history_cmd(es);
+                Ok(None)
+            },
+            Key::Down => {
+                self.history_index = self.history_index.saturating_sub(1);
+                self.load_history_cmd(es);
+                Ok(None)
+            },
+            Key::Backspace => {
+                self.cursor_mutator.pop_char(pending_command);
+                self.cursor_index -= 1;
+                Ok(None)
+            },
+            Key::Enter => {
+                let cmdstr = self.command_line.text();
+                { es.write().unwrap().line_command_history.push(cmdstr.clone()); }
+                line_command::run_line_command(&self.commands, cs, es, &cmdstr)
+            }
+            Key::Escape => {
+                Ok(Some(Box::new(NormalMode::new())))
+            },
+            _ => Ok(None)
+        }
+    }
+}
+
+impl fmt::Display for CommandMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "command")
+    }
+}
+
+// picking a side for each conflict left in a buffer by a merge
+pub struct MergeMode {
+    conflicts_left: usize
+}
+
+impl MergeMode {
+    pub fn new(conflicts_left: usize) -> MergeMode {
+        MergeMode { conflicts_left }
+    }
+}
+
+impl Mode for MergeMode {
+    fn mode_tag(&self) -> ModeTag {
+        ModeTag::Merge
+    }
+
+    fn event(&mut self, k: Key, client: PClientState, state: PEditorState) -> ModeEventResult {
+        match k {
+            Key::Escape => {
+                Ok(Some(Box::new(NormalMode::new())))
+            },
+            Key::Char(c) => {
+                let mut es = state.write().unwrap();
+                let buf = es.current_buffer_mut().ok_or_else(|| Error::InvalidCommand("no buffer to merge".into()))?;
+                let conflicts = buf.merge_conflicts();
+                let cur = buf.cursor_index;
+                match c {
+                    'n' => if let Some(n) = conflicts.iter().find(|c| c.range.start > cur).or_else(|| conflicts.first()) {
+                        buf.cursor_index = n.range.start;
                     },
-                    VirtualKeyCode::Back => {
-                        self.cursor_mutator.pop_char(pending_command);
-                        self.cursor_index -= 1;
-                        Ok(None)
+                    'N' => if let Some(n) = conflicts.iter().rev().find(|c| c.range.start < cur).or_else(|| conflicts.last()) {
+                        buf.cursor_index = n.range.start;
                     },
-                    VirtualKeyCode::Return => {
-                        let cmdstr = self.command_line.text();
-                        { es.write().unwrap().line_command_history.push(cmdstr.clone()); }
-                        if let Some((cmdix, args)) = self.commands.iter().enumerate()
-                            .filter_map(|(i,cmd)| cmd.0.captures(&cmdstr).map(|c| (i, c))).nth(0)
-                        {
-                            let cmd = self.commands[cmdix].1.clone();
-                            cmd.process(cs, es, &args)
-                        } else {
-                            Err(Error::InvalidCommand(cmdstr))
+                    // take ours, theirs, both, or neither for the conflict the cursor is in, or the next one
+                    'l' | 's' | 'b' | 'o' => {
+                        let t = match conflicts.iter().find(|c| c.range.end > cur).or_else(|| conflicts.first()) {
+                            Some(t) => t.clone(),
+                            None => return Ok(None)
+                        };
+                        let section = |r: &Range<usize>| buf.text.copy_range(r.start, r.end);
+                        let replacement = match c {
+                            'l' => section(&t.ours),
+                            's' => section(&t.theirs),
+                            'b' => section(&t.ours) + &section(&t.theirs),
+                            _ => section(&t.base)
+                        };
+                        buf.text.delete_range(t.range.start, t.range.end);
+                        buf.text.insert_range(&replacement, t.range.start);
+                        buf.cursor_index = t.range.start;
+                        self.conflicts_left = conflicts.len() - 1;
+                        if buf.finish_merge() {
+                            let m = format!("Finished merging {}:{}", buf.server_name, buf.display_path());
+                            drop(es);
+                            ClientState::process_usr_msgp(client, UserMessage::info(m, None));
+                            return Ok(Some(Box::new(NormalMode::new())));
                         }
-                    }
-                    VirtualKeyCode::Escape => {
-                        Ok(Some(Box::new(NormalMode::new())))
                     },
-                    _ => Ok(None)
+                    _ => {}
                 }
+                Ok(None)
             },
             _ => Ok(None)
         }
     }
 }
 
-impl fmt::Display for CommandMode {
+impl fmt::Display for MergeMode {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "command")
+        write!(f, "merge [{} left]", self.conflicts_left)
+    }
+}
+
+// asks about each match of a substitute command with the `c` flag in turn
+pub struct SubstituteConfirmMode {
+    replacements: Vec<line_command::Replacement>,
+    next: usize,
+    // how far the replacements made so far have moved the text after them
+    shift: isize,
+    first_action: usize,
+    current: Range<usize>
+}
+
+impl SubstituteConfirmMode {
+    pub fn new(buf: &mut buffer::Buffer, replacements: Vec<line_command::Replacement>) -> SubstituteConfirmMode {
+        let mut m = SubstituteConfirmMode {
+            replacements, next: 0, shift: 0,
+            first_action: buf.text.next_action_id,
+            current: 0..0
+        };
+        m.show_next(buf);
+        m
+    }
+
+    fn show_next(&mut self, buf: &mut buffer::Buffer) {
+        let r = &self.replacements[self.next].range;
+        let start = (r.start as isize + self.shift) as usize;
+        // selections include the character at their end
+        self.current = start .. (start + r.len()).saturating_sub(1).max(start);
+        buf.cursor_index = start;
+    }
+}
+
+impl Mode for SubstituteConfirmMode {
+    fn mode_tag(&self) -> ModeTag {
+        ModeTag::Substitute
+    }
+
+    fn selection(&self) -> Option<Selection> {
+        Some(Selection::Chars(self.current.clone()))
+    }
+
+    fn event(&mut self, k: Key, _client: PClientState, state: PEditorState) -> ModeEventResult {
+        let c = match k {
+            Key::Escape => 'q',
+            Key::Char(c) => c,
+            _ => return Ok(None)
+        };
+        let mut es = state.write().unwrap();
+        let buf = es.current_buffer_mut().ok_or_else(|| Error::InvalidCommand("no buffer to substitute in".into()))?;
+        match c {
+            // replace this match, and then either continue or stop
+            'y' | 'l' => {
+                self.shift = self.replacements[self.next].apply(buf, self.shift);
+                self.next = if c == 'l' { self.replacements.len() } else { self.next + 1 };
+            },
+            'n' => self.next += 1,
+            // replace this and every match after it
+            'a' => {
+                for r in self.replacements[self.next..].iter() {
+                    self.shift = r.apply(buf, self.shift);
+                }
+                self.next = self.replacements.len();
+            },
+            'q' => self.next = self.replacements.len(),
+            _ => return Ok(None)
+        }
+        if self.next < self.replacements.len() {
+            self.show_next(buf);
+            return Ok(None);
+        }
+        // everything replaced is undone together
+        buf.text.squash_history(self.first_action);
+        Ok(Some(Box::new(NormalMode::new())))
+    }
+}
+
+impl fmt::Display for SubstituteConfirmMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "substitute? [y/n/a/l/q] {}/{}", self.next + 1, self.replacements.len())
     }
 }
 
@@ -505,9 +1018,9 @@ impl Mode for UserMessageInteractionMode {
         CursorStyle::Box
     }
 
-    fn event(&mut self, e: Event, state: PClientState, _: PEditorState) -> ModeEventResult {
-        match e {
-            Event::ReceivedCharacter(c) if c.is_digit(10) => {
+    fn event(&mut self, k: Key, state: PClientState, _: PEditorState) -> ModeEventResult {
+        match k {
+            Key::Char(c) if c.is_digit(10) => {
                 let sel = c.to_digit(10).unwrap() as usize;
                 if sel < 1 || sel > 9 { return Ok(None); }
                 let um: UserMessage = { let mut s = state.write().unwrap();
@@ -520,41 +1033,36 @@ impl Mode for UserMessageInteractionMode {
                 }
                 Ok(Some(Box::new(NormalMode::new())))
             },
-            Event::KeyboardInput { input: KeyboardInput { virtual_keycode: Some(vk), state: ElementState::Pressed, .. }, .. } => {
-                match vk {
-                    VirtualKeyCode::Escape => {
-                        Ok(Some(Box::new(NormalMode::new())))
-                    },
-                    VirtualKeyCode::Return | VirtualKeyCode::Back | VirtualKeyCode::Delete => {
-                        let mut s = state.write().unwrap();
-                        if s.usrmsgs.len() == 0 {
-                            return Ok(Some(Box::new(NormalMode::new())));
-                        }
-                        let sm = s.selected_usrmsg; 
-                        s.usrmsgs.remove(sm);
-                        s.selected_usrmsg = sm.saturating_sub(1);
-                        if s.usrmsgs.len() == 0 {
-                            Ok(Some(Box::new(NormalMode::new())))
-                        } else {
-                            Ok(None)
-                        }
-                    },
-                    VirtualKeyCode::E => {
-                        state.write().unwrap().usrmsgs.clear();
-                        Ok(Some(Box::new(NormalMode::new())))
-                    },
-                    VirtualKeyCode::J => {
-                        let mut s = state.write().unwrap();
-                        s.selected_usrmsg = (s.selected_usrmsg + 1).min(s.usrmsgs.len().saturating_sub(1));
-                        Ok(None)
-                    },
-                    VirtualKeyCode::K => {
-                        let mut s = state.write().unwrap();
-                        s.selected_usrmsg = s.selected_usrmsg.saturating_sub(1);
-                        Ok(None)
-                    }
-                    _ => Ok(None)
+            Key::Escape => {
+                Ok(Some(Box::new(NormalMode::new())))
+            },
+            Key::Enter | Key::Backspace | Key::Delete => {
+                let mut s = state.write().unwrap();
+                if s.usrmsgs.len() == 0 {
+                    return Ok(Some(Box::new(NormalMode::new())));
+                }
+                let sm = s.selected_usrmsg; 
+                s.usrmsgs.remove(sm);
+                s.selected_usrmsg = sm.saturating_sub(1);
+                if s.usrmsgs.len() == 0 {
+                    Ok(Some(Box::new(NormalMode::new())))
+                } else {
+                    Ok(None)
                 }
+            },
+            Key::Char('e') => {
+                state.write().unwrap().usrmsgs.clear();
+                Ok(Some(Box::new(NormalMode::new())))
+            },
+            Key::Char('j') => {
+                let mut s = state.write().unwrap();
+                s.selected_usrmsg = (s.selected_usrmsg + 1).min(s.usrmsgs.len().saturating_sub(1));
+                Ok(None)
+            },
+            Key::Char('k') => {
+                let mut s = state.write().unwrap();
+                s.selected_usrmsg = s.selected_usrmsg.saturating_sub(1);
+                Ok(None)
             }
             _ => Ok(None)
         }
@@ -567,3 +1075,129 @@ impl fmt::Display for UserMessageInteractionMode {
     }
 }
 
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pk_common::diff::{merge3, MergeChunk};
+
+    #[test]
+    fn merge_mode() {
+        let base = "a\nb\nc\nd\ne\n";
+        let chunks = merge3(base, "a\nB\nc\nD\ne\n", "a\nb2\nc\nd2\ne\n");
+        assert_eq!(chunks.iter().filter(|c| matches!(c, MergeChunk::Conflict { .. })).count(), 2);
+        let (cs, es) = crate::editor_state::test::state_with_text(base);
+        {
+            let mut es = es.write().unwrap();
+            let b = &mut es.buffers[0];
+            b.currently_in_conflict = true;
+            b.start_merge(&chunks, 7);
+            // edits outside of the conflicts don't change them
+            b.text.insert_range("top\n", 0);
+            b.cursor_index = 0;
+        }
+        let mut mode = MergeMode::new(2);
+        let text = |es: &PEditorState| es.read().unwrap().buffers[0].text.text();
+        // the second conflict is resolved first, with the server's side
+        assert!(mode.event(Key::Char('n'), cs.clone(), es.clone()).unwrap().is_none());
+        assert!(mode.event(Key::Char('n'), cs.clone(), es.clone()).unwrap().is_none());
+        assert!(mode.event(Key::Char('s'), cs.clone(), es.clone()).unwrap().is_none());
+        assert_eq!(mode.conflicts_left, 1);
+        assert!(text(&es).ends_with("c\nd2\ne\n"));
+        assert!(text(&es).starts_with("top\na\n<<<<<<< local\nB\n"));
+        assert_eq!(es.read().unwrap().buffers[0].merge_conflicts().len(), 1);
+        // both sides of the first one, which finishes the merge
+        assert!(mode.event(Key::Char('N'), cs.clone(), es.clone()).unwrap().is_none());
+        assert!(mode.event(Key::Char('b'), cs.clone(), es.clone()).unwrap().is_some());
+        assert_eq!(text(&es), "top\na\nB\nb2\nc\nd2\ne\n");
+        let es = es.read().unwrap();
+        assert_eq!(es.buffers[0].merging, None);
+        assert_eq!(es.buffers[0].version, 7);
+        assert!(!es.buffers[0].currently_in_conflict);
+    }
+
+    // types `keys` in Normal mode with the cursor at `cursor`, returning the text and where the cursor ends up
+    fn type_keys(es: &PEditorState, cursor: usize, keys: &str) -> (String, usize) {
+        let cs = Arc::new(RwLock::new(ClientState::default()));
+        es.write().unwrap().buffers[0].cursor_index = cursor;
+        let mut mode: Box<dyn Mode> = Box::new(NormalMode::new());
+        for k in crate::keys::parse_keys(keys) {
+            if let Some(m) = mode.event(k, cs.clone(), es.clone()).unwrap() {
+                mode = m;
+            }
+        }
+        assert_eq!(mode.mode_tag(), ModeTag::Normal);
+        let es = es.read().unwrap();
+        (es.buffers[0].text.text(), es.buffers[0].cursor_index)
+    }
+
+    fn edit(text: &str, cursor: usize, keys: &str) -> (String, usize) {
+        let (_, es) = crate::editor_state::test::state_with_text(text);
+        type_keys(&es, cursor, keys)
+    }
+
+    #[test]
+    fn block_edits() {
+        let text = "abcd\nefgh\nij\n";
+        let (_, es) = crate::editor_state::test::state_with_text(text);
+        assert_eq!(type_keys(&es, 1, "<C-v>jjly"), (text.into(), 1));
+        assert_eq!(es.read().unwrap().registers[&'"'], "bc\nfg\nj");
+        assert_eq!(type_keys(&es, 1, "<C-v>jjld"), ("ad\neh\ni\n".into(), 1));
+        assert_eq!(es.read().unwrap().registers[&'"'], "bc\nfg\nj");
+        // the whole edit is undone at once
+        es.write().unwrap().buffers[0].text.undo();
+        assert_eq!(es.read().unwrap().buffers[0].text.text(), text);
+        assert_eq!(edit(text, 6, "<C-v>kld").0, "ad\neh\nij\n");
+        // lines too short to reach the block are left alone
+        assert_eq!(edit(text, 2, "<C-v>jjd").0, "abd\nefh\nij\n");
+    }
+
+    #[test]
+    fn block_put() {
+        let text = "abcd\nefgh\nij\n";
+        let (_, es) = crate::editor_state::test::state_with_text(text);
+        es.write().unwrap().registers.insert('"', "X".into());
+        assert_eq!(type_keys(&es, 1, "<C-v>jlp"), ("aXd\neXh\nij\n".into(), 1));
+        es.write().unwrap().registers.insert('a', "1\n2\n".into());
+        assert_eq!(type_keys(&es, 0, "<C-v>j\"ap"), ("1Xd\n2Xh\nij\n".into(), 0));
+        es.write().unwrap().buffers[0].text.undo();
+        assert_eq!(es.read().unwrap().buffers[0].text.text(), "aXd\neXh\nij\n");
+    }
+
+    #[test]
+    fn block_insert() {
+        let text = "abcd\nefgh\nij\n";
+        assert_eq!(edit(text, 1, "<C-v>jjI--<Esc>"), ("a--bcd\ne--fgh\ni--j\n".into(), 1));
+        assert_eq!(edit(text, 6, "<C-v>jlA!<Esc>").0, "abcd\nefg!h\nij !\n");
+        assert_eq!(edit(text, 1, "<C-v>jcX<Esc>"), ("aXcd\neXgh\nij\n".into(), 1));
+        // only the first line changes when more than one line is typed
+        assert_eq!(edit(text, 1, "<C-v>jIx<CR>y<Esc>").0, "ax\nybcd\nefgh\nij\n");
+        let (_, es) = crate::editor_state::test::state_with_text(text);
+        type_keys(&es, 0, "<C-v>jI#<Esc>");
+        es.write().unwrap().buffers[0].text.undo();
+        assert_eq!(es.read().unwrap().buffers[0].text.text(), text);
+    }
+
+    #[test]
+    fn visual_put() {
+        let (_, es) = crate::editor_state::test::state_with_text("one two\nthree\n");
+        es.write().unwrap().registers.insert('"', "X".into());
+        assert_eq!(type_keys(&es, 0, "vllp"), ("X two\nthree\n".into(), 0));
+        // lines put over a linewise selection stay whole lines
+        assert_eq!(type_keys(&es, 6, "Vp"), ("X two\nX\n".into(), 6));
+        es.write().unwrap().registers.insert('"', "a\nb\n".into());
+        assert_eq!(type_keys(&es, 2, "vp"), ("X a\nb\nwo\nX\n".into(), 2));
+        let mut es = es.write().unwrap();
+        es.buffers[0].text.undo();
+        assert_eq!(es.buffers[0].text.text(), "X two\nX\n");
+        assert!(put_over(&mut es, 'q', 0..1, false).is_err());
+    }
+
+    #[test]
+    fn linewise_change() {
+        let text = "one\ntwo\nthree\n";
+        assert_eq!(edit(text, 5, "VcX<Esc>").0, "one\nX\nthree\n");
+        assert_eq!(edit(text, 0, "VjcX<Esc>").0, "X\nthree\n");
+        assert_eq!(edit(text, 9, "Vc<Esc>").0, "one\ntwo\n\n");
+        assert_eq!(edit("one\ntwo", 5, "VcX<Esc>").0, "one\nX");
+    }
+}
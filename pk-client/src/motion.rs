@@ -1,6 +1,7 @@
 use super::*;
 use std::ops::Range;
 use crate::buffer::Buffer;
+use crate::syntax_highlight::SyntaxKind;
 
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
 pub enum CharClass {
@@ -27,7 +28,201 @@ impl CharClassify for char {
 
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
 pub enum TextObject {
-    Word, BigWord, Paragraph, Block(char)
+    Word, BigWord, Paragraph, Sentence, Tag, Block(char),
+    // one argument in a list in brackets, separated by commas
+    Argument,
+    // a function, class, comment or string, as the highlighter parsed them
+    Syntax(SyntaxKind)
+}
+
+// `H`, `M` and `L` go to these lines of the screen
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum ScreenLine {
+    Top, Middle, Bottom
+}
+
+// whether the line starting at `ln` has nothing on it. The end of the text counts too
+fn empty_line(buf: &Buffer, ln: usize) -> bool {
+    buf.text.char_at(ln).map_or(true, |c| c == '\n')
+}
+
+// the start of the next empty line after the paragraph at `at`, or the end of the text
+fn paragraph_forward(buf: &Buffer, at: usize) -> usize {
+    let len = buf.text.len();
+    let mut ln = buf.current_start_of_line(at);
+    // from an empty line, the next paragraph is the one after it
+    while ln < len && empty_line(buf, ln) { ln = buf.next_line_index(ln); }
+    while ln < len && !empty_line(buf, ln) { ln = buf.next_line_index(ln); }
+    ln
+}
+
+// the start of the empty line before the paragraph at `at`, or the start of the text
+fn paragraph_backward(buf: &Buffer, at: usize) -> usize {
+    let mut ln = buf.current_start_of_line(at);
+    if ln == 0 { return 0; }
+    let from_empty = empty_line(buf, ln);
+    ln = buf.last_line_index(ln);
+    if from_empty {
+        while ln > 0 && empty_line(buf, ln) { ln = buf.last_line_index(ln); }
+    }
+    while ln > 0 && !empty_line(buf, ln) { ln = buf.last_line_index(ln); }
+    ln
+}
+
+// where sentences start, from the start of the paragraph `at` is in until `after` of them have been found
+// past `at`. A sentence ends with `.`, `!` or `?`, and any closing brackets or quotes, followed by white
+// space, and an empty line is a sentence of its own
+fn sentence_starts(buf: &Buffer, at: usize, after: usize) -> Vec<usize> {
+    let mut ix = buf.current_start_of_line(at);
+    while ix > 0 && !empty_line(buf, buf.last_line_index(ix)) { ix = buf.last_line_index(ix); }
+    let mut starts = Vec::new();
+    let mut found = 0;
+    // the last character was the end of a sentence
+    let mut ended = false;
+    // the next character that isn't white space starts a sentence
+    let mut boundary = true;
+    let mut line_empty = true;
+    for c in buf.text.chars(ix) {
+        let mut start = false;
+        if c.is_whitespace() {
+            if c == '\n' && line_empty {
+                start = true;
+                boundary = true;
+            } else if ended {
+                boundary = true;
+            }
+            ended = false;
+        } else {
+            start = boundary;
+            boundary = false;
+            ended = ".!?".contains(c) || (ended && ")]\"'".contains(c));
+        }
+        if start {
+            starts.push(ix);
+            if ix > at {
+                found += 1;
+                if found >= after { break; }
+            }
+        }
+        line_empty = c == '\n';
+        ix += c.len_utf8();
+    }
+    starts
+}
+
+// the first unpaired bracket after or before `from`, skipping over nested pairs. Brackets in strings and
+// comments don't count, unless `from` is inside the same one
+fn unpaired_bracket(buf: &Buffer, from: usize, (open, close): (char, char), direction: Direction) -> Option<usize> {
+    let syntax = buf.syntax();
+    let literal = syntax.and_then(|s| s.literal_at(from)).filter(|l| l.start < from);
+    let counts = |ix: usize| syntax.map_or(true, |s| s.literal_at(ix) == literal);
+    let (nest, unnest) = match direction {
+        Direction::Forward => (open, close),
+        Direction::Backward => (close, open)
+    };
+    let mut depth = 0usize;
+    let mut step = |ix: usize, ch: char| {
+        if ch == nest && counts(ix) {
+            depth += 1;
+        } else if ch == unnest && counts(ix) {
+            if depth == 0 { return Some(ix); }
+            depth -= 1;
+        }
+        None
+    };
+    match direction {
+        Direction::Forward => {
+            let mut ix = from + buf.text.char_at(from)?.len_utf8();
+            if ix >= buf.text.len() { return None; }
+            for ch in buf.text.chars(ix) {
+                if let Some(m) = step(ix, ch) { return Some(m); }
+                ix += ch.len_utf8();
+            }
+            None
+        },
+        Direction::Backward => buf.text.copy_range(0, from).char_indices().rev().find_map(|(ix, ch)| step(ix, ch))
+    }
+}
+
+// the bracket that pairs with the one at `at`, skipping over nested pairs
+pub fn matching_bracket(buf: &Buffer, at: usize) -> Option<usize> {
+    let c = buf.text.char_at(at)?;
+    let pair = match c {
+        '(' | ')' => ('(', ')'),
+        '[' | ']' => ('[', ']'),
+        '{' | '}' => ('{', '}'),
+        '<' | '>' => ('<', '>'),
+        _ => return None
+    };
+    unpaired_bracket(buf, at, pair, if c == pair.0 { Direction::Forward } else { Direction::Backward })
+}
+
+// the range between a pair of delimiters, with them for `a` objects and without them for `i` ones
+fn pair_range(start: usize, end: usize, include: bool) -> Range<usize> {
+    if include { start .. end } else { start + 1 .. end - 1 }
+}
+
+// the whole of a function or class and just its body, which the parser sometimes leaves out of the
+// scope it gives. A body is in braces, or is the lines indented under a signature ending with `:`
+fn with_body(buf: &Buffer, span: &Range<usize>) -> (Range<usize>, Range<usize>) {
+    let text = buf.text.copy_range(span.start, span.end);
+    let last = text.trim_end().char_indices().last().map_or(span.start, |(i, _)| span.start + i);
+    match buf.text.char_at(last) {
+        Some(':') => {
+            let indent = |line: &str| line.len() - line.trim_start().len();
+            let header = buf.current_start_of_line(span.start);
+            let header_indent = indent(&buf.text.copy_range(header, buf.next_line_index(header)));
+            let body = buf.next_line_index(last);
+            let mut end = body;
+            let mut ln = body;
+            while ln < buf.text.len() {
+                let next = buf.next_line_index(ln);
+                let line = buf.text.copy_range(ln, next);
+                // blank lines don't end the body, but aren't taken after it either
+                if !line.trim().is_empty() {
+                    if indent(&line) <= header_indent { break; }
+                    end = next;
+                }
+                ln = next;
+            }
+            (span.start .. end.max(span.end), body .. end)
+        },
+        _ => {
+            // the body might already be in the span, or come after it
+            let open = match buf.text.char_at(last) {
+                Some('}') => matching_bracket(buf, last).filter(|o| *o >= span.start),
+                _ => None
+            }.or_else(|| {
+                let syntax = buf.syntax();
+                let mut ix = span.start;
+                for ch in buf.text.chars(span.start) {
+                    if syntax.map_or(true, |s| s.literal_at(ix).is_none()) {
+                        match ch {
+                            '{' => return Some(ix),
+                            ';' | '}' => return None,
+                            _ => {}
+                        }
+                    }
+                    ix += ch.len_utf8();
+                }
+                None
+            });
+            match open.and_then(|o| matching_bracket(buf, o).map(|c| (o, c))) {
+                Some((open, close)) => {
+                    // keep the lines the braces are on out of the body
+                    let mut start = open + 1;
+                    if let Some(nl) = buf.text.index_of('\n', start).filter(|nl| *nl < close) {
+                        if buf.text.copy_range(start, nl).trim().is_empty() { start = nl + 1; }
+                    }
+                    let mut end = close;
+                    let ln = buf.current_start_of_line(close);
+                    if ln > start && buf.text.copy_range(ln, close).trim().is_empty() { end = ln; }
+                    (span.start .. close + 1, start .. end)
+                },
+                None => (span.clone(), span.clone())
+            }
+        }
+    }
 }
 
 fn matching_block_char(c: char) -> char {
@@ -87,49 +282,169 @@ impl TextObject {
                 range.end -= 1;
                 range
             },
+            TextObject::Block(quote @ '"') | TextObject::Block(quote @ '\'') => {
+                // the parser knows which quote a string starts with, but otherwise take the closest ones
+                let string = buf.syntax().and_then(|s| s.spans_around(SyntaxKind::String, cursor_index)
+                    .find(|s| buf.text.char_at(s.range.start) == Some(*quote)));
+                if let Some(s) = string {
+                    return if include { s.range.start .. s.range.end - 1 } else { s.inner.start .. s.inner.end.max(s.inner.start + 1) - 1 };
+                }
+                let start = if buf.text.char_at(cursor_index) == Some(*quote) { Some(cursor_index) }
+                    else { buf.text.last_index_of(*quote, cursor_index) };
+                match start.and_then(|s| buf.text.index_of(*quote, s + 1).map(|e| (s, e))) {
+                    Some((start, end)) => pair_range(start, end, include),
+                    None => cursor_index + 1 .. cursor_index
+                }
+            },
             TextObject::Block(open_char) => {
-                println!("---");
-                let mut range = cursor_index..cursor_index;
-
-                let mut chars = buf.text.chars(range.start).rev().peekable();
-                while chars.peek().map(|cc| cc != open_char).unwrap_or(false) {
-                    chars.next();
-                    range.start -= 1;
+                let pair = (*open_char, matching_block_char(*open_char));
+                let mut start = if buf.text.char_at(cursor_index) == Some(pair.0) { Some(cursor_index) }
+                    else { unpaired_bracket(buf, cursor_index, pair, Direction::Backward) };
+                for _ in 1..count {
+                    start = start.and_then(|s| unpaired_bracket(buf, s, pair, Direction::Backward));
+                }
+                match start.and_then(|s| matching_bracket(buf, s).map(|e| (s, e))) {
+                    Some((start, end)) => pair_range(start, end, include),
+                    None => cursor_index + 1 .. cursor_index
                 }
-                if !include { range.start += 1; }
-
-                let close_char = matching_block_char(*open_char);
-                let mut chars = buf.text.chars(range.end).peekable();
-                let mut count = 0;
-                loop {
-                    match chars.peek() {
-                        Some(ch) if *ch == *open_char => {
-                            println!("+");
-                            count += 1;
+            },
+            TextObject::Argument => {
+                // the innermost brackets around the cursor, or around the string or comment it's in
+                let from = buf.syntax().and_then(|s| s.literal_at(cursor_index)).map_or(cursor_index, |l| l.start);
+                let brackets = [('(', ')'), ('[', ']'), ('{', '}')].iter()
+                    .filter_map(|pair| unpaired_bracket(buf, from, *pair, Direction::Backward))
+                    .max()
+                    .and_then(|open| matching_bracket(buf, open).map(|close| (open, close)));
+                let (open, close) = match brackets {
+                    Some(b) => b,
+                    None => return cursor_index + 1 .. cursor_index
+                };
+                // the commas between arguments, which aren't in nested brackets, strings or comments
+                let syntax = buf.syntax();
+                let mut commas = vec![open];
+                let mut depth = 0usize;
+                let mut ix = open + 1;
+                for ch in buf.text.copy_range(open + 1, close).chars() {
+                    if syntax.map_or(true, |s| s.literal_at(ix).is_none()) {
+                        match ch {
+                            '(' | '[' | '{' => depth += 1,
+                            ')' | ']' | '}' => depth = depth.saturating_sub(1),
+                            ',' if depth == 0 => commas.push(ix),
+                            _ => {}
                         }
-                        Some(ch) if *ch == close_char => {
-                            if count == 0 {
-                                break;
-                            } else {
-                                println!("-");
-                                count -= 1;
-                            }
-                        },
-                        Some(_) => { }
-                        None => break
                     }
-                    range.end += 1;
-                    println!("{:?}",chars.next());
+                    ix += ch.len_utf8();
                 }
-                /*while chars.peek().map(|cc| *cc != mc).unwrap_or(false) {
-                  chars.next();
-                  range.end += 1;
-                  }*/
-                if !include { range.end -= 1; }
-
-                range
+                commas.push(close);
+                let i = commas.iter().rposition(|c| *c < cursor_index).unwrap_or(0).min(commas.len() - 2);
+                let (before, after) = (commas[i], commas[i + 1]);
+                let arg = buf.text.copy_range(before + 1, after);
+                let start = before + 1 + (arg.len() - arg.trim_start().len());
+                let end = (before + 1 + arg.trim_end().len()).max(start);
+                if !include { return start .. end.max(start + 1) - 1; }
+                // `aa` takes the comma after the argument and the space up to the next one, or the comma before
+                // it if it's the last one
+                if after != close {
+                    let next = buf.text.copy_range(after + 1, close);
+                    start .. after + (next.len() - next.trim_start().len())
+                } else if before != open {
+                    before .. end.max(before + 1) - 1
+                } else {
+                    start .. end.max(start + 1) - 1
+                }
+            },
+            TextObject::Syntax(kind) => {
+                let syntax = match buf.syntax() {
+                    Some(s) => s,
+                    None => return cursor_index + 1 .. cursor_index
+                };
+                let range = match kind {
+                    SyntaxKind::Comment | SyntaxKind::String => {
+                        syntax.spans_around(*kind, cursor_index).nth(count - 1)
+                            .map(|s| if include { s.range.clone() } else { s.inner.clone() })
+                    },
+                    SyntaxKind::Function | SyntaxKind::Class => {
+                        // functions and classes starting before the cursor that it's in once their body is added
+                        let i = syntax.spans.partition_point(|s| s.range.start <= cursor_index);
+                        syntax.spans[..i].iter().rev()
+                            .filter(|s| s.kind == *kind)
+                            .map(|s| with_body(buf, &s.range))
+                            .filter(|(whole, _)| whole.contains(&cursor_index))
+                            .nth(count - 1)
+                            .map(|(whole, body)| if include { whole } else { body })
+                    }
+                };
+                match range {
+                    Some(r) => r.start .. r.end.max(r.start + 1) - 1,
+                    None => cursor_index + 1 .. cursor_index
+                }
+            },
+            // text objects are inclusive, so one with nothing in it ends just before it starts
+            TextObject::Paragraph => {
+                let len = buf.text.len();
+                let mut start = buf.current_start_of_line(cursor_index);
+                let empty = empty_line(buf, start);
+                while start > 0 && empty_line(buf, buf.last_line_index(start)) == empty {
+                    start = buf.last_line_index(start);
+                }
+                // `ip` is a paragraph or a run of empty lines, and `ap` is that and whatever comes after it
+                let mut end = start;
+                for _ in 0..(if include { count * 2 } else { count }) {
+                    if end >= len { break; }
+                    let run = empty_line(buf, end);
+                    while end < len && empty_line(buf, end) == run { end = buf.next_line_index(end); }
+                }
+                // the last paragraph has no empty lines after it, so `ap` takes the ones before it instead
+                if include && !empty && end > start && !empty_line(buf, buf.current_start_of_line(end - 1)) {
+                    while start > 0 && empty_line(buf, buf.last_line_index(start)) {
+                        start = buf.last_line_index(start);
+                    }
+                }
+                start .. end.max(start + 1) - 1
+            },
+            TextObject::Sentence => {
+                let starts = sentence_starts(buf, cursor_index, count);
+                let start = starts.iter().rev().find(|s| **s <= cursor_index).cloned().unwrap_or(0);
+                let mut end = starts.iter().filter(|s| **s > cursor_index).nth(count - 1).cloned()
+                    .unwrap_or_else(|| buf.text.len());
+                // `as` keeps the white space after the sentence, and `is` leaves it
+                if !include {
+                    while end > start && buf.text.char_at(end - 1).map_or(false, char::is_whitespace) {
+                        end -= 1;
+                    }
+                }
+                start .. end.max(start + 1) - 1
             },
-            _ => unimplemented!()
+            TextObject::Tag => {
+                let text = buf.text.copy_range(0, buf.text.len());
+                let tag = regex::Regex::new(r"<(/?)([A-Za-z][^\s/>]*)[^>]*>").unwrap();
+                let mut open: Vec<(&str, Range<usize>)> = Vec::new();
+                // pairs of tags around the cursor, innermost first
+                let mut around = Vec::new();
+                for cap in tag.captures_iter(&text) {
+                    let m = cap.get(0).unwrap();
+                    let name = cap.get(2).unwrap().as_str();
+                    if m.as_str().ends_with("/>") { continue; }
+                    if cap.get(1).unwrap().as_str() == "/" {
+                        // tags left open inside the one closing, like `<br>`, don't pair with anything
+                        if let Some(i) = open.iter().rposition(|(n, _)| *n == name) {
+                            let (_, o) = open.remove(i);
+                            open.truncate(i);
+                            if o.start <= cursor_index && cursor_index < m.end() {
+                                around.push((o, m.range()));
+                                if around.len() >= count { break; }
+                            }
+                        }
+                    } else {
+                        open.push((name, m.range()));
+                    }
+                }
+                match around.last() {
+                    Some((o, c)) if include => o.start .. c.end - 1,
+                    Some((o, c)) => o.end .. c.start.max(o.end) - 1,
+                    None => cursor_index + 1 .. cursor_index
+                }
+            }
         }
     }
 }
@@ -153,20 +468,60 @@ pub enum MotionType {
     Line(Direction),
     StartOfLine,
     EndOfLine,
-    Paragraph,
+    // `{` and `}`
+    Paragraph(Direction),
+    // `(` and `)`
+    Sentence(Direction),
+    // `%` with no count
+    MatchingBracket,
+    // `50%` goes to the line halfway through the text
+    FilePercent(usize),
+    ScreenLine(ScreenLine),
+    // Ctrl-D and Ctrl-U
+    HalfPage(Direction),
     An(TextObject),
     Inner(TextObject),
     NextSearchMatch(Direction),
+    // the start of the next or previous difference from the version the buffer is being compared with
+    Hunk(Direction),
+    // `'a` goes to the first character on the mark's line, and `` `a `` goes right to it
+    Mark { name: char, linewise: bool },
+    // a line number counting from 1, or the last line
+    ToLine(Option<usize>),
     Passthrough(usize, usize)
 }
 
 impl MotionType {
     pub fn inclusive(&self) -> bool {
         match self {
-            MotionType::NextChar { .. } | MotionType::RepeatNextChar { .. } => true,
+            MotionType::NextChar { direction: Direction::Forward, .. } | MotionType::RepeatNextChar { .. } => true,
             MotionType::An(_) | MotionType::Inner(_) => true,
             MotionType::Passthrough(_,_) => true,
-            MotionType::EndOfLine => true,
+            MotionType::EndOfLine | MotionType::MatchingBracket => true,
+            _ => false
+        }
+    }
+
+    // whether operators work on whole lines with this motion
+    pub fn linewise(&self) -> bool {
+        match self {
+            MotionType::Line(_) | MotionType::ToLine(_) | MotionType::FilePercent(_) | MotionType::ScreenLine(_)
+                | MotionType::HalfPage(_) | MotionType::Mark { linewise: true, .. } => true,
+            _ => false
+        }
+    }
+
+    // whether this needs to know where strings, comments and functions are
+    pub fn uses_syntax(&self) -> bool {
+        matches!(self, MotionType::An(_) | MotionType::Inner(_) | MotionType::MatchingBracket)
+    }
+
+    // whether moving like this goes in the jump list
+    pub fn is_jump(&self) -> bool {
+        match self {
+            MotionType::NextSearchMatch(_) | MotionType::Mark { .. } | MotionType::ToLine(_) => true,
+            MotionType::Paragraph(_) | MotionType::Sentence(_) | MotionType::MatchingBracket
+                | MotionType::FilePercent(_) | MotionType::ScreenLine(_) => true,
             _ => false
         }
     }
@@ -194,6 +549,46 @@ impl Motion {
     pub fn passthrough(r: &Range<usize>) -> Motion {
         Motion { count: 1, mo: MotionType::Passthrough(r.start, r.end) }
     }
+
+    // `H`, `M`, `L`, Ctrl-D and Ctrl-U depend on which lines are on screen, so they become a motion to a line
+    // once that's known. Anything else stays the same
+    pub fn on_screen(&self, buf: &Buffer, cursor_index: usize, lines: Range<usize>) -> Motion {
+        let last = buf.last_line();
+        let top = lines.start.min(last);
+        let bottom = lines.end.saturating_sub(1).min(last).max(top);
+        let line = match self.mo {
+            MotionType::ScreenLine(ScreenLine::Top) => (top + self.count - 1).min(bottom),
+            MotionType::ScreenLine(ScreenLine::Middle) => top + (bottom - top) / 2,
+            MotionType::ScreenLine(ScreenLine::Bottom) => bottom.saturating_sub(self.count - 1).max(top),
+            MotionType::HalfPage(direction) => {
+                let lines = ((bottom - top + 1) / 2).max(1) * self.count;
+                let cur = buf.line_for_index(cursor_index);
+                match direction {
+                    Direction::Forward => (cur + lines).min(last),
+                    Direction::Backward => cur.saturating_sub(lines)
+                }
+            },
+            _ => return *self
+        };
+        Motion { count: 1, mo: MotionType::ToLine(Some(line + 1)) }
+    }
+
+    // the text an operator works on, from start to end. Inclusive motions take the character they end on,
+    // and linewise ones every line they touch
+    pub fn op_range(&self, buf: &mut Buffer, cursor_index: usize, multiplier: usize) -> Range<usize> {
+        let r = self.range(buf, cursor_index, multiplier);
+        let len = buf.text.len();
+        if self.mo.linewise() {
+            return buf.current_start_of_line(r.start.min(r.end)) .. buf.next_line_index(r.start.max(r.end));
+        }
+        let r = match self.mo {
+            MotionType::An(_) | MotionType::Inner(_) => r.start .. (r.end + 1).max(r.start),
+            _ if r.end < r.start => r.end .. if self.mo.inclusive() { r.start + 1 } else { r.start },
+            _ if self.mo.inclusive() => r.start .. r.end + 1,
+            _ => r
+        };
+        r.start.min(len) .. r.end.min(len)
+    }
     pub fn parse(c: &mut std::iter::Peekable<std::str::Chars>, opchar: Option<char>, wholecmd: &str) -> Result<Motion, Error> {
         let count = take_number(c);
         let txo = match c.peek() {
@@ -212,6 +607,7 @@ impl Motion {
             Some('g') => {
                 c.next();
                 match c.peek() {
+                    Some('g') => MotionType::ToLine(Some(count.unwrap_or(1))),
                     Some('e') => MotionType::EndOfWord(Direction::Backward),
                     Some('E') => MotionType::EndOfBigWord(Direction::Backward),
                     Some(';') => MotionType::RepeatNextChar { opposite: true },
@@ -219,6 +615,33 @@ impl Motion {
                     None => return Err(Error::IncompleteCommand)
                 }
             },
+            Some(&bc) if bc == ']' || bc == '[' => {
+                c.next();
+                match c.peek() {
+                    Some('c') => MotionType::Hunk(if bc == ']' { Direction::Forward } else { Direction::Backward }),
+                    Some(_) => return Err(Error::UnknownCommand(String::from(wholecmd))),
+                    None => return Err(Error::IncompleteCommand)
+                }
+            },
+            Some('G') => MotionType::ToLine(count),
+            Some('%') => match count {
+                Some(n) => MotionType::FilePercent(n),
+                None => MotionType::MatchingBracket
+            },
+            Some('}') => MotionType::Paragraph(Direction::Forward),
+            Some('{') => MotionType::Paragraph(Direction::Backward),
+            Some(')') => MotionType::Sentence(Direction::Forward),
+            Some('(') => MotionType::Sentence(Direction::Backward),
+            Some('H') => MotionType::ScreenLine(ScreenLine::Top),
+            Some('M') => MotionType::ScreenLine(ScreenLine::Middle),
+            Some('L') => MotionType::ScreenLine(ScreenLine::Bottom),
+            Some(&mc) if mc == '\'' || mc == '`' => {
+                c.next();
+                MotionType::Mark {
+                    name: c.next().ok_or(Error::IncompleteCommand)?,
+                    linewise: mc == '\''
+                }
+            },
             Some('^') => MotionType::StartOfLine,
             Some('$') => MotionType::EndOfLine,
             Some('_') => MotionType::WholeLine,
@@ -248,12 +671,19 @@ impl Motion {
                     Some('w') => TextObject::Word,
                     Some('W') => TextObject::BigWord,
                     Some('p') => TextObject::Paragraph,
+                    Some('s') => TextObject::Sentence,
+                    Some('t') => TextObject::Tag,
                     Some('{') | Some('}') => TextObject::Block('{'),
                     Some('(') | Some(')') => TextObject::Block('('),
                     Some('[') | Some(']') => TextObject::Block('['),
                     Some('<') | Some('>') => TextObject::Block('<'),
                     Some('"')  => TextObject::Block('"'),
                     Some('\'') => TextObject::Block('\''),
+                    Some('a') => TextObject::Argument,
+                    Some('f') => TextObject::Syntax(SyntaxKind::Function),
+                    Some('C') => TextObject::Syntax(SyntaxKind::Class),
+                    Some('c') => TextObject::Syntax(SyntaxKind::Comment),
+                    Some('q') => TextObject::Syntax(SyntaxKind::String),
                     Some(_) => return Err(Error::UnknownCommand(String::from(wholecmd))),
                     None => return Err(Error::IncompleteCommand)
                 };
@@ -474,10 +904,68 @@ impl Motion {
                 },
                 
                 MotionType::NextSearchMatch(direction) => {
-                    range.end = buf.next_query_index(range.start + 1, *direction, true).unwrap_or(range.start);
+                    let from = match direction {
+                        Direction::Forward => range.start + 1,
+                        Direction::Backward => range.start
+                    };
+                    range.end = buf.next_query_index(from, *direction, true).unwrap_or(range.start);
+                },
+
+                MotionType::Hunk(direction) => {
+                    let line = buf.line_for_index(range.end);
+                    let target = buf.diff.as_ref().and_then(|d| match direction {
+                        Direction::Forward => d.hunks.iter().find(|h| h.new.start > line),
+                        Direction::Backward => d.hunks.iter().rev().find(|h| h.new.start < line)
+                    }).map(|h| h.new.start);
+                    if let Some(l) = target {
+                        range.end = buf.line_start_index(l);
+                    }
+                },
+
+                MotionType::Mark { name, linewise } => {
+                    // like `;` with nothing to repeat, there's nowhere to go
+                    if let Some(ix) = buf.mark(*name) {
+                        range.end = if *linewise { buf.first_non_blank(ix) } else { ix };
+                    }
+                },
+
+                MotionType::ToLine(line) => {
+                    let line = line.map_or(buf.last_line(), |l| l.saturating_sub(1).min(buf.last_line()));
+                    range.end = buf.first_non_blank(buf.line_start_index(line));
+                },
+
+                MotionType::FilePercent(percent) => {
+                    let line = ((percent * (buf.last_line() + 1) + 99) / 100).saturating_sub(1).min(buf.last_line());
+                    range.end = buf.first_non_blank(buf.line_start_index(line));
                 },
 
-                _ => unimplemented!()
+                MotionType::Paragraph(Direction::Forward) => { range.end = paragraph_forward(buf, range.end); },
+                MotionType::Paragraph(Direction::Backward) => { range.end = paragraph_backward(buf, range.end); },
+
+                MotionType::Sentence(Direction::Forward) => {
+                    range.end = sentence_starts(buf, range.end, 1).into_iter().find(|s| *s > range.end)
+                        .unwrap_or_else(|| buf.text.len());
+                },
+                MotionType::Sentence(Direction::Backward) => {
+                    if range.end > 0 {
+                        range.end = sentence_starts(buf, range.end - 1, 1).into_iter().rev().find(|s| *s < range.end)
+                            .unwrap_or(0);
+                    }
+                },
+
+                // the bracket matching the first one under or after the cursor on its line
+                MotionType::MatchingBracket => {
+                    let eol = buf.next_line_index(range.end);
+                    let bracket = buf.text.index_of_pred(|c| "()[]{}".contains(c), range.end).filter(|b| *b < eol);
+                    if let Some(m) = bracket.and_then(|b| matching_bracket(buf, b)) {
+                        range.end = m;
+                    }
+                },
+
+                // these only move once `on_screen` has made them into a line to go to
+                MotionType::ScreenLine(_) | MotionType::HalfPage(_) => {},
+
+                MotionType::Passthrough(..) | MotionType::An(_) | MotionType::Inner(_) => unreachable!()
             }
         }
         range
@@ -499,59 +987,74 @@ mod tests {
 
     #[test]
     fn txo_char() {
-        let b = create_line_test_buffer();
+        let mut b = create_line_test_buffer();
         let mo = Motion {
             mo: MotionType::Char(Direction::Forward),
             count: 1
         };
-        assert_eq!(mo.range(&b, 4, 1), 4..5);
+        assert_eq!(mo.range(&mut b, 4, 1), 4..5);
     }
 
     #[test]
     fn txo_line() {
 
-        let b = create_line_test_buffer();
+        let mut b = create_line_test_buffer();
         let mo = Motion {
             mo: MotionType::Line(Direction::Forward),
             count: 1
         };
-        assert_eq!(mo.range(&b, 4, 1), 4..8);
+        assert_eq!(mo.range(&mut b, 4, 1), 4..8);
     }
 
     #[test]
     fn txo_start_of_line() {
-        let b = create_line_test_buffer();
+        let mut b = create_line_test_buffer();
         let mo = Motion {
             mo: MotionType::StartOfLine,
             count: 1
         };
-        assert_eq!(mo.range(&b, 4, 1), 4..4);
+        assert_eq!(mo.range(&mut b, 4, 1), 4..4);
     }      
 
     #[test]
     fn txo_end_of_line() {
-        let b = create_line_test_buffer();
+        let mut b = create_line_test_buffer();
         let mo = Motion {
             mo: MotionType::EndOfLine,
             count: 1
         };
-        assert_eq!(mo.range(&b, 4, 1), 4..6);
+        assert_eq!(mo.range(&mut b, 4, 1), 4..6);
     }      
 
     #[test]
     fn txo_line_backward() {
-        let b = create_line_test_buffer();
+        let mut b = create_line_test_buffer();
         let mo = Motion {
             mo: MotionType::Line(Direction::Backward),
             count: 1
         };
-        assert_eq!(mo.range(&b, 4, 1), 4..0);
+        assert_eq!(mo.range(&mut b, 4, 1), 4..0);
+    }
+
+    #[test]
+    fn txo_diff_hunk() {
+        let mut b = Buffer::with_text("abc\nxyz\nghi\njkl\n");
+        b.start_diff("server".into(), "abc\ndef\nghi\n");
+        let mut mo = Motion {
+            mo: MotionType::Hunk(Direction::Forward),
+            count: 1
+        };
+        assert_eq!(mo.range(&mut b, 0, 1), 0..4);
+        assert_eq!(mo.range(&mut b, 4, 1), 4..12);
+        assert_eq!(mo.range(&mut b, 12, 1), 12..12);
+        mo.mo = MotionType::Hunk(Direction::Backward);
+        assert_eq!(mo.range(&mut b, 12, 1), 12..4);
     }
 
     fn run_repeated_test<'a>(b: &mut Buffer, cursor_index: &mut usize, mo: &Motion, 
                              correct_ends: impl Iterator<Item=&'a usize>, assert_msg: &str) {
         for (i, cwb) in correct_ends.enumerate() {
-            let r = mo.range(&b, *cursor_index, 1);
+            let r = mo.range(b, *cursor_index, 1);
             assert_eq!(r.end, *cwb, "{} i={} ci={}", assert_msg, i, *cursor_index);
             *cursor_index = r.end;
         }
@@ -560,7 +1063,7 @@ mod tests {
     fn run_repeated_test_then_offset<'a>(b: &mut Buffer, cursor_index: &mut usize, mo: &Motion, 
                                          correct_ends: impl Iterator<Item=&'a usize>, offset: isize, assert_msg: &str) {
         for (i, cwb) in correct_ends.enumerate() {
-            let r = mo.range(&b, *cursor_index, 1);
+            let r = mo.range(b, *cursor_index, 1);
             assert_eq!(r.end, *cwb, "{} i={}", assert_msg, i);
             *cursor_index = (r.end as isize + offset) as usize;
         }
@@ -700,14 +1203,14 @@ mod tests {
         let mut mo = Motion {
             mo: MotionType::An(TextObject::Word), count: 1
         };
-        assert_eq!(mo.range(&b, 3, 1), 1..7);
+        assert_eq!(mo.range(&mut b, 3, 1), 1..7);
         mo.count += 1;
-        assert_eq!(mo.range(&b, 3, 1), 1..12);
+        assert_eq!(mo.range(&mut b, 3, 1), 1..12);
         mo.count += 1;
-        assert_eq!(mo.range(&b, 3, 1), 1..14);
+        assert_eq!(mo.range(&mut b, 3, 1), 1..14);
 
         mo.count = 1;
-        assert_eq!(mo.range(&b, 6, 1), 5..11);
+        assert_eq!(mo.range(&mut b, 6, 1), 5..11);
     }
 
     #[test]
@@ -716,14 +1219,14 @@ mod tests {
         let mut mo = Motion {
             mo: MotionType::Inner(TextObject::Word), count: 1
         };
-        assert_eq!(mo.range(&b, 3, 1), 1..4);
+        assert_eq!(mo.range(&mut b, 3, 1), 1..4);
         mo.count += 1;
-        assert_eq!(mo.range(&b, 3, 1), 1..6);
+        assert_eq!(mo.range(&mut b, 3, 1), 1..6);
         mo.count += 1;
-        assert_eq!(mo.range(&b, 3, 1), 1..10);
+        assert_eq!(mo.range(&mut b, 3, 1), 1..10);
 
         mo.count = 1;
-        assert_eq!(mo.range(&b, 6, 1), 5..6);
+        assert_eq!(mo.range(&mut b, 6, 1), 5..6);
     }
 
     #[test]
@@ -732,14 +1235,14 @@ mod tests {
         let mut mo = Motion {
             mo: MotionType::An(TextObject::BigWord), count: 1
         };
-        assert_eq!(mo.range(&b, 3, 1), 1..7);
+        assert_eq!(mo.range(&mut b, 3, 1), 1..7);
         mo.count += 1;
-        assert_eq!(mo.range(&b, 3, 1), 1..12);
+        assert_eq!(mo.range(&mut b, 3, 1), 1..12);
         mo.count += 1;
-        assert_eq!(mo.range(&b, 3, 1), 1..16);
+        assert_eq!(mo.range(&mut b, 3, 1), 1..16);
 
         mo.count = 1;
-        assert_eq!(mo.range(&b, 6, 1), 5..11);
+        assert_eq!(mo.range(&mut b, 6, 1), 5..11);
     }
 
     #[test]
@@ -748,14 +1251,14 @@ mod tests {
         let mut mo = Motion {
             mo: MotionType::Inner(TextObject::BigWord), count: 1
         };
-        assert_eq!(mo.range(&b, 3, 1), 1..4);
+        assert_eq!(mo.range(&mut b, 3, 1), 1..4);
         mo.count += 1;
-        assert_eq!(mo.range(&b, 3, 1), 1..6);
+        assert_eq!(mo.range(&mut b, 3, 1), 1..6);
         mo.count += 1;
-        assert_eq!(mo.range(&b, 3, 1), 1..12); // this doesn't quite agree with Vim, but it seems questionable either way
+        assert_eq!(mo.range(&mut b, 3, 1), 1..12); // this doesn't quite agree with Vim, but it seems questionable either way
 
         mo.count = 1;
-        assert_eq!(mo.range(&b, 6, 1), 5..6);
+        assert_eq!(mo.range(&mut b, 6, 1), 5..6);
     }
 
     #[test]
@@ -766,22 +1269,22 @@ mod tests {
         };
 
         let mut cursor_index = 0;
-        assert_eq!(mo.range(&b, cursor_index, 1), 0..20, "on <");
+        assert_eq!(mo.range(&mut b, cursor_index, 1), 0..20, "on <");
         cursor_index += 3;
-        assert_eq!(mo.range(&b, cursor_index, 1), 0..20, "in <");
+        assert_eq!(mo.range(&mut b, cursor_index, 1), 0..20, "in <");
 
         cursor_index = 1;
         mo.mo = MotionType::An(TextObject::Block('('));
-        assert_eq!(mo.range(&b, cursor_index, 1), 1..9, "on first (");
+        assert_eq!(mo.range(&mut b, cursor_index, 1), 1..9, "on first (");
         cursor_index += 2;
-        assert_eq!(mo.range(&b, cursor_index, 1), 1..9, "in first (");
+        assert_eq!(mo.range(&mut b, cursor_index, 1), 1..9, "in first (");
 
         cursor_index += 2;
-        assert_eq!(mo.range(&b, cursor_index, 1), 4..6, "in nested (");
+        assert_eq!(mo.range(&mut b, cursor_index, 1), 4..6, "in nested (");
 
         cursor_index = 15;
         mo.mo = MotionType::An(TextObject::Block('{'));
-        assert_eq!(mo.range(&b, cursor_index, 1), 11..19, "in {{");
+        assert_eq!(mo.range(&mut b, cursor_index, 1), 11..19, "in {{");
     }
 
     #[test]
@@ -792,24 +1295,168 @@ mod tests {
         };
 
         let mut cursor_index = 0;
-        assert_eq!(mo.range(&b, cursor_index, 1), 1..19, "on <");
+        assert_eq!(mo.range(&mut b, cursor_index, 1), 1..19, "on <");
         cursor_index += 3;
-        assert_eq!(mo.range(&b, cursor_index, 1), 1..19, "in <");
+        assert_eq!(mo.range(&mut b, cursor_index, 1), 1..19, "in <");
 
         cursor_index = 1;
         mo.mo = MotionType::Inner(TextObject::Block('('));
-        assert_eq!(mo.range(&b, cursor_index, 1), 2..8, "on first (");
+        assert_eq!(mo.range(&mut b, cursor_index, 1), 2..8, "on first (");
         cursor_index += 2;
-        assert_eq!(mo.range(&b, cursor_index, 1), 2..8, "in first (");
+        assert_eq!(mo.range(&mut b, cursor_index, 1), 2..8, "in first (");
 
         cursor_index += 2;
-        assert_eq!(mo.range(&b, cursor_index, 1), 5..5, "in nested (");
+        assert_eq!(mo.range(&mut b, cursor_index, 1), 5..5, "in nested (");
 
         cursor_index = 15;
         mo.mo = MotionType::Inner(TextObject::Block('{'));
-        assert_eq!(mo.range(&b, cursor_index, 1), 12..18, "in {{");
+        assert_eq!(mo.range(&mut b, cursor_index, 1), 12..18, "in {{");
     }
 
+    fn create_paragraph_test_buffer() -> Buffer {
+        Buffer::with_text("ab\ncd\n\n\nef\ngh\n\nij")
+    }
 
-}
+    #[test]
+    fn txo_paragraph() {
+        let mut b = create_paragraph_test_buffer();
+        let mut mo = Motion { mo: MotionType::Paragraph(Direction::Forward), count: 1 };
+        let mut cursor_index = 0;
+        run_repeated_test(&mut b, &mut cursor_index, &mo, [6, 14, 17, 17].iter(), "forward");
+        mo.mo = MotionType::Paragraph(Direction::Backward);
+        cursor_index = 16;
+        run_repeated_test(&mut b, &mut cursor_index, &mo, [14, 7, 0, 0].iter(), "backward");
+        mo = Motion { mo: MotionType::Paragraph(Direction::Forward), count: 2 };
+        assert_eq!(mo.range(&mut b, 0, 1), 0..14);
+    }
+
+    #[test]
+    fn txo_object_paragraph() {
+        let mut b = create_paragraph_test_buffer();
+        let mut mo = Motion { mo: MotionType::Inner(TextObject::Paragraph), count: 1 };
+        assert_eq!(mo.range(&mut b, 1, 1), 0..5);
+        assert_eq!(mo.range(&mut b, 7, 1), 6..7, "empty lines");
+        mo.mo = MotionType::An(TextObject::Paragraph);
+        assert_eq!(mo.range(&mut b, 1, 1), 0..7);
+        assert_eq!(mo.range(&mut b, 16, 1), 14..16, "last paragraph");
+    }
+
+    #[test]
+    fn txo_sentence() {
+        let mut b = Buffer::with_text("Hello there. How are you?  Fine!\n\nNext one.");
+        let mut mo = Motion { mo: MotionType::Sentence(Direction::Forward), count: 1 };
+        let mut cursor_index = 0;
+        run_repeated_test(&mut b, &mut cursor_index, &mo, [13, 27, 33, 34, 43].iter(), "forward");
+        mo.mo = MotionType::Sentence(Direction::Backward);
+        cursor_index = 36;
+        run_repeated_test(&mut b, &mut cursor_index, &mo, [34, 33, 27, 13, 0, 0].iter(), "backward");
+    }
+
+    #[test]
+    fn txo_object_sentence() {
+        let mut b = Buffer::with_text("Hello there. How are you?  Fine!\n\nNext one.");
+        let mut mo = Motion { mo: MotionType::Inner(TextObject::Sentence), count: 1 };
+        assert_eq!(mo.range(&mut b, 15, 1), 13..24);
+        mo.mo = MotionType::An(TextObject::Sentence);
+        assert_eq!(mo.range(&mut b, 15, 1), 13..26);
+        mo.count = 2;
+        assert_eq!(mo.range(&mut b, 0, 1), 0..26);
+    }
+
+    #[test]
+    fn txo_object_tag() {
+        let mut b = Buffer::with_text("<a><b>x</b><br></a><c></c>");
+        let mut mo = Motion { mo: MotionType::Inner(TextObject::Tag), count: 1 };
+        assert_eq!(mo.range(&mut b, 6, 1), 6..6);
+        assert_eq!(mo.range(&mut b, 12, 1), 3..14, "in unclosed tag");
+        assert_eq!(mo.op_range(&mut b, 20, 1), 22..22, "empty");
+        mo.mo = MotionType::An(TextObject::Tag);
+        assert_eq!(mo.range(&mut b, 6, 1), 3..10);
+        mo.count = 2;
+        assert_eq!(mo.range(&mut b, 6, 1), 0..18);
+    }
+
+    #[test]
+    fn txo_matching_bracket() {
+        let mut b = Buffer::with_text("f(a[b]{c}) x\n(");
+        let mo = Motion { mo: MotionType::MatchingBracket, count: 1 };
+        assert_eq!(mo.range(&mut b, 0, 1), 0..9);
+        assert_eq!(mo.range(&mut b, 9, 1), 9..1);
+        assert_eq!(mo.range(&mut b, 3, 1), 3..5);
+        assert_eq!(mo.range(&mut b, 10, 1), 10..10, "no bracket after the cursor on the line");
+        assert_eq!(mo.range(&mut b, 13, 1), 13..13, "unmatched");
+        assert_eq!(mo.op_range(&mut b, 9, 1), 1..10);
+    }
+
+    #[test]
+    fn txo_object_argument() {
+        let mut b = Buffer::with_text("f(a, g(b, c), d)");
+        let mut mo = Motion { mo: MotionType::Inner(TextObject::Argument), count: 1 };
+        assert_eq!(mo.range(&mut b, 2, 1), 2..2);
+        assert_eq!(mo.range(&mut b, 5, 1), 5..11);
+        assert_eq!(mo.range(&mut b, 8, 1), 7..7, "on a comma in nested brackets");
+        mo.mo = MotionType::An(TextObject::Argument);
+        assert_eq!(mo.range(&mut b, 2, 1), 2..4);
+        assert_eq!(mo.range(&mut b, 14, 1), 12..14, "last argument");
+    }
+
+    fn create_syntax_test_buffer() -> Buffer {
+        let mut b = Buffer::with_text("fn f() {\n    g(\")\", 1) // )\n}\n");
+        b.path = "test.rs".into();
+        let (_, syntax) = crate::syntax_highlight::Highlighter::from_toml(None).compute_highlighting(&b);
+        b.set_syntax(syntax);
+        b
+    }
 
+    #[test]
+    fn txo_syntax_objects() {
+        let mut b = create_syntax_test_buffer();
+        let mo = Motion { mo: MotionType::MatchingBracket, count: 1 };
+        assert_eq!(mo.range(&mut b, 13, 1), 13..21, "skips the bracket in the string");
+        assert_eq!(mo.range(&mut b, 7, 1), 7..28, "skips the bracket in the comment");
+        let mut mo = Motion { mo: MotionType::Inner(TextObject::Block('(')), count: 1 };
+        assert_eq!(mo.range(&mut b, 20, 1), 15..20);
+        mo.mo = MotionType::Inner(TextObject::Argument);
+        assert_eq!(mo.range(&mut b, 16, 1), 15..17);
+        mo.mo = MotionType::Inner(TextObject::Syntax(SyntaxKind::String));
+        assert_eq!(mo.range(&mut b, 15, 1), 16..16);
+        mo.mo = MotionType::An(TextObject::Syntax(SyntaxKind::Comment));
+        assert_eq!(mo.range(&mut b, 26, 1), 23..26);
+        mo.mo = MotionType::An(TextObject::Syntax(SyntaxKind::Function));
+        assert_eq!(mo.range(&mut b, 16, 1), 0..28);
+        mo.mo = MotionType::Inner(TextObject::Syntax(SyntaxKind::Function));
+        assert_eq!(mo.range(&mut b, 16, 1), 9..27);
+        let mut b = Buffer::with_text("class A:\n    def f(self):\n        return 1\n\n    x = 2\ny = 3\n");
+        b.path = "test.py".into();
+        let (_, syntax) = crate::syntax_highlight::Highlighter::from_toml(None).compute_highlighting(&b);
+        b.set_syntax(syntax);
+        mo.mo = MotionType::An(TextObject::Syntax(SyntaxKind::Function));
+        assert_eq!(mo.range(&mut b, 30, 1), 9..42, "indented body");
+        mo.mo = MotionType::Inner(TextObject::Syntax(SyntaxKind::Function));
+        assert_eq!(mo.range(&mut b, 30, 1), 26..42);
+        mo.mo = MotionType::An(TextObject::Syntax(SyntaxKind::Class));
+        assert_eq!(mo.range(&mut b, 30, 1), 0..53);
+    }
+
+    #[test]
+    fn txo_lines() {
+        let mut b = create_line_test_buffer();
+        let mut mo = Motion { mo: MotionType::FilePercent(50), count: 1 };
+        assert_eq!(mo.range(&mut b, 0, 1), 0..4);
+        mo.mo = MotionType::ScreenLine(ScreenLine::Bottom);
+        assert_eq!(mo.on_screen(&b, 0, 0..2), Motion { mo: MotionType::ToLine(Some(2)), count: 1 });
+        mo.mo = MotionType::HalfPage(Direction::Forward);
+        assert_eq!(mo.on_screen(&b, 4, 0..3), Motion { mo: MotionType::ToLine(Some(3)), count: 1 });
+        mo.mo = MotionType::Line(Direction::Forward);
+        assert_eq!(mo.op_range(&mut b, 5, 1), 4..12, "linewise");
+    }
+
+    #[test]
+    fn op_range_backward() {
+        let mut b = create_word_test_buffer();
+        let mo = Motion { mo: MotionType::Word(Direction::Backward), count: 1 };
+        assert_eq!(mo.op_range(&mut b, 7, 1), 5..7);
+        let mo = Motion { mo: MotionType::NextChar { c: 'w', place_before: false, direction: Direction::Backward }, count: 1 };
+        assert_eq!(mo.op_range(&mut b, 12, 1), 10..12);
+    }
+}
@@ -1,8 +1,9 @@
 
 use runic::*;
 use pk_common::piece_table::PieceTable;
-use crate::mode::CursorStyle;
+use crate::mode::{CursorStyle, Selection};
 use crate::config::{Config, Colorscheme, ColorschemeSel};
+use pk_common::protocol;
 
 trait CursorStyleDraw {
     fn paint(&self, rx: &mut RenderContext, char_bounds: &Rect, em_bounds: &Rect, col: Color);
@@ -69,6 +70,8 @@ pub struct PieceTableRenderer {
     pub em_bounds: Rect,
     pub cursor_style: CursorStyle,
     pub highlight_line: bool,
+    // matches of a search to mark in the text, set before painting
+    pub search_matches: Vec<Range<usize>>,
     layout_cashe: HashMap<usize, (u64, TextLayout)>
 }
 
@@ -79,6 +82,7 @@ impl PieceTableRenderer {
             em_bounds,
             cursor_style: CursorStyle::Underline,
             highlight_line: true,
+            search_matches: Vec::new(),
             layout_cashe: HashMap::new()
         }
     }
@@ -131,12 +135,21 @@ impl PieceTableRenderer {
         cur_pos.x += self.em_bounds.w * 7.0;
     }
     
-    fn paint_visual_selection(&mut self, rx: &mut RenderContext, config: &Config, cur_pos: &Point, layout: &TextLayout, cur_range: Range<usize>, sel_range: &Range<usize>) {
+    // a block selection covers the same columns of each of its lines, cut short where a line is
+    fn paint_visual_selection(&mut self, rx: &mut RenderContext, config: &Config, cur_pos: &Point, layout: &TextLayout, cur_range: Range<usize>,
+                              line_num: usize, line_start: usize, selection: &Selection) {
+        let sel_range = match selection {
+            Selection::Chars(r) => r.clone(),
+            Selection::Block { lines, columns, .. } => {
+                if !lines.contains(&line_num) { return; }
+                line_start + columns.start .. line_start + columns.end - 1
+            }
+        };
         if sel_range.start < cur_range.start && sel_range.end < cur_range.start { return; } // skip if the selection is totally before the current range
         if sel_range.start > cur_range.end   && sel_range.end > cur_range.end   { return; } // skip if the selection is totally after the current range
         let start = cur_range.start.max(sel_range.start);
         let end   = cur_range.end  .min(sel_range.end);
-        if start >= end { return; }
+        if start > end { return; }
         let start_rect = layout.char_bounds(start - cur_range.start);
         let end_rect = layout.char_bounds(end - cur_range.start);
         let r = Rect::pnwh(*cur_pos + Point::xy(start_rect.x, 0.0), end_rect.x-start_rect.x + end_rect.w, start_rect.h.max(end_rect.h));
@@ -145,15 +158,72 @@ impl PieceTableRenderer {
         rx.set_color(config.colors.foreground);
     }
 
+    fn paint_search_matches(&self, rx: &mut RenderContext, config: &Config, cur_pos: &Point, layout: &TextLayout, cur_range: Range<usize>) {
+        rx.set_color(config.colors.accent[2].with_alpha(0.35));
+        for m in self.search_matches.iter() {
+            let start = cur_range.start.max(m.start);
+            let end = cur_range.end.min(m.end);
+            if start >= end { continue; }
+            let start_rect = layout.char_bounds(start - cur_range.start);
+            let end_rect = layout.char_bounds(end - cur_range.start);
+            rx.fill_rect(Rect::pnwh(*cur_pos + Point::xy(start_rect.x, 0.0), end_rect.x-start_rect.x, start_rect.h));
+        }
+        rx.set_color(config.colors.foreground);
+    }
+
+    // tint the backgrounds of ranges of lines, like the differences in a diff. Empty ranges are drawn as
+    // a thin bar between lines
+    pub fn paint_line_tints(&self, rx: &mut RenderContext, viewport_start: usize, bounds: Rect, tints: &[(Range<usize>, Color)]) {
+        let viewport_end = self.viewport_end(viewport_start, &bounds);
+        for (lines, col) in tints {
+            if lines.end < viewport_start || lines.start > viewport_end { continue; }
+            let y = bounds.y + (lines.start.max(viewport_start) - viewport_start) as f32 * self.em_bounds.h;
+            rx.set_color(*col);
+            if lines.is_empty() {
+                rx.fill_rect(Rect::xywh(bounds.x, y - 1.0, bounds.w, 2.0));
+            } else {
+                let n = lines.end.min(viewport_end + 1) - lines.start.max(viewport_start);
+                rx.fill_rect(Rect::xywh(bounds.x, y, bounds.w, n as f32 * self.em_bounds.h));
+            }
+        }
+    }
+
+    // other clients' cursors are drawn as lines, and their selections tinted, in a color picked by client
+    fn paint_remote_cursors(&mut self, rx: &mut RenderContext, config: &Config, cur_pos: &Point, layout: &TextLayout,
+                            cur_range: Range<usize>, at_end_of_table: bool, remote_cursors: &[protocol::RemoteCursor]) {
+        for rc in remote_cursors {
+            let col = config.colors.accent[(rc.client.0 % 8) as usize];
+            if let Some((start, end)) = rc.selection {
+                let (lo, hi) = (start.min(end), start.max(end));
+                let (start, end) = (lo.max(cur_range.start), hi.min(cur_range.end));
+                if start < end {
+                    let start_rect = layout.char_bounds(start - cur_range.start);
+                    let end_rect = layout.char_bounds(end - cur_range.start);
+                    rx.set_color(col.with_alpha(0.2));
+                    rx.fill_rect(Rect::pnwh(*cur_pos + Point::xy(start_rect.x, 0.0), end_rect.x-start_rect.x + end_rect.w,
+                        start_rect.h.max(end_rect.h)));
+                }
+            }
+            if rc.cursor >= cur_range.start && (rc.cursor < cur_range.end || (rc.cursor == cur_range.end && !at_end_of_table)) {
+                let curbounds = layout.char_bounds(rc.cursor - cur_range.start).offset(*cur_pos);
+                CursorStyle::Line.paint(rx, &curbounds, &self.em_bounds, col);
+            }
+        }
+        rx.set_color(config.colors.foreground);
+    }
+
     pub fn paint(&mut self, rx: &mut RenderContext, table: &PieceTable,
-                 viewport_start: usize, cursor_index: usize, config: &Config, bounds: Rect,
-                 highlights: Option<&Vec<Highlight>>, line_numbers: bool, selection: Option<&Range<usize>>)
+                 viewport_start: usize, cursor_index: usize, cursors: &[usize], config: &Config, bounds: Rect,
+                 highlights: Option<&Vec<Highlight>>, line_numbers: bool, selection: Option<&Selection>,
+                 remote_cursors: &[protocol::RemoteCursor])
     {
         rx.set_color(config.colors.foreground);
         let mut global_index = 0usize;
         let mut cur_pos = Point::xy(bounds.x, bounds.y); 
         if line_numbers { cur_pos.x += self.em_bounds.w * 7.0; }
         let mut line_num = 0usize;
+        // lines can be split across pieces, so this is where the line being drawn started
+        let mut line_start = 0usize;
         let viewport_end = self.viewport_end(viewport_start, &bounds);
         let table_len = table.len();
         //self.paint_start_of_line(rx, &mut cur_pos, line_num);
@@ -167,23 +237,39 @@ impl PieceTableRenderer {
                 let ln = ln.unwrap();
                 
                 if line_num < viewport_start {
+                    global_index += ln.len();
                     if lni.peek().is_some() {
                         line_num+=1; 
                         global_index += 1;
+                        line_start = global_index;
                     }
-                    global_index += ln.len();
                     continue;
                 }
                 
                 let layout = self.generate_line_layout(ln, global_index, rx, &config.colors, highlights);
                 rx.draw_text_layout(cur_pos, &layout);
                 
-                if selection.is_some() {
-                    self.paint_visual_selection(rx, config, &mut cur_pos, &layout, global_index .. global_index+ln.len(), selection.as_ref().unwrap());
+                if !self.search_matches.is_empty() {
+                    self.paint_search_matches(rx, config, &cur_pos, &layout, global_index .. global_index+ln.len());
+                }
+
+                if let Some(selection) = selection {
+                    self.paint_visual_selection(rx, config, &cur_pos, &layout, global_index .. global_index+ln.len(), line_num, line_start, selection);
+                }
+
+                if !remote_cursors.is_empty() {
+                    self.paint_remote_cursors(rx, config, &cur_pos, &layout, global_index .. global_index+ln.len(),
+                        lni.peek().is_none() && global_index+ln.len() != table_len, remote_cursors);
                 }
                 
-                if cursor_index >= global_index && cursor_index < global_index+ln.len() ||
-                    ((lni.peek().is_some() || cursor_index == table_len) && cursor_index == global_index+ln.len()) {
+                let ends_line = lni.peek().is_some();
+                let on_line = |c: usize| c >= global_index && c < global_index+ln.len() ||
+                    ((ends_line || c == table_len) && c == global_index+ln.len());
+                for &c in cursors.iter().filter(|c| on_line(**c)) {
+                    let curbounds = layout.char_bounds(c - global_index).offset(cur_pos);
+                    self.cursor_style.paint(rx, &curbounds, &self.em_bounds, config.colors.three_quarter_gray);
+                }
+                if on_line(cursor_index) {
                     let curbounds = layout.char_bounds(cursor_index - global_index).offset(cur_pos);
                     self.cursor_style.paint(rx, &curbounds, &self.em_bounds, config.colors.foreground);
                     if self.highlight_line {
@@ -204,6 +290,7 @@ impl PieceTableRenderer {
                     if line_numbers { self.paint_line_numbers(rx, config, &mut cur_pos, line_num); }
                     cur_pos.y += text_size.h.min(self.em_bounds.h);
                     global_index += 1;
+                    line_start = global_index;
                     if line_num > viewport_end { break 'top; }
                     //if cur_pos.y + text_size.h > bounds.h { break; }
                 } else {
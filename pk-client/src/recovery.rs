@@ -0,0 +1,157 @@
+use serde::{Serialize, Deserialize};
+use std::path::{Path, PathBuf};
+use pk_common::piece_table::PieceTable;
+use crate::buffer::Buffer;
+use super::Error;
+
+// everything needed to put a buffer back the way it was if the client goes down before the server
+// has the edits
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RecoveredBuffer {
+    pub server_name: String,
+    pub path: PathBuf,
+    // the server version that the edits in `text` are based on
+    pub base_version: usize,
+    pub text: PieceTable
+}
+
+impl RecoveredBuffer {
+    // the text the piece table started out with, before any of the recorded actions
+    pub fn base_text(&self) -> &str {
+        &self.text.sources[0]
+    }
+}
+
+// a journal entry that is ready to be written
+pub struct JournalSnapshot {
+    jpath: PathBuf,
+    data: Vec<u8>
+}
+
+impl JournalSnapshot {
+    pub fn write(self) -> Result<(), Error> {
+        if let Some(dir) = self.jpath.parent() {
+            std::fs::create_dir_all(dir).map_err(Error::from_other)?;
+        }
+        // write then rename, so that a crash while writing doesn't destroy the previous journal
+        let mut tmp_name = std::ffi::OsString::from(".");
+        tmp_name.push(self.jpath.file_name().unwrap_or_default());
+        let tmp_path = self.jpath.with_file_name(tmp_name);
+        std::fs::write(&tmp_path, self.data).map_err(Error::from_other)?;
+        std::fs::rename(&tmp_path, &self.jpath).map_err(Error::from_other)
+    }
+}
+
+// one journal file per buffer in the recovery directory, written as the buffer is edited and removed
+// once the server has acknowledged the edits
+pub struct RecoveryJournal {
+    dir: Option<PathBuf>
+}
+
+impl Default for RecoveryJournal {
+    fn default() -> RecoveryJournal {
+        RecoveryJournal { dir: None }
+    }
+}
+
+impl RecoveryJournal {
+    pub fn new(dir: PathBuf) -> RecoveryJournal {
+        RecoveryJournal { dir: Some(dir) }
+    }
+
+    fn journal_path(&self, server_name: &str, path: &Path) -> Option<PathBuf> {
+        let name: String = format!("{}%{}", server_name, path.to_string_lossy()).chars()
+            .map(|c| if c == '/' || c == '\\' || c == ':' { '%' } else { c })
+            .collect();
+        self.dir.as_ref().map(|d| d.join(name))
+    }
+
+    // the journal for a buffer as it is now, to be written once the buffer isn't locked anymore
+    pub fn snapshot(&self, b: &Buffer) -> Result<Option<JournalSnapshot>, Error> {
+        let jpath = match self.journal_path(&b.server_name, &b.path) {
+            Some(p) => p,
+            None => return Ok(None)
+        };
+        // serialize by hand so that the piece table doesn't need to be cloned
+        #[derive(Serialize)]
+        struct Entry<'b> { server_name: &'b str, path: &'b Path, base_version: usize, text: &'b PieceTable }
+        let data = serde_cbor::to_vec(&Entry {
+            server_name: &b.server_name, path: &b.path, base_version: b.version, text: &b.text
+        }).map_err(Error::from_other)?;
+        Ok(Some(JournalSnapshot { jpath, data }))
+    }
+
+    pub fn load(&self, server_name: &str, path: &Path) -> Result<Option<RecoveredBuffer>, Error> {
+        let jpath = match self.journal_path(server_name, path) {
+            Some(p) => p,
+            None => return Ok(None)
+        };
+        match std::fs::read(&jpath) {
+            Ok(data) => serde_cbor::from_slice(&data).map(Some).map_err(Error::from_other),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(Error::from_other(e))
+        }
+    }
+
+    pub fn remove(&self, server_name: &str, path: &Path) -> Result<(), Error> {
+        if let Some(jpath) = self.journal_path(server_name, path) {
+            match std::fs::remove_file(&jpath) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(Error::from_other(
+                    std::io::Error::new(e.kind(), format!("removing recovery journal {}: {}", jpath.to_string_lossy(), e)))),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    // all buffers that have journals left over, presumably from a previous run that didn't exit cleanly
+    pub fn list(&self) -> Vec<RecoveredBuffer> {
+        let dir = match self.dir.as_ref().and_then(|d| std::fs::read_dir(d).ok()) {
+            Some(d) => d,
+            None => return Vec::new()
+        };
+        dir.filter_map(|e| e.ok())
+            .filter(|e| !e.file_name().to_string_lossy().starts_with('.'))
+            .filter_map(|e| std::fs::read(e.path()).ok())
+            .filter_map(|data| serde_cbor::from_slice(&data).ok())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn write_load_remove() {
+        let dir = std::env::temp_dir().join(format!("pk-recovery-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let journal = RecoveryJournal::new(dir.clone());
+        let mut b = Buffer::with_text("base\n");
+        b.server_name = "local".into();
+        b.path = "/a/b.txt".into();
+        b.version = 3;
+        b.text.insert_range("edited ", 0);
+        journal.snapshot(&b).unwrap().unwrap().write().unwrap();
+
+        let r = journal.load("local", Path::new("/a/b.txt")).unwrap().unwrap();
+        assert_eq!((r.server_name.as_str(), r.base_version), ("local", 3));
+        assert_eq!(r.base_text(), "base\n");
+        assert_eq!(r.text.text(), "edited base\n");
+        assert!(journal.load("other", Path::new("/a/b.txt")).unwrap().is_none());
+        assert_eq!(journal.list().len(), 1);
+
+        journal.remove("local", Path::new("/a/b.txt")).unwrap();
+        assert!(journal.load("local", Path::new("/a/b.txt")).unwrap().is_none());
+        assert!(journal.list().is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn no_directory() {
+        let journal = RecoveryJournal::default();
+        assert!(journal.snapshot(&Buffer::with_text("x")).unwrap().is_none());
+        assert!(journal.load("local", Path::new("/x")).unwrap().is_none());
+        assert!(journal.list().is_empty());
+    }
+}
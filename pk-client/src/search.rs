@@ -0,0 +1,159 @@
+use pk_common::piece_table::PieceTable;
+use pk_common::Direction;
+use std::ops::Range;
+
+// whether a pattern has an uppercase letter that stands for itself, leaving out escapes like `\W`, `\pL`,
+// `\p{Lu}` and `\x7F` where uppercase letters mean something else
+fn has_uppercase(pattern: &str) -> bool {
+    let mut chars = pattern.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            if c.is_uppercase() { return true; }
+            continue;
+        }
+        let digits = match chars.next() {
+            Some('p') | Some('P') => 1,
+            Some('x') => 2,
+            Some('u') => 4,
+            Some('U') => 8,
+            _ => 0
+        };
+        if digits == 0 { continue; }
+        if chars.peek() == Some(&'{') {
+            for c in &mut chars {
+                if c == '}' { break; }
+            }
+        } else {
+            for _ in 0..digits { chars.next(); }
+        }
+    }
+    false
+}
+
+// a compiled search pattern. Searches are case insensitive unless the pattern has an uppercase letter in it
+// outside of an escape like `\S`
+pub struct Query {
+    pub pattern: String,
+    regex: regex::Regex
+}
+
+impl Query {
+    pub fn new(pattern: &str) -> Result<Query, regex::Error> {
+        Query::with_case(pattern, !has_uppercase(pattern))
+    }
+
+    pub fn with_case(pattern: &str, ignore_case: bool) -> Result<Query, regex::Error> {
+        let regex = regex::RegexBuilder::new(pattern)
+            .case_insensitive(ignore_case)
+            .build()?;
+        Ok(Query { pattern: pattern.into(), regex })
+    }
+
+    pub fn regex(&self) -> &regex::Regex {
+        &self.regex
+    }
+
+    // calls `f` with every match that starts in `start..end`, in order, until it returns false. Matches
+    // never span lines, so the table is searched a line at a time as its characters come out of it
+    // instead of copying the whole text for every search
+    pub fn for_each_match<F: FnMut(Range<usize>) -> bool>(&self, table: &PieceTable, start: usize, end: usize, mut f: F) {
+        let end = end.min(table.len());
+        let mut line_start = table.last_index_of('\n', start).map(|i| i+1).unwrap_or(0);
+        if line_start >= end { return; }
+        let mut chars = table.chars(line_start);
+        let mut line = String::new();
+        loop {
+            line.clear();
+            let mut more_lines = false;
+            for c in &mut chars {
+                if c == '\n' { more_lines = true; break; }
+                line.push(c);
+            }
+            for m in self.regex.find_iter(&line) {
+                let m = line_start + m.start() .. line_start + m.end();
+                if m.start >= end { return; }
+                if m.start >= start && !f(m) { return; }
+            }
+            line_start += line.len() + 1;
+            if !more_lines || line_start >= end { return; }
+        }
+    }
+
+    pub fn matches(&self, table: &PieceTable, start: usize, end: usize) -> Vec<Range<usize>> {
+        let mut matches = Vec::new();
+        self.for_each_match(table, start, end, |m| { matches.push(m); true });
+        matches
+    }
+
+    // the first match starting at or after `from`, or the last one starting before it, going around the
+    // end of the text if `wrap` is set
+    pub fn next_match(&self, table: &PieceTable, from: usize, direction: Direction, wrap: bool) -> Option<Range<usize>> {
+        let mut found = None;
+        match direction {
+            Direction::Forward => {
+                self.for_each_match(table, from, table.len(), |m| { found = Some(m); false });
+                if found.is_none() && wrap {
+                    self.for_each_match(table, 0, from, |m| { found = Some(m); false });
+                }
+            },
+            Direction::Backward => {
+                // lines only come out of the table going forwards, so keep the last match seen
+                self.for_each_match(table, 0, from, |m| { found = Some(m); true });
+                if found.is_none() && wrap {
+                    self.for_each_match(table, from, table.len(), |m| { found = Some(m); true });
+                }
+            }
+        }
+        found
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn smart_case() {
+        let table = PieceTable::with_text("foo Foo FOO\n");
+        let count = |pattern: &str| Query::new(pattern).unwrap().matches(&table, 0, table.len()).len();
+        assert_eq!(count("foo"), 3);
+        assert_eq!(count("Foo"), 1);
+        // escapes with uppercase letters in them don't make the search case sensitive
+        assert_eq!(count(r"\Sfoo|foo\B"), 0);
+        assert_eq!(count(r"\bfoo\b"), 3);
+        assert_eq!(count(r"\bfoo\W"), 2);
+        assert_eq!(count(r"\x46oo"), 3);
+        assert_eq!(count(r"\x{46}OO"), 1);
+        assert!(!has_uppercase(r"\p{Lu}oo"));
+        assert_eq!(count(r"\pLoo"), 3);
+        assert!(!has_uppercase(r"a\Sb\Wc\Bd\D\\"));
+        assert!(has_uppercase(r"\\A"));
+        assert!(has_uppercase(r"\u00E9X"));
+    }
+
+    #[test]
+    fn matches_by_line() {
+        let table = PieceTable::with_text("ab\nxab ab\n\nab");
+        let q = Query::new("ab").unwrap();
+        assert_eq!(q.matches(&table, 0, table.len()), vec![0..2, 4..6, 7..9, 11..13]);
+        // only matches starting in the range count, but they can go past its end
+        assert_eq!(q.matches(&table, 5, 8), vec![7..9]);
+        assert_eq!(q.matches(&table, 4, 100), vec![4..6, 7..9, 11..13]);
+        // a match can't span lines
+        assert!(Query::new(r"b\nx").unwrap().matches(&table, 0, table.len()).is_empty());
+        assert_eq!(Query::new("^ab$").unwrap().matches(&table, 0, table.len()), vec![0..2, 11..13]);
+    }
+
+    #[test]
+    fn next_match() {
+        let table = PieceTable::with_text("one two\none\n");
+        let q = Query::new("one").unwrap();
+        assert_eq!(q.next_match(&table, 0, Direction::Forward, false), Some(0..3));
+        assert_eq!(q.next_match(&table, 1, Direction::Forward, false), Some(8..11));
+        assert_eq!(q.next_match(&table, 9, Direction::Forward, false), None);
+        assert_eq!(q.next_match(&table, 9, Direction::Forward, true), Some(0..3));
+        assert_eq!(q.next_match(&table, 8, Direction::Backward, false), Some(0..3));
+        assert_eq!(q.next_match(&table, 0, Direction::Backward, false), None);
+        assert_eq!(q.next_match(&table, 0, Direction::Backward, true), Some(8..11));
+    }
+}
@@ -1,5 +1,6 @@
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
 use futures::prelude::*;
 use pk_common::protocol;
 use super::Error;
@@ -31,19 +32,41 @@ impl Future for FutureResponse {
     }
 }
 
+// how long to wait for a server to respond before deciding that it is unreachable
+const REQUEST_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
 pub struct Server {
     socket: nng::Socket,
     responses: Arc<Mutex<HashMap<protocol::MessageId, protocol::Response>>>,
     wakers: Arc<Mutex<HashMap<protocol::MessageId, futures::task::Waker>>>,
     next_msg_id: protocol::MessageId,
-    thread_pool: futures::executor::ThreadPool
+    thread_pool: futures::executor::ThreadPool,
+    connected: Arc<AtomicBool>,
+    client_id: protocol::ClientId
 }
 
 impl Server {
-    fn process(aio: &nng::Aio, cx: &nng::Context,
+    // if a request fails in transport, complete it with an error so that whoever is waiting on it
+    // finds out, and remember that the server is currently unreachable
+    fn fail_request(msg_id: protocol::MessageId, e: nng::Error,
+               responses: &Arc<Mutex<HashMap<protocol::MessageId, protocol::Response>>>,
+               wakers: &Arc<Mutex<HashMap<protocol::MessageId, futures::task::Waker>>>,
+               connected: &Arc<AtomicBool>) {
+        connected.store(false, Ordering::SeqCst);
+        {
+            let mut r = responses.lock().unwrap();
+            r.insert(msg_id, protocol::Response::Error { message: format!("server unreachable: {}", e) });
+        }
+        if let Some(w) = wakers.lock().unwrap().remove(&msg_id) {
+            w.wake();
+        }
+    }
+
+    fn process(aio: &nng::Aio, cx: &nng::Context, msg_id: protocol::MessageId,
                responses: &Arc<Mutex<HashMap<protocol::MessageId, protocol::Response>>>,
                wakers: &Arc<Mutex<HashMap<protocol::MessageId, futures::task::Waker>>>,
                thread_pool: &futures::executor::ThreadPool,
+               connected: &Arc<AtomicBool>,
                res: nng::AioResult) {
         use nng::AioResult;
         // println!("process {:?}", res);
@@ -58,6 +81,7 @@ impl Server {
             AioResult::Recv(Ok(m)) => {
                 let responses = responses.clone();
                 let wakers = wakers.clone();
+                let connected = connected.clone();
                 // run this on the thread pool to escape the NNG worker thread's small stack.
                 // Unneccessary on optimized builds, but it's probably good to keep as much
                 // computation out of the Aio handler as possible
@@ -66,9 +90,21 @@ impl Server {
                         Ok(r) => r,
                         Err(e) => { println!("error decoding response: {}", e); return; }
                     };
+                    // a server that is shutting down is as good as gone, so that edits get queued
+                    // until it comes back
+                    let msg = match resp.msg {
+                        protocol::Response::ShuttingDown => {
+                            connected.store(false, Ordering::SeqCst);
+                            protocol::Response::Error { message: String::from("server unreachable: shutting down") }
+                        },
+                        msg => {
+                            connected.store(true, Ordering::SeqCst);
+                            msg
+                        }
+                    };
                     {
                         let mut r = responses.lock().unwrap();
-                        r.insert(resp.req_id, resp.msg);
+                        r.insert(resp.req_id, msg);
                     }
                     {
                         let mut w = wakers.lock().unwrap();
@@ -81,38 +117,88 @@ impl Server {
             },
             AioResult::Send(Err((_,e)))  => {
                 println!("error in nng AIO, send! {:?}", e);
+                Server::fail_request(msg_id, e, responses, wakers, connected);
             }
             AioResult::Recv(Err(e)) => {
                 println!("error in nng AIO, recv! {:?}", e);
+                Server::fail_request(msg_id, e, responses, wakers, connected);
             },
             _ => panic!("unexpected AioResult")
         }
     }
 
-    pub fn init(url: &str, thread_pool: futures::executor::ThreadPool) -> Result<Server, Error> {
+    pub fn init(url: &str, thread_pool: futures::executor::ThreadPool, client_id: protocol::ClientId) -> Result<Server, Error> {
         let socket = nng::Socket::new(nng::Protocol::Req0).map_err(Error::from_other)?;
 
         let responses = Arc::new(Mutex::new(HashMap::new()));
         let wakers = Arc::new(Mutex::new(HashMap::new()));
 
+        // nng redials on its own after a disconnect, so watching the pipes is enough to know when
+        // the server comes back
+        let connected = Arc::new(AtomicBool::new(false));
+        let conn = connected.clone();
+        socket.pipe_notify(move |_, ev| match ev {
+            nng::PipeEvent::AddPost => conn.store(true, Ordering::SeqCst),
+            nng::PipeEvent::RemovePost => conn.store(false, Ordering::SeqCst),
+            _ => {}
+        }).map_err(Error::from_other)?;
+
         socket.dial(url).map_err(Error::from_other)?;
+        connected.store(true, Ordering::SeqCst);
 
         Ok(Server {
             responses, wakers, socket, next_msg_id: protocol::MessageId(1),
-            thread_pool
+            thread_pool, connected, client_id
         })
     }
 
+    // start a pk-server in the background listening on `url`, returning the executable that was started.
+    // It keeps running after this client exits, until it has been idle for a while
+    pub fn spawn_local(url: &str, config: &crate::config::LocalServerConfig) -> Result<std::path::PathBuf, Error> {
+        use std::process::{Command, Stdio};
+        use std::path::PathBuf;
+        let exe = config.executable.as_ref().map(PathBuf::from).unwrap_or_else(|| {
+            std::env::current_exe().ok()
+                .and_then(|p| p.parent().map(|d| d.join(format!("pk-server{}", std::env::consts::EXE_SUFFIX))))
+                .filter(|p| p.exists())
+                .unwrap_or_else(|| PathBuf::from("pk-server"))
+        });
+        let mut cmd = Command::new(&exe);
+        cmd.arg("--idle-exit").arg(config.idle_exit.to_string()).arg(url)
+            .stdin(Stdio::null()).stdout(Stdio::null()).stderr(Stdio::null());
+        // detach from the client's terminal so that things like ^C don't take the server down too
+        #[cfg(unix)] {
+            use std::os::unix::process::CommandExt;
+            cmd.process_group(0);
+        }
+        #[cfg(windows)] {
+            use std::os::windows::process::CommandExt;
+            const DETACHED_PROCESS: u32 = 0x00000008;
+            const CREATE_NEW_PROCESS_GROUP: u32 = 0x00000200;
+            cmd.creation_flags(DETACHED_PROCESS | CREATE_NEW_PROCESS_GROUP);
+        }
+        let mut child = cmd.spawn().map_err(Error::from_other)?;
+        // reap the server if it exits while this client is still running
+        std::thread::spawn(move || { let _ = child.wait(); });
+        Ok(exe)
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::SeqCst)
+    }
+
     pub fn request(&mut self, msg: protocol::Request) -> impl Future<Output=protocol::Response> {
         let mut wmsg = nng::Message::new().unwrap();
         let msg_id = self.next_msg_id;
-        serde_cbor::to_writer(&mut wmsg, &protocol::MsgRequest { msg_id, msg }).unwrap();
+        serde_cbor::to_writer(&mut wmsg, &protocol::MsgRequest { msg_id, client: self.client_id, msg }).unwrap();
         let cx = nng::Context::new(&self.socket).map_err(Error::from_other).unwrap();
         let context = cx.clone();
         let resp = self.responses.clone();
         let waks = self.wakers.clone();
         let tp = self.thread_pool.clone();
-        let aio = nng::Aio::new(move |aio, res| Server::process(&aio, &cx, &resp, &waks, &tp, res)).unwrap();
+        let conn = self.connected.clone();
+        let aio = nng::Aio::new(move |aio, res| Server::process(&aio, &cx, msg_id, &resp, &waks, &tp, &conn, res)).unwrap();
+        aio.set_timeout(Some(REQUEST_TIMEOUT)).unwrap();
         context.send(&aio, wmsg).unwrap();
         self.next_msg_id = protocol::MessageId(self.next_msg_id.0 + 1);
         FutureResponse { msg_id, responses: self.responses.clone(), wakers: self.wakers.clone(), aio }
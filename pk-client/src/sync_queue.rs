@@ -0,0 +1,167 @@
+use serde::{Serialize, Deserialize};
+use std::path::{Path, PathBuf};
+use super::Error;
+
+// a version of a buffer that has been sent to (or is waiting to be sent to) a server but hasn't
+// been acknowledged yet
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PendingSync {
+    pub server_name: String,
+    pub path: PathBuf,
+    // the last version of the file that the server acknowledged
+    pub base_version: usize,
+    pub text: String,
+    #[serde(skip)]
+    pub in_flight: bool
+}
+
+// queue of unacknowledged buffer versions. If a server becomes unreachable, the queue is written
+// out to disk so that the edits survive until the server comes back, even if the client exits
+pub struct SyncQueue {
+    location: Option<PathBuf>,
+    pending: Vec<PendingSync>
+}
+
+impl Default for SyncQueue {
+    fn default() -> SyncQueue {
+        SyncQueue { location: None, pending: Vec::new() }
+    }
+}
+
+impl SyncQueue {
+    pub fn load(location: PathBuf) -> Result<SyncQueue, Error> {
+        let pending = match std::fs::read(&location) {
+            Ok(data) => serde_cbor::from_slice(&data).map_err(Error::from_other)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(Error::from_other(e))
+        };
+        Ok(SyncQueue { location: Some(location), pending })
+    }
+
+    pub fn get(&self, server_name: &str, path: &Path) -> Option<&PendingSync> {
+        self.pending.iter().find(|p| p.server_name == server_name && p.path == path)
+    }
+
+    pub fn get_mut(&mut self, server_name: &str, path: &Path) -> Option<&mut PendingSync> {
+        self.pending.iter_mut().find(|p| p.server_name == server_name && p.path == path)
+    }
+
+    // since syncs always send the whole text, only the newest version of a file needs to be kept
+    pub fn push(&mut self, p: PendingSync) {
+        match self.get_mut(&p.server_name, &p.path) {
+            Some(existing) => *existing = p,
+            None => self.pending.push(p)
+        }
+    }
+
+    // the queue on disk is updated too if there is one, which is the only way this can fail
+    pub fn remove(&mut self, server_name: &str, path: &Path) -> Result<Option<PendingSync>, Error> {
+        let ix = match self.pending.iter().position(|p| p.server_name == server_name && p.path == path) {
+            Some(ix) => ix,
+            None => return Ok(None)
+        };
+        let p = self.pending.remove(ix);
+        if self.location.as_ref().map_or(false, |l| l.exists()) {
+            self.persist()?;
+        }
+        Ok(Some(p))
+    }
+
+    // marks the versions of files on a server that aren't open in a buffer and aren't already being sent
+    // as in flight, and returns them so they can be sent without waiting for the file to be opened
+    pub fn start_replay(&mut self, server_name: &str, is_open: impl Fn(&Path) -> bool) -> Vec<PendingSync> {
+        self.pending.iter_mut()
+            .filter(|p| p.server_name == server_name && !p.in_flight && !is_open(&p.path))
+            .map(|p| { p.in_flight = true; p.clone() })
+            .collect()
+    }
+
+    // a version that was sent didn't make it, so it can be tried again
+    pub fn failed(&mut self, server_name: &str, path: &Path) {
+        if let Some(p) = self.get_mut(server_name, path) {
+            p.in_flight = false;
+        }
+    }
+
+    pub fn pending_for<'s>(&'s self, server_name: &'s str) -> impl Iterator<Item=&'s PendingSync> + 's {
+        self.pending.iter().filter(move |p| p.server_name == server_name)
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn persist(&self) -> Result<(), Error> {
+        let location = match self.location.as_ref() {
+            Some(l) => l,
+            None => return Ok(())
+        };
+        if self.pending.len() == 0 {
+            return match std::fs::remove_file(location) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(Error::from_other(e)),
+                _ => Ok(())
+            };
+        }
+        if let Some(dir) = location.parent() {
+            std::fs::create_dir_all(dir).map_err(Error::from_other)?;
+        }
+        let data = serde_cbor::to_vec(&self.pending).map_err(Error::from_other)?;
+        std::fs::write(location, data).map_err(Error::from_other)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn pending(server_name: &str, path: &str, base_version: usize, text: &str) -> PendingSync {
+        PendingSync {
+            server_name: server_name.into(), path: path.into(), base_version, text: text.into(), in_flight: false
+        }
+    }
+
+    #[test]
+    fn push_coalesces() {
+        let mut q = SyncQueue::default();
+        q.push(pending("a", "/x", 1, "one"));
+        q.push(pending("b", "/x", 1, "other server"));
+        q.push(pending("a", "/x", 1, "two"));
+        assert_eq!(q.len(), 2);
+        assert_eq!(q.get("a", Path::new("/x")).unwrap().text, "two");
+        assert_eq!(q.pending_for("b").count(), 1);
+        assert_eq!(q.remove("a", Path::new("/x")).unwrap().unwrap().text, "two");
+        assert!(q.get("a", Path::new("/x")).is_none());
+    }
+
+    #[test]
+    fn replay() {
+        let mut q = SyncQueue::default();
+        q.push(pending("a", "/open", 1, "open"));
+        q.push(pending("a", "/closed", 3, "closed"));
+        q.push(pending("b", "/elsewhere", 1, "elsewhere"));
+        let replayed = q.start_replay("a", |p| p == Path::new("/open"));
+        assert_eq!(replayed.len(), 1);
+        assert_eq!(replayed[0].path, Path::new("/closed"));
+        assert!(q.get("a", Path::new("/closed")).unwrap().in_flight);
+        assert!(q.start_replay("a", |_| false).iter().all(|p| p.path == Path::new("/open")), "not sent twice");
+        q.failed("a", Path::new("/closed"));
+        assert_eq!(q.start_replay("a", |_| false).len(), 1, "retried after failing");
+    }
+
+    #[test]
+    fn persist_and_load() {
+        let location = std::env::temp_dir().join(format!("pk-sync-queue-test-{}", std::process::id()));
+        let mut q = SyncQueue::load(location.clone()).unwrap();
+        assert_eq!(q.len(), 0);
+        q.push(pending("a", "/x", 2, "text"));
+        q.persist().unwrap();
+        let mut loaded = SyncQueue::load(location.clone()).unwrap();
+        let p = loaded.get("a", Path::new("/x")).unwrap();
+        assert_eq!((p.base_version, p.text.as_str(), p.in_flight), (2, "text", false));
+        assert!(loaded.remove("a", Path::new("/x")).unwrap().is_some());
+        assert!(!location.exists(), "an empty queue removes the file");
+        std::fs::write(&location, b"not a queue").unwrap();
+        assert!(SyncQueue::load(location.clone()).is_err());
+        std::fs::remove_file(&location).unwrap();
+    }
+}
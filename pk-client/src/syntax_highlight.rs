@@ -487,9 +487,83 @@ impl<'a> Highlighter<'a> {
 }
 }
 
+// the kinds of syntax that text objects can select, found from the scopes the parser gives the text
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SyntaxKind {
+    Function, Class, Comment, String
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyntaxSpan {
+    pub kind: SyntaxKind,
+    pub range: Range<usize>,
+    // the range without delimiters like quotes or `//`, for comments and strings
+    pub inner: Range<usize>
+}
+
+// what the parser found out about the structure of a buffer's text
+#[derive(Debug, Default)]
+pub struct Syntax {
+    // ordered by start
+    pub spans: Vec<SyntaxSpan>,
+    // every string and comment, where brackets don't count, in order without overlapping
+    literals: Vec<Range<usize>>
+}
+
+impl Syntax {
+    // the string or comment that contains `index`
+    pub fn literal_at(&self, index: usize) -> Option<Range<usize>> {
+        let i = self.literals.partition_point(|r| r.start <= index);
+        self.literals[..i].last().filter(|r| r.contains(&index)).cloned()
+    }
+
+    // the spans of some kind that contain `index`, innermost first
+    pub fn spans_around(&self, kind: SyntaxKind, index: usize) -> impl Iterator<Item=&SyntaxSpan> {
+        let i = self.spans.partition_point(|s| s.range.start <= index);
+        self.spans[..i].iter().rev().filter(move |s| s.kind == kind && s.range.contains(&index))
+    }
+}
+
+// the scope names that each kind of syntax has, which work across most of the bundled syntaxes
+struct SyntaxScopes {
+    comment: Scope, string: Scope,
+    functions: Vec<Scope>,
+    classes: Vec<Scope>,
+    delimiters: Vec<Scope>
+}
+
+impl SyntaxScopes {
+    fn new() -> SyntaxScopes {
+        let scopes = |names: &[&str]| names.iter().map(|n| Scope::new(n).unwrap()).collect();
+        SyntaxScopes {
+            comment: Scope::new("comment").unwrap(),
+            string: Scope::new("string").unwrap(),
+            functions: scopes(&["meta.function", "meta.method"]),
+            classes: scopes(&["meta.class", "meta.struct", "meta.enum", "meta.union", "meta.impl", "meta.trait", "meta.interface"]),
+            delimiters: scopes(&["punctuation.definition.comment", "punctuation.definition.string"])
+        }
+    }
+
+    fn kind(&self, scope: Scope) -> Option<SyntaxKind> {
+        let any = |scopes: &[Scope]| scopes.iter().any(|s| s.is_prefix_of(scope));
+        if self.comment.is_prefix_of(scope) {
+            Some(SyntaxKind::Comment)
+        } else if self.string.is_prefix_of(scope) {
+            Some(SyntaxKind::String)
+        } else if any(&self.functions) {
+            Some(SyntaxKind::Function)
+        } else if any(&self.classes) {
+            Some(SyntaxKind::Class)
+        } else {
+            None
+        }
+    }
+}
+
 pub struct Highlighter {
     synset: SyntaxSet,
-    color_sel: syntect_highlighter::Theme
+    color_sel: syntect_highlighter::Theme,
+    scopes: SyntaxScopes
 }
 
 impl Highlighter {
@@ -506,11 +580,12 @@ impl Highlighter {
                                     style: syntect_highlighter::StyleModifier::fg(
                                         ColorschemeSel::from_toml(rule.get("style").unwrap()).unwrap())
                                 }).collect())).unwrap_or_else(Vec::new)
-            }
+            },
+            scopes: SyntaxScopes::new()
         }
     }
 
-    pub fn compute_highlighting(&self, buf: &buffer::Buffer) -> Vec<Highlight> {
+    pub fn compute_highlighting(&self, buf: &buffer::Buffer) -> (Vec<Highlight>, Syntax) {
         // dbg!(&self.color_sel);
         let mut parser = ParseState::new(buf.path.extension().and_then(|s| s.to_str())
             .and_then(|ext| self.synset.find_syntax_by_extension(ext))
@@ -518,19 +593,75 @@ impl Highlighter {
         let mut hi = Vec::new();
         let hl = syntect_highlighter::Highlighter::new(&self.color_sel);
         let mut hlstate = syntect_highlighter::HighlightState::new(&hl, ScopeStack::new());
+        let mut syntax = Syntax::default();
+        // the scopes that are open, with where each one started
+        let mut path = ScopeStack::new();
+        let mut open: Vec<(Scope, usize)> = Vec::new();
+        let mut delimiters: Vec<Range<usize>> = Vec::new();
         let mut gi = 0;
         //let tx = buf.text.text();
         //println!("highlighting text:\n{}", tx);
         for (ops, ln) in buf.text.text().lines()
             .map(|ln| (parser.parse_line(ln, &self.synset), ln))
         {
+            for (i, op) in ops.iter() {
+                let at = gi + i;
+                path.apply_with_hook(op, |op, _| match op {
+                    BasicScopeStackOp::Push(scope) => open.push((scope, at)),
+                    BasicScopeStackOp::Pop => if let Some((scope, start)) = open.pop() {
+                        let range = start..at;
+                        if self.scopes.delimiters.iter().any(|d| d.is_prefix_of(scope)) {
+                            delimiters.push(range);
+                        } else if let Some(kind) = self.scopes.kind(scope) {
+                            // only the outermost function or class is kept, since parts of one like its
+                            // parameters have scopes that start the same way
+                            let nested = (kind == SyntaxKind::Function || kind == SyntaxKind::Class)
+                                && open.iter().any(|(s, _)| self.scopes.kind(*s) == Some(kind));
+                            if range.is_empty() || nested { return; }
+                            let mut inner = range.clone();
+                            if kind == SyntaxKind::Comment || kind == SyntaxKind::String {
+                                for d in delimiters.iter().rev().take_while(|d| d.start >= range.start) {
+                                    if d.start == range.start && d.end <= inner.end { inner.start = d.end; }
+                                    if d.end == range.end && d.start >= inner.start { inner.end = d.start; }
+                                }
+                                // anything found inside a string or comment is part of it
+                                while syntax.literals.last().map_or(false, |l| l.start >= range.start) { syntax.literals.pop(); }
+                                syntax.literals.push(range.clone());
+                            }
+                            syntax.spans.push(SyntaxSpan { kind, range, inner });
+                        }
+                    }
+                });
+            }
             hi.extend(syntect_highlighter::RangedHighlightIterator::new(&mut hlstate, &ops[..], ln, &hl)
                 .map(|(style, _, range)| {
                     Highlight::foreground((range.start + gi) .. (range.end + gi), style.foreground)
                 }));
             gi += ln.len() + 1;
         }
-        hi
+        syntax.spans.sort_by_key(|s| (s.range.start, std::cmp::Reverse(s.range.end)));
+        // the parser can scope one function or class in pieces, one after another
+        let mut spans: Vec<SyntaxSpan> = Vec::with_capacity(syntax.spans.len());
+        let (mut function, mut class) = (None, None);
+        for span in syntax.spans.drain(..) {
+            let last = match span.kind {
+                SyntaxKind::Function => &mut function,
+                SyntaxKind::Class => &mut class,
+                _ => { spans.push(span); continue; }
+            };
+            match last.map(|i: usize| &mut spans[i]) {
+                Some(prev) if span.range.start <= prev.range.end => {
+                    prev.range.end = prev.range.end.max(span.range.end);
+                    prev.inner = prev.range.clone();
+                },
+                _ => {
+                    *last = Some(spans.len());
+                    spans.push(span);
+                }
+            }
+        }
+        syntax.spans = spans;
+        (hi, syntax)
     }
 }
 
@@ -0,0 +1,337 @@
+use std::ops::Range;
+use crate::piece_table::PieceTable;
+
+// a run of lines that differ between two versions, as the lines it replaces in the old version and
+// the lines that replace them in the new version. Either range can be empty
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Hunk {
+    pub old: Range<usize>,
+    pub new: Range<usize>
+}
+
+// split text into lines, keeping the line endings so that the lines can be put back together exactly
+pub fn split_lines(s: &str) -> Vec<&str> {
+    s.split_inclusive('\n').collect()
+}
+
+// the smallest set of hunks that turns `old` into `new`, using Myers' algorithm
+pub fn diff<T: PartialEq>(old: &[T], new: &[T]) -> Vec<Hunk> {
+    // most edits are small, so don't make the algorithm look at the lines that obviously match
+    let prefix = old.iter().zip(new.iter()).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..].iter().rev().zip(new[prefix..].iter().rev()).take_while(|(a, b)| a == b).count();
+    let a = &old[prefix..old.len()-suffix];
+    let b = &new[prefix..new.len()-suffix];
+    let (n, m) = (a.len() as isize, b.len() as isize);
+    if n == 0 && m == 0 { return Vec::new(); }
+
+    // v[k] is the furthest x reached on diagonal k = x - y. Each step only touches diagonals -d..=d,
+    // so only that part of v is kept for backtracking
+    let max = n + m;
+    let offset = max + 1;
+    let mut v = vec![0isize; 2 * max as usize + 3];
+    let mut trace: Vec<Vec<isize>> = Vec::new();
+    'search: for d in 0..=max {
+        trace.push(v[(offset-d-1) as usize ..= (offset+d+1) as usize].to_vec());
+        for k in (-d..=d).step_by(2) {
+            let mut x = if k == -d || (k != d && v[(offset+k-1) as usize] < v[(offset+k+1) as usize]) {
+                v[(offset+k+1) as usize]
+            } else {
+                v[(offset+k-1) as usize] + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1; y += 1;
+            }
+            v[(offset+k) as usize] = x;
+            if x >= n && y >= m { break 'search; }
+        }
+    }
+
+    // walk back through the steps to find which lines matched
+    let mut matches = Vec::new();
+    let (mut x, mut y) = (n, m);
+    for (d, tv) in trace.iter().enumerate().rev() {
+        let d = d as isize;
+        let at = |k: isize| tv[(k + d + 1) as usize];
+        let k = x - y;
+        let prev_k = if k == -d || (k != d && at(k-1) < at(k+1)) { k + 1 } else { k - 1 };
+        let prev_x = at(prev_k);
+        let prev_y = prev_x - prev_k;
+        while x > prev_x && y > prev_y {
+            x -= 1; y -= 1;
+            matches.push((x as usize, y as usize));
+        }
+        if d > 0 {
+            x = prev_x; y = prev_y;
+        }
+    }
+    matches.reverse();
+
+    let mut hunks = Vec::new();
+    let (mut ox, mut oy) = (0, 0);
+    for (mx, my) in matches.into_iter().chain(std::iter::once((n as usize, m as usize))) {
+        if mx > ox || my > oy {
+            hunks.push(Hunk { old: prefix+ox .. prefix+mx, new: prefix+oy .. prefix+my });
+        }
+        ox = mx + 1; oy = my + 1;
+    }
+    hunks
+}
+
+// the hunks between the lines of two piece tables
+pub fn diff_tables(old: &PieceTable, new: &PieceTable) -> Vec<Hunk> {
+    let (old, new) = (old.text(), new.text());
+    diff(&split_lines(&old), &split_lines(&new))
+}
+
+// the line in the old version that lines up with `line` in the new version, for scrolling the two
+// side by side. Lines inside a hunk line up with the start of the hunk
+pub fn old_line_for(hunks: &[Hunk], line: usize) -> usize {
+    line_across(hunks, line, |h| (&h.new, &h.old))
+}
+
+// the line in the new version that lines up with `line` in the old version
+pub fn new_line_for(hunks: &[Hunk], line: usize) -> usize {
+    line_across(hunks, line, |h| (&h.old, &h.new))
+}
+
+// `sides` picks the side `line` is on and the side to find the line on
+fn line_across(hunks: &[Hunk], line: usize, sides: impl Fn(&Hunk) -> (&Range<usize>, &Range<usize>)) -> usize {
+    let mut delta = 0isize;
+    for h in hunks {
+        let (from, to) = sides(h);
+        if line < from.start { break; }
+        if line < from.end {
+            return to.start + (line - from.start).min(to.len().saturating_sub(1));
+        }
+        delta = to.end as isize - from.end as isize;
+    }
+    (line as isize + delta).max(0) as usize
+}
+
+// a piece of the result of a three way merge
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum MergeChunk {
+    // text that both sides agree on, or that only one side changed
+    Clean(String),
+    // both sides changed the same part of the base differently
+    Conflict { base: String, ours: String, theirs: String }
+}
+
+// merge the changes made from `base` to `ours` and from `base` to `theirs`, line by line. Changes that
+// don't overlap are both kept; overlapping ones are conflicts unless both sides made the same change
+pub fn merge3(base: &str, ours: &str, theirs: &str) -> Vec<MergeChunk> {
+    let (bl, ol, tl) = (split_lines(base), split_lines(ours), split_lines(theirs));
+    let mut hunks: Vec<(bool, Hunk)> = diff(&bl, &ol).into_iter().map(|h| (true, h))
+        .chain(diff(&bl, &tl).into_iter().map(|h| (false, h)))
+        .collect();
+    hunks.sort_by_key(|(_, h)| (h.old.start, h.old.end));
+
+    let mut chunks = Vec::new();
+    let mut clean = String::new();
+    let mut base_ix = 0;
+    let mut i = 0;
+    while i < hunks.len() {
+        // gather every hunk that touches this region of the base, from either side
+        let start = hunks[i].1.old.start;
+        let mut end = hunks[i].1.old.end;
+        let mut j = i + 1;
+        while j < hunks.len() && hunks[j].1.old.start <= end {
+            end = end.max(hunks[j].1.old.end);
+            j += 1;
+        }
+        let region = &hunks[i..j];
+        clean.extend(bl[base_ix..start].iter().cloned());
+        base_ix = end;
+
+        // what each side has in place of base[start..end]
+        let side_text = |is_ours: bool, lines: &[&str]| -> Option<String> {
+            let hs: Vec<&Hunk> = region.iter().filter(|(s, _)| *s == is_ours).map(|(_, h)| h).collect();
+            let (first, last) = (hs.first()?, hs.last()?);
+            let from = first.new.start - (first.old.start - start);
+            let to = last.new.end + (end - last.old.end);
+            Some(lines[from..to].concat())
+        };
+        match (side_text(true, &ol), side_text(false, &tl)) {
+            (Some(o), Some(t)) if o != t => {
+                if !clean.is_empty() {
+                    chunks.push(MergeChunk::Clean(std::mem::take(&mut clean)));
+                }
+                chunks.push(MergeChunk::Conflict { base: bl[start..end].concat(), ours: o, theirs: t });
+            },
+            (Some(s), _) | (None, Some(s)) => clean.push_str(&s),
+            (None, None) => unreachable!()
+        }
+        i = j;
+    }
+    clean.extend(bl[base_ix..].iter().cloned());
+    if !clean.is_empty() {
+        chunks.push(MergeChunk::Clean(clean));
+    }
+    chunks
+}
+
+pub fn has_conflicts(chunks: &[MergeChunk]) -> bool {
+    chunks.iter().any(|c| matches!(c, MergeChunk::Conflict { .. }))
+}
+
+pub const OURS_MARKER: &str = "<<<<<<<";
+pub const BASE_MARKER: &str = "|||||||";
+pub const SEPARATOR_MARKER: &str = "=======";
+pub const THEIRS_MARKER: &str = ">>>>>>>";
+
+// a conflict written out with markers, as byte ranges into the merged text
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct MarkedConflict {
+    // from the start of the first marker to the end of the last one
+    pub range: Range<usize>,
+    pub ours: Range<usize>,
+    pub base: Range<usize>,
+    pub theirs: Range<usize>
+}
+
+// the merged text, with conflicts written out between diff3 style markers so they can be resolved by
+// hand, and where each of the conflicts ended up
+pub fn with_markers(chunks: &[MergeChunk], ours_label: &str, theirs_label: &str) -> (String, Vec<MarkedConflict>) {
+    // add a section and its marker, and return where the section is
+    fn push_section(out: &mut String, text: &str, marker: String) -> Range<usize> {
+        let start = out.len();
+        out.push_str(text);
+        if !text.is_empty() && !text.ends_with('\n') {
+            out.push('\n');
+        }
+        let end = out.len();
+        out.push_str(&marker);
+        start..end
+    }
+    let mut out = String::new();
+    let mut conflicts = Vec::new();
+    for c in chunks {
+        match c {
+            MergeChunk::Clean(s) => out.push_str(s),
+            MergeChunk::Conflict { base, ours, theirs } => {
+                if !out.is_empty() && !out.ends_with('\n') {
+                    out.push('\n');
+                }
+                let start = out.len();
+                out.push_str(&format!("{} {}\n", OURS_MARKER, ours_label));
+                let ours = push_section(&mut out, ours, format!("{} base\n", BASE_MARKER));
+                let base = push_section(&mut out, base, format!("{}\n", SEPARATOR_MARKER));
+                let theirs = push_section(&mut out, theirs, format!("{} {}\n", THEIRS_MARKER, theirs_label));
+                conflicts.push(MarkedConflict { range: start..out.len(), ours, base, theirs });
+            }
+        }
+    }
+    (out, conflicts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply(old: &[&str], new: &[&str], hunks: &[Hunk]) -> Vec<String> {
+        let mut out = Vec::new();
+        let mut ix = 0;
+        for h in hunks {
+            out.extend(old[ix..h.old.start].iter().map(|s| s.to_string()));
+            out.extend(new[h.new.clone()].iter().map(|s| s.to_string()));
+            ix = h.old.end;
+        }
+        out.extend(old[ix..].iter().map(|s| s.to_string()));
+        out
+    }
+
+    #[test]
+    fn diff_lines() {
+        let old = ["a", "b", "c", "d", "e", "f"];
+        let new = ["a", "x", "c", "d", "f", "g"];
+        let hunks = diff(&old, &new);
+        assert_eq!(hunks, vec![
+            Hunk { old: 1..2, new: 1..2 },
+            Hunk { old: 4..5, new: 4..4 },
+            Hunk { old: 6..6, new: 5..6 }
+        ]);
+        assert_eq!(apply(&old, &new, &hunks), new.iter().map(|s| s.to_string()).collect::<Vec<_>>());
+        assert_eq!(diff(&old, &old), vec![]);
+        assert_eq!(diff(&[], &old), vec![Hunk { old: 0..0, new: 0..6 }]);
+    }
+
+    #[test]
+    fn diff_random() {
+        use rand::prelude::*;
+        let mut rng = rand::thread_rng();
+        for _ in 0..200 {
+            let old: Vec<u8> = (0..rng.gen_range(0, 30)).map(|_| rng.gen_range(0, 4)).collect();
+            let new: Vec<u8> = (0..rng.gen_range(0, 30)).map(|_| rng.gen_range(0, 4)).collect();
+            let hunks = diff(&old, &new);
+            let mut out = Vec::new();
+            let mut ix = 0;
+            for h in hunks.iter() {
+                assert!(!h.old.is_empty() || !h.new.is_empty());
+                out.extend_from_slice(&old[ix..h.old.start]);
+                out.extend_from_slice(&new[h.new.clone()]);
+                ix = h.old.end;
+            }
+            out.extend_from_slice(&old[ix..]);
+            assert_eq!(out, new);
+        }
+    }
+
+    #[test]
+    fn line_up() {
+        let old = ["a", "b", "c", "d", "e", "f"];
+        let new = ["a", "x", "y", "c", "d", "f"];
+        let hunks = diff(&old, &new);
+        let lined_up: Vec<usize> = (0..new.len()).map(|l| old_line_for(&hunks, l)).collect();
+        assert_eq!(lined_up, vec![0, 1, 1, 2, 3, 5]);
+        let lined_up: Vec<usize> = (0..old.len()).map(|l| new_line_for(&hunks, l)).collect();
+        assert_eq!(lined_up, vec![0, 1, 3, 4, 5, 5]);
+    }
+
+    #[test]
+    fn merge_clean() {
+        let base = "one\ntwo\nthree\nfour\n";
+        let ours = "one\n2\nthree\nfour\n";
+        let theirs = "one\ntwo\nthree\nfour\nfive\n";
+        assert_eq!(merge3(base, ours, theirs), vec![MergeChunk::Clean("one\n2\nthree\nfour\nfive\n".into())]);
+        // both sides making the same change isn't a conflict
+        assert_eq!(merge3(base, ours, ours), vec![MergeChunk::Clean(ours.into())]);
+    }
+
+    #[test]
+    fn merge_conflict() {
+        let base = "one\ntwo\nthree\n";
+        let ours = "one\n2\nthree\n";
+        let theirs = "one\nTWO\nthree\n";
+        let chunks = merge3(base, ours, theirs);
+        assert_eq!(chunks, vec![
+            MergeChunk::Clean("one\n".into()),
+            MergeChunk::Conflict { base: "two\n".into(), ours: "2\n".into(), theirs: "TWO\n".into() },
+            MergeChunk::Clean("three\n".into())
+        ]);
+        assert!(has_conflicts(&chunks));
+
+        let (marked, found) = with_markers(&chunks, "local", "server");
+        assert_eq!(marked, "one\n<<<<<<< local\n2\n||||||| base\ntwo\n=======\nTWO\n>>>>>>> server\nthree\n");
+        assert_eq!(found.len(), 1);
+        let c = &found[0];
+        assert_eq!(&marked[c.ours.clone()], "2\n");
+        assert_eq!(&marked[c.base.clone()], "two\n");
+        assert_eq!(&marked[c.theirs.clone()], "TWO\n");
+        assert_eq!(&marked[..c.range.start], "one\n");
+        assert_eq!(&marked[c.range.end..], "three\n");
+    }
+
+    #[test]
+    fn markers_without_final_newline() {
+        let chunks = vec![
+            MergeChunk::Clean("one".into()),
+            MergeChunk::Conflict { base: "".into(), ours: "2".into(), theirs: "TWO".into() }
+        ];
+        let (marked, found) = with_markers(&chunks, "a", "b");
+        assert_eq!(marked, "one\n<<<<<<< a\n2\n||||||| base\n=======\nTWO\n>>>>>>> b\n");
+        let c = &found[0];
+        assert_eq!((&marked[c.ours.clone()], &marked[c.base.clone()], &marked[c.theirs.clone()]), ("2\n", "", "TWO\n"));
+        assert_eq!(c.range, 4..marked.len());
+    }
+}
@@ -0,0 +1,197 @@
+use serde::{Serialize, Deserialize};
+
+#[derive(Serialize, Deserialize, Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub enum TextEncoding {
+    #[default]
+    Utf8,
+    Utf16LE, Utf16BE, Latin1
+}
+
+impl std::fmt::Display for TextEncoding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", match self {
+            TextEncoding::Utf8 => "utf-8",
+            TextEncoding::Utf16LE => "utf-16le",
+            TextEncoding::Utf16BE => "utf-16be",
+            TextEncoding::Latin1 => "latin-1"
+        })
+    }
+}
+
+impl std::str::FromStr for TextEncoding {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s.to_ascii_lowercase().replace('-', "").as_str() {
+            "utf8" => Ok(TextEncoding::Utf8),
+            "utf16" | "utf16le" => Ok(TextEncoding::Utf16LE),
+            "utf16be" => Ok(TextEncoding::Utf16BE),
+            "latin1" | "iso88591" => Ok(TextEncoding::Latin1),
+            _ => Err(())
+        }
+    }
+}
+
+// a character that can't be represented in the encoding a file is being written in
+#[derive(Debug, PartialEq, Eq)]
+pub struct UnencodableChar(pub char, pub TextEncoding);
+
+impl std::fmt::Display for UnencodableChar {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?} can not be encoded as {}", self.0, self.1)
+    }
+}
+
+impl std::error::Error for UnencodableChar {}
+
+impl TextEncoding {
+    pub fn bom(&self) -> &'static [u8] {
+        match self {
+            TextEncoding::Utf8 => &[0xef, 0xbb, 0xbf],
+            TextEncoding::Utf16LE => &[0xff, 0xfe],
+            TextEncoding::Utf16BE => &[0xfe, 0xff],
+            TextEncoding::Latin1 => &[]
+        }
+    }
+
+    // guess the encoding of `data`, returning the decoded text, the encoding, and whether there was a
+    // byte order mark. Latin-1 is the fallback since any sequence of bytes is valid Latin-1
+    pub fn detect_and_decode(data: &[u8]) -> (String, TextEncoding, bool) {
+        for enc in [TextEncoding::Utf8, TextEncoding::Utf16LE, TextEncoding::Utf16BE].iter() {
+            let bom = enc.bom();
+            if data.starts_with(bom) {
+                if let Some(s) = enc.decode(&data[bom.len()..]) {
+                    return (s, *enc, true);
+                }
+            }
+        }
+        // text that is mostly ASCII encoded as UTF-16 has a zero in every other byte
+        if data.len() >= 2 && data.len().is_multiple_of(2) {
+            let zeros_at = |parity: usize| data.iter().skip(parity).step_by(2).filter(|b| **b == 0).count();
+            let half = data.len() / 2;
+            let guess = if zeros_at(1) > half / 2 && zeros_at(0) == 0 {
+                Some(TextEncoding::Utf16LE)
+            } else if zeros_at(0) > half / 2 && zeros_at(1) == 0 {
+                Some(TextEncoding::Utf16BE)
+            } else {
+                None
+            };
+            if let Some(s) = guess.and_then(|enc| enc.decode(data).map(|s| (s, enc))) {
+                return (s.0, s.1, false);
+            }
+        }
+        match std::str::from_utf8(data) {
+            Ok(s) => (s.to_owned(), TextEncoding::Utf8, false),
+            Err(_) => (TextEncoding::Latin1.decode(data).unwrap(), TextEncoding::Latin1, false)
+        }
+    }
+
+    // decode `data` (without a byte order mark), or None if it isn't valid in this encoding
+    pub fn decode(&self, data: &[u8]) -> Option<String> {
+        match self {
+            TextEncoding::Utf8 => std::str::from_utf8(data).ok().map(String::from),
+            TextEncoding::Utf16LE | TextEncoding::Utf16BE => {
+                if !data.len().is_multiple_of(2) { return None; }
+                let units = data.chunks(2).map(|c| if *self == TextEncoding::Utf16LE {
+                    u16::from_le_bytes([c[0], c[1]])
+                } else {
+                    u16::from_be_bytes([c[0], c[1]])
+                });
+                std::char::decode_utf16(units).collect::<Result<String, _>>().ok()
+            },
+            TextEncoding::Latin1 => Some(data.iter().map(|b| *b as char).collect())
+        }
+    }
+
+    // decode `data`, replacing anything that isn't valid with U+FFFD. For decoding pieces of a
+    // file, where the start or end might be cut off in the middle of a character
+    pub fn decode_lossy(&self, data: &[u8]) -> String {
+        match self {
+            TextEncoding::Utf8 => String::from_utf8_lossy(data).into_owned(),
+            TextEncoding::Utf16LE | TextEncoding::Utf16BE => {
+                let units = data.chunks_exact(2).map(|c| if *self == TextEncoding::Utf16LE {
+                    u16::from_le_bytes([c[0], c[1]])
+                } else {
+                    u16::from_be_bytes([c[0], c[1]])
+                });
+                std::char::decode_utf16(units).map(|c| c.unwrap_or(std::char::REPLACEMENT_CHARACTER)).collect()
+            },
+            TextEncoding::Latin1 => self.decode(data).unwrap()
+        }
+    }
+
+    pub fn encode(&self, s: &str, bom: bool) -> Result<Vec<u8>, UnencodableChar> {
+        let mut out = Vec::with_capacity(s.len() + 3);
+        if bom {
+            out.extend_from_slice(self.bom());
+        }
+        match self {
+            TextEncoding::Utf8 => out.extend_from_slice(s.as_bytes()),
+            TextEncoding::Utf16LE => for u in s.encode_utf16() { out.extend_from_slice(&u.to_le_bytes()) },
+            TextEncoding::Utf16BE => for u in s.encode_utf16() { out.extend_from_slice(&u.to_be_bytes()) },
+            TextEncoding::Latin1 => for c in s.chars() {
+                if (c as u32) > 0xff {
+                    return Err(UnencodableChar(c, *self));
+                }
+                out.push(c as u8);
+            }
+        }
+        Ok(out)
+    }
+}
+
+// binary files have NUL bytes in them, which text almost never does unless it's UTF-16
+pub fn looks_binary(sample: &[u8]) -> bool {
+    if !sample.contains(&0) { return false; }
+    let even = &sample[..sample.len() & !1];
+    !matches!(TextEncoding::detect_and_decode(even).1, TextEncoding::Utf16LE | TextEncoding::Utf16BE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detect_utf8() {
+        assert_eq!(TextEncoding::detect_and_decode("héllo\n".as_bytes()),
+            ("héllo\n".into(), TextEncoding::Utf8, false));
+        assert_eq!(TextEncoding::detect_and_decode(b"\xef\xbb\xbfhi"),
+            ("hi".into(), TextEncoding::Utf8, true));
+    }
+
+    #[test]
+    fn detect_utf16() {
+        assert_eq!(TextEncoding::detect_and_decode(b"\xff\xfeh\x00i\x00"),
+            ("hi".into(), TextEncoding::Utf16LE, true));
+        assert_eq!(TextEncoding::detect_and_decode(b"\xfe\xff\x00h\x00i"),
+            ("hi".into(), TextEncoding::Utf16BE, true));
+        assert_eq!(TextEncoding::detect_and_decode(b"h\x00i\x00\n\x00"),
+            ("hi\n".into(), TextEncoding::Utf16LE, false));
+    }
+
+    #[test]
+    fn detect_latin1() {
+        assert_eq!(TextEncoding::detect_and_decode(b"caf\xe9"),
+            ("café".into(), TextEncoding::Latin1, false));
+    }
+
+    #[test]
+    fn binary() {
+        assert!(looks_binary(b"\x7fELF\x02\x01\x01\x00\x00\x00\x00\x00"));
+        assert!(!looks_binary(b"just some text\n"));
+        assert!(!looks_binary(b"h\x00i\x00\n\x00"));
+    }
+
+    #[test]
+    fn round_trip() {
+        let text = "a ∑ b é\n";
+        for enc in [TextEncoding::Utf8, TextEncoding::Utf16LE, TextEncoding::Utf16BE].iter() {
+            for bom in [false, true].iter() {
+                let data = enc.encode(text, *bom).unwrap();
+                assert_eq!(TextEncoding::detect_and_decode(&data), (text.into(), *enc, *bom));
+            }
+        }
+        assert_eq!(TextEncoding::Latin1.encode(text, false), Err(UnencodableChar('∑', TextEncoding::Latin1)));
+        assert_eq!(TextEncoding::Latin1.encode("é", false), Ok(vec![0xe9]));
+    }
+}
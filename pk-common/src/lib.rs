@@ -14,14 +14,34 @@ impl Direction {
 }
 
 pub mod piece_table;
+pub mod encoding;
+pub mod diff;
+
+// the per-user address that the local server listens on unless told otherwise, so that users on the
+// same machine don't end up sharing a server
+pub fn local_server_url() -> String {
+    let user = std::env::var("USER").or_else(|_| std::env::var("USERNAME")).unwrap_or_else(|_| String::from("default"));
+    if cfg!(windows) {
+        format!("ipc://pk-{}", user)
+    } else {
+        let dir = std::env::var_os("XDG_RUNTIME_DIR").map(std::path::PathBuf::from).unwrap_or_else(std::env::temp_dir);
+        format!("ipc://{}", dir.join(format!("pk-{}.sock", user)).to_string_lossy())
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum VisualKind {
+    Char, Line, Block
+}
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum ModeTag {
-    Normal, Insert, Command, Visual, UserMessage, Search(Direction)
+    Normal, Insert, Command, Visual(VisualKind), UserMessage, Search(Direction), Merge, Substitute
 }
 
 pub mod protocol {
     use serde::{Serialize, Deserialize};
+    pub use crate::encoding::TextEncoding;
 
     #[derive(Serialize, Deserialize, Copy, Clone, PartialEq, Eq, Hash, Debug)]
     pub struct MessageId(pub u64);
@@ -29,6 +49,29 @@ pub mod protocol {
     #[derive(Serialize, Deserialize, Copy, Clone, PartialEq, Eq, Hash, Debug)]
     pub struct FileId(pub u64);
 
+    #[derive(Serialize, Deserialize, Copy, Clone, PartialEq, Eq, Hash, Debug)]
+    pub struct WorkspaceId(pub u64);
+
+    // picked by each client when it starts, so that the server can tell clients apart
+    #[derive(Serialize, Deserialize, Copy, Clone, PartialEq, Eq, Hash, Debug)]
+    pub struct ClientId(pub u64);
+
+    // where another client is in a file
+    #[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+    pub struct RemoteCursor {
+        pub client: ClientId,
+        pub name: String,
+        pub cursor: usize,
+        pub selection: Option<(usize, usize)>
+    }
+
+    // the client that has claimed a file. Nobody else can change the file until it lets go
+    #[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+    pub struct FileLock {
+        pub client: ClientId,
+        pub name: String
+    }
+
     #[derive(Serialize, Deserialize, Copy, Clone, PartialEq, Eq, Debug)]
     pub enum LineEnding {
         LF, CRLF
@@ -61,59 +104,187 @@ pub mod protocol {
     }
     #[derive(Serialize, Deserialize, PartialEq, Eq, Clone)]
     pub struct FileType {
-        data: [u8; 4]
+        name: String
+    }
+
+    impl FileType {
+        pub fn name(&self) -> &str {
+            &self.name
+        }
     }
 
     impl Default for FileType {
         fn default() -> Self {
-            FileType {
-                data: [b't', b'e', b'x', b't']
-            }
+            FileType::from("text")
         }
     }
 
     impl From<&str> for FileType {
         fn from(s: &str) -> Self {
-            assert!(s.len() >= 4);
-            let b = s.as_bytes();
-            FileType { data: [b[0], b[1], b[2], b[3]] }
+            FileType { name: s.to_owned() }
         }
     }
 
     impl std::fmt::Display for FileType {
         fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-            unsafe {
-                write!(f, "{}", std::str::from_utf8_unchecked(&self.data))
-            }
+            write!(f, "{}", self.name)
         }
     }
 
     impl std::fmt::Debug for FileType {
         fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-            unsafe {
-                write!(f, "FileType({})", std::str::from_utf8_unchecked(&self.data))
-            }
+            write!(f, "FileType({})", self.name)
         }
     }
 
+    // editing settings that depend on the type of file. Anything left as None falls back to the
+    // client's configuration
     #[derive(Serialize, Deserialize, Default, Debug, PartialEq, Eq, Clone)]
+    pub struct FileTypeSettings {
+        pub tabstop: Option<usize>,
+        pub soft_tab: Option<bool>,
+        pub line_comment: Option<String>,
+        pub block_comment: Option<(String, String)>
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
     pub struct TextFormat {
         pub line_ending: LineEnding,
-        pub stype: FileType
+        pub stype: FileType,
+        pub encoding: TextEncoding,
+        // whether the file starts with a byte order mark
+        pub bom: bool,
+        // whether to make sure that the file ends with a newline when it is written
+        pub final_newline: bool,
+        pub settings: FileTypeSettings
+    }
+
+    impl Default for TextFormat {
+        fn default() -> Self {
+            TextFormat {
+                line_ending: LineEnding::default(),
+                stype: FileType::default(),
+                encoding: TextEncoding::default(),
+                bom: false,
+                final_newline: true,
+                settings: FileTypeSettings::default()
+            }
+        }
+    }
+
+    impl std::fmt::Display for TextFormat {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "{} {}{} {:?}{}", self.stype, self.encoding, if self.bom { "+bom" } else { "" },
+                self.line_ending, if self.final_newline { "" } else { " noeol" })
+        }
+    }
+
+    // how the contents of a file are presented to the client
+    #[derive(Serialize, Deserialize, Copy, Clone, PartialEq, Eq, Debug)]
+    pub enum FileView {
+        // the text of the file
+        Text,
+        // a hex dump of a binary file
+        Hex
+    }
+
+    // files that are too large to send all at once are sent in pieces. Byte offsets are into the
+    // file on disk
+    #[derive(Serialize, Deserialize, Copy, Clone, PartialEq, Eq, Debug)]
+    pub struct FileWindow {
+        // how far into the file the contents sent so far reach
+        pub end: u64,
+        pub total: u64
+    }
+
+    impl FileWindow {
+        pub fn complete(&self) -> bool {
+            self.end >= self.total
+        }
+    }
+
+    // when the server writes the contents of a file back to disk
+    #[derive(Serialize, Deserialize, Copy, Clone, PartialEq, Eq, Debug)]
+    #[serde(rename_all = "snake_case")]
+    pub enum AutosavePolicy {
+        // write whenever the file has changed, at most once every n seconds
+        Interval(u64),
+        // write once the file hasn't changed for n seconds
+        OnIdle(u64),
+        // only write when explicitly asked to with `Request::WriteFile`, or once nobody has the file open
+        Manual
+    }
+
+    impl Default for AutosavePolicy {
+        fn default() -> Self {
+            AutosavePolicy::Interval(1)
+        }
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct OpenFileStatus {
+        pub id: FileId,
+        pub path: Option<std::path::PathBuf>,
+        pub version: usize,
+        // the version that is on disk
+        pub disk_version: usize,
+        pub autosave: AutosavePolicy,
+        // how long the oldest change that hasn't been written to disk has been waiting
+        pub unsaved_for_ms: Option<u64>,
+        pub lock: Option<FileLock>
+    }
+
+    // what the server is up to, for diagnosing problems
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct ServerStatus {
+        pub uptime_secs: u64,
+        pub open_files: Vec<OpenFileStatus>,
+        pub workspaces: usize,
+        pub connected_clients: usize,
+        // names of the clients that have been heard from recently
+        pub clients: Vec<String>,
+        pub requests: u64,
+        // requests that were answered with an error
+        pub errors: u64,
+        pub write_errors: u64
     }
 
     #[derive(Serialize, Deserialize, Debug)]
     pub enum Request {
+        /* server */
+        ServerStatus,
+        // write every file and exit
+        Shutdown,
+        /* presence */
+        // tell the server what to call this client
+        Hello { name: String },
+        // where this client's cursor is in a file. The response is where everyone else's is
+        UpdatePresence { id: FileId, cursor: usize, selection: Option<(usize, usize)> },
+        // keep other clients from syncing a file until it's unlocked, closed, or this client goes away
+        LockFile { id: FileId },
+        UnlockFile { id: FileId },
+        /* workspaces */
+        OpenWorkspace { root: std::path::PathBuf },
+        // every file in the workspace that isn't ignored, relative to the root
+        ListWorkspaceFiles { id: WorkspaceId },
         /* files */
-        OpenFile { path: std::path::PathBuf },
+        // relative paths are relative to the root of `workspace` if there is one
+        OpenFile { path: std::path::PathBuf, workspace: Option<WorkspaceId> },
         SyncFile { id: FileId, new_text: String, version: usize },
         ReloadFile(FileId),
         CloseFile(FileId),
+        WriteFile { id: FileId },
+        SetTextFormat { id: FileId, format: TextFormat },
+        SetAutosavePolicy { id: FileId, policy: AutosavePolicy },
+        ReadFileWindow { id: FileId, offset: u64, len: u64 },
+        // the text of a file as the server has it, or as it is on disk, for comparing with
+        ReadFileText { id: FileId, from_disk: bool },
     }
 
     #[derive(Serialize, Deserialize, Debug)]
     pub struct MsgRequest {
         pub msg_id: MessageId,
+        pub client: ClientId,
         pub msg: Request
     }
 
@@ -121,17 +292,52 @@ pub mod protocol {
     pub enum Response {
         Ack,
         Error { message: String },
+        // the server is going away and didn't process the request
+        ShuttingDown,
+        ServerStatus(ServerStatus),
+        Presence { id: FileId, cursors: Vec<RemoteCursor>, lock: Option<FileLock> },
+        // another client has the file locked, so the request was refused
+        Locked { id: FileId, by: FileLock },
         VersionConflict {
             id: FileId,
             client_version_recieved: usize,
             server_version: usize,
-            server_text: String
+            server_text: String,
+            // the text the client's changes were made to, if the server still has it, for merging
+            base_text: Option<String>
+        },
+        WorkspaceInfo {
+            id: WorkspaceId,
+            root: std::path::PathBuf,
+            name: String
+        },
+        WorkspaceFiles {
+            id: WorkspaceId,
+            paths: Vec<std::path::PathBuf>
         },
         FileInfo {
             id: FileId,
+            // the absolute path of the file, as resolved by the server
+            path: std::path::PathBuf,
+            // the workspace that the file is in, if any
+            workspace: Option<WorkspaceId>,
             contents: String,
             version: usize,
-            format: TextFormat
+            format: TextFormat,
+            view: FileView,
+            // if the file is only partially loaded. Partially loaded and hex view files are read only
+            window: Option<FileWindow>
+        },
+        FileWindow {
+            id: FileId,
+            contents: String,
+            window: FileWindow
+        },
+        FileText {
+            id: FileId,
+            // the server version the text is from, even if it was read from disk
+            version: usize,
+            contents: String
         },
     }
 
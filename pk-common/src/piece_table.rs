@@ -2,6 +2,8 @@
 #![allow(unused_variables)]
 use serde::{Serialize, Deserialize};
 use super::Direction;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 #[derive(Copy,Clone,Debug, Serialize, Deserialize)]
 pub struct Piece {
@@ -43,6 +45,7 @@ impl Action {
     fn new(pt: &mut PieceTable) -> Action {
         let id = pt.next_action_id;
         pt.next_action_id += 1;
+        pt.undone.clear();
         Action {
             changes: Vec::new(), id
         }
@@ -58,13 +61,31 @@ impl Action {
 }
 
 
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct PieceTable {
     pub sources: Vec<String>,
     pub pieces: Vec<Piece>,
     pub history: Vec<Action>,
-    pub next_action_id: usize
+    pub next_action_id: usize,
+    #[serde(skip)]
+    anchors: HashMap<usize, usize>,
+    // actions that have been undone, most recent last, until something else changes the table
+    #[serde(skip)]
+    undone: Vec<Action>
 }
+
+// a position in a table that moves along as text is inserted or deleted before it, and is gone once the
+// text it is on is deleted, including by undoing the change that inserted it
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Anchor(usize);
+
+// an anchor's id and index, and the source and index in it of the character it's on, or None past the end
+// of the text
+type AnchorSource = (usize, usize, Option<(usize, usize)>);
+
+// anchors are numbered across every table, so an anchor kept past the table it was made in never finds
+// a place in another one
+static NEXT_ANCHOR: AtomicUsize = AtomicUsize::new(0);
 // is it ok to have empty, zero length pieces in the table? for now these algorithms assume that it
 // is, which is a bit janky but oh well. A garbage collection routine could probably be written
 
@@ -75,7 +96,9 @@ impl Default for PieceTable {
 }
 
 pub struct TableMutator {
-    piece_ix: usize, action: Action
+    piece_ix: usize, action: Action,
+    // where the next character goes in the table
+    index: usize
 }
 
 impl TableMutator {
@@ -83,6 +106,8 @@ impl TableMutator {
         pt.pieces[self.piece_ix].length += 1;
         let si = pt.pieces[self.piece_ix].source;
         pt.sources[si].push(c);
+        pt.anchors_inserted(self.index, c.len_utf8());
+        self.index += c.len_utf8();
     }
 
     pub fn pop_char(&mut self, pt: &mut PieceTable) -> bool {
@@ -91,7 +116,10 @@ impl TableMutator {
         }
         pt.pieces[self.piece_ix].length -= 1;
         let si = pt.pieces[self.piece_ix].source;
-        pt.sources[si].pop();
+        if let Some(c) = pt.sources[si].pop() {
+            pt.anchors_deleted(self.index - c.len_utf8(), self.index);
+            self.index -= c.len_utf8();
+        }
         false
     }
     
@@ -99,6 +127,8 @@ impl TableMutator {
         pt.pieces[self.piece_ix].length += s.len();
         let si = pt.pieces[self.piece_ix].source;
         pt.sources[si].push_str(s);
+        pt.anchors_inserted(self.index, s.len());
+        self.index += s.len();
     }
 
     pub fn finish(mut self, pt: &mut PieceTable) {
@@ -227,7 +257,92 @@ impl<'table> PieceTable {
         PieceTable {
             sources: vec![s.to_string()],
             pieces: vec![ Piece { source: 0, start: 0, length: s.len() } ],
-            history: Vec::new(), next_action_id: start_aid 
+            history: Vec::new(), next_action_id: start_aid,
+            anchors: HashMap::new(), undone: Vec::new()
+        }
+    }
+
+    pub fn anchor(&mut self, index: usize) -> Anchor {
+        let a = Anchor(NEXT_ANCHOR.fetch_add(1, Ordering::Relaxed));
+        self.anchors.insert(a.0, index);
+        a
+    }
+
+    // where an anchor is now, or None if its text was deleted
+    pub fn anchor_index(&self, a: Anchor) -> Option<usize> {
+        self.anchors.get(&a.0).cloned()
+    }
+
+    pub fn remove_anchor(&mut self, a: Anchor) {
+        self.anchors.remove(&a.0);
+    }
+
+    // put an anchor somewhere else, even if its text was deleted
+    pub fn move_anchor(&mut self, a: Anchor, index: usize) {
+        self.anchors.insert(a.0, index);
+    }
+
+    // every anchor in `range`, and where it is
+    pub fn anchors_in(&self, range: std::ops::Range<usize>) -> Vec<(Anchor, usize)> {
+        self.anchors.iter().filter(|(_, ix)| range.contains(ix)).map(|(a, ix)| (Anchor(*a), *ix)).collect()
+    }
+
+    // text was inserted at `index`, so anchors at or after it move along
+    fn anchors_inserted(&mut self, index: usize, len: usize) {
+        for a in self.anchors.values_mut() {
+            if *a >= index { *a += len; }
+        }
+    }
+
+    // anchors on deleted text are gone, and the ones after it move back
+    fn anchors_deleted(&mut self, start: usize, end: usize) {
+        self.anchors.retain(|_, a| *a < start || *a >= end);
+        for a in self.anchors.values_mut() {
+            if *a >= end { *a -= end - start; }
+        }
+    }
+
+    // the character each anchor is on, with where the anchor is now
+    fn anchor_sources(&self) -> Vec<AnchorSource> {
+        let mut starts = Vec::with_capacity(self.pieces.len());
+        let mut ix = 0;
+        for p in self.pieces.iter() {
+            starts.push(ix);
+            ix += p.length;
+        }
+        self.anchors.iter().map(|(a, at)| {
+            let i = starts.partition_point(|s| s <= at);
+            let src = (0..i).rev().find(|i| *at < starts[*i] + self.pieces[*i].length)
+                .map(|i| (self.pieces[i].source, self.pieces[i].start + at - starts[i]));
+            (*a, *at, src)
+        }).collect()
+    }
+
+    // find the characters anchors were on after the pieces have been rearranged by undo or redo. Anchors
+    // whose characters aren't in the table anymore are gone
+    fn restore_anchors(&mut self, sources: Vec<AnchorSource>) {
+        let len = self.len();
+        for (a, old, src) in sources {
+            let mut ix = 0;
+            let mut found: Option<usize> = None;
+            if let Some((source, offset)) = src {
+                for p in self.pieces.iter() {
+                    if p.source == source && offset >= p.start && offset < p.start + p.length {
+                        let at = ix + offset - p.start;
+                        // the same text can be in more than one piece, so take the closest
+                        if found.map_or(true, |f| f.abs_diff(old) > at.abs_diff(old)) {
+                            found = Some(at);
+                        }
+                    }
+                    ix += p.length;
+                }
+            } else {
+                found = Some(len);
+            }
+            match found {
+                Some(at) => { self.anchors.insert(a, at); },
+                None => { self.anchors.remove(&a); }
+            }
         }
     }
 
@@ -273,13 +388,43 @@ impl<'table> PieceTable {
 
     pub fn undo(&mut self) {
         if let Some(action) = self.history.pop() {
-            println!("undoing {:?}", action);
+            let anchors = self.anchor_sources();
             for change in action.iter().rev() {
                 self.reverse_change(&change);
             }
+            self.restore_anchors(anchors);
+            self.undone.push(action);
         }
     }
 
+    // make the last change that was undone again. It gets a new action id, since the text is different
+    // from how it was when the id was last the most recent
+    pub fn redo(&mut self) -> bool {
+        match self.undone.pop() {
+            Some(mut action) => {
+                let anchors = self.anchor_sources();
+                for change in action.iter() {
+                    self.enact_change(change);
+                }
+                self.restore_anchors(anchors);
+                action.id = self.next_action_id;
+                self.next_action_id += 1;
+                self.history.push(action);
+                true
+            },
+            None => false
+        }
+    }
+
+    // merge every action since the one with id `from` into a single action, so that they are undone all at once
+    pub fn squash_history(&mut self, from: usize) {
+        let start = self.history.iter().position(|a| a.id >= from).unwrap_or(self.history.len());
+        if self.history.len() - start < 2 { return; }
+        let squashed: Vec<Action> = self.history.drain(start..).collect();
+        let id = squashed.last().unwrap().id;
+        self.history.push(Action { changes: squashed.into_iter().flat_map(|a| a.changes).collect(), id });
+    }
+
     pub fn most_recent_action_id(&self) -> usize {
         if self.history.len() == 0 {
             0
@@ -290,14 +435,23 @@ impl<'table> PieceTable {
     }
 
     pub fn insert_range(&mut self, s: &str, index: usize) {
-        if s.len() == 0 { return; }
+        if s.is_empty() { return; }
         let new_piece = Piece { source: self.sources.len(), start: 0, length: s.len() };
         self.sources.push(String::from(s));
         self.insert_raw_piece(index, new_piece);
     }
 
+    // add text to the end that was always meant to be there, like more of a file that is loaded a piece
+    // at a time, so it can't be undone
+    pub fn append_unrecorded(&mut self, s: &str) {
+        if s.is_empty() { return; }
+        self.pieces.push(Piece { source: self.sources.len(), start: 0, length: s.len() });
+        self.sources.push(String::from(s));
+    }
+
     pub fn insert_raw_piece(&mut self, index: usize, new_piece: Piece) {
         assert!(new_piece.source < self.sources.len());
+        self.anchors_inserted(index, new_piece.length);
         let mut ix = 0usize;
         let mut action = Action::new(self);
         for (i,p) in self.pieces.iter().enumerate() {
@@ -366,12 +520,13 @@ impl<'table> PieceTable {
             ix += p.length;
         }
         let insertion_piece_index = insertion_piece_index.unwrap();
-        TableMutator { piece_ix: insertion_piece_index, action }
+        TableMutator { piece_ix: insertion_piece_index, action, index }
     }
 
     /// deletes the range [start, end)
     pub fn delete_range(&mut self, start: usize, end: usize) {
         assert!(end > start, "tried to delete a invalid range {}..{}", start, end);
+        self.anchors_deleted(start, end);
         let mut start_piece: Option<(usize,usize)> = None;
         let mut end_piece:   Option<(usize,usize)> = None;
         let mut mid_pieces:  Vec<usize>            = Vec::new();
@@ -842,6 +997,100 @@ mod tests {
         assert_eq!(pt.text(), "hi");
     }
 
+    #[test]
+    fn anchors() {
+        let mut pt = PieceTable::with_text("one\ntwo\nthree\n");
+        let two = pt.anchor(7);
+        let three = pt.anchor(13);
+        pt.insert_range("zero\n", 0);
+        assert_eq!(pt.anchor_index(two), Some(12));
+        let mut m = pt.insert_mutator(8);
+        m.push_str(&mut pt, "ne");
+        m.push_char(&mut pt, 'X');
+        m.pop_char(&mut pt);
+        m.finish(&mut pt);
+        assert_eq!(pt.text(), "zero\nonene\ntwo\nthree\n");
+        assert_eq!(pt.anchor_index(two), Some(14));
+        pt.delete_range(11, 15);
+        assert_eq!(pt.anchor_index(two), None);
+        assert_eq!(pt.anchor_index(three), Some(16));
+        assert_eq!(pt.char_at(16), Some('\n'));
+        pt.remove_anchor(three);
+        assert_eq!(pt.anchor_index(three), None);
+        assert_eq!(pt.anchors_in(0..20), vec![]);
+        pt.move_anchor(two, 3);
+        assert_eq!(pt.anchors_in(0..4), vec![(two, 3)]);
+        assert_eq!(pt.anchors_in(4..20), vec![]);
+    }
+
+    #[test]
+    fn anchors_undo_redo() {
+        let mut pt = PieceTable::with_text("one two\n");
+        let two = pt.anchor(4);
+        let end = pt.anchor(8);
+        pt.insert_range("three ", 4);
+        assert_eq!((pt.anchor_index(two), pt.anchor_index(end)), (Some(10), Some(14)));
+        pt.undo();
+        assert_eq!(pt.text(), "one two\n");
+        assert_eq!((pt.anchor_index(two), pt.anchor_index(end)), (Some(4), Some(8)));
+        assert!(pt.redo());
+        assert_eq!(pt.text(), "one three two\n");
+        assert_eq!((pt.anchor_index(two), pt.anchor_index(end)), (Some(10), Some(14)));
+        // text deleted and put back by undo keeps the anchors after it, but not the ones on it
+        let t = pt.anchor(4);
+        pt.delete_range(2, 9);
+        assert_eq!((pt.anchor_index(two), pt.anchor_index(t)), (Some(3), None));
+        pt.undo();
+        assert_eq!(pt.text(), "one three two\n");
+        assert_eq!((pt.anchor_index(two), pt.anchor_index(t)), (Some(10), None));
+        // undoing an insert loses the anchors on the inserted text
+        let mut m = pt.insert_mutator(0);
+        m.push_str(&mut pt, "zero ");
+        m.finish(&mut pt);
+        let z = pt.anchor(1);
+        pt.undo();
+        assert_eq!((pt.anchor_index(two), pt.anchor_index(z), pt.anchor_index(end)), (Some(10), None, Some(14)));
+        assert!(pt.redo());
+        assert_eq!((pt.anchor_index(two), pt.anchor_index(end)), (Some(15), Some(19)));
+        // anything new means there's nothing to redo
+        pt.undo();
+        pt.insert_range("!", 0);
+        assert!(!pt.redo());
+        assert_eq!(pt.text(), "!one three two\n");
+        assert_eq!(pt.anchor_index(end), Some(15));
+    }
+
+    #[test]
+    fn undo_squashed() {
+        let mut pt = PieceTable::with_text("hello world");
+        pt.insert_range("!", 11);
+        let from = pt.next_action_id;
+        pt.delete_range(6, 11);
+        pt.insert_range("there", 6);
+        pt.delete_range(0, 1);
+        pt.insert_range("j", 0);
+        pt.squash_history(from);
+        assert_eq!(pt.text(), "jello there!");
+        assert_eq!(pt.history.len(), 2);
+        pt.undo();
+        assert_eq!(pt.text(), "hello world!");
+        pt.undo();
+        assert_eq!(pt.text(), "hello world");
+    }
+
+    #[test]
+    fn append_unrecorded() {
+        let mut pt = PieceTable::with_text("hello");
+        pt.insert_range("!", 5);
+        pt.append_unrecorded(" world");
+        assert_eq!(pt.text(), "hello! world");
+        assert_eq!(pt.history.len(), 1);
+        pt.undo();
+        assert_eq!(pt.text(), "hello world");
+        pt.undo();
+        assert_eq!(pt.text(), "hello world");
+    }
+
     #[test]
     fn undo_delete_range_single_piece() {
         let mut pt = PieceTable::with_text("hello");
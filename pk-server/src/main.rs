@@ -4,6 +4,7 @@ use pk_common::protocol;
 use std::collections::HashMap;
 use std::sync::{Arc, RwLock};
 use std::path::{PathBuf, Path};
+use log::{error, warn, info, debug};
 
 #[derive(Debug)]
 enum ServerError { 
@@ -12,6 +13,11 @@ enum ServerError {
     IoError(std::io::Error),
     InternalError,
     BadFileId(protocol::FileId),
+    BadWorkspaceId(protocol::WorkspaceId),
+    ConfigError(String),
+    ReadOnly(protocol::FileId),
+    BadVersion(protocol::FileId, usize),
+    EncodingError(pk_common::encoding::UnencodableChar),
     UnknownMessage
 }
 
@@ -33,6 +39,12 @@ impl From<std::io::Error> for ServerError {
     }
 }
 
+impl From<pk_common::encoding::UnencodableChar> for ServerError {
+    fn from(e: pk_common::encoding::UnencodableChar) -> Self {
+        Self::EncodingError(e)
+    }
+}
+
 impl std::fmt::Display for ServerError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -40,6 +52,11 @@ impl std::fmt::Display for ServerError {
             Self::TransportError(e) => write!(f, "error in transport: {}", e),
             Self::IoError(e) => write!(f, "io error: {}", e),
             Self::BadFileId(id) => write!(f, "unrecongized file id: {:?}", id),
+            Self::BadWorkspaceId(id) => write!(f, "unrecognized workspace id: {:?}", id),
+            Self::ConfigError(msg) => write!(f, "configuration error: {}", msg),
+            Self::ReadOnly(id) => write!(f, "file {:?} is read only", id),
+            Self::BadVersion(id, v) => write!(f, "invalid version {} for file {:?}", v, id),
+            Self::EncodingError(e) => write!(f, "encoding error: {}", e),
             Self::UnknownMessage => write!(f, "unrecongized message recieved"),
             Self::InternalError => write!(f, "internal error"),
         }
@@ -52,6 +69,7 @@ impl std::error::Error for ServerError {
             Self::MessageSerdeError(e) => Some(e),
             Self::TransportError(e) => Some(e),
             Self::IoError(e) => Some(e),
+            Self::EncodingError(e) => Some(e),
             _ => None
         }
     }
@@ -60,215 +78,1444 @@ impl std::error::Error for ServerError {
 mod filetype_table {
     use serde::Deserialize;
     use std::path::Path;
+    use super::protocol;
 
     #[derive(Deserialize, Debug)]
     pub struct FileType {
         pub name: String,
-        pub ext: Vec<String>
+        #[serde(default)]
+        pub ext: Vec<String>,
+        // globs matched against the whole file name, like `Makefile` or `Dockerfile.*`
+        #[serde(default)]
+        pub filenames: Vec<String>,
+        // interpreters named in a `#!` line, like `python` or `bash`
+        #[serde(default)]
+        pub shebang: Vec<String>,
+        // text that files of this type start with, like `<?xml`
+        #[serde(default)]
+        pub starts_with: Vec<String>,
+        #[serde(default)]
+        pub autosave: protocol::AutosavePolicy,
+        pub tabstop: Option<usize>,
+        pub soft_tab: Option<bool>,
+        pub line_comment: Option<String>,
+        pub block_comment: Option<(String, String)>
     }
 
-    #[derive(Deserialize, Debug)]
+    impl FileType {
+        pub fn validate(&self) -> Result<(), String> {
+            if self.name.is_empty() {
+                return Err(String::from("filetype with empty name"));
+            }
+            if self.tabstop == Some(0) {
+                return Err(format!("filetype {} has a tabstop of zero", self.name));
+            }
+            Ok(())
+        }
+
+        fn settings(&self) -> protocol::FileTypeSettings {
+            protocol::FileTypeSettings {
+                tabstop: self.tabstop,
+                soft_tab: self.soft_tab,
+                line_comment: self.line_comment.clone(),
+                block_comment: self.block_comment.clone()
+            }
+        }
+    }
+
+    #[derive(Debug, Default)]
     pub struct FileTypeTable {
         filetype: Vec<FileType>
     }
 
-    impl FileTypeTable {
-        pub fn analyze(&self, path: impl AsRef<Path>) -> super::protocol::FileType {
-            use super::protocol::FileType;
-            match path.as_ref().extension().and_then(|oss| oss.to_str()) {
-                Some(ext) => {
-                    for ft in self.filetype.iter() {
-                        if ft.ext.iter().any(|x| ext == x) {
-                            return FileType::from(ft.name.as_str());
+    // settings for every file in a workspace, which take precedence over the file type but not over a
+    // modeline in the file itself
+    #[derive(Deserialize, Default, Debug, Clone)]
+    #[serde(rename_all = "kebab-case")]
+    pub struct ProjectSettings {
+        pub tabstop: Option<usize>,
+        pub soft_tab: Option<bool>,
+        pub autosave: Option<protocol::AutosavePolicy>
+    }
+
+    pub struct Analysis {
+        pub stype: protocol::FileType,
+        pub settings: protocol::FileTypeSettings,
+        pub autosave: protocol::AutosavePolicy
+    }
+
+    // `*` matches any run of characters, `?` matches any one character
+    pub fn glob_match(pattern: &str, s: &str) -> bool {
+        let p: Vec<char> = pattern.chars().collect();
+        let s: Vec<char> = s.chars().collect();
+        let (mut pi, mut si) = (0, 0);
+        // where to go back to if the rest of the pattern doesn't match after the last `*`
+        let mut backtrack: Option<(usize, usize)> = None;
+        while si < s.len() {
+            if pi < p.len() && (p[pi] == '?' || p[pi] == s[si]) {
+                pi += 1; si += 1;
+            } else if pi < p.len() && p[pi] == '*' {
+                backtrack = Some((pi, si));
+                pi += 1;
+            } else if let Some((bp, bs)) = backtrack {
+                pi = bp + 1; si = bs + 1;
+                backtrack = Some((bp, bs + 1));
+            } else {
+                return false;
+            }
+        }
+        p[pi..].iter().all(|c| *c == '*')
+    }
+
+    // settings from a vim style modeline in the first or last few lines of a file, for example
+    // `// vim: set ft=rust ts=4 et:`. `pk:` works as well
+    #[derive(Default, Debug)]
+    struct Modeline {
+        filetype: Option<String>,
+        tabstop: Option<usize>,
+        soft_tab: Option<bool>
+    }
+
+    impl Modeline {
+        fn find(contents: &str) -> Modeline {
+            let lines: Vec<&str> = contents.lines().collect();
+            let mut ml = Modeline::default();
+            let head = lines.iter().take(5);
+            let tail = lines.iter().skip(lines.len().saturating_sub(5).max(5));
+            for ln in head.chain(tail) {
+                let opts = ["vim:", "vi:", "pk:"].iter()
+                    .filter_map(|tag| ln.find(tag).filter(|i| *i == 0 || ln[..*i].ends_with(char::is_whitespace))
+                                .map(|i| &ln[i+tag.len()..]))
+                    .next();
+                if let Some(opts) = opts {
+                    for opt in opts.split(|c: char| c.is_whitespace() || c == ':').filter(|o| !o.is_empty()) {
+                        let (key, value) = match opt.find('=') {
+                            Some(i) => (&opt[..i], Some(&opt[i+1..])),
+                            None => (opt, None)
+                        };
+                        match (key, value) {
+                            ("ft", Some(v)) | ("filetype", Some(v)) => ml.filetype = Some(v.to_owned()),
+                            ("ts", Some(v)) | ("tabstop", Some(v)) => ml.tabstop = v.parse().ok().filter(|ts| *ts > 0).or(ml.tabstop),
+                            ("et", None) | ("expandtab", None) => ml.soft_tab = Some(true),
+                            ("noet", None) | ("noexpandtab", None) => ml.soft_tab = Some(false),
+                            _ => {}
                         }
                     }
-                    FileType::default()
+                }
+            }
+            ml
+        }
+    }
+
+    impl FileTypeTable {
+        pub fn new(filetype: Vec<FileType>) -> FileTypeTable {
+            FileTypeTable { filetype }
+        }
+
+        fn by_name(&self, name: &str) -> Option<&FileType> {
+            self.filetype.iter().find(|ft| ft.name == name)
+        }
+
+        fn by_filename(&self, path: &Path) -> Option<&FileType> {
+            let name = path.file_name().and_then(|oss| oss.to_str())?;
+            self.filetype.iter().find(|ft| ft.filenames.iter().any(|g| glob_match(g, name)))
+        }
+
+        fn by_extension(&self, path: &Path) -> Option<&FileType> {
+            let ext = path.extension().and_then(|oss| oss.to_str())?;
+            self.filetype.iter().find(|ft| ft.ext.iter().any(|x| ext == x))
+        }
+
+        fn by_shebang(&self, contents: &str) -> Option<&FileType> {
+            let line = contents.lines().next()?.strip_prefix("#!")?;
+            let mut words = line.split_whitespace();
+            let mut interp = words.next()?.rsplit('/').next()?;
+            // `#!/usr/bin/env [-S] python3`
+            if interp == "env" {
+                interp = words.find(|w| !w.starts_with('-'))?;
+            }
+            // python3.8 -> python
+            let interp = interp.trim_end_matches(|c: char| c.is_ascii_digit() || c == '.');
+            self.filetype.iter().find(|ft| ft.shebang.iter().any(|s| s == interp))
+        }
+
+        fn by_content(&self, contents: &str) -> Option<&FileType> {
+            let start = contents.trim_start();
+            self.filetype.iter().find(|ft| ft.starts_with.iter().any(|s| start.starts_with(s.as_str())))
+        }
+
+        pub fn analyze(&self, path: impl AsRef<Path>, contents: &str, project: Option<&ProjectSettings>) -> Analysis {
+            let path = path.as_ref();
+            let modeline = Modeline::find(contents);
+            let ft = modeline.filetype.as_ref().and_then(|name| self.by_name(name))
+                .or_else(|| self.by_filename(path))
+                .or_else(|| self.by_extension(path))
+                .or_else(|| self.by_shebang(contents))
+                .or_else(|| self.by_content(contents));
+            let mut a = match ft {
+                Some(ft) => Analysis {
+                    stype: protocol::FileType::from(ft.name.as_str()),
+                    settings: ft.settings(),
+                    autosave: ft.autosave
                 },
-                None => FileType::default()
+                None => Analysis {
+                    stype: modeline.filetype.as_ref().map(|n| protocol::FileType::from(n.as_str())).unwrap_or_default(),
+                    settings: protocol::FileTypeSettings::default(),
+                    autosave: protocol::AutosavePolicy::default()
+                }
+            };
+            if let Some(p) = project {
+                if p.tabstop.is_some() { a.settings.tabstop = p.tabstop; }
+                if p.soft_tab.is_some() { a.settings.soft_tab = p.soft_tab; }
+                if let Some(autosave) = p.autosave { a.autosave = autosave; }
             }
+            if modeline.tabstop.is_some() { a.settings.tabstop = modeline.tabstop; }
+            if modeline.soft_tab.is_some() { a.settings.soft_tab = modeline.soft_tab; }
+            a
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+
+        fn table() -> FileTypeTable {
+            #[derive(Deserialize)]
+            struct Types { filetype: Vec<FileType> }
+            let types: Types = toml::from_str(r#"
+                [[filetype]]
+                name = "python"
+                ext = ["py"]
+                shebang = ["python"]
+                [[filetype]]
+                name = "shell"
+                ext = ["sh"]
+                shebang = ["sh", "bash"]
+                [[filetype]]
+                name = "make"
+                filenames = ["Makefile", "*.mk"]
+            "#).unwrap();
+            FileTypeTable::new(types.filetype)
+        }
+
+        #[test]
+        fn glob() {
+            assert!(glob_match("Makefile", "Makefile"));
+            assert!(!glob_match("Makefile", "Makefile.am"));
+            assert!(glob_match("*.mk", "rules.mk"));
+            assert!(glob_match("*.mk", ".mk"));
+            assert!(!glob_match("*.mk", "rules.mkx"));
+            assert!(glob_match("Dockerfile.*", "Dockerfile.dev"));
+            assert!(glob_match("a?c", "abc"));
+            assert!(!glob_match("a?c", "ac"));
+            assert!(glob_match("*a*b", "xxaxxab"));
+            assert!(!glob_match("*a*b", "xxaxxa"));
+            assert!(glob_match("**", ""));
+            assert!(!glob_match("", "a"));
+        }
+
+        #[test]
+        fn modeline() {
+            let ml = Modeline::find("// vim: set ft=rust ts=4 et:\nfn main() {}\n");
+            assert_eq!(ml.filetype.as_deref(), Some("rust"));
+            assert_eq!(ml.tabstop, Some(4));
+            assert_eq!(ml.soft_tab, Some(true));
+
+            let text = "x\n".repeat(20) + "# pk: tabstop=2 noexpandtab\n";
+            let ml = Modeline::find(&text);
+            assert_eq!(ml.tabstop, Some(2));
+            assert_eq!(ml.soft_tab, Some(false));
+
+            // only near the start or end of the file, and not in the middle of a word
+            let text = "x\n".repeat(10) + "vim: ts=4\n" + &"x\n".repeat(10);
+            assert_eq!(Modeline::find(&text).tabstop, None);
+            assert_eq!(Modeline::find("envim: ts=4\n").tabstop, None);
+
+            assert_eq!(Modeline::find("vim: ts=0\n").tabstop, None);
+            assert_eq!(Modeline::find("vim: ts=8\nvim: ts=0\n").tabstop, Some(8));
+            assert_eq!(Modeline::find("vim: ts=x\n").tabstop, None);
+        }
+
+        #[test]
+        fn shebang() {
+            let t = table();
+            let name = |s: &str| t.by_shebang(s).map(|ft| ft.name.clone());
+            assert_eq!(name("#!/bin/sh\n").as_deref(), Some("shell"));
+            assert_eq!(name("#!/usr/bin/env bash\n").as_deref(), Some("shell"));
+            assert_eq!(name("#!/usr/bin/env -S python3 -u\n").as_deref(), Some("python"));
+            assert_eq!(name("#! /usr/bin/python3.8\n").as_deref(), Some("python"));
+            assert_eq!(name("#!/usr/bin/perl\n"), None);
+            assert_eq!(name("# /bin/sh\n"), None);
+            assert_eq!(name(""), None);
+        }
+
+        #[test]
+        fn analyze_precedence() {
+            let t = table();
+            assert_eq!(t.analyze("a.py", "#!/bin/sh\n", None).stype, protocol::FileType::from("python"));
+            assert_eq!(t.analyze("a", "#!/bin/sh\n", None).stype, protocol::FileType::from("shell"));
+            assert_eq!(t.analyze("a.py", "# vim: ft=make\n", None).stype, protocol::FileType::from("make"));
+            assert_eq!(t.analyze("sub/rules.mk", "", None).stype, protocol::FileType::from("make"));
         }
     }
 }
 
-use filetype_table::FileTypeTable;
+use filetype_table::{FileTypeTable, ProjectSettings};
 
+struct ServerConfig {
+    // keep a copy of the previous contents of each file next to it as `<file name>.pk~`
+    backups: bool,
+    // files larger than this many bytes are sent to clients a window at a time, and are read only
+    page_threshold: u64,
+    // exit after this many seconds without any clients connected
+    idle_exit: Option<u64>,
+    filetype_table: FileTypeTable
+}
+
+// what a configuration file can contain. Everything is optional, anything missing comes from the
+// defaults in default.server.toml
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct ConfigFile {
+    backups: Option<bool>,
+    page_threshold: Option<u64>,
+    idle_exit: Option<u64>,
+    #[serde(default)]
+    filetype: Vec<filetype_table::FileType>
+}
+
+impl ConfigFile {
+    fn parse(src: &str, name: &str) -> Result<ConfigFile, ServerError> {
+        let cf: ConfigFile = toml::from_str(src)
+            .map_err(|e| ServerError::ConfigError(format!("{}: {}", name, e)))?;
+        if cf.page_threshold == Some(0) {
+            return Err(ServerError::ConfigError(format!("{}: page-threshold must be greater than zero", name)));
+        }
+        for ft in cf.filetype.iter() {
+            ft.validate().map_err(|e| ServerError::ConfigError(format!("{}: {}", name, e)))?;
+        }
+        Ok(cf)
+    }
+}
+
+// settings given on the command line, which win over the configuration file
+#[derive(Clone)]
+struct ConfigOverrides {
+    backups: bool,
+    page_threshold: Option<u64>,
+    idle_exit: Option<u64>
+}
+
+impl ConfigOverrides {
+    fn from_args(args: &mut pico_args::Arguments) -> Result<ConfigOverrides, ServerError> {
+        Ok(ConfigOverrides {
+            backups: args.contains("--backups"),
+            page_threshold: args.opt_value_from_str("--page-threshold")
+                .map_err(|e| ServerError::ConfigError(format!("--page-threshold: {}", e)))?,
+            idle_exit: args.opt_value_from_str("--idle-exit")
+                .map_err(|e| ServerError::ConfigError(format!("--idle-exit: {}", e)))?
+        })
+    }
+}
+
+impl ServerConfig {
+    fn load(path: &Path, overrides: &ConfigOverrides) -> Result<ServerConfig, ServerError> {
+        let defaults = ConfigFile::parse(include_str!("../default.server.toml"), "default configuration")?;
+        let user = match std::fs::read_to_string(path) {
+            Ok(src) => Some(ConfigFile::parse(&src, &path.to_string_lossy())?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(ServerError::ConfigError(format!("{}: {}", path.to_string_lossy(), e)))
+        };
+        let (backups, page_threshold, idle_exit, mut filetypes) = match user {
+            Some(u) => (u.backups.or(defaults.backups), u.page_threshold.or(defaults.page_threshold),
+                        u.idle_exit.or(defaults.idle_exit), u.filetype),
+            None => (defaults.backups, defaults.page_threshold, defaults.idle_exit, Vec::new())
+        };
+        // filetypes from the user's configuration replace default ones with the same name
+        for ft in defaults.filetype {
+            if !filetypes.iter().any(|f| f.name == ft.name) {
+                filetypes.push(ft);
+            }
+        }
+        Ok(ServerConfig {
+            backups: overrides.backups || backups.unwrap_or(false),
+            page_threshold: overrides.page_threshold.or(page_threshold).unwrap_or(16 * 1024 * 1024),
+            // zero means never
+            idle_exit: overrides.idle_exit.or(idle_exit).filter(|secs| *secs > 0),
+            filetype_table: FileTypeTable::new(filetypes)
+        })
+    }
+}
+
+// reloads the configuration file whenever it changes
+struct ConfigWatcher {
+    server: Arc<RwLock<Server>>,
+    path: PathBuf,
+    overrides: ConfigOverrides,
+    last_modified: Option<std::time::SystemTime>
+}
+
+impl ConfigWatcher {
+    fn new(server: Arc<RwLock<Server>>, path: PathBuf, overrides: ConfigOverrides) -> ConfigWatcher {
+        let last_modified = std::fs::metadata(&path).and_then(|m| m.modified()).ok();
+        ConfigWatcher { server, path, overrides, last_modified }
+    }
+
+    fn run(&mut self) {
+        loop {
+            std::thread::sleep(std::time::Duration::from_secs(2));
+            let modified = std::fs::metadata(&self.path).and_then(|m| m.modified()).ok();
+            if modified == self.last_modified { continue; }
+            self.last_modified = modified;
+            match ServerConfig::load(&self.path, &self.overrides) {
+                Ok(config) => {
+                    info!("reloaded configuration path={:?}", self.path);
+                    self.server.write().unwrap().config = config;
+                },
+                // keep going with the old configuration until the file is fixed
+                Err(e) => error!("error reloading configuration, keeping previous configuration error=\"{}\"", e)
+            }
+        }
+    }
+}
+
+// size of the window of a large file sent when it is opened
+const PAGE_SIZE: u64 = 1024 * 1024;
+// largest window that a client can ask for at once
+const MAX_WINDOW_SIZE: u64 = 16 * 1024 * 1024;
+// how many old versions of each file are kept around for merging conflicts. Clients sync about once
+// a second, so this covers a little while of someone else editing
+const VERSION_HISTORY_LEN: usize = 32;
+
+fn read_range(path: &Path, offset: u64, len: u64) -> std::io::Result<Vec<u8>> {
+    use std::io::{Read, Seek, SeekFrom};
+    let mut f = std::fs::File::open(path)?;
+    f.seek(SeekFrom::Start(offset))?;
+    let mut data = Vec::with_capacity(len as usize);
+    f.take(len).read_to_end(&mut data)?;
+    Ok(data)
+}
+
+// `xxd` style hex dump, 16 bytes per line. `offset` is where `data` starts in the file
+fn hex_dump(data: &[u8], offset: u64) -> String {
+    use std::fmt::Write;
+    let mut out = String::with_capacity(data.len() * 4 + 16);
+    for (i, line) in data.chunks(16).enumerate() {
+        write!(out, "{:08x}:", offset + i as u64 * 16).unwrap();
+        for j in 0..16 {
+            if j % 2 == 0 { out.push(' '); }
+            match line.get(j) {
+                Some(b) => write!(out, "{:02x}", b).unwrap(),
+                None => out.push_str("  ")
+            }
+        }
+        out.push_str("  ");
+        out.extend(line.iter().map(|b| if b.is_ascii_graphic() || *b == b' ' { *b as char } else { '.' }));
+        out.push('\n');
+    }
+    out
+}
+
+// write `contents` to `path` so that the file is never left half written: the new contents go into a
+// temporary file in the same directory, which is flushed to disk and then renamed over the original
+fn write_atomic(path: &Path, contents: &[u8], backup: bool) -> Result<(), ServerError> {
+    use std::io::Write;
+    // write through symlinks instead of replacing them
+    let path = match std::fs::canonicalize(path) {
+        Ok(p) => p,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => path.to_owned(),
+        Err(e) => return Err(ServerError::IoError(e))
+    };
+    let dir = path.parent().filter(|d| !d.as_os_str().is_empty()).unwrap_or_else(|| Path::new("."));
+    let file_name = path.file_name().ok_or_else(|| ServerError::IoError(
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "path does not name a file")))?;
+    let mut tmp_name = std::ffi::OsString::from(".");
+    tmp_name.push(file_name);
+    tmp_name.push(format!(".pk-tmp{}", std::process::id()));
+    let tmp_path = dir.join(tmp_name);
+
+    let existing = match std::fs::metadata(&path) {
+        Ok(m) => Some(m),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => return Err(ServerError::IoError(e))
+    };
+
+    let res = (|| {
+        let mut f = std::fs::File::create(&tmp_path)?;
+        f.write_all(contents)?;
+        if let Some(m) = existing.as_ref() {
+            f.set_permissions(m.permissions())?;
+            #[cfg(unix)] {
+                use std::os::unix::fs::MetadataExt;
+                // only works if we're allowed to, but a file owned by someone else is rare enough
+                // that it isn't worth failing the write over
+                let _ = std::os::unix::fs::fchown(&f, Some(m.uid()), Some(m.gid()));
+            }
+        }
+        f.sync_all()?;
+        if backup && existing.is_some() {
+            let mut backup_name = file_name.to_owned();
+            backup_name.push(".pk~");
+            std::fs::copy(&path, dir.join(backup_name))?;
+        }
+        std::fs::rename(&tmp_path, &path)
+    })();
+    if let Err(e) = res {
+        let _ = std::fs::remove_file(&tmp_path);
+        return Err(ServerError::IoError(e));
+    }
+
+    // make sure the rename itself is durable
+    #[cfg(unix)] {
+        if let Ok(d) = std::fs::File::open(dir) {
+            let _ = d.sync_all();
+        }
+    }
+    Ok(())
+}
+
+// what can go in the `.pk.toml` file at the root of a workspace
+#[derive(serde::Deserialize, Default)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct WorkspaceFile {
+    name: Option<String>,
+    // globs for files and directories to leave out of the workspace, matched against each part of a
+    // path relative to the root as well as the whole path
+    #[serde(default)]
+    ignore: Vec<String>,
+    #[serde(flatten)]
+    settings: ProjectSettings
+}
+
+// most directories that are in the way when looking for a file
+const DEFAULT_IGNORE: &[&str] = &[".git", ".hg", ".svn", "target", "node_modules", "*.pk~"];
+// stop walking huge trees at some point, the list is only useful for finding files anyway
+const MAX_WORKSPACE_FILES: usize = 100_000;
+
+#[derive(Clone)]
+struct Workspace {
+    root: PathBuf,
+    name: String,
+    ignore: Vec<String>,
+    settings: ProjectSettings
+}
+
+impl Workspace {
+    fn open(root: &Path) -> Result<Workspace, ServerError> {
+        let root = std::fs::canonicalize(root)?;
+        if !root.is_dir() {
+            return Err(ServerError::IoError(std::io::Error::new(std::io::ErrorKind::InvalidInput,
+                format!("{} is not a directory", root.to_string_lossy()))));
+        }
+        let config_path = root.join(".pk.toml");
+        let wf: WorkspaceFile = match std::fs::read_to_string(&config_path) {
+            Ok(src) => toml::from_str(&src)
+                .map_err(|e| ServerError::ConfigError(format!("{}: {}", config_path.to_string_lossy(), e)))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => WorkspaceFile::default(),
+            Err(e) => return Err(ServerError::IoError(e))
+        };
+        if wf.settings.tabstop == Some(0) {
+            return Err(ServerError::ConfigError(format!("{}: tabstop must be greater than zero", config_path.to_string_lossy())));
+        }
+        let name = wf.name.unwrap_or_else(|| root.file_name()
+            .map_or_else(|| root.to_string_lossy().into_owned(), |n| n.to_string_lossy().into_owned()));
+        let mut ignore = wf.ignore;
+        ignore.extend(DEFAULT_IGNORE.iter().map(|s| String::from(*s)));
+        Ok(Workspace { root, name, ignore, settings: wf.settings })
+    }
+
+    fn is_ignored(&self, rel_path: &Path) -> bool {
+        let whole = rel_path.to_string_lossy();
+        self.ignore.iter().any(|g| filetype_table::glob_match(g, &whole)
+            || rel_path.iter().any(|part| filetype_table::glob_match(g, &part.to_string_lossy())))
+    }
+
+    // all files under the root that aren't ignored, relative to the root. Symlinked directories
+    // aren't followed so that a link cycle can't go on forever
+    fn files(&self) -> Result<Vec<PathBuf>, ServerError> {
+        let mut files = Vec::new();
+        let mut dirs = vec![PathBuf::new()];
+        while let Some(dir) = dirs.pop() {
+            let entries = match std::fs::read_dir(self.root.join(&dir)) {
+                Ok(e) => e,
+                // a subdirectory we can't read shouldn't keep the rest from being listed
+                Err(e) if !dir.as_os_str().is_empty() => { warn!("skipping unreadable directory path={:?} error=\"{}\"", dir, e); continue; },
+                Err(e) => return Err(ServerError::IoError(e))
+            };
+            for entry in entries.filter_map(|e| e.ok()) {
+                let rel = dir.join(entry.file_name());
+                if self.is_ignored(&rel) { continue; }
+                match entry.file_type() {
+                    Ok(t) if t.is_dir() => dirs.push(rel),
+                    Ok(_) => files.push(rel),
+                    Err(_) => {}
+                }
+                if files.len() >= MAX_WORKSPACE_FILES { return Ok(files); }
+            }
+        }
+        files.sort();
+        Ok(files)
+    }
+}
+
+// make `path` absolute, following symlinks, so that the same file opened by different names ends up
+// as one File. Files that don't exist yet are resolved as far as their directory
+fn resolve_path(path: &Path) -> Result<PathBuf, ServerError> {
+    match std::fs::canonicalize(path) {
+        Ok(p) => Ok(p),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let path = if path.is_relative() { std::env::current_dir()?.join(path) } else { path.to_owned() };
+            match (path.parent().map(std::fs::canonicalize), path.file_name()) {
+                (Some(Ok(dir)), Some(name)) => Ok(dir.join(name)),
+                _ => Ok(path)
+            }
+        },
+        Err(e) => Err(ServerError::IoError(e))
+    }
+}
+
+// read and decode a whole file, which is empty if it doesn't exist
+fn read_text(path: Option<&Path>, format: &protocol::TextFormat) -> Result<String, ServerError> {
+    let path = match path {
+        Some(p) => p,
+        None => return Ok(String::new())
+    };
+    let data = match std::fs::read(path) {
+        Ok(d) => d,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(ServerError::IoError(e))
+    };
+    let bom = format.encoding.bom();
+    let skip = if format.bom && data.starts_with(bom) { bom.len() } else { 0 };
+    let mut text = format.encoding.decode_lossy(&data[skip..]);
+    if format.line_ending == protocol::LineEnding::CRLF {
+        text = text.replace("\r\n", "\n");
+    }
+    Ok(text)
+}
+
+// the bytes to write to disk for `contents` in `format`
+fn encode_text(contents: &str, format: &protocol::TextFormat) -> Result<Vec<u8>, ServerError> {
+    let mut text = std::borrow::Cow::Borrowed(contents);
+    if format.final_newline && !text.is_empty() && !text.ends_with('\n') {
+        text.to_mut().push('\n');
+    }
+    if format.line_ending == protocol::LineEnding::CRLF { 
+        text = std::borrow::Cow::Owned(text.replace("\n", "\r\n"));
+    }
+    Ok(format.encoding.encode(&text, format.bom)?)
+}
 
-#[derive(Default)]
 struct File {
     path: Option<PathBuf>,
     contents: String,
     current_version: usize,
+    // the version and format that were last written to disk
+    disk_version: usize,
+    disk_format: protocol::TextFormat,
     format: protocol::TextFormat,
+    view: protocol::FileView,
+    // set if the file is too large to be sent all at once, in which case `contents` is just the
+    // first window of the file
+    window: Option<protocol::FileWindow>,
+    autosave: protocol::AutosavePolicy,
+    last_change: std::time::Instant,
+    // the first change since the file was last written
+    first_unsaved_change: Option<std::time::Instant>,
+    last_write: std::time::Instant,
+    // the client that has claimed the file, if any
+    lock: Option<protocol::ClientId>,
+    // recent versions of the file, oldest first, so that conflicting changes can be merged
+    history: std::collections::VecDeque<(usize, String)>
 }
 
 impl File {
 
-    fn from_path<P: AsRef<Path>>(p: P, filetype_table: &FileTypeTable) -> Result<File, ServerError> {
+    fn from_path<P: AsRef<Path>>(p: P, config: &ServerConfig, project: Option<&ProjectSettings>) -> Result<File, ServerError> {
         let path = p.as_ref().to_owned();
-        let mut contents = match std::fs::read_to_string(&path) {
-            Ok(s) => s,
-            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        let total = match std::fs::metadata(&path) {
+            Ok(m) => m.len(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
             Err(e) => return Err(ServerError::IoError(e))
         };
+        // only look at the start of the file to decide how to treat it, since it might be huge
+        let sample = if total > 0 { read_range(&path, 0, PAGE_SIZE)? } else { Vec::new() };
+        let view = if pk_common::encoding::looks_binary(&sample[..sample.len().min(8192)]) {
+            protocol::FileView::Hex
+        } else {
+            protocol::FileView::Text
+        };
+        // hex dumps are about four times as large as the file they're of
+        let paged = match view {
+            protocol::FileView::Text => total > config.page_threshold,
+            protocol::FileView::Hex => total * 4 > config.page_threshold
+        };
+
+        let data = if paged || total <= PAGE_SIZE { sample } else { std::fs::read(&path)? };
+        // don't let a character cut off at the end of the sample throw off encoding detection
+        let detect_len = if paged { data.iter().rposition(|b| *b == b'\n').map_or(data.len(), |i| i + 1) } else { data.len() };
+        let (mut contents, encoding, bom) = protocol::TextEncoding::detect_and_decode(&data[..detect_len]);
+        let analysis = config.filetype_table.analyze(&path, if view == protocol::FileView::Text { &contents } else { "" }, project);
         let fmt = protocol::TextFormat {
             line_ending: protocol::LineEnding::from_analysis(&contents),
-            stype: filetype_table.analyze(&path)
+            stype: analysis.stype,
+            encoding, bom,
+            final_newline: contents.is_empty() || contents.ends_with('\n'),
+            settings: analysis.settings
         };
         if fmt.line_ending == protocol::LineEnding::CRLF {
             contents = contents.replace("\r\n", "\n");
         }
-        Ok(File {
+        if view == protocol::FileView::Hex {
+            contents = hex_dump(&data, 0);
+        }
+        let now = std::time::Instant::now();
+        let mut file = File {
+            disk_format: fmt.clone(),
             format: fmt,
+            view, window: None,
+            autosave: analysis.autosave,
             path: Some(path), contents,
-            current_version: 0,
-        })
+            current_version: 0, disk_version: 0,
+            last_change: now, first_unsaved_change: None, last_write: now, lock: None,
+            history: std::collections::VecDeque::new()
+        };
+        if paged {
+            file.window = Some(protocol::FileWindow { end: 0, total });
+            let (contents, window) = file.read_window(0, PAGE_SIZE)?;
+            file.contents = contents;
+            // if the first window has all of the file anyway, there is nothing to page
+            file.window = Some(window).filter(|w| !w.complete());
+        }
+        Ok(file)
     }
 
-    fn write_to_disk(&self) -> Result<(), ServerError> {
-        if let Some(path) = self.path.as_ref() {
-            println!("writing {} v{} to disk", path.to_str().unwrap_or(""), self.current_version);
-            if self.format.line_ending == protocol::LineEnding::CRLF { 
-                std::fs::write(path, self.contents.replace("\n", "\r\n"))?;
-            } else {
-                std::fs::write(path, &self.contents)?;
+    fn read_only(&self) -> bool {
+        self.view == protocol::FileView::Hex || self.window.is_some()
+    }
+
+    // read part of a paged file, as it should be shown to the client. The window is adjusted so
+    // that it ends on a line boundary
+    fn read_window(&self, offset: u64, len: u64) -> Result<(String, protocol::FileWindow), ServerError> {
+        use protocol::{FileView, FileWindow, TextEncoding};
+        let (path, total) = match (self.path.as_ref(), self.window) {
+            (Some(p), Some(w)) => (p, w.total),
+            _ => return Ok((self.contents.clone(), FileWindow { end: 0, total: 0 }))
+        };
+        let len = len.min(MAX_WINDOW_SIZE);
+        match self.view {
+            FileView::Hex => {
+                let offset = offset & !15;
+                let data = read_range(path, offset, (len + 15) & !15)?;
+                Ok((hex_dump(&data, offset), FileWindow { end: offset + data.len() as u64, total }))
+            },
+            FileView::Text => {
+                let mut data = read_range(path, offset, len)?;
+                if offset + (data.len() as u64) < total {
+                    match self.format.encoding {
+                        TextEncoding::Utf16LE | TextEncoding::Utf16BE => data.truncate(data.len() & !1),
+                        TextEncoding::Utf8 | TextEncoding::Latin1 => {
+                            if let Some(nl) = data.iter().rposition(|b| *b == b'\n') {
+                                data.truncate(nl + 1);
+                            }
+                        }
+                    }
+                }
+                let skip = if offset == 0 && self.format.bom { self.format.encoding.bom().len() } else { 0 };
+                let mut text = self.format.encoding.decode_lossy(&data[skip.min(data.len())..]);
+                if self.format.line_ending == protocol::LineEnding::CRLF {
+                    text = text.replace("\r\n", "\n");
+                }
+                Ok((text, FileWindow { end: offset + data.len() as u64, total }))
             }
         }
+    }
+
+    // what is on disk right now, decoded the way it was when it was last read or written
+    fn disk_text(&self) -> Result<String, ServerError> {
+        read_text(self.path.as_deref(), &self.disk_format)
+    }
+
+    fn is_dirty(&self) -> bool {
+        !self.read_only() && (self.disk_version < self.current_version || self.disk_format != self.format)
+    }
+
+    fn needs_autosave(&self) -> bool {
+        use protocol::AutosavePolicy;
+        if !self.is_dirty() { return false; }
+        match self.autosave {
+            AutosavePolicy::Interval(secs) => self.last_write.elapsed().as_secs() >= secs,
+            AutosavePolicy::OnIdle(secs) => self.last_change.elapsed().as_secs() >= secs,
+            AutosavePolicy::Manual => false
+        }
+    }
+
+    fn save(&mut self, config: &ServerConfig) -> Result<(), ServerError> {
+        self.write_to_disk(config)?;
+        self.disk_version = self.current_version;
+        self.disk_format = self.format.clone();
+        self.first_unsaved_change = None;
+        self.last_write = std::time::Instant::now();
         Ok(())
     }
+
+    // the bytes that should be on disk for the current contents and format
+    fn encoded_contents(&self) -> Result<Vec<u8>, ServerError> {
+        encode_text(&self.contents, &self.format)
+    }
+
+    fn write_to_disk(&self, config: &ServerConfig) -> Result<(), ServerError> {
+        if let Some(path) = self.path.as_ref() {
+            info!("writing file path={:?} version={}", path, self.current_version);
+            write_atomic(path, &self.encoded_contents()?, config.backups)?;
+        }
+        Ok(())
+    }
+}
+
+// how long a client can go without making a request before it's taken to be away. Clients report their
+// cursors every second or so
+const PRESENCE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+// what the server knows about a client that is using it
+struct ClientPresence {
+    name: String,
+    last_seen: std::time::Instant,
+    // set once the client hasn't been heard from for `PRESENCE_TIMEOUT`. Its cursors aren't shown and its
+    // locks are let go, but its files stay open, since it may just be offline and come back with edits
+    away: bool,
+    // the files this client has open, with its cursor and selection in each
+    files: HashMap<protocol::FileId, (usize, Option<(usize, usize)>)>
+}
+
+#[derive(Default)]
+struct ServerStats {
+    requests: u64,
+    errors: u64,
+    write_errors: u64,
+    // updated by the socket's pipe notifications, which don't have access to the server
+    connected_clients: Arc<std::sync::atomic::AtomicUsize>
 }
 
 struct Server {
+    started: std::time::Instant,
+    // once set, requests are turned away and main writes everything out and exits
+    shutting_down: bool,
+    stats: ServerStats,
     open_files: HashMap<protocol::FileId, File>,
     next_file_id: protocol::FileId,
-    filetype_table: FileTypeTable
+    workspaces: HashMap<protocol::WorkspaceId, Workspace>,
+    next_workspace_id: protocol::WorkspaceId,
+    clients: HashMap<protocol::ClientId, ClientPresence>,
+    config: ServerConfig
 }
 
 impl Server {
-    fn new(filetype_table: FileTypeTable) -> Self {
+    fn new(config: ServerConfig) -> Self {
         Server {
+            started: std::time::Instant::now(),
+            shutting_down: false,
+            stats: ServerStats::default(),
             open_files: HashMap::new(),
             next_file_id: protocol::FileId(1),
-            filetype_table
+            workspaces: HashMap::new(),
+            next_workspace_id: protocol::WorkspaceId(1),
+            clients: HashMap::new(),
+            config
+        }
+    }
+
+    // the innermost workspace that `path` is in
+    fn workspace_for(&self, path: &Path) -> Option<protocol::WorkspaceId> {
+        self.workspaces.iter()
+            .filter(|(_, ws)| path.starts_with(&ws.root))
+            .max_by_key(|(_, ws)| ws.root.components().count())
+            .map(|(id, _)| *id)
+    }
+
+    // whether any client other than `client` has `id` open
+    fn open_elsewhere(&self, client: protocol::ClientId, id: protocol::FileId) -> bool {
+        self.clients.iter().any(|(c, p)| *c != client && p.files.contains_key(&id))
+    }
+
+    // who has `id` locked, if anyone
+    fn file_lock(&self, id: protocol::FileId) -> Option<protocol::FileLock> {
+        self.open_files.get(&id).and_then(|f| f.lock).map(|c| protocol::FileLock {
+            client: c,
+            name: self.clients.get(&c).map_or_else(|| format!("client {}", c.0), |p| p.name.clone())
+        })
+    }
+
+    // the lock on `id` if a client other than `client` holds it
+    fn locked_by_other(&self, client: protocol::ClientId, id: protocol::FileId) -> Option<protocol::FileLock> {
+        self.file_lock(id).filter(|l| l.client != client)
+    }
+
+    // let go of every lock `client` holds, because it closed the files or went away
+    fn release_locks(&mut self, client: protocol::ClientId) {
+        for (id, file) in self.open_files.iter_mut() {
+            if file.lock == Some(client) {
+                info!("releasing lock client={} file={}", client.0, id.0);
+                file.lock = None;
+            }
+        }
+    }
+
+    // `client` is done with a file, which is closed unless some other client still has it open
+    fn close_file(&mut self, client: protocol::ClientId, id: protocol::FileId) -> Result<(), ServerError> {
+        if let Some(file) = self.open_files.get_mut(&id) {
+            if file.lock == Some(client) { file.lock = None; }
         }
+        // someone else is still editing it
+        if self.open_elsewhere(client, id) {
+            return Ok(());
+        }
+        let mut file = self.open_files.remove(&id)
+            .ok_or(ServerError::BadFileId(id))?;
+        // even files that are only saved manually are written, like `flush_all` does, since nobody is left
+        // to write their changes
+        if file.is_dirty() {
+            file.save(&self.config)?;
+        }
+        Ok(())
     }
 
-    fn process_request(&mut self, msg: protocol::Request) -> Result<protocol::Response, ServerError> {
-        println!("request = {:?}", msg);
+    fn process_request(&mut self, client: protocol::ClientId, msg: protocol::Request) -> Result<protocol::Response, ServerError> {
         use protocol::*;
         match msg {
-            Request::OpenFile { path } => {
-                let (id, contents, version, format) = 
-                    if let Some((id, buf)) = self.open_files.iter().find(|b| b.1.path.as_ref().map(|p| *p == path).unwrap_or(false)) {
-                        (*id, buf.contents.clone(), buf.current_version, buf.format.clone())
+            Request::Shutdown => {
+                info!("shutdown requested by client");
+                self.shutting_down = true;
+                Ok(Response::Ack)
+            },
+            Request::Hello { name } => {
+                info!("client said hello client={} name={:?}", client.0, name);
+                self.clients.get_mut(&client).unwrap().name = name;
+                Ok(Response::Ack)
+            },
+            Request::UpdatePresence { id, cursor, selection } => {
+                if !self.open_files.contains_key(&id) {
+                    return Err(ServerError::BadFileId(id));
+                }
+                self.clients.get_mut(&client).unwrap().files.insert(id, (cursor, selection));
+                let cursors = self.clients.iter()
+                    .filter(|(c, p)| **c != client && !p.away)
+                    .filter_map(|(c, p)| p.files.get(&id).map(|(cursor, selection)| RemoteCursor {
+                        client: *c, name: p.name.clone(), cursor: *cursor, selection: *selection
+                    }))
+                    .collect();
+                Ok(Response::Presence { id, cursors, lock: self.file_lock(id) })
+            },
+            Request::LockFile { id } => {
+                if !self.open_files.contains_key(&id) {
+                    return Err(ServerError::BadFileId(id));
+                }
+                if let Some(by) = self.locked_by_other(client, id) {
+                    return Ok(Response::Locked { id, by });
+                }
+                info!("file locked client={} file={}", client.0, id.0);
+                self.open_files.get_mut(&id).unwrap().lock = Some(client);
+                Ok(Response::Ack)
+            },
+            Request::UnlockFile { id } => {
+                if !self.open_files.contains_key(&id) {
+                    return Err(ServerError::BadFileId(id));
+                }
+                if let Some(by) = self.locked_by_other(client, id) {
+                    return Ok(Response::Locked { id, by });
+                }
+                self.open_files.get_mut(&id).unwrap().lock = None;
+                Ok(Response::Ack)
+            },
+            Request::ServerStatus => {
+                let mut open_files: Vec<OpenFileStatus> = self.open_files.iter().map(|(id, f)| OpenFileStatus {
+                    id: *id,
+                    path: f.path.clone(),
+                    version: f.current_version,
+                    disk_version: f.disk_version,
+                    autosave: f.autosave,
+                    unsaved_for_ms: f.first_unsaved_change.filter(|_| f.is_dirty()).map(|t| t.elapsed().as_millis() as u64),
+                    lock: self.file_lock(*id)
+                }).collect();
+                open_files.sort_by_key(|f| f.id.0);
+                Ok(Response::ServerStatus(protocol::ServerStatus {
+                    uptime_secs: self.started.elapsed().as_secs(),
+                    open_files,
+                    workspaces: self.workspaces.len(),
+                    clients: self.clients.values().filter(|p| !p.away).map(|p| p.name.clone()).collect(),
+                    connected_clients: self.stats.connected_clients.load(std::sync::atomic::Ordering::SeqCst),
+                    requests: self.stats.requests,
+                    errors: self.stats.errors,
+                    write_errors: self.stats.write_errors
+                }))
+            },
+            Request::OpenWorkspace { root } => {
+                // opening a workspace again rereads its settings
+                let ws = Workspace::open(&root)?;
+                let id = match self.workspaces.iter().find(|(_, w)| w.root == ws.root) {
+                    Some((id, _)) => *id,
+                    None => {
+                        let id = self.next_workspace_id;
+                        self.next_workspace_id = protocol::WorkspaceId(id.0 + 1);
+                        id
                     }
-                    else {
-                        let buf = File::from_path(&path, &self.filetype_table)?;
+                };
+                let resp = Response::WorkspaceInfo { id, root: ws.root.clone(), name: ws.name.clone() };
+                self.workspaces.insert(id, ws);
+                Ok(resp)
+            },
+            Request::ListWorkspaceFiles { id } => {
+                let paths = self.workspaces.get(&id)
+                    .ok_or(ServerError::BadWorkspaceId(id))?
+                    .files()?;
+                Ok(Response::WorkspaceFiles { id, paths })
+            },
+            Request::OpenFile { path, workspace } => {
+                let path = match workspace {
+                    Some(wid) if path.is_relative() => self.workspaces.get(&wid)
+                        .ok_or(ServerError::BadWorkspaceId(wid))?.root.join(path),
+                    _ => path
+                };
+                let path = resolve_path(&path)?;
+                let workspace = self.workspace_for(&path);
+                let id = match self.open_files.iter().find(|b| b.1.path.as_ref().map(|p| *p == path).unwrap_or(false)) {
+                    Some((id, _)) => *id,
+                    None => {
+                        let project = workspace.map(|id| &self.workspaces[&id].settings);
+                        let buf = File::from_path(&path, &self.config, project)?;
                         let id = self.next_file_id;
                         self.next_file_id = protocol::FileId(self.next_file_id.0 + 1);
-                        let res = (id, buf.contents.clone(), buf.current_version, buf.format.clone());
                         self.open_files.insert(id, buf);
-                        res
-                    };
+                        id
+                    }
+                };
+                self.clients.get_mut(&client).unwrap().files.entry(id).or_insert((0, None));
+                let buf = &self.open_files[&id];
                 Ok(Response::FileInfo {
-                    id,
-                    contents,
-                    version,
-                    format
+                    id, path, workspace,
+                    contents: buf.contents.clone(),
+                    version: buf.current_version,
+                    format: buf.format.clone(),
+                    view: buf.view,
+                    window: buf.window
                 })
             },
             Request::SyncFile { id, new_text, version } => {
-                let file = self.open_files.get_mut(&id).ok_or_else(|| ServerError::BadFileId(id))?;
-                if file.current_version >= version {
+                if let Some(by) = self.locked_by_other(client, id) {
+                    return Ok(Response::Locked { id, by });
+                }
+                let file = self.open_files.get_mut(&id).ok_or(ServerError::BadFileId(id))?;
+                if file.read_only() {
+                    Err(ServerError::ReadOnly(id))
+                } else if file.current_version >= version {
+                    // the client made its changes to the version before the one it sent
+                    let base = version.checked_sub(1).ok_or(ServerError::BadVersion(id, version))?;
+                    let base_text = file.history.iter().find(|(v, _)| *v == base).map(|(_, t)| t.clone());
                     Ok(Response::VersionConflict {
                         id,
                         client_version_recieved: version,
                         server_version: file.current_version,
-                        server_text: file.contents.clone()
+                        server_text: file.contents.clone(),
+                        base_text
                     })
                 } else {
+                    if file.history.len() == VERSION_HISTORY_LEN {
+                        file.history.pop_front();
+                    }
+                    let old_text = std::mem::replace(&mut file.contents, new_text);
+                    file.history.push_back((file.current_version, old_text));
                     file.current_version = version;
-                    file.contents = new_text;
+                    file.last_change = std::time::Instant::now();
+                    file.first_unsaved_change.get_or_insert(file.last_change);
                     Ok(Response::Ack)
                 }
             },
             Request::CloseFile(id) => {
-                self.open_files.remove(&id)
-                    .ok_or_else(|| ServerError::BadFileId(id))?
-                    .write_to_disk()?;
+                self.clients.get_mut(&client).unwrap().files.remove(&id);
+                self.close_file(client, id)?;
+                Ok(Response::Ack)
+            },
+            Request::WriteFile { id } => {
+                if let Some(by) = self.locked_by_other(client, id) {
+                    return Ok(Response::Locked { id, by });
+                }
+                let file = self.open_files.get_mut(&id)
+                    .ok_or(ServerError::BadFileId(id))?;
+                if file.read_only() {
+                    return Err(ServerError::ReadOnly(id));
+                }
+                file.save(&self.config)?;
+                Ok(Response::Ack)
+            },
+            Request::SetTextFormat { id, format } => {
+                if let Some(by) = self.locked_by_other(client, id) {
+                    return Ok(Response::Locked { id, by });
+                }
+                let file = self.open_files.get_mut(&id)
+                    .ok_or(ServerError::BadFileId(id))?;
+                if file.read_only() {
+                    return Err(ServerError::ReadOnly(id));
+                }
+                // refuse conversions that would lose text, before anything gets written
+                format.encoding.encode(&file.contents, false)?;
+                file.format = format;
+                Ok(Response::Ack)
+            },
+            Request::ReadFileWindow { id, offset, len } => {
+                let file = self.open_files.get_mut(&id)
+                    .ok_or(ServerError::BadFileId(id))?;
+                let (contents, window) = file.read_window(offset, len)?;
+                // the client has all of the file now, so it can be edited like any other
+                if window.complete() && file.window.is_some() && file.view == protocol::FileView::Text {
+                    file.contents = file.disk_text()?;
+                    file.window = None;
+                }
+                Ok(Response::FileWindow { id, contents, window })
+            },
+            Request::ReadFileText { id, from_disk } => {
+                let file = self.open_files.get(&id)
+                    .ok_or(ServerError::BadFileId(id))?;
+                if file.read_only() {
+                    return Err(ServerError::ReadOnly(id));
+                }
+                let contents = if from_disk { file.disk_text()? } else { file.contents.clone() };
+                Ok(Response::FileText { id, version: file.current_version, contents })
+            },
+            Request::SetAutosavePolicy { id, policy } => {
+                self.open_files.get_mut(&id)
+                    .ok_or(ServerError::BadFileId(id))?
+                    .autosave = policy;
                 Ok(Response::Ack)
             },
             _ => Err(ServerError::UnknownMessage)
         }
     }
 
+    // nobody is using the server. Files left open by clients that went away don't count, since
+    // everything is written out before the server exits
+    fn is_idle(&self) -> bool {
+        self.stats.connected_clients.load(std::sync::atomic::Ordering::SeqCst) == 0
+    }
+
+    // write every file that has changes, even ones that are only saved when the user asks, since
+    // they would be lost otherwise
+    fn flush_all(&mut self) {
+        for file in self.open_files.values_mut() {
+            if !file.is_dirty() { continue; }
+            if let Err(e) = file.save(&self.config) {
+                self.stats.write_errors += 1;
+                error!("error writing file path={:?} error=\"{}\"", file.path, e);
+            }
+        }
+    }
+
+    // note that a client is still around, and mark the ones that haven't been heard from in a while as away
+    fn client_seen(&mut self, client: protocol::ClientId) {
+        let now = std::time::Instant::now();
+        let gone: Vec<protocol::ClientId> = self.clients.iter()
+            .filter(|(c, p)| **c != client && !p.away && now.duration_since(p.last_seen) >= PRESENCE_TIMEOUT)
+            .map(|(c, _)| *c)
+            .collect();
+        for c in gone {
+            let p = self.clients.get_mut(&c).unwrap();
+            info!("client went away client={} name={:?}", c.0, p.name);
+            p.away = true;
+            self.release_locks(c);
+        }
+        let p = self.clients.entry(client)
+            .or_insert_with(|| ClientPresence { name: format!("client {}", client.0), last_seen: now, away: false, files: HashMap::new() });
+        p.last_seen = now;
+        p.away = false;
+    }
+
+    fn request_done(&mut self, summary: &str, start: std::time::Instant, res: Result<protocol::Response, ServerError>) -> protocol::Response {
+        match res {
+            Ok(resp) => {
+                debug!("request {} elapsed_us={}", summary, start.elapsed().as_micros());
+                resp
+            },
+            Err(err) => {
+                self.stats.errors += 1;
+                warn!("request failed {} error=\"{}\"", summary, err);
+                protocol::Response::Error { message: format!("{}", err) }
+            }
+        }
+    }
+
+    // process a request, keeping track of how it went
+    fn handle_request(&mut self, client: protocol::ClientId, msg: protocol::Request) -> protocol::Response {
+        if self.shutting_down {
+            return protocol::Response::ShuttingDown;
+        }
+        self.client_seen(client);
+        let summary = format!("client={} {}", client.0, describe_request(&msg));
+        let start = std::time::Instant::now();
+        self.stats.requests += 1;
+        let res = self.process_request(client, msg);
+        self.request_done(&summary, start, res)
+    }
+
+    // handle a request that does slow io without holding the server lock while the io happens. `prepare`
+    // runs with the lock held and gets what the io needs, then `finish` does the io
+    fn handle_unlocked<T>(server: &RwLock<Self>, client: protocol::ClientId, msg: &protocol::Request,
+                          prepare: impl FnOnce(&mut Self) -> Result<T, ServerError>,
+                          finish: impl FnOnce(T) -> Result<protocol::Response, ServerError>) -> protocol::Response
+    {
+        let summary = format!("client={} {}", client.0, describe_request(msg));
+        let start = std::time::Instant::now();
+        let prepared = {
+            let mut srv = server.write().unwrap();
+            if srv.shutting_down {
+                return protocol::Response::ShuttingDown;
+            }
+            srv.client_seen(client);
+            srv.stats.requests += 1;
+            match prepare(&mut srv) {
+                Ok(p) => p,
+                Err(e) => return srv.request_done(&summary, start, Err(e))
+            }
+        };
+        let res = finish(prepared);
+        server.write().unwrap().request_done(&summary, start, res)
+    }
+
+    // walking a large workspace takes a while
+    fn list_workspace_files(server: &RwLock<Self>, client: protocol::ClientId, id: protocol::WorkspaceId) -> protocol::Response {
+        Server::handle_unlocked(server, client, &protocol::Request::ListWorkspaceFiles { id },
+            |srv| srv.workspaces.get(&id).cloned().ok_or(ServerError::BadWorkspaceId(id)),
+            |ws| Ok(protocol::Response::WorkspaceFiles { id, paths: ws.files()? }))
+    }
+
+    // so does reading a large file
+    fn read_disk_text(server: &RwLock<Self>, client: protocol::ClientId, id: protocol::FileId) -> protocol::Response {
+        Server::handle_unlocked(server, client, &protocol::Request::ReadFileText { id, from_disk: true },
+            |srv| {
+                let file = srv.open_files.get(&id).ok_or(ServerError::BadFileId(id))?;
+                if file.read_only() {
+                    return Err(ServerError::ReadOnly(id));
+                }
+                Ok((file.path.clone(), file.disk_format.clone(), file.current_version))
+            },
+            |(path, format, version)| Ok(protocol::Response::FileText { id, version, contents: read_text(path.as_deref(), &format)? }))
+    }
+
     fn callback(server: Arc<RwLock<Self>>, aio: &nng::Aio, cx: &nng::Context, res: nng::AioResult) {
         match res {
-            nng::AioResult::Send(Ok(_)) => while let Err(e) = cx.recv(aio) { println!("error recieving message {}", e); },
+            // once the socket is closed for shutdown, everything fails with Closed and the worker is done
+            nng::AioResult::Send(Err((_, nng::Error::Closed))) | nng::AioResult::Recv(Err(nng::Error::Closed)) => {},
+            nng::AioResult::Send(Ok(_)) => while let Err(e) = cx.recv(aio) {
+                if let nng::Error::Closed = e { break; }
+                error!("error recieving message error=\"{}\"", e);
+            },
+            nng::AioResult::Send(Err((_, e))) => {
+                error!("error sending response error=\"{}\"", e);
+                let _ = cx.recv(aio);
+            },
             nng::AioResult::Recv(Ok(raw_msg)) => {
                 let resp = serde_cbor::from_slice(raw_msg.as_slice())
                     .map(|req: protocol::MsgRequest| protocol::MsgResponse {
                         req_id: req.msg_id,
-                        msg: server.write().unwrap()
-                                .process_request(req.msg)
-                                .unwrap_or_else(|err| protocol::Response::Error{message: format!("{}", err)})
-                    }).unwrap_or_else(|err| protocol::MsgResponse {
-                        req_id: protocol::MessageId(0),
-                        msg: protocol::Response::Error { message: format!("error decoding request {}", err) }
+                        msg: match req.msg {
+                            protocol::Request::ListWorkspaceFiles { id } => Server::list_workspace_files(&server, req.client, id),
+                            protocol::Request::ReadFileText { id, from_disk: true } => Server::read_disk_text(&server, req.client, id),
+                            msg => server.write().unwrap().handle_request(req.client, msg)
+                        }
+                    }).unwrap_or_else(|err| {
+                        warn!("error decoding request error=\"{}\"", err);
+                        protocol::MsgResponse {
+                            req_id: protocol::MessageId(0),
+                            msg: protocol::Response::Error { message: format!("error decoding request {}", err) }
+                        }
                     });
-                // println!("response = {:?}", resp);
                 let mut msg = nng::Message::new().expect("create message");
                 serde_cbor::to_writer(&mut msg, &resp).expect("serialize message");
-                cx.send(aio, msg).unwrap();
+                if let Err((_, e)) = cx.send(aio, msg) {
+                    error!("error sending response error=\"{}\"", e);
+                }
             },
-            nng::AioResult::Recv(Err(e)) => { println!("error on recv {}", e); cx.recv(aio).unwrap(); },
+            nng::AioResult::Recv(Err(e)) => { error!("error on recv error=\"{}\"", e); cx.recv(aio).unwrap(); },
             _ => panic!()
         }
     }
 }
 
+// a short description of a request for the log, leaving out the contents of files
+fn describe_request(req: &protocol::Request) -> String {
+    use protocol::Request::*;
+    match req {
+        ServerStatus => String::from("kind=ServerStatus"),
+        Shutdown => String::from("kind=Shutdown"),
+        Hello { name } => format!("kind=Hello name={:?}", name),
+        UpdatePresence { id, cursor, .. } => format!("kind=UpdatePresence file={} cursor={}", id.0, cursor),
+        LockFile { id } => format!("kind=LockFile file={}", id.0),
+        UnlockFile { id } => format!("kind=UnlockFile file={}", id.0),
+        OpenWorkspace { root } => format!("kind=OpenWorkspace root={:?}", root),
+        ListWorkspaceFiles { id } => format!("kind=ListWorkspaceFiles workspace={}", id.0),
+        OpenFile { path, workspace } => format!("kind=OpenFile path={:?} workspace={:?}", path, workspace.map(|w| w.0)),
+        SyncFile { id, new_text, version } => format!("kind=SyncFile file={} version={} len={}", id.0, version, new_text.len()),
+        ReloadFile(id) => format!("kind=ReloadFile file={}", id.0),
+        CloseFile(id) => format!("kind=CloseFile file={}", id.0),
+        WriteFile { id } => format!("kind=WriteFile file={}", id.0),
+        SetTextFormat { id, format } => format!("kind=SetTextFormat file={} format=\"{}\"", id.0, format),
+        SetAutosavePolicy { id, policy } => format!("kind=SetAutosavePolicy file={} policy={:?}", id.0, policy),
+        ReadFileWindow { id, offset, len } => format!("kind=ReadFileWindow file={} offset={} len={}", id.0, offset, len),
+        ReadFileText { id, from_disk } => format!("kind=ReadFileText file={} from_disk={}", id.0, from_disk),
+    }
+}
+
+// writes log records as `<unix time> <level> <message>` lines, where messages are a short description
+// followed by `key=value` pairs
+struct FileLogger {
+    level: log::LevelFilter,
+    out: std::sync::Mutex<Box<dyn std::io::Write + Send>>
+}
+
+impl FileLogger {
+    // log to `path`, or to stderr if there isn't one or it can't be opened
+    fn init(path: Option<&Path>, level: log::LevelFilter) {
+        let file = path.map(|p| {
+            if let Some(dir) = p.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+            std::fs::OpenOptions::new().create(true).append(true).open(p)
+        });
+        let out: Box<dyn std::io::Write + Send> = match file {
+            Some(Ok(f)) => Box::new(f),
+            Some(Err(e)) => {
+                eprintln!("error opening log file {}, logging to stderr: {}", path.unwrap().to_string_lossy(), e);
+                Box::new(std::io::stderr())
+            },
+            None => Box::new(std::io::stderr())
+        };
+        let logger = FileLogger { level, out: std::sync::Mutex::new(out) };
+        if log::set_logger(Box::leak(Box::new(logger))).is_ok() {
+            log::set_max_level(level);
+        }
+    }
+}
+
+impl log::Log for FileLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &log::Record) {
+        use std::io::Write;
+        if !self.enabled(record.metadata()) { return; }
+        let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default();
+        let mut out = self.out.lock().unwrap();
+        let _ = writeln!(out, "{}.{:03} {:5} {}", now.as_secs(), now.subsec_millis(), record.level(), record.args());
+    }
+
+    fn flush(&self) {
+        use std::io::Write;
+        let _ = self.out.lock().unwrap().flush();
+    }
+}
+
 struct AutosaveWorker {
-    server: Arc<RwLock<Server>>,
-    disk_versions: HashMap<protocol::FileId, usize>
+    server: Arc<RwLock<Server>>
 }
 
 impl AutosaveWorker {
     fn new(server: Arc<RwLock<Server>>) -> AutosaveWorker {
         AutosaveWorker {
-            server, disk_versions: HashMap::new()
+            server
         }
     }
 
     fn run(&mut self) {
         loop {
             std::thread::sleep(std::time::Duration::from_secs(1));
+            self.save_due();
+        }
+    }
+
+    // write out every file whose autosave is due. Writing syncs the file to disk, which can be slow, so
+    // the server is only locked to copy out what to write and to record it afterwards
+    fn save_due(&self) {
+        let (due, backups) = {
             let srv = self.server.read().unwrap();
-            for (file_id, file) in srv.open_files.iter() {
-                let disk_version = self.disk_versions.entry(*file_id).or_insert(0);
-                if *disk_version < file.current_version {
-                    println!("save v{} < v{} - {:?}", *disk_version,
-                             file.current_version, file.path.as_ref());
-                    match file.write_to_disk() {
-                        Ok(()) => *disk_version = file.current_version,
-                        Err(e) => {
-                            println!("error syncing {} to disk: {}",
-                                     file.path.as_ref().and_then(|p| p.to_str()).unwrap_or(""), e);
-                        }
-                    }
+            let due = srv.open_files.iter()
+                .filter(|(_, file)| file.needs_autosave())
+                .map(|(id, file)| {
+                    debug!("autosave path={:?} disk_version={} version={}", file.path, file.disk_version, file.current_version);
+                    (*id, file.path.clone(), file.contents.clone(), file.format.clone(), file.current_version)
+                })
+                .collect::<Vec<_>>();
+            (due, srv.config.backups)
+        };
+        for (id, path, contents, format, version) in due {
+            let result = match path.as_ref() {
+                Some(path) => {
+                    info!("writing file path={:?} version={}", path, version);
+                    encode_text(&contents, &format).and_then(|data| write_atomic(path, &data, backups))
+                },
+                None => Ok(())
+            };
+            let mut srv = self.server.write().unwrap();
+            if let Err(e) = result {
+                srv.stats.write_errors += 1;
+                error!("error writing file path={:?} error=\"{}\"", path, e);
+                continue;
+            }
+            if let Some(file) = srv.open_files.get_mut(&id) {
+                // this is what's on disk now, even if a newer version was written while this one was
+                file.disk_version = version;
+                file.disk_format = format;
+                file.last_write = std::time::Instant::now();
+                // the file only becomes clean if nothing changed while it was being written
+                if file.current_version == version {
+                    file.first_unsaved_change = None;
                 }
             }
         }
     }
 }
 
+// how long to keep telling clients that the server is shutting down before closing the socket
+const SHUTDOWN_GRACE_PERIOD: std::time::Duration = std::time::Duration::from_millis(500);
+
 fn main() -> Result<(), ServerError> {
-    let server_address = std::env::args().skip(1).next().expect("require nng url to listen on");
+    let mut args = pico_args::Arguments::from_env();
+    let overrides = ConfigOverrides::from_args(&mut args)?;
+    let config_path = match args.opt_value_from_str::<_, PathBuf>("--config")
+        .map_err(|e| ServerError::ConfigError(format!("--config: {}", e)))?
+    {
+        Some(p) => p,
+        None => directories_next::ProjectDirs::from("", "", "pk")
+            .ok_or_else(|| ServerError::ConfigError(String::from("could not find configuration directory")))?
+            .config_dir().join("server.toml")
+    };
+    let log_level: log::LevelFilter = args.opt_value_from_str("--log-level")
+        .map_err(|e| ServerError::ConfigError(format!("--log-level: {}", e)))?
+        .unwrap_or(log::LevelFilter::Info);
+    let log_path = match args.opt_value_from_str::<_, PathBuf>("--log-file")
+        .map_err(|e| ServerError::ConfigError(format!("--log-file: {}", e)))?
+    {
+        Some(p) => Some(p),
+        None => directories_next::ProjectDirs::from("", "", "pk").map(|pd| pd.data_dir().join("server.log"))
+    };
+    FileLogger::init(log_path.as_deref(), log_level);
+    let config = ServerConfig::load(&config_path, &overrides)?;
+    let server_address = args.free()
+        .map_err(|e| ServerError::ConfigError(format!("command line: {}", e)))?
+        .into_iter().next().unwrap_or_else(pk_common::local_server_url);
 
     let socket = nng::Socket::new(nng::Protocol::Rep0)?;
 
     //let pool = threadpool::ThreadPool::new(8);
-    let filetype_table = toml::from_str(&std::fs::read_to_string("./filetypes.toml")?).expect("parse filetype table");
-    //println!("filetypes = {:?}", filetype_table);
-    let server = Arc::new(RwLock::new(Server::new(filetype_table)));
+    let server = Arc::new(RwLock::new(Server::new(config)));
+
+    let connected_clients = server.read().unwrap().stats.connected_clients.clone();
+    socket.pipe_notify(move |_, ev| {
+        use std::sync::atomic::Ordering;
+        match ev {
+            nng::PipeEvent::AddPost => { connected_clients.fetch_add(1, Ordering::SeqCst); },
+            nng::PipeEvent::RemovePost => { connected_clients.fetch_sub(1, Ordering::SeqCst); },
+            _ => {}
+        }
+    })?;
 
     let ts = (0..8).map(|_| {
         let cx = nng::Context::new(&socket)?;
@@ -279,14 +1526,14 @@ fn main() -> Result<(), ServerError> {
 
     }).collect::<Vec<nng::Result<_>>>();
     
-    println!("listening on {}", &server_address);
+    info!("listening url={}", &server_address);
 
     socket.listen(&server_address)?;
 
     for w in ts.iter() {
         match w {
             Ok((aio, cx)) => cx.recv(aio)?,
-            Err(e) => println!("error starting worker thread {}", e)
+            Err(e) => error!("error starting worker thread error=\"{}\"", e)
         }
     }
 
@@ -295,7 +1542,491 @@ fn main() -> Result<(), ServerError> {
         autosave_worker.run();
     });
 
-    std::thread::park();
+    let mut config_watcher = ConfigWatcher::new(server.clone(), config_path, overrides);
+    std::thread::spawn(move || {
+        config_watcher.run();
+    });
+
+    let signalled = Arc::new(std::sync::atomic::AtomicBool::new(false));
+    let sig = signalled.clone();
+    if let Err(e) = ctrlc::set_handler(move || sig.store(true, std::sync::atomic::Ordering::SeqCst)) {
+        warn!("could not install signal handler, files may not be written if the server is killed error=\"{}\"", e);
+    }
 
+    let mut idle_since: Option<std::time::Instant> = None;
+    loop {
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        if signalled.load(std::sync::atomic::Ordering::SeqCst) {
+            info!("shutdown requested by signal");
+            server.write().unwrap().shutting_down = true;
+        }
+        let mut srv = server.write().unwrap();
+        if srv.shutting_down { break; }
+        if let Some(idle_exit) = srv.config.idle_exit {
+            if srv.is_idle() {
+                let since = *idle_since.get_or_insert_with(std::time::Instant::now);
+                if since.elapsed().as_secs() >= idle_exit {
+                    info!("shutting down after being idle idle_secs={}", idle_exit);
+                    srv.shutting_down = true;
+                }
+            } else {
+                idle_since = None;
+            }
+        }
+    }
+
+    // keep answering for a moment so that clients with requests in flight find out that the server is
+    // going away instead of timing out
+    std::thread::sleep(SHUTDOWN_GRACE_PERIOD);
+    for (_, cx) in ts.into_iter().flatten() {
+        cx.close();
+    }
+    socket.close();
+
+    server.write().unwrap().flush_all();
+    info!("shut down");
+    log::logger().flush();
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use protocol::{Request, Response, AutosavePolicy};
+
+    const CLIENT: protocol::ClientId = protocol::ClientId(1);
+
+    // an empty directory for a test to put files in
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("pk-server-test-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn no_overrides() -> ConfigOverrides {
+        ConfigOverrides { backups: false, page_threshold: None, idle_exit: None }
+    }
+
+    fn test_server() -> Server {
+        Server::new(ServerConfig::load(Path::new("/nonexistent/server.toml"), &no_overrides()).unwrap())
+    }
+
+    fn open(srv: &mut Server, client: protocol::ClientId, path: &Path) -> protocol::FileId {
+        match srv.handle_request(client, Request::OpenFile { path: path.into(), workspace: None }) {
+            Response::FileInfo { id, .. } => id,
+            r => panic!("unexpected response {:?}", r)
+        }
+    }
+
+    fn sync(srv: &mut Server, client: protocol::ClientId, id: protocol::FileId, text: &str, version: usize) -> Response {
+        srv.handle_request(client, Request::SyncFile { id, new_text: text.into(), version })
+    }
+
+    #[test]
+    fn config_defaults() {
+        let config = test_server().config;
+        assert!(!config.backups);
+        assert_eq!(config.page_threshold, 16 * 1024 * 1024);
+        assert_eq!(config.idle_exit, None);
+        let a = config.filetype_table.analyze("a.rs", "", None);
+        assert_eq!(a.stype, protocol::FileType::from("rust"));
+        assert_eq!(a.settings.line_comment.as_deref(), Some("//"));
+    }
+
+    #[test]
+    fn config_layering() {
+        let dir = test_dir("config-layering");
+        let path = dir.join("server.toml");
+        std::fs::write(&path, r#"
+            page-threshold = 1000
+            idle-exit = 60
+            [[filetype]]
+            name = "rust"
+            ext = ["rs", "rlib"]
+            tabstop = 2
+            [[filetype]]
+            name = "ini"
+            ext = ["ini"]
+            line_comment = ";"
+        "#).unwrap();
+        let config = ServerConfig::load(&path, &no_overrides()).unwrap();
+        assert!(!config.backups);
+        assert_eq!(config.page_threshold, 1000);
+        assert_eq!(config.idle_exit, Some(60));
+        // the user's rust replaces the default one entirely
+        let a = config.filetype_table.analyze("a.rlib", "", None);
+        assert_eq!(a.stype, protocol::FileType::from("rust"));
+        assert_eq!(a.settings.tabstop, Some(2));
+        assert_eq!(a.settings.line_comment, None);
+        // new types are added, and the other defaults stay
+        assert_eq!(config.filetype_table.analyze("a.ini", "", None).stype, protocol::FileType::from("ini"));
+        assert_eq!(config.filetype_table.analyze("a.py", "", None).stype, protocol::FileType::from("python"));
+
+        let config = ServerConfig::load(&path, &ConfigOverrides { backups: true, page_threshold: Some(5), idle_exit: Some(0) }).unwrap();
+        assert!(config.backups);
+        assert_eq!(config.page_threshold, 5);
+        assert_eq!(config.idle_exit, None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn config_errors() {
+        let dir = test_dir("config-errors");
+        let path = dir.join("server.toml");
+        for src in ["page-threshold = 0", "no-such-option = 1", "[[filetype]]\nname = \"x\"\ntabstop = 0", "backups = 1"] {
+            std::fs::write(&path, src).unwrap();
+            assert!(matches!(ServerConfig::load(&path, &no_overrides()), Err(ServerError::ConfigError(_))), "{}", src);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn workspace_ignore() {
+        let mut ignore = vec![String::from("*.log"), String::from("build/out"), String::from("secret?")];
+        ignore.extend(DEFAULT_IGNORE.iter().map(|s| String::from(*s)));
+        let ws = Workspace { root: PathBuf::from("/w"), name: String::from("w"), ignore, settings: ProjectSettings::default() };
+        for p in ["target", "a/target/debug/x", ".git/HEAD", "src/node_modules/m.js", "a.rs.pk~", "logs/x.log",
+                  "build/out", "secret1", "a/secret2/b"] {
+            assert!(ws.is_ignored(Path::new(p)), "{}", p);
+        }
+        for p in ["src/main.rs", "targets", "a.log.txt", "build", "build/src", "secret", "secret12", "my.git"] {
+            assert!(!ws.is_ignored(Path::new(p)), "{}", p);
+        }
+    }
+
+    #[test]
+    fn workspace_files() {
+        let dir = test_dir("workspace-files");
+        std::fs::create_dir_all(dir.join("src/deep")).unwrap();
+        std::fs::create_dir_all(dir.join("target/debug")).unwrap();
+        for f in ["src/main.rs", "src/deep/x.rs", "target/debug/pk", "README.md", "notes.tmp"] {
+            std::fs::write(dir.join(f), "").unwrap();
+        }
+        std::fs::write(dir.join(".pk.toml"), "ignore = [\"*.tmp\"]\n").unwrap();
+        let server = RwLock::new(test_server());
+        let id = match server.write().unwrap().handle_request(CLIENT, Request::OpenWorkspace { root: dir.clone() }) {
+            Response::WorkspaceInfo { id, .. } => id,
+            r => panic!("unexpected response {:?}", r)
+        };
+        match Server::list_workspace_files(&server, CLIENT, id) {
+            Response::WorkspaceFiles { paths, .. } => assert_eq!(paths, [".pk.toml", "README.md", "src/deep/x.rs", "src/main.rs"]
+                .iter().map(PathBuf::from).collect::<Vec<_>>()),
+            r => panic!("unexpected response {:?}", r)
+        }
+        let bad = protocol::WorkspaceId(id.0 + 1);
+        assert!(matches!(Server::list_workspace_files(&server, CLIENT, bad), Response::Error { .. }));
+        assert_eq!(server.read().unwrap().stats.errors, 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // a log destination that can be looked at after the logger is done with it
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> { self.0.lock().unwrap().write(buf) }
+        fn flush(&mut self) -> std::io::Result<()> { Ok(()) }
+    }
+
+    #[test]
+    fn logger() {
+        use log::Log;
+        let buf = SharedBuf::default();
+        let logger = FileLogger { level: log::LevelFilter::Info, out: std::sync::Mutex::new(Box::new(buf.clone())) };
+        let record = |level, msg| logger.log(&log::Record::builder().level(level).args(format_args!("{}", msg)).build());
+        record(log::Level::Error, "broken thing=1");
+        record(log::Level::Warn, "careful");
+        record(log::Level::Info, "hello client=2");
+        record(log::Level::Debug, "too much");
+        record(log::Level::Trace, "far too much");
+        assert!(logger.enabled(&log::Metadata::builder().level(log::Level::Info).build()));
+        assert!(!logger.enabled(&log::Metadata::builder().level(log::Level::Debug).build()));
+
+        let out = String::from_utf8(buf.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines.len(), 3);
+        for (ln, rest) in lines.iter().zip(["ERROR broken thing=1", "WARN  careful", "INFO  hello client=2"]) {
+            let (time, msg) = ln.split_once(' ').unwrap();
+            assert_eq!(msg, rest);
+            let (secs, millis) = time.split_once('.').unwrap();
+            assert!(secs.parse::<u64>().unwrap() > 0);
+            assert_eq!(millis.len(), 3);
+            assert!(millis.parse::<u32>().is_ok());
+        }
+    }
+
+    #[test]
+    fn autosave_policies() {
+        let dir = test_dir("autosave-policies");
+        let srv = test_server();
+        let mut file = File::from_path(dir.join("a.txt"), &srv.config, None).unwrap();
+        assert!(!file.needs_autosave(), "nothing changed");
+        file.current_version = 1;
+        file.autosave = AutosavePolicy::Interval(0);
+        assert!(file.needs_autosave());
+        file.autosave = AutosavePolicy::OnIdle(0);
+        assert!(file.needs_autosave());
+        file.autosave = AutosavePolicy::OnIdle(60);
+        assert!(!file.needs_autosave(), "changed too recently");
+        file.autosave = AutosavePolicy::Manual;
+        assert!(!file.needs_autosave());
+        let cf = ConfigFile::parse("[[filetype]]\nname = \"notes\"\next = [\"note\"]\nautosave = { on_idle = 5 }\n", "test").unwrap();
+        let table = FileTypeTable::new(cf.filetype);
+        assert_eq!(table.analyze("x.note", "", None).autosave, AutosavePolicy::OnIdle(5));
+        assert_eq!(table.analyze("x.other", "", None).autosave, AutosavePolicy::default());
+    }
+
+    #[test]
+    fn autosave_worker() {
+        let dir = test_dir("autosave-worker");
+        let path = dir.join("a.txt");
+        let mut srv = test_server();
+        let id = open(&mut srv, CLIENT, &path);
+        srv.handle_request(CLIENT, Request::SetAutosavePolicy { id, policy: AutosavePolicy::Interval(0) });
+        sync(&mut srv, CLIENT, id, "saved\n", 1);
+        let server = Arc::new(RwLock::new(srv));
+        let worker = AutosaveWorker::new(server.clone());
+        worker.save_due();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "saved\n");
+        assert!(!server.read().unwrap().open_files[&id].is_dirty());
+        worker.save_due();
+        assert_eq!(server.read().unwrap().stats.write_errors, 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn write_and_close() {
+        let dir = test_dir("write-and-close");
+        let path = dir.join("a.txt");
+        std::fs::write(&path, "old\n").unwrap();
+        let mut srv = test_server();
+        let id = open(&mut srv, CLIENT, &path);
+        srv.handle_request(CLIENT, Request::SetAutosavePolicy { id, policy: AutosavePolicy::Manual });
+        assert!(matches!(sync(&mut srv, CLIENT, id, "new\n", 1), Response::Ack));
+        assert!(matches!(srv.handle_request(CLIENT, Request::WriteFile { id }), Response::Ack));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "new\n");
+        assert!(!srv.open_files[&id].is_dirty());
+
+        // closing writes changes that haven't been, even to manually saved files
+        sync(&mut srv, CLIENT, id, "unsaved\n", 2);
+        srv.handle_request(CLIENT, Request::CloseFile(id));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "unsaved\n");
+        let id = open(&mut srv, CLIENT, &path);
+        sync(&mut srv, CLIENT, id, "closed\n", 1);
+        srv.handle_request(CLIENT, Request::CloseFile(id));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "closed\n");
+        assert!(srv.open_files.is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn flush_all_writes_everything() {
+        let dir = test_dir("flush-all");
+        let (a, b) = (dir.join("a.txt"), dir.join("b.txt"));
+        let mut srv = test_server();
+        let ida = open(&mut srv, CLIENT, &a);
+        let idb = open(&mut srv, CLIENT, &b);
+        srv.handle_request(CLIENT, Request::SetAutosavePolicy { id: ida, policy: AutosavePolicy::Manual });
+        sync(&mut srv, CLIENT, ida, "a\n", 1);
+        sync(&mut srv, CLIENT, idb, "b\n", 1);
+        srv.flush_all();
+        assert_eq!(std::fs::read_to_string(&a).unwrap(), "a\n");
+        assert_eq!(std::fs::read_to_string(&b).unwrap(), "b\n");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn idle_with_files_left_open() {
+        let dir = test_dir("idle");
+        let mut srv = test_server();
+        assert!(srv.is_idle());
+        srv.stats.connected_clients.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        open(&mut srv, CLIENT, &dir.join("a.txt"));
+        assert!(!srv.is_idle());
+        srv.stats.connected_clients.fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+        assert!(srv.is_idle());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn read_file_text() {
+        let dir = test_dir("read-file-text");
+        let path = dir.join("a.txt");
+        std::fs::write(&path, "on disk\r\n").unwrap();
+        let server = RwLock::new(test_server());
+        let id = open(&mut server.write().unwrap(), CLIENT, &path);
+        sync(&mut server.write().unwrap(), CLIENT, id, "edited\n", 1);
+        match Server::read_disk_text(&server, CLIENT, id) {
+            Response::FileText { contents, version, .. } => assert_eq!((contents.as_str(), version), ("on disk\n", 1)),
+            r => panic!("unexpected response {:?}", r)
+        }
+        match server.write().unwrap().handle_request(CLIENT, Request::ReadFileText { id, from_disk: false }) {
+            Response::FileText { contents, .. } => assert_eq!(contents, "edited\n"),
+            r => panic!("unexpected response {:?}", r)
+        }
+        assert!(matches!(Server::read_disk_text(&server, CLIENT, protocol::FileId(id.0 + 1)), Response::Error { .. }));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn sync_version_zero() {
+        let dir = test_dir("sync-version-zero");
+        let mut srv = test_server();
+        let id = open(&mut srv, CLIENT, &dir.join("a.txt"));
+        assert!(matches!(sync(&mut srv, CLIENT, id, "x", 0), Response::Error { .. }));
+        assert!(matches!(sync(&mut srv, CLIENT, id, "x", 1), Response::Ack));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn locks() {
+        let dir = test_dir("locks");
+        let path = dir.join("a.txt");
+        let other = protocol::ClientId(2);
+        let mut srv = test_server();
+        let id = open(&mut srv, CLIENT, &path);
+        open(&mut srv, other, &path);
+        let locked = |r: Response| matches!(r, Response::Locked { by, .. } if by.client == CLIENT);
+
+        assert!(matches!(srv.handle_request(CLIENT, Request::LockFile { id }), Response::Ack));
+        // locking again is fine for the holder and not for anyone else
+        assert!(matches!(srv.handle_request(CLIENT, Request::LockFile { id }), Response::Ack));
+        assert!(locked(srv.handle_request(other, Request::LockFile { id })));
+        assert!(locked(srv.handle_request(other, Request::UnlockFile { id })));
+        assert!(locked(sync(&mut srv, other, id, "theirs\n", 1)));
+        assert!(locked(srv.handle_request(other, Request::WriteFile { id })));
+        let format = srv.open_files[&id].format.clone();
+        assert!(locked(srv.handle_request(other, Request::SetTextFormat { id, format })));
+        assert!(matches!(sync(&mut srv, CLIENT, id, "mine\n", 1), Response::Ack));
+        assert!(matches!(srv.handle_request(CLIENT, Request::WriteFile { id }), Response::Ack));
+
+        assert!(matches!(srv.handle_request(CLIENT, Request::UnlockFile { id }), Response::Ack));
+        assert!(matches!(sync(&mut srv, other, id, "theirs\n", 2), Response::Ack));
+        assert!(matches!(srv.handle_request(other, Request::LockFile { id }), Response::Ack));
+        // closing the file lets go of the lock
+        srv.handle_request(other, Request::CloseFile(id));
+        assert_eq!(srv.open_files[&id].lock, None);
+        assert!(matches!(srv.handle_request(CLIENT, Request::LockFile { id: protocol::FileId(id.0 + 100) }), Response::Error { .. }));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn away_client_keeps_files() {
+        let dir = test_dir("away-client");
+        let other = protocol::ClientId(2);
+        let (shared, own) = (dir.join("shared.txt"), dir.join("own.txt"));
+        let mut srv = test_server();
+        let ids = open(&mut srv, CLIENT, &shared);
+        open(&mut srv, other, &shared);
+        let ido = open(&mut srv, other, &own);
+        sync(&mut srv, other, ido, "unsaved\n", 1);
+        srv.handle_request(other, Request::LockFile { id: ids });
+        srv.handle_request(other, Request::UpdatePresence { id: ids, cursor: 0, selection: None });
+        srv.clients.get_mut(&other).unwrap().last_seen = std::time::Instant::now().checked_sub(PRESENCE_TIMEOUT).unwrap();
+
+        // the silent client's lock and cursor are gone, but not its files
+        match srv.handle_request(CLIENT, Request::UpdatePresence { id: ids, cursor: 0, selection: None }) {
+            Response::Presence { cursors, lock, .. } => assert!(cursors.is_empty() && lock.is_none()),
+            r => panic!("unexpected response {:?}", r)
+        }
+        assert!(srv.clients[&other].away);
+        assert_eq!(srv.open_files.len(), 2);
+        srv.handle_request(CLIENT, Request::CloseFile(ids));
+        assert!(srv.open_files.contains_key(&ids), "still open for the client that went away");
+        // once it's back, the edits it made while offline still sync
+        assert!(matches!(sync(&mut srv, other, ido, "edited offline\n", 2), Response::Ack));
+        assert!(matches!(sync(&mut srv, other, ids, "shared edit\n", 1), Response::Ack));
+        assert!(!srv.clients[&other].away);
+        assert_eq!(srv.open_files[&ido].contents, "edited offline\n");
+        assert_eq!(srv.open_files[&ids].contents, "shared edit\n");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn write_atomic_overwrites() {
+        let dir = test_dir("write-atomic");
+        let path = dir.join("a.txt");
+        write_atomic(&path, b"first", false).unwrap();
+        write_atomic(&path, b"second", false).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"second");
+        let names: Vec<_> = std::fs::read_dir(&dir).unwrap().map(|e| e.unwrap().file_name()).collect();
+        assert_eq!(names, vec![std::ffi::OsString::from("a.txt")], "no temporary file or backup left behind");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn write_atomic_backup() {
+        let dir = test_dir("write-atomic-backup");
+        let path = dir.join("a.txt");
+        write_atomic(&path, b"first", true).unwrap();
+        assert!(!dir.join("a.txt.pk~").exists(), "nothing to back up for a new file");
+        write_atomic(&path, b"second", true).unwrap();
+        assert_eq!(std::fs::read(dir.join("a.txt.pk~")).unwrap(), b"first");
+        assert_eq!(std::fs::read(&path).unwrap(), b"second");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn write_atomic_failure_keeps_original() {
+        let dir = test_dir("write-atomic-failure");
+        let path = dir.join("a.txt");
+        std::fs::write(&path, "original").unwrap();
+        // something in the way of the temporary file
+        let tmp = dir.join(format!(".a.txt.pk-tmp{}", std::process::id()));
+        std::fs::create_dir(&tmp).unwrap();
+        assert!(write_atomic(&path, b"new", true).is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "original");
+        assert!(!dir.join("a.txt.pk~").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn paged_file_editable_once_loaded() {
+        let dir = test_dir("paged-file");
+        let path = dir.join("big.txt");
+        let text: String = (0..PAGE_SIZE / 8).map(|i| format!("{:07}\n", i)).collect::<String>() + "the end\n";
+        std::fs::write(&path, &text).unwrap();
+        let mut srv = Server::new(ServerConfig::load(Path::new("/nonexistent/server.toml"),
+            &ConfigOverrides { page_threshold: Some(1024), ..no_overrides() }).unwrap());
+        let (id, first) = match srv.handle_request(CLIENT, Request::OpenFile { path: path.clone(), workspace: None }) {
+            Response::FileInfo { id, contents, window: Some(w), .. } => { assert!(!w.complete()); (id, contents) },
+            r => panic!("unexpected response {:?}", r)
+        };
+        assert!(matches!(sync(&mut srv, CLIENT, id, "x", 1), Response::Error { .. }));
+        let rest = match srv.handle_request(CLIENT, Request::ReadFileWindow { id, offset: PAGE_SIZE, len: PAGE_SIZE }) {
+            Response::FileWindow { contents, window, .. } => { assert!(window.complete()); contents },
+            r => panic!("unexpected response {:?}", r)
+        };
+        assert_eq!(first + &rest, text);
+        assert!(matches!(sync(&mut srv, CLIENT, id, "x", 1), Response::Ack));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn small_file_over_threshold_not_paged() {
+        let dir = test_dir("small-paged-file");
+        let path = dir.join("a.txt");
+        std::fs::write(&path, "hello\n".repeat(100)).unwrap();
+        let mut srv = Server::new(ServerConfig::load(Path::new("/nonexistent/server.toml"),
+            &ConfigOverrides { page_threshold: Some(10), ..no_overrides() }).unwrap());
+        match srv.handle_request(CLIENT, Request::OpenFile { path, workspace: None }) {
+            Response::FileInfo { window, .. } => assert!(window.is_none()),
+            r => panic!("unexpected response {:?}", r)
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn write_atomic_through_symlink() {
+        let dir = test_dir("write-atomic-symlink");
+        let path = dir.join("a.txt");
+        std::fs::write(&path, "original").unwrap();
+        std::os::unix::fs::symlink(&path, dir.join("link")).unwrap();
+        write_atomic(&dir.join("link"), b"new", false).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "new");
+        assert!(std::fs::symlink_metadata(dir.join("link")).unwrap().file_type().is_symlink());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}